@@ -0,0 +1,74 @@
+// Comparing two baselines, i.e. saved `--format json` outputs, to track how the diagnostics in a
+// tree change over time, e.g. the burn-down of violations which were grandfathered in.
+
+use crate::diagnostic::Diagnostic;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+/// Reads a baseline written by `--format json`.
+pub fn read(path: &Path) -> Result<Vec<Diagnostic>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read baseline {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("failed to parse baseline {}", path.display()))
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    pub added: Vec<Diagnostic>,
+    pub fixed: Vec<Diagnostic>,
+    pub unchanged: Vec<Diagnostic>,
+}
+
+/// Compares the diagnostics in baselines `old` and `new`. Diagnostics are matched by file,
+/// severity, and message, but not by line, so that unrelated edits which move a violation don't
+/// count as fixing it and adding another.
+pub fn compare(old: &[Diagnostic], new: &[Diagnostic]) -> Comparison {
+    let key = |diagnostic: &Diagnostic| {
+        (
+            diagnostic.path.clone(),
+            diagnostic.severity,
+            diagnostic.message.clone(),
+        )
+    };
+    let mut old_by_key = BTreeMap::<_, Vec<&Diagnostic>>::new();
+    for diagnostic in old {
+        old_by_key
+            .entry(key(diagnostic))
+            .or_default()
+            .push(diagnostic);
+    }
+
+    let mut comparison = Comparison::default();
+    for diagnostic in new {
+        match old_by_key
+            .get_mut(&key(diagnostic))
+            .and_then(|matches| matches.pop())
+        {
+            Some(_) => comparison.unchanged.push(diagnostic.clone()),
+            None => comparison.added.push(diagnostic.clone()),
+        }
+    }
+    comparison.fixed = old_by_key.into_values().flatten().cloned().collect();
+    comparison.fixed.sort();
+
+    comparison
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (label, diagnostics) in [
+            ("added", &self.added),
+            ("fixed", &self.fixed),
+            ("unchanged", &self.unchanged),
+        ] {
+            writeln!(f, "{}: {}", label, diagnostics.len())?;
+            for diagnostic in diagnostics {
+                writeln!(f, "  {}", diagnostic)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+// Publishing diagnostics to Bitbucket Cloud as a Code Insights report on the commit being checked,
+// with an annotation for each, so that they show up on the commit and its pull requests.
+
+use crate::diagnostic::{Diagnostic, Severity};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+
+const REPORT_ID: &str = "if-change-then-change";
+
+// Bitbucket limits how many annotations can be sent at once, and how many a report can have.
+const ANNOTATIONS_PER_REQUEST: usize = 100;
+const MAX_ANNOTATIONS: usize = 1000;
+
+/// The annotation for `diagnostic`, the `i`th in the report.
+fn annotation(i: usize, diagnostic: &Diagnostic) -> Value {
+    let mut annotation = json!({
+        "external_id": format!("{}-{}", REPORT_ID, i),
+        "annotation_type": "BUG",
+        "summary": diagnostic.message,
+        "path": diagnostic.path,
+        "severity": match diagnostic.severity {
+            Severity::Error => "HIGH",
+            Severity::Warning => "MEDIUM",
+            Severity::Info => "LOW",
+        },
+    });
+    if let Some(lineno) = diagnostic.start_line {
+        annotation["line"] = json!(lineno + 1);
+    }
+    annotation
+}
+
+/// The report for `diagnostics`, which fails if there are any errors.
+fn report(diagnostics: &[Diagnostic]) -> Value {
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let mut details = format!(
+        "{} errors, {} other problems",
+        errors,
+        diagnostics.len() - errors
+    );
+    if diagnostics.len() > MAX_ANNOTATIONS {
+        details += &format!(
+            " (only the first {} are annotated; see the build log for the rest)",
+            MAX_ANNOTATIONS
+        );
+    }
+    json!({
+        "title": "if-change-then-change",
+        "details": details,
+        "report_type": "BUG",
+        "reporter": "if-change-then-change",
+        "result": if errors > 0 { "FAILED" } else { "PASSED" },
+    })
+}
+
+/// Publishes `diagnostics` as a Code Insights report (replacing the one from any earlier run)
+/// on $BITBUCKET_COMMIT of $BITBUCKET_WORKSPACE/$BITBUCKET_REPO_SLUG, as set in Pipelines.
+///
+/// With $BITBUCKET_ACCESS_TOKEN, requests are authenticated with it; otherwise they're sent
+/// through the Pipelines proxy, which authenticates them on the build's behalf.
+pub fn publish(diagnostics: &[Diagnostic]) -> Result<()> {
+    let env = |name: &str| {
+        std::env::var(name).map_err(|_| anyhow!("{} must be set to publish a report", name))
+    };
+    let token = std::env::var("BITBUCKET_ACCESS_TOKEN").ok();
+    let (agent, default_api_url) = match &token {
+        Some(_) => (ureq::agent(), "https://api.bitbucket.org/2.0"),
+        None => (
+            ureq::AgentBuilder::new()
+                .proxy(ureq::Proxy::new("localhost:29418")?)
+                .build(),
+            // The proxy can only authenticate what it can read, i.e. plain HTTP.
+            "http://api.bitbucket.org/2.0",
+        ),
+    };
+    let api_url = std::env::var("BITBUCKET_API_URL").unwrap_or(default_api_url.to_string());
+    let report_url = format!(
+        "{}/repositories/{}/{}/commit/{}/reports/{}",
+        api_url,
+        env("BITBUCKET_WORKSPACE")?,
+        env("BITBUCKET_REPO_SLUG")?,
+        env("BITBUCKET_COMMIT")?,
+        REPORT_ID
+    );
+    let request = |method: &str, url: &str| {
+        let request = agent.request(method, url);
+        match &token {
+            Some(token) => request.set("Authorization", &format!("Bearer {}", token)),
+            None => request,
+        }
+    };
+
+    // Replacing a report keeps its annotations, so delete it (if there is one) first.
+    match request("DELETE", &report_url).call() {
+        Ok(_) | Err(ureq::Error::Status(404, _)) => {}
+        Err(err) => return Err(anyhow!("DELETE {} failed: {}", report_url, err)),
+    }
+    request("PUT", &report_url)
+        .send_json(report(diagnostics))
+        .map_err(|err| anyhow!("PUT {} failed: {}", report_url, err))?;
+
+    let annotations = diagnostics
+        .iter()
+        .take(MAX_ANNOTATIONS)
+        .enumerate()
+        .map(|(i, diagnostic)| annotation(i, diagnostic))
+        .collect::<Vec<_>>();
+    let annotations_url = format!("{}/annotations", report_url);
+    for batch in annotations.chunks(ANNOTATIONS_PER_REQUEST) {
+        request("POST", &annotations_url)
+            .send_json(batch)
+            .map_err(|err| anyhow!("POST {} failed: {}", annotations_url, err))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bitbucket::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn report_and_annotations() -> anyhow::Result<()> {
+        let diagnostics = [
+            Diagnostic {
+                path: "b.sh".to_string(),
+                start_line: Some(2),
+                end_line: Some(5),
+                message: "expected change here due to change in a.sh:2-5".to_string(),
+                ..Default::default()
+            },
+            Diagnostic {
+                path: "c.sh".to_string(),
+                message: "then-change target is optional".to_string(),
+                severity: Severity::Warning,
+                ..Default::default()
+            },
+        ];
+
+        assert_that!(report(&diagnostics)["result"]).is_equal_to(json!("FAILED"));
+        assert_that!(report(&diagnostics[1..])["result"]).is_equal_to(json!("PASSED"));
+        assert_that!(annotation(0, &diagnostics[0])).is_equal_to(json!({
+            "external_id": "if-change-then-change-0",
+            "annotation_type": "BUG",
+            "summary": "expected change here due to change in a.sh:2-5",
+            "path": "b.sh",
+            "severity": "HIGH",
+            "line": 3,
+        }));
+        assert_that!(annotation(1, &diagnostics[1]).get("line")).is_none();
+
+        Ok(())
+    }
+}
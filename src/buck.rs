@@ -0,0 +1,91 @@
+// Support for being run from Buck2 build rules, which pass file lists in argfiles and may refer
+// to files by their location under buck-out rather than in the source tree.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::ffi::OsString;
+
+/// Replaces every "@path" in `args` with the arguments listed in the file at `path`, one per
+/// line (as Buck2 writes them, with any shell quoting). The program name is left alone.
+pub fn expand_argfiles(args: impl IntoIterator<Item = OsString>) -> Result<Vec<OsString>> {
+    let mut expanded = Vec::new();
+    for (i, arg) in args.into_iter().enumerate() {
+        match arg.to_str().and_then(|arg| arg.strip_prefix('@')) {
+            Some(path) if i > 0 && !path.is_empty() => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read argfile {}", path))?;
+                expanded.extend(parse_argfile(&contents).into_iter().map(OsString::from));
+            }
+            _ => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+fn parse_argfile(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let quoted = |quote| {
+                line.strip_prefix(quote)
+                    .and_then(|line: &str| line.strip_suffix(quote))
+            };
+            match (quoted('\''), quoted('"')) {
+                // In single quotes, the only escape is closing the quotes around a quote: '\''
+                (Some(unquoted), _) => unquoted.replace("'\\''", "'"),
+                (_, Some(unquoted)) => unquoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+                _ => line.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Maps `path` to the file in the source tree it was built from, if it's an output under
+/// buck-out (e.g. of a rule which copies sources), and otherwise returns it unchanged.
+pub fn source_path(path: &str) -> String {
+    // buck-out/v2/gen/<cell>/<configuration hash>/<package>/__<target>__/<path within package>
+    let buck_out_path =
+        Regex::new(r"^(?:.*/)?buck-out/v2/gen(?:-anon)?/[^/]+/[0-9a-f]+/(?:(.*)/)?__[^/]+__/(.+)$")
+            .expect("static regex is valid");
+    match buck_out_path.captures(path) {
+        Some(captures) => match captures.get(1) {
+            Some(package) => format!("{}/{}", package.as_str(), &captures[2]),
+            None => captures[2].to_string(),
+        },
+        None => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::buck::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn argfiles_and_buck_out_paths() -> anyhow::Result<()> {
+        assert_that!(parse_argfile(
+            "src/a.sh\n'src/it'\\''s.sh'\n\"src/b c.sh\"\n\n"
+        ))
+        .is_equal_to(vec![
+            "src/a.sh".to_string(),
+            "src/it's.sh".to_string(),
+            "src/b c.sh".to_string(),
+        ]);
+
+        assert_that!(source_path(
+            "buck-out/v2/gen/root/904931f735703749/src/config/__copy__/settings.sh"
+        ))
+        .is_equal_to("src/config/settings.sh".to_string());
+        assert_that!(source_path(
+            "/repo/buck-out/v2/gen/root/904931f735703749/__top__/a.sh"
+        ))
+        .is_equal_to("a.sh".to_string());
+        assert_that!(source_path("src/config/settings.sh"))
+            .is_equal_to("src/config/settings.sh".to_string());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1930 @@
+use crate::codeowners::CodeOwners;
+use crate::comment;
+use crate::config::{Config, Rule, CONFIG_FILE};
+use crate::date;
+use crate::diagnostic::{Diagnostic, Location, Severity};
+use crate::fix::Fix;
+use crate::git;
+use crate::if_change_then_change2::{
+    forward_slashes, BlockKey, BlockNode, Correspondence, FileNode, FileNodeParseError,
+    Granularity, ParseOptions,
+};
+use crate::suggest;
+use crate::tree::{self, Contents, Tree};
+use anyhow::{Context, Result};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use sha2::Digest;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// Parse results for every file we've read, keyed by path and content hash, so that long-lived
+/// processes (e.g. the daemon) only re-parse files whose contents have actually changed.
+///
+/// With a directory (see `on_disk`), successful parses are also saved there, so that separate
+/// runs (e.g. in hooks and CI) can share them. Entries are never evicted; deleting the directory
+/// clears the cache.
+///
+/// A resident cache (see `resident`) also keeps the contents of every working tree file it reads,
+/// so that re-checking a file whose mtime and size haven't changed doesn't read it again.
+#[derive(Default)]
+pub struct ParseCache {
+    entries: HashMap<String, (u64, Result<FileNode, FileNodeParseError>)>,
+    dir: Option<PathBuf>,
+    // Locked only to look files up and store them, so that files can be read concurrently.
+    files: Option<Mutex<HashMap<String, ResidentFile>>>,
+}
+
+/// A working tree file's contents (as returned by `Tree::read_lossy`), and the metadata they
+/// were read with.
+struct ResidentFile {
+    modified: SystemTime,
+    len: u64,
+    contents: (String, bool),
+}
+
+impl ParseCache {
+    pub fn on_disk(dir: PathBuf) -> ParseCache {
+        ParseCache {
+            dir: Some(dir),
+            ..Default::default()
+        }
+    }
+
+    /// A cache for long-lived processes (e.g. the daemon), which keeps file contents in memory.
+    pub fn resident() -> ParseCache {
+        ParseCache {
+            files: Some(Mutex::default()),
+            ..Default::default()
+        }
+    }
+
+    /// Reads `path` from `tree`, from memory if this is a resident cache and the file hasn't
+    /// been modified since it was last read.
+    pub fn read(&self, tree: &Tree, path: &str) -> std::io::Result<(Contents, bool)> {
+        let Some(files) = self
+            .files
+            .as_ref()
+            .filter(|_| matches!(tree, Tree::WorkingTree))
+        else {
+            return tree.read_contents(path);
+        };
+        let files = || files.lock().expect("resident files lock is not poisoned");
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                files().remove(path);
+                return Err(err);
+            }
+        };
+        let modified = metadata.modified()?;
+        if let Some(file) = files().get(path) {
+            if file.modified == modified && file.len == metadata.len() {
+                log::debug!("resident file cache hit: {}", path);
+                let (contents, lossy) = &file.contents;
+                return Ok((Contents::Owned(contents.clone()), *lossy));
+            }
+        }
+
+        let read_at = SystemTime::now();
+        let (contents, lossy) = tree.read_contents(path)?;
+        // Like git's index, we can't trust an mtime from the last second or so: the file could
+        // be written again without its mtime changing, so it's re-read until it's old enough.
+        let settled = read_at
+            .duration_since(modified)
+            .is_ok_and(|age| age >= Duration::from_secs(1));
+        match &contents {
+            // Mapped files are large, and cheap to map again, so they're not kept in memory.
+            Contents::Owned(owned) if settled => {
+                files().insert(
+                    path.to_string(),
+                    ResidentFile {
+                        modified,
+                        len: metadata.len(),
+                        contents: (owned.clone(), lossy),
+                    },
+                );
+            }
+            _ => {
+                files().remove(path);
+            }
+        }
+        Ok((contents, lossy))
+    }
+
+    pub fn parse(
+        &mut self,
+        path: &str,
+        contents: &str,
+        parse_options: &ParseOptions,
+    ) -> Result<FileNode, FileNodeParseError> {
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if let Some((cached_hash, parsed)) = self.entries.get(path) {
+            if *cached_hash == hash {
+                log::debug!("parse cache hit: {}", path);
+                return parsed.clone();
+            }
+        }
+
+        let parsed = match &self.dir {
+            Some(dir) => ParseCache::parse_on_disk(dir, path, contents, parse_options),
+            None => FileNode::from_str_with_options(path, contents, parse_options),
+        };
+        self.entries
+            .insert(path.to_string(), (hash, parsed.clone()));
+        parsed
+    }
+
+    fn parse_on_disk(
+        dir: &Path,
+        path: &str,
+        contents: &str,
+        parse_options: &ParseOptions,
+    ) -> Result<FileNode, FileNodeParseError> {
+        // Unlike DefaultHasher, this is stable across builds. How a file parses depends on the
+        // version of this tool and the parse options, as well as its path and contents.
+        let key = sha2::Sha256::new()
+            .chain_update(env!("CARGO_PKG_VERSION"))
+            .chain_update([0])
+            .chain_update(format!("{:?}", parse_options))
+            .chain_update([0])
+            .chain_update(path)
+            .chain_update([0])
+            .chain_update(contents)
+            .finalize();
+        let entry = dir.join(format!("{:x}.json", key));
+
+        if let Some(file_node) = std::fs::read_to_string(&entry)
+            .ok()
+            .and_then(|cached| serde_json::from_str(&cached).ok())
+        {
+            log::debug!("on-disk parse cache hit: {}", path);
+            return Ok(file_node);
+        }
+
+        let parsed = FileNode::from_str_with_options(path, contents, parse_options);
+        // Files which fail to parse are rare, and cheap to re-parse, so only successes are saved.
+        if let Ok(file_node) = &parsed {
+            // Written to a temporary file first, so that concurrent runs never read a partial entry.
+            let tmp = entry.with_extension(format!("{}.tmp", std::process::id()));
+            let written = std::fs::create_dir_all(dir)
+                .and_then(|_| std::fs::write(&tmp, serde_json::to_string(file_node)?))
+                .and_then(|_| std::fs::rename(&tmp, &entry));
+            if let Err(err) = written {
+                log::warn!("failed to write parse cache entry for {}: {}", path, err);
+            }
+        }
+        parsed
+    }
+
+    /// How many files are in the cache, and how many blocks were parsed from them.
+    pub fn counts(&self) -> (usize, usize) {
+        let blocks = self
+            .entries
+            .values()
+            .filter_map(|(_, parsed)| parsed.as_ref().ok())
+            .map(|file_node| file_node.blocks.len())
+            .sum();
+        (self.entries.len(), blocks)
+    }
+}
+
+/// The message for a diagnostic about `expected` (where a then-change of the block at `origin`
+/// points) not having changed along with it, with both ends of the coupling spelled out the same
+/// way. `note` is appended as is.
+pub fn missing_change_message(origin: &Location, expected: &str, note: &str) -> String {
+    format!(
+        "change in {} requires a change in {} \u{2014} none found{}",
+        origin.position(),
+        expected,
+        note
+    )
+}
+
+/// The message for a diagnostic about `target` (a then-change of the block at `origin`) not
+/// having a corresponding block.
+pub fn missing_block_message(target: &BlockKey, origin: &Location) -> String {
+    match &target.name {
+        Some(name) => format!(
+            "expected an if-change({}) in this file that matches {}",
+            name,
+            origin.position()
+        ),
+        None => format!(
+            "expected an if-change-then-change in this file that matches {}",
+            origin.position()
+        ),
+    }
+}
+
+/// The fix for a then-change `target` of `block` with no corresponding block: adding one which
+/// points back at `block`.
+fn scaffold_fix(
+    block: &BlockNode,
+    target: &BlockKey,
+    contents_by_path: &HashMap<String, Contents>,
+) -> Fix {
+    let origin_content = contents_by_path
+        .get(&block.key.path)
+        .map(|contents| {
+            contents
+                .lines()
+                .skip(block.body_range().start)
+                .take(block.body_range().len())
+                .collect::<Vec<_>>()
+                .join("\n")
+        })
+        .unwrap_or_default();
+    Fix::ScaffoldBlock {
+        name: target.name.clone(),
+        then_change: match &block.key.name {
+            Some(name) => format!("{}#{}", block.key.path, name),
+            None => block.key.path.clone(),
+        },
+        origin_content,
+    }
+}
+
+/// Attaches the fix removing the then-change targets on `linenos` of a block, which do not exist,
+/// to `diagnostics` about those lines. They share the one fix, so that if it removes every target
+/// of the block, it removes the block too.
+pub fn attach_remove_fix(diagnostics: &mut [Diagnostic], linenos: Vec<usize>) {
+    if linenos.is_empty() {
+        return;
+    }
+    for diagnostic in diagnostics.iter_mut() {
+        if diagnostic
+            .start_line
+            .is_some_and(|lineno| linenos.contains(&lineno))
+        {
+            diagnostic.fix = Some(Fix::RemoveThenChange {
+                linenos: linenos.clone(),
+            });
+        }
+    }
+}
+
+/// A warning that the then-change target on line `lineno` of `path` still references `old_path`,
+/// which the diff renames to `new_path`.
+pub fn renamed_target_warning(
+    path: &str,
+    lineno: usize,
+    old_path: &str,
+    new_path: &str,
+) -> Diagnostic {
+    Diagnostic {
+        path: path.to_string(),
+        start_line: Some(lineno),
+        end_line: None,
+        message: format!(
+            "then-change references '{}', which this diff renames to '{}'; update the then-change to match",
+            old_path, new_path
+        ),
+        severity: Severity::Warning,
+        fix: Some(Fix::RenameThenChange {
+            lineno,
+            old_path: old_path.to_string(),
+            new_path: new_path.to_string(),
+        }),
+        ..Default::default()
+    }
+}
+
+/// A warning that `path` had to be decoded lossily (see tree::decode), so that any non-ASCII
+/// text in its directives, e.g. block names, may not match what's written elsewhere.
+pub fn lossy_decoding_warning(path: &str) -> Diagnostic {
+    Diagnostic {
+        path: path.to_string(),
+        start_line: None,
+        end_line: None,
+        message: "file is not valid UTF-8, so invalid bytes were replaced when reading it; its directives are still enforced, but non-ASCII text in them may not match".to_string(),
+        severity: Severity::Warning,
+        ..Default::default()
+    }
+}
+
+/// A note that `path` has directives but was not parsed for blocks, since it says it's generated
+/// (see ParseOptions::is_generated), in case it's only mistaken for a generated file.
+pub fn generated_file_info(path: &str) -> Diagnostic {
+    Diagnostic {
+        path: path.to_string(),
+        start_line: None,
+        end_line: None,
+        message:
+            "file is marked as generated, so its if-change-then-change directives were not checked"
+                .to_string(),
+        severity: Severity::Info,
+        ..Default::default()
+    }
+}
+
+/// An error about the first unresolved merge conflict in `contents`, if there is one, i.e. lines
+/// starting with "<<<<<<<", "=======" and ">>>>>>>", in that order. Conflicted files are not
+/// parsed, since the directives on both sides would be tangled together.
+pub fn merge_conflict_diagnostic(path: &str, contents: &str) -> Option<Diagnostic> {
+    let mut start = None;
+    let mut separated = false;
+    for (i, line) in contents.lines().enumerate() {
+        if line.starts_with("<<<<<<<") {
+            (start, separated) = (Some(i), false);
+        } else if line.starts_with("=======") && start.is_some() {
+            separated = true;
+        } else if line.starts_with(">>>>>>>") && separated {
+            return Some(Diagnostic {
+                path: path.to_string(),
+                start_line: start,
+                end_line: Some(i + 1),
+                message: "file contains unresolved merge conflicts; skipping".to_string(),
+                ..Default::default()
+            });
+        }
+    }
+    None
+}
+
+/// A warning about `block` if it has expired as of `today` (a YYYY-MM-DD date).
+pub fn expired_block_diagnostic(block: &BlockNode, today: &str) -> Option<Diagnostic> {
+    let expires = block.expires.as_deref()?;
+    if expires >= today {
+        return None;
+    }
+
+    Some(Diagnostic {
+        path: block.key.path.clone(),
+        start_line: Some(block.content_range().start),
+        end_line: None,
+        message: format!(
+            "if-change expired on {}: check whether this coupling still holds, then extend or remove the expiry",
+            expires
+        ),
+        severity: Severity::Warning,
+        ..Default::default()
+    })
+}
+
+/// An error about `block` if its hash does not match its content in `contents` (the contents of
+/// the file it's in).
+pub fn stale_hash_diagnostic(block: &BlockNode, contents: &str) -> Option<Diagnostic> {
+    if !block.has_stale_hash(contents) {
+        return None;
+    }
+
+    Some(Diagnostic {
+        path: block.key.path.clone(),
+        start_line: Some(block.content_range().start),
+        end_line: None,
+        message: "if-change content no longer matches its hash: check that its then-change targets are still in sync, then run `to-be-named lint --update-digests`".to_string(),
+        ..Default::default()
+    })
+}
+
+/// Warnings about then-change targets of `block` which repeat an earlier one, which are removed
+/// from `block` so that they don't produce duplicate diagnostics; see BlockNode::dedup_then_change.
+pub fn duplicate_target_warnings(block: &mut BlockNode) -> Vec<Diagnostic> {
+    block
+        .dedup_then_change()
+        .into_iter()
+        .map(|((then_change_lineno, then_change_key), first_lineno)| {
+            let target = then_change_key.to_string();
+            let first = block
+                .then_change
+                .iter()
+                .find(|(lineno, _)| *lineno == first_lineno)
+                .map(|(_, key)| key.to_string())
+                .unwrap_or_default();
+            let message = if target == first {
+                format!(
+                    "then-change lists '{}' more than once (first on line {}), so this one is ignored",
+                    target,
+                    first_lineno + 1
+                )
+            } else {
+                format!(
+                    "then-change target '{}' is the same as '{}' on line {}, so it is ignored",
+                    target,
+                    first,
+                    first_lineno + 1
+                )
+            };
+            Diagnostic {
+                path: block.key.path.clone(),
+                start_line: Some(then_change_lineno),
+                end_line: None,
+                message,
+                severity: Severity::Warning,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+/// Which changes don't count as modifications to a range of lines; see Config::ignore_whitespace,
+/// Config::ignore_comments and BlockNode::ignore.
+#[derive(Clone, Copy, Debug, Default)]
+struct ChangeFilter<'a> {
+    ignore_whitespace: bool,
+    ignore_comments: bool,
+    ignore: Option<&'a Regex>,
+}
+
+/// Whether `diff` adds or removes any lines within `range` (0-indexed, post-diff line numbers).
+///
+/// With `ignore_whitespace`, a run of added and removed lines only counts if it changes something
+/// other than whitespace, e.g. reindenting or rewrapping lines doesn't count. With
+/// `ignore_comments`, added and removed lines which are only comments don't count at all, and
+/// neither do lines matching `ignore`.
+fn modifies_range(diff: &unidiff::PatchedFile, range: &Range<usize>, filter: ChangeFilter) -> bool {
+    let run_modifies_range =
+        |(in_range, removed, added): (bool, String, String)| in_range && removed != added;
+
+    for hunk in diff.hunks() {
+        // TODO- we can skip hunks with no intersection
+        // The post-diff line (0-indexed) that the next line of the hunk comes before. Hunks which
+        // add nothing (e.g. pure deletions with no context) start after target_start instead of
+        // at it.
+        let mut next_lineno = if hunk.target_length == 0 {
+            hunk.target_start
+        } else {
+            hunk.target_start.saturating_sub(1)
+        };
+        // Whether the current run of added and removed lines touches `range`, and the text it
+        // removes and adds, without whitespace.
+        let mut run: Option<(bool, String, String)> = None;
+        for line in hunk.lines() {
+            let in_range = match line.target_line_no {
+                // target_line_no is 1-indexed
+                Some(lineno) => {
+                    next_lineno = lineno;
+                    range.contains(&(lineno - 1))
+                }
+                // Removed lines sit between two post-diff lines, and are only in range if both
+                // of those lines are.
+                None => range.start < next_lineno && next_lineno < range.end,
+            };
+            if !line.is_added() && !line.is_removed() {
+                if run.take().is_some_and(run_modifies_range) {
+                    return true;
+                }
+                continue;
+            }
+            if filter.ignore_comments && comment::is_comment_line(&diff.target_file, &line.value)
+                || filter
+                    .ignore
+                    .is_some_and(|ignore| ignore.is_match(&line.value))
+            {
+                continue;
+            }
+            if in_range && !filter.ignore_whitespace {
+                return true;
+            }
+            let (run_in_range, removed, added) = run.get_or_insert_default();
+            *run_in_range |= in_range;
+            let text = if line.is_added() { added } else { removed };
+            text.extend(line.value.chars().filter(|ch| !ch.is_whitespace()));
+        }
+        if run.is_some_and(run_modifies_range) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether `diff` adds line `lineno` (0-indexed, post-diff), as opposed to leaving it as it was.
+fn adds_line(diff: &unidiff::PatchedFile, lineno: usize) -> bool {
+    diff.hunks()
+        .iter()
+        .flat_map(|hunk| hunk.lines())
+        .any(|line| line.is_added() && line.target_line_no == Some(lineno + 1))
+}
+
+/// Whether `diff` modifies `range` of `block`, other than by lines matching its ignore pattern.
+fn modifies_block(
+    diff: &unidiff::PatchedFile,
+    block: &BlockNode,
+    range: &Range<usize>,
+    filter: ChangeFilter,
+) -> bool {
+    // Patterns were validated when parsing.
+    let ignore = block
+        .ignore
+        .as_ref()
+        .and_then(|pattern| Regex::new(pattern).ok());
+    modifies_range(
+        diff,
+        range,
+        ChangeFilter {
+            ignore: ignore.as_ref(),
+            ..filter
+        },
+    )
+}
+
+/// The blocks that `block`'s then-changes require changes to, i.e. the corresponding block of each
+/// of its targets, if they have one. Optional targets, and the targets of then-change-any blocks,
+/// aren't required individually, so they're left out.
+fn required_blocks<'a>(
+    block: &'a BlockNode,
+    file_nodes_by_path: &'a HashMap<String, FileNode>,
+) -> impl Iterator<Item = &'a BlockNode> {
+    block
+        .then_change
+        .iter()
+        .filter(|(_, then_change_key)| !block.then_change_any && !then_change_key.optional)
+        .filter_map(|(_, then_change_key)| {
+            match file_nodes_by_path
+                .get(&then_change_key.path)?
+                .correspondence(block, then_change_key)
+            {
+                Correspondence::Block(regions) => Some(regions[0]),
+                _ => None,
+            }
+        })
+}
+
+/// The shortest cycle of then-changes from `start` back to itself (see required_blocks), starting
+/// with `start`, if it goes through at least two other blocks. Blocks which point at each other
+/// directly are the usual way of coupling two blocks, and don't need pointing out as a cycle.
+fn find_cycle<'a>(
+    start: &'a BlockNode,
+    file_nodes_by_path: &'a HashMap<String, FileNode>,
+) -> Option<Vec<&'a BlockNode>> {
+    // Breadth-first, so that the first path back to `start` is the shortest.
+    let mut parents: BTreeMap<Location, &BlockNode> = BTreeMap::new();
+    let mut queue = VecDeque::from([start]);
+    while let Some(block) = queue.pop_front() {
+        for next in required_blocks(block, file_nodes_by_path) {
+            if next == start {
+                let mut cycle = vec![block];
+                while let Some(parent) = parents.get(&cycle[cycle.len() - 1].location()) {
+                    cycle.push(parent);
+                }
+                cycle.reverse();
+                return (cycle.len() >= 3).then_some(cycle);
+            }
+            if let btree_map::Entry::Vacant(entry) = parents.entry(next.location()) {
+                entry.insert(block);
+                queue.push_back(next);
+            }
+        }
+    }
+    None
+}
+
+/// The shortest chain of then-changes (see required_blocks) from `start` to each block which
+/// can only be reached through some other block, i.e. is not one of `start`'s own targets.
+fn transitive_chains<'a>(
+    start: &'a BlockNode,
+    file_nodes_by_path: &'a HashMap<String, FileNode>,
+) -> Vec<Vec<&'a BlockNode>> {
+    let mut chains = Vec::new();
+    let mut visited = BTreeSet::from([start.location()]);
+    let mut queue = VecDeque::from([vec![start]]);
+    while let Some(chain) = queue.pop_front() {
+        for next in required_blocks(chain[chain.len() - 1], file_nodes_by_path) {
+            if !visited.insert(next.location()) {
+                continue;
+            }
+            let mut next_chain = chain.clone();
+            next_chain.push(next);
+            if next_chain.len() > 2 {
+                chains.push(next_chain.clone());
+            }
+            queue.push_back(next_chain);
+        }
+    }
+    chains
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub tree: Tree,
+    pub config: Config,
+    // Whether to report directives that look like they're in string literals; see
+    // ParseOptions::strict.
+    pub strict: bool,
+    // Whether to report then-change targets whose corresponding block does not point back at
+    // the block with the then-change, i.e. couplings which are only enforced in one direction.
+    pub require_reciprocal: bool,
+    // How many files to read at once; see map_concurrently.
+    pub io_concurrency: usize,
+    // Whether then-change targets which do not exist are only reported as info, for trees which
+    // are known to be missing some of what they reference (e.g. exported subsets of a repo).
+    pub allow_missing_targets: bool,
+    // Which changed files to check, and which files to report diagnostics in.
+    pub paths: PathFilter,
+}
+
+/// Which paths are in scope, from --only and --exclude: those matching any `only` pattern (or
+/// all of them, if there are none) and no `exclude` pattern. Patterns are globs, or directories
+/// followed by "/..." for everything under them, e.g. "services/payments/...".
+#[derive(Clone, Debug, Default)]
+pub struct PathFilter {
+    only: Vec<GlobMatcher>,
+    exclude: Vec<GlobMatcher>,
+}
+
+impl PathFilter {
+    pub fn new(only: &[String], exclude: &[String]) -> Result<PathFilter> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    let glob = match pattern.strip_suffix("...") {
+                        Some(dir) if dir.is_empty() || dir.ends_with('/') => format!("{}**", dir),
+                        _ => pattern.clone(),
+                    };
+                    Glob::new(&glob)
+                        .map(|glob| glob.compile_matcher())
+                        .with_context(|| format!("invalid path pattern: '{}'", pattern))
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(PathFilter {
+            only: compile(only)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        (self.only.is_empty() || self.only.iter().any(|only| only.is_match(path)))
+            && !self.exclude.iter().any(|exclude| exclude.is_match(path))
+    }
+}
+
+/// How many files are read at once by default, which mostly matters on network filesystems,
+/// and when reading from a git revision (a process per file).
+pub const DEFAULT_IO_CONCURRENCY: usize = 8;
+
+impl Options {
+    /// Options for checking diffs with paths relative to the current directory, which is also
+    /// where the config is loaded from.
+    pub fn load() -> Result<Options> {
+        Ok(Options {
+            config: Config::load(Path::new("."))?.unwrap_or_default(),
+            io_concurrency: DEFAULT_IO_CONCURRENCY,
+            ..Default::default()
+        })
+    }
+}
+
+/// Calls `f` on every item in `items` from up to `concurrency` threads at once, returning the
+/// results in the same order as `items`.
+fn map_concurrently<T: Sync, R: Send>(
+    items: &[T],
+    concurrency: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    if concurrency <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let workers = (0..concurrency.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(item) = items.get(i) else {
+                            return results;
+                        };
+                        results.push((i, f(item)));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("worker threads do not panic"))
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// What came of trying to read a file found by the search in `check_diff`.
+enum FileRead {
+    /// The file is over the size limit (the second element), so it wasn't read.
+    TooLarge(u64, u64),
+    Read(std::io::Result<(Contents, bool)>),
+}
+
+/// Diagnostics for every `rule` whose `when` glob matches a path in `changed_paths`, but whose
+/// `then` glob matches none of them.
+fn rule_diagnostics(rules: &[Rule], changed_paths: &HashSet<String>) -> Result<Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    for rule in rules {
+        let (when, then) = rule.matchers()?;
+        if changed_paths.iter().any(|path| then.is_match(path)) {
+            continue;
+        }
+        for path in changed_paths.iter().filter(|path| when.is_match(path)) {
+            let origin = Location {
+                path: path.clone(),
+                ..Default::default()
+            };
+            diagnostics.push(Diagnostic {
+                path: rule.then.clone(),
+                start_line: None,
+                end_line: None,
+                message: missing_change_message(
+                    &origin,
+                    &format!("some file matching '{}'", rule.then),
+                    &format!(" (rule in {})", CONFIG_FILE),
+                ),
+                related: Some(origin),
+                ..Default::default()
+            });
+        }
+    }
+    Ok(diagnostics)
+}
+
+/// A parsed diff, along with what unidiff doesn't tell us about it.
+pub struct Diff {
+    patch_set: unidiff::PatchSet,
+    is_git_diff: bool,
+    /// The files renamed by the diff, old path to new path. unidiff doesn't expose git's rename
+    /// headers (and skips renames without changes entirely), so we pick them out ourselves.
+    pub renames: HashMap<String, String>,
+}
+
+impl Diff {
+    /// The directory to check the diff from, if not the current one: the root of the enclosing
+    /// git repository, when the diff's paths are relative to it rather than to the current
+    /// directory, as `git diff`'s are even when it's run from a subdirectory.
+    pub fn root(&self) -> Option<PathBuf> {
+        let root = git::repo_root().ok()?;
+        if std::env::current_dir().ok()?.canonicalize().ok()? == root.canonicalize().ok()? {
+            return None;
+        }
+        let paths = self
+            .patch_set
+            .files()
+            .iter()
+            .map(|patched_file| patched_file.target_file.as_str())
+            .filter(|path| *path != "/dev/null")
+            .map(|path| match self.is_git_diff {
+                true => path.strip_prefix("b/").unwrap_or(path),
+                false => path,
+            })
+            .collect::<Vec<_>>();
+        let any_exist_under = |dir: &Path| paths.iter().any(|path| dir.join(path).exists());
+        (!any_exist_under(Path::new(".")) && any_exist_under(&root)).then_some(root)
+    }
+}
+
+/// Parses the diff read from `input` as it's read, a file at a time, so that large diffs aren't
+/// held in memory twice (once as text, and again as a PatchSet).
+pub fn parse_diff(mut input: impl BufRead) -> Result<Diff> {
+    let mut patch_set = unidiff::PatchSet::new();
+    let mut is_git_diff = None;
+    let mut renames = HashMap::new();
+    let mut rename_from = None;
+
+    // The text of the file currently being read, which is complete once the next starts.
+    let mut section = String::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input
+            .read_until(b'\n', &mut line)
+            .context("Error reading diff")?
+            == 0
+        {
+            break;
+        }
+        // Diffs of files in legacy encodings aren't valid UTF-8 either; see tree::decode.
+        let mut line = String::from_utf8_lossy(&line).into_owned();
+        // unidiff would otherwise leave the "\r" of CRLF diffs (e.g. from Windows checkouts) at
+        // the end of paths and lines; files themselves are split with str::lines, which drops it.
+        if line.ends_with("\r\n") {
+            line.truncate(line.len() - 2);
+            line.push('\n');
+        }
+        is_git_diff.get_or_insert_with(|| line.starts_with("diff --git"));
+
+        // Every file in a git diff (or `diff -r`) starts with a "diff" line, which can't be part
+        // of a hunk. Plain diffs have nothing to split them on, so they're parsed in one go.
+        if line.starts_with("diff ") && !section.is_empty() {
+            patch_set.parse(&section).context("Error parsing diff")?;
+            section.clear();
+        }
+        if let Some(path) = line.strip_prefix("rename from ") {
+            rename_from = Some(path.trim_end_matches('\n').to_string());
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            if let Some(rename_from) = rename_from.take() {
+                renames.insert(rename_from, path.trim_end_matches('\n').to_string());
+            }
+        }
+        section.push_str(&line);
+    }
+    patch_set.parse(&section).context("Error parsing diff")?;
+
+    // Diffs made on Windows by tools other than git may use "\" in paths, which would otherwise
+    // never match the "/" of directives and of the paths we walk.
+    for patched_file in patch_set.files_mut() {
+        patched_file.source_file = forward_slashes(&patched_file.source_file);
+        patched_file.target_file = forward_slashes(&patched_file.target_file);
+    }
+    let renames = renames
+        .into_iter()
+        .map(|(from, to)| (forward_slashes(&from), forward_slashes(&to)))
+        .collect();
+
+    Ok(Diff {
+        patch_set,
+        is_git_diff: is_git_diff.unwrap_or_default(),
+        renames,
+    })
+}
+
+/// Checks a diff (as read from stdin) against the if-change-then-change blocks in the files it
+/// touches, and returns the resulting diagnostics in sorted order.
+pub fn check(input: String, options: &Options, cache: &mut ParseCache) -> Result<Vec<Diagnostic>> {
+    check_diff(&parse_diff(input.as_bytes())?, options, cache)
+}
+
+/// Like `check`, for a diff which has already been parsed.
+pub fn check_diff(
+    diff: &Diff,
+    options: &Options,
+    cache: &mut ParseCache,
+) -> Result<Vec<Diagnostic>> {
+    let mut diagnostics = Vec::new();
+    let Diff {
+        patch_set,
+        is_git_diff,
+        renames,
+    } = diff;
+    let is_git_diff = *is_git_diff;
+
+    // We want to key this map by the path at HEAD corresponding to a given diff
+    let diffs_by_post_diff_path = patch_set
+        .files()
+        .iter()
+        .inspect(|patched_file| {
+            log::info!("patched file in diff: {}", patched_file.target_file);
+        })
+        .filter_map(|patched_file| {
+            if is_git_diff {
+                let source_path_valid = patched_file.source_file.starts_with("a/") || patched_file.source_file == "/dev/null";
+                let target_path_valid = patched_file.target_file.starts_with("b/") || patched_file.target_file == "/dev/null";
+
+                // Do some light git diff validation. There are only two cases where the source file and target file are not
+                // prefixed with "a/" and "b/" respectively: when a file has been added (source file is /dev/null) and when
+                // a file has been deleted (target file is /dev/null).
+                if !source_path_valid || !target_path_valid {
+                    diagnostics.push(Diagnostic {
+                        path: "stdin".to_string(),
+                        // TODO- $lines should reference the lines of the diff
+                        start_line: None,
+                        end_line: None,
+                        message: format!(
+                            "invalid git diff: expected a/before.path -> b/after.path, but got '{}' -> '{}'",
+                            patched_file.source_file,
+                            patched_file.target_file,
+                        ),
+                        ..Default::default()
+                    });
+                    return None;
+                }
+
+                if patched_file.target_file.starts_with("b/") {
+                    // In a "diff --git", the pre-diff and post-diff paths are prefixed with "a/" and "b/". We have
+                    // to strip these prefixes ourselves, because unidiff::PatchedFile does not expose metadata about
+                    // whether or not it represents a "diff --git" or normal diff. (PatchedFile.path() does do some
+                    // stripping here, but it uses the source file and is poorly implemented.)
+                    Some((patched_file.target_file[2..].to_string(), patched_file))
+                } else {
+                    // We don't index deleted files in diffs_by_post_diff_path, because we can't read a deleted file
+                    // (after we build this hashmap, the next thing we do is parse if-change-then-change blocks out
+                    // of all files changed in the diff).
+                    None
+                }
+            } else {
+                if patched_file.target_file == "/dev/null" {
+                    return None;
+                }
+
+                Some((patched_file.target_file.clone(), patched_file))
+            }
+        })
+        .collect::<HashMap<String, &unidiff::PatchedFile>>();
+
+    // Every path touched by the diff, including deleted files, for directory targets.
+    let changed_paths = patch_set
+        .files()
+        .iter()
+        .flat_map(|patched_file| [&patched_file.source_file, &patched_file.target_file])
+        .filter(|path| *path != "/dev/null")
+        .map(|path| {
+            if is_git_diff {
+                path.get(2..).unwrap_or_default().to_string()
+            } else {
+                path.clone()
+            }
+        })
+        .collect::<HashSet<String>>();
+
+    let change_filter = ChangeFilter {
+        ignore_whitespace: options.config.ignore_whitespace,
+        ignore_comments: options.config.ignore_comments,
+        ignore: None,
+    };
+    let parse_options = ParseOptions {
+        strict: options.strict,
+        ..options.config.parse_options()
+    };
+
+    // Rules from the config are enforced on paths alone, without reading any files.
+    diagnostics.extend(rule_diagnostics(&options.config.rules, &changed_paths)?);
+
+    // To discover and parse all the if-change-then-change blocks relevant to this change, we do a
+    // BFS starting from every path present in the diff, and then move on to every then-change
+    // referenced in each file we read.
+    //
+    // We also hang on to the contents of every file we read, for resolving then-change targets
+    // which are spans of lines rather than blocks.
+    let mut contents_by_path = HashMap::new();
+    // Then-change targets which turned out to be directories, rather than files.
+    let mut directory_targets = HashSet::new();
+    // Every file in the tree, listed the first time a then-change target turns out not to exist.
+    let mut all_files = None;
+    let file_nodes_by_path = {
+        let mut ret = HashMap::new();
+        // Files in the diff which weren't read, since they're outside of --only/--exclude, with
+        // the diagnostic to report if reading them fails.
+        let mut unparsed = HashMap::new();
+        // Each path to read is queued along with the diagnostic to report if reading it fails,
+        // whose message is completed with the reason, e.g. "... that is a directory: 'foo'".
+        let mut search = VecDeque::new();
+        // Every path ever queued, so that a file many others point at (even several in the same
+        // round, before any of them is parsed) is still only read and parsed once. Then-changes
+        // pointing at a file that's already queued can still fail to read it, so they're kept
+        // (with why they failed, once we know) to be reported like the first.
+        let mut queued_paths = HashSet::new();
+        let mut other_referrers: HashMap<String, Vec<Diagnostic>> = HashMap::new();
+        let mut read_failures: HashMap<String, std::io::Error> = HashMap::new();
+        for path in diffs_by_post_diff_path.keys() {
+            let diagnostic_if_read_fails = Diagnostic {
+                path: "stdin".to_string(),
+                // TODO- for files we're reading because they were in the diff,
+                //       start_line should be the line in the diff
+                start_line: None,
+                end_line: None,
+                message: "diff references file".to_string(),
+                ..Default::default()
+            };
+            // Files outside of --only/--exclude are only read if something in scope points at
+            // them, like any other unparsed file.
+            if options.paths.matches(path) {
+                queued_paths.insert(path.clone());
+                search.push_back((diagnostic_if_read_fails, path.clone()));
+            } else {
+                unparsed.insert(path.clone(), diagnostic_if_read_fails);
+            }
+        }
+
+        let read = |cache: &ParseCache, path: &String| {
+            // If we can't tell how big the file is, reading it will fail and tell us why.
+            let max_file_size = options.config.max_file_size(path);
+            if let Some(size) = options
+                .tree
+                .size(path)
+                .ok()
+                .filter(|size| *size > max_file_size)
+            {
+                return FileRead::TooLarge(size, max_file_size);
+            }
+
+            // $path entries come from one of two sources: either it is a path present in the input
+            // diffs, or it is a then-change path in one of the former paths. In the first case,
+            // this is where we do the file-exists validation; in the second case, we check
+            // `Tree::exists` before attempting to read the file here.
+            FileRead::Read(match (&options.tree, diffs_by_post_diff_path.get(path)) {
+                (Tree::GitRevision(_), Some(diff)) => {
+                    let pre_diff_path = if diff.source_file == "/dev/null" {
+                        None
+                    } else if is_git_diff {
+                        Some(&diff.source_file[2..])
+                    } else {
+                        Some(diff.source_file.as_str())
+                    };
+                    match pre_diff_path {
+                        Some(pre_diff_path) => {
+                            options.tree.read_lossy(pre_diff_path).map(|(base, lossy)| {
+                                (Contents::Owned(tree::apply(&base, diff)), lossy)
+                            })
+                        }
+                        None => Ok((Contents::Owned(tree::apply("", diff)), false)),
+                    }
+                }
+                _ => cache.read(&options.tree, path),
+            })
+        };
+
+        // Everything queued so far is read at once, and then processed in the order it was
+        // queued, exactly as if each file had been read just before it was processed; anything
+        // that queues is read in the next round.
+        while !search.is_empty() {
+            let queued = search.drain(..).collect::<Vec<_>>();
+            let paths = queued.iter().map(|(_, path)| path).collect::<Vec<_>>();
+            let reads = map_concurrently(&paths, options.io_concurrency, |path| read(cache, path));
+            for ((diagnostic_if_read_fails, path), read) in queued.into_iter().zip(reads) {
+                let file_contents = match read {
+                    FileRead::TooLarge(size, max_file_size) => {
+                        diagnostics.push(Diagnostic {
+                            path: path.clone(),
+                            start_line: None,
+                            end_line: None,
+                            message: format!(
+                                "file too large to analyze ({} bytes, over the limit of {}), so its if-change-then-change blocks were not checked",
+                                size, max_file_size
+                            ),
+                            severity: Severity::Info,
+                            ..Default::default()
+                        });
+                        continue;
+                    }
+                    FileRead::Read(file_contents) => file_contents,
+                };
+                let file_contents = match file_contents {
+                    Ok((file_contents, lossy)) => {
+                        if lossy {
+                            diagnostics.push(lossy_decoding_warning(&path));
+                        }
+                        file_contents
+                    }
+                    Err(err) => {
+                        // TODO- in what cases does the post-diff path not exist?
+                        // TODO- if a file is deleted, the post-diff path is... /dev/null?
+                        let referrers = std::iter::once(diagnostic_if_read_fails)
+                            .chain(other_referrers.remove(&path).unwrap_or_default());
+                        for diagnostic_if_read_fails in referrers {
+                            diagnostics.push(Diagnostic {
+                                message: tree::read_error_message(
+                                    &diagnostic_if_read_fails.message,
+                                    &path,
+                                    &err,
+                                ),
+                                ..diagnostic_if_read_fails
+                            });
+                        }
+                        read_failures.insert(path, err);
+                        continue;
+                    }
+                };
+                if let Some(diagnostic) = merge_conflict_diagnostic(&path, &file_contents) {
+                    diagnostics.push(diagnostic);
+                    continue;
+                }
+                match cache.parse(&path, &file_contents, &parse_options) {
+                    Err(error) => {
+                        diagnostics.extend(error.diagnostics);
+                    }
+                    Ok(mut file_node) => {
+                        if file_node.generated
+                            && diffs_by_post_diff_path.contains_key(&path)
+                            && parse_options.may_contain_directives(&file_contents)
+                        {
+                            diagnostics.push(generated_file_info(&path));
+                        }
+                        file_node
+                            .blocks
+                            .extend(options.config.virtual_blocks(&path, &file_contents)?);
+                        for block in file_node.blocks.iter_mut() {
+                            diagnostics.extend(duplicate_target_warnings(block));
+                            // Then-changes pointing at a file this diff renames are followed to its
+                            // new path, since that's almost certainly what they mean now.
+                            for (then_change_lineno, then_change_key) in
+                                block.then_change.iter_mut()
+                            {
+                                let Some(new_path) = renames.get(&then_change_key.path) else {
+                                    continue;
+                                };
+                                diagnostics.push(renamed_target_warning(
+                                    &block.key.path,
+                                    *then_change_lineno,
+                                    &then_change_key.path,
+                                    new_path,
+                                ));
+                                then_change_key.path = new_path.clone();
+                            }
+                            // Targets which do not exist, for --fix to remove, and where their
+                            // diagnostics start.
+                            let mut dangling = Vec::new();
+                            let first_diagnostic = diagnostics.len();
+                            block.then_change = block
+                            .then_change
+                            .drain(..)
+                            .filter(|(then_change_lineno, then_change_key)| {
+                                if block.key.path == then_change_key.path {
+                                    // Self-referential then-change entries are ignored, and
+                                    // only reported if the config asks for it.
+                                    if options.config.warn_self_references {
+                                        diagnostics.push(Diagnostic {
+                                            path: block.key.path.clone(),
+                                            start_line: Some(*then_change_lineno),
+                                            end_line: None,
+                                            message: "then-change points at its own file; did you mean another path?".to_string(),
+                                            severity: Severity::Warning,
+                                            ..Default::default()
+                                        });
+                                    }
+                                    return false;
+                                }
+                                if diffs_by_post_diff_path.contains_key(&then_change_key.path) {
+                                    if let Some(diagnostic) = unparsed.remove(&then_change_key.path) {
+                                        queued_paths.insert(then_change_key.path.clone());
+                                        search.push_back((diagnostic, then_change_key.path.clone()));
+                                    }
+                                    return true;
+                                }
+                                if then_change_key.is_url() {
+                                    return true;
+                                }
+                                if then_change_key.path.is_empty() {
+                                    diagnostics.push(Diagnostic {
+                                        path: block.key.path.clone(),
+                                        start_line: Some(*then_change_lineno),
+                                        end_line: None,
+                                        message: "then-change does not reference a valid path"
+                                            .to_string(),
+                                        ..Default::default()
+                                    });
+                                    return false;
+                                }
+                                let exists = options.tree.exists(&then_change_key.path);
+                                if !exists.as_ref().is_ok_and(|exists| *exists) {
+                                    let err = exists
+                                        .err()
+                                        .unwrap_or_else(|| std::io::ErrorKind::NotFound.into());
+                                    // Files outside a sparse checkout exist as far as everyone
+                                    // else is concerned; we just can't see them.
+                                    if err.kind() == std::io::ErrorKind::NotFound
+                                        && matches!(options.tree, Tree::WorkingTree)
+                                        && git::is_skip_worktree(&then_change_key.path)
+                                            .unwrap_or(false)
+                                    {
+                                        diagnostics.push(Diagnostic {
+                                            path: block.key.path.clone(),
+                                            start_line: Some(*then_change_lineno),
+                                            end_line: None,
+                                            message: format!(
+                                                "then-change target '{}' is outside the sparse checkout, so it was not checked",
+                                                then_change_key.path
+                                            ),
+                                            severity: Severity::Info,
+                                            ..Default::default()
+                                        });
+                                        return false;
+                                    }
+                                    let mut message = tree::read_error_message(
+                                        "then-change references file",
+                                        &then_change_key.path,
+                                        &err,
+                                    );
+                                    let missing = err.kind() == std::io::ErrorKind::NotFound;
+                                    // Targets which are allowed to be missing aren't mistakes, so
+                                    // there's nothing to suggest or fix.
+                                    let allowed = missing
+                                        && (options.allow_missing_targets
+                                            || then_change_key.may_not_exist);
+                                    if missing && !allowed {
+                                        let files = all_files.get_or_insert_with(|| {
+                                            options.tree.files().unwrap_or_default()
+                                        });
+                                        message +=
+                                            &suggest::did_you_mean(&then_change_key.path, files);
+                                        dangling.push(*then_change_lineno);
+                                    }
+                                    diagnostics.push(Diagnostic {
+                                        path: block.key.path.clone(),
+                                        start_line: Some(*then_change_lineno),
+                                        end_line: None,
+                                        message,
+                                        severity: if allowed {
+                                            Severity::Info
+                                        } else {
+                                            Severity::Error
+                                        },
+                                        ..Default::default()
+                                    });
+                                    return false;
+                                }
+                                if options.tree.is_dir(&then_change_key.path) {
+                                    directory_targets.insert(then_change_key.path.clone());
+                                    return true;
+                                }
+                                let diagnostic_if_read_fails = Diagnostic {
+                                    path: block.key.path.clone(),
+                                    start_line: Some(*then_change_lineno),
+                                    end_line: None,
+                                    message: "then-change references file".to_string(),
+                                    ..Default::default()
+                                };
+                                if queued_paths.insert(then_change_key.path.clone()) {
+                                    search.push_back((
+                                        diagnostic_if_read_fails,
+                                        then_change_key.path.clone(),
+                                    ));
+                                } else if let Some(err) = read_failures.get(&then_change_key.path) {
+                                    diagnostics.push(Diagnostic {
+                                        message: tree::read_error_message(
+                                            &diagnostic_if_read_fails.message,
+                                            &then_change_key.path,
+                                            err,
+                                        ),
+                                        ..diagnostic_if_read_fails
+                                    });
+                                } else {
+                                    other_referrers
+                                        .entry(then_change_key.path.clone())
+                                        .or_default()
+                                        .push(diagnostic_if_read_fails);
+                                }
+                                true
+                            })
+                            .collect();
+                            attach_remove_fix(&mut diagnostics[first_diagnostic..], dangling);
+                        }
+                        ret.insert(path.clone(), file_node);
+                    }
+                };
+                contents_by_path.insert(path, file_contents);
+            }
+        }
+
+        ret
+    };
+
+    // Hashes are checked for every block we've read, not just the modified ones: a stale hash on
+    // a block the diff doesn't touch means its counterparts may already be out of sync.
+    for (path, file_node) in file_nodes_by_path.iter() {
+        if let Some(contents) = contents_by_path.get(path) {
+            diagnostics.extend(
+                file_node
+                    .blocks
+                    .iter()
+                    .filter_map(|block| stale_hash_diagnostic(block, contents)),
+            );
+        }
+    }
+
+    // Before we can generate diagnostics, we also need to know, for each
+    // if-change-then-change block, whether or not its contents were modified.
+    //
+    // for every ictc-block
+    //   find all intersecting patch hunks
+    //   for each intersecting patch hunk
+    //     check if the intersection in the ictc-block contains added/removed lines in the hunk
+    //     (hunks have both added/removed lines and also context lines)
+    //     if so, mark the block as "modified"
+    //
+    // Files are independent of each other here, and in building diagnostics below, so both are
+    // done for several files at once.
+    let parallelism = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let modified_blocks_by_path = {
+        let changed_files = file_nodes_by_path
+            .iter()
+            .filter(|(path, _)| options.paths.matches(path))
+            .filter_map(|(path, file_node)| {
+                let diff = diffs_by_post_diff_path.get(path)?;
+                Some((path, file_node, *diff))
+            })
+            .collect::<Vec<_>>();
+
+        map_concurrently(&changed_files, parallelism, |(path, file_node, diff)| {
+            let modified_blocks = file_node
+                .blocks
+                .iter()
+                .filter(|ictc_block| {
+                    modifies_block(diff, ictc_block, &ictc_block.content_range(), change_filter)
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            (path.to_string(), modified_blocks)
+        })
+        .into_iter()
+        .filter(|(_, modified_blocks)| !modified_blocks.is_empty())
+        .map(|(path, modified_blocks)| (path, FileNode::new(modified_blocks)))
+        .collect::<HashMap<_, _>>()
+    };
+
+    // Now that we know which if-change-then-change blocks have and have not been modified in the
+    // current diff, we can actually build diagnostics
+    //
+    // for every ictc-block
+    //   if the ifchange block is in the "modified block" set
+    //     for every thenchange block
+    //       if the thenchange block exists in the "modified block" set
+    //         do nothing
+    //       else
+    //         add diagnostic
+    //
+    // Blocks which point at each other in a longer cycle must all change together, so rather than
+    // reporting one link of the cycle at a time (each only showing up once the last is fixed), we
+    // report the whole cycle at once. Blocks in reported cycles are mapped to their cycle's index.
+    let mut cycle_indices = BTreeMap::new();
+    let is_modified = |block: &BlockNode| {
+        modified_blocks_by_path
+            .get(&block.key.path)
+            .is_some_and(|file_node| file_node.blocks.contains(block))
+    };
+    // Each cycle is reported from the first of its modified blocks, in order, so that the same
+    // change always produces the same report.
+    let mut modified_blocks = modified_blocks_by_path
+        .values()
+        .flat_map(|file_node| file_node.blocks.iter())
+        .collect::<Vec<_>>();
+    modified_blocks.sort_by_key(|block| block.location());
+    for ictc_block in modified_blocks {
+        if cycle_indices.contains_key(&ictc_block.location()) {
+            continue;
+        }
+        let Some(cycle) = find_cycle(ictc_block, &file_nodes_by_path) else {
+            continue;
+        };
+        let (changed, unchanged): (Vec<&BlockNode>, Vec<&BlockNode>) =
+            cycle.iter().partition(|block| is_modified(block));
+        let Some((first_unchanged, other_unchanged)) = unchanged.split_first() else {
+            continue;
+        };
+
+        let index = cycle_indices.len();
+        for block in cycle.iter() {
+            cycle_indices.insert(block.location(), index);
+        }
+        let positions = |blocks: &[&BlockNode]| {
+            blocks
+                .iter()
+                .map(|block| block.location().position().to_string())
+                .collect::<Vec<_>>()
+        };
+        let mut message = format!(
+            "expected change here: {} -> {} form a cycle of then-changes, so they must all change together, but only {} changed",
+            positions(&cycle).join(" -> "),
+            cycle[0].location().position(),
+            positions(&changed).join(", "),
+        );
+        if !other_unchanged.is_empty() {
+            message += &format!(
+                " (also unchanged: {})",
+                positions(other_unchanged).join(", ")
+            );
+        }
+        let range = first_unchanged.content_range();
+        diagnostics.push(Diagnostic {
+            path: first_unchanged.key.path.clone(),
+            start_line: Some(range.start),
+            end_line: Some(range.end),
+            message,
+            related: Some(ictc_block.location()),
+            ..Default::default()
+        });
+    }
+
+    // CODEOWNERS is only loaded if there turns out to be a diagnostic to attribute.
+    let codeowners = OnceLock::new();
+    let today = date::today();
+    let check_file = |file_node: &FileNode| {
+        let mut diagnostics = Vec::new();
+        let mut checked_names = HashSet::new();
+        for ictc_block in file_node.blocks.iter() {
+            // Modifying an expired block is as good a time as any to re-examine it.
+            diagnostics.extend(expired_block_diagnostic(ictc_block, &today));
+            // Regions sharing a name are one logical block, which only needs checking once.
+            if let Some(name) = &ictc_block.key.name {
+                if !checked_names.insert((&ictc_block.key.path, name)) {
+                    continue;
+                }
+            }
+
+            let origin = ictc_block.location();
+            // Diagnostics for every then-change target that was not changed as expected; with
+            // then-change-any, these only matter if no target was changed.
+            let mut missing_changes = Vec::new();
+            let mut any_target_changed = false;
+
+            'targets: for (then_change_lineno, then_change_key) in ictc_block.then_change.iter() {
+                // There's no way to tell whether a URL has been updated, so all we can do is remind.
+                if then_change_key.is_url() {
+                    diagnostics.push(Diagnostic {
+                        path: ictc_block.key.path.clone(),
+                        start_line: Some(*then_change_lineno),
+                        end_line: None,
+                        message: format!(
+                            "remember to update {} due to change in {}",
+                            then_change_key.path,
+                            origin.position()
+                        ),
+                        related: Some(origin.clone()),
+                        severity: Severity::Info,
+                        ..Default::default()
+                    });
+                    continue;
+                }
+
+                let missing: Vec<Diagnostic> = 'target: {
+                    // Directory targets are satisfied by a change to any file under the directory.
+                    if directory_targets.contains(&then_change_key.path) {
+                        let prefix = format!("{}/", then_change_key.path.trim_end_matches('/'));
+                        if changed_paths.iter().any(|path| path.starts_with(&prefix)) {
+                            break 'target Vec::new();
+                        }
+                        break 'target vec![Diagnostic {
+                            path: then_change_key.path.clone(),
+                            start_line: None,
+                            end_line: None,
+                            message: missing_change_message(
+                                &origin,
+                                &format!("some file under '{}'", prefix),
+                                "",
+                            ),
+                            related: Some(origin.clone()),
+                            ..Default::default()
+                        }];
+                    }
+
+                    // Line range and symbol targets have no block of their own; all we need is a
+                    // change somewhere in the lines they span.
+                    if !then_change_key.expects_block() {
+                        let Some(lines) = contents_by_path
+                            .get(&then_change_key.path)
+                            .and_then(|contents| then_change_key.resolve_lines(contents))
+                        else {
+                            // The target could not be read, which we've already reported.
+                            continue 'targets;
+                        };
+                        let lines = match lines {
+                            Ok(lines) => lines,
+                            Err(message) => {
+                                diagnostics.push(Diagnostic {
+                                    path: ictc_block.key.path.clone(),
+                                    start_line: Some(*then_change_lineno),
+                                    end_line: None,
+                                    message,
+                                    ..Default::default()
+                                });
+                                continue 'targets;
+                            }
+                        };
+                        if diffs_by_post_diff_path
+                            .get(&then_change_key.path)
+                            .is_some_and(|diff| modifies_range(diff, &lines, change_filter))
+                        {
+                            break 'target Vec::new();
+                        }
+                        let expected = Location {
+                            path: then_change_key.path.clone(),
+                            start_line: Some(lines.start),
+                            end_line: Some(lines.end),
+                        };
+                        break 'target vec![Diagnostic {
+                            path: then_change_key.path.clone(),
+                            start_line: Some(lines.start),
+                            end_line: Some(lines.end),
+                            message: missing_change_message(
+                                &origin,
+                                &expected.position().to_string(),
+                                "",
+                            ),
+                            related: Some(origin.clone()),
+                            ..Default::default()
+                        }];
+                    }
+
+                    if !contents_by_path.contains_key(&then_change_key.path) {
+                        // The target could not be read, which we've already reported.
+                        continue 'targets;
+                    }
+
+                    // Generated files have no blocks of their own, and targets with file granularity
+                    // don't need one; either way, any change to the file will do.
+                    let granularity = options
+                        .config
+                        .granularity(ictc_block, &then_change_key.path);
+                    if granularity == Granularity::File
+                        || file_nodes_by_path
+                            .get(&then_change_key.path)
+                            .is_some_and(|file_node| file_node.generated)
+                    {
+                        if diffs_by_post_diff_path
+                            .get(&then_change_key.path)
+                            .is_some_and(|diff| {
+                                modifies_range(diff, &(0..usize::MAX), change_filter)
+                            })
+                        {
+                            break 'target Vec::new();
+                        }
+                        break 'target vec![Diagnostic {
+                            path: then_change_key.path.clone(),
+                            start_line: None,
+                            end_line: None,
+                            message: missing_change_message(&origin, &then_change_key.path, ""),
+                            related: Some(origin.clone()),
+                            ..Default::default()
+                        }];
+                    }
+
+                    // The corresponding block is resolved against every block in the target file,
+                    // not just the modified ones, so that a change to some other block pointing back
+                    // here doesn't count.
+                    let correspondence = match file_nodes_by_path.get(&then_change_key.path) {
+                        Some(file_node) => file_node.correspondence(ictc_block, then_change_key),
+                        None => Correspondence::None,
+                    };
+                    let mut block_range = None;
+                    // Set if the corresponding block's directives changed, but strict granularity
+                    // required a change to its body.
+                    let mut only_directives_changed = false;
+                    match correspondence {
+                        Correspondence::None => {}
+                        Correspondence::Block(regions) => {
+                            // Links within a cycle were reported along with the rest of the cycle.
+                            if cycle_indices
+                                .get(&regions[0].location())
+                                .is_some_and(|index| {
+                                    cycle_indices.get(&ictc_block.location()) == Some(index)
+                                })
+                            {
+                                continue 'targets;
+                            }
+                            if options.require_reciprocal
+                                && !regions
+                                    .iter()
+                                    .any(|region| region.points_back_at(ictc_block))
+                            {
+                                diagnostics.push(Diagnostic {
+                                    path: then_change_key.path.clone(),
+                                    start_line: Some(regions[0].content_range().start),
+                                    end_line: Some(regions[0].content_range().end),
+                                    message: format!(
+                                        "expected a then-change pointing back at {}, so that changes here also require changes there",
+                                        origin.position(),
+                                    ),
+                                    related: Some(origin.clone()),
+                                    ..Default::default()
+                                });
+                            }
+                            let modified_blocks =
+                                modified_blocks_by_path.get(&then_change_key.path);
+                            let modified_regions = regions
+                                .iter()
+                                .filter(|region| {
+                                    modified_blocks
+                                        .is_some_and(|file_node| file_node.blocks.contains(region))
+                                })
+                                .collect::<Vec<_>>();
+                            if granularity != Granularity::Strict && !modified_regions.is_empty() {
+                                break 'target Vec::new();
+                            }
+                            let diff = diffs_by_post_diff_path.get(&then_change_key.path);
+                            if modified_regions.iter().any(|region| {
+                                diff.is_some_and(|diff| {
+                                    modifies_block(
+                                        diff,
+                                        region,
+                                        &region.body_range(),
+                                        change_filter,
+                                    )
+                                })
+                            }) {
+                                break 'target Vec::new();
+                            }
+                            only_directives_changed = !modified_regions.is_empty();
+                            block_range = Some(regions[0].content_range());
+                        }
+                        Correspondence::Ambiguous(candidates) => {
+                            diagnostics.push(Diagnostic {
+                                path: ictc_block.key.path.clone(),
+                                start_line: Some(*then_change_lineno),
+                                end_line: None,
+                                message: format!(
+                                    "ambiguous correspondence: several blocks in '{}' point back at this one ({}); use then-change {}#name to pick one",
+                                    then_change_key.path,
+                                    candidates
+                                        .iter()
+                                        .map(|block| block.location().position().to_string())
+                                        .collect::<Vec<_>>()
+                                        .join(", "),
+                                    then_change_key.path,
+                                ),
+                                ..Default::default()
+                            });
+                            continue 'targets;
+                        }
+                    }
+
+                    let mut missing = Vec::new();
+                    if block_range.is_none() {
+                        missing.push(Diagnostic {
+                            path: then_change_key.path.clone(),
+                            start_line: block_range.as_ref().map(|range| range.start),
+                            end_line: block_range.as_ref().map(|range| range.end),
+                            message: missing_block_message(then_change_key, &origin),
+                            related: Some(origin.clone()),
+                            fix: Some(scaffold_fix(ictc_block, then_change_key, &contents_by_path)),
+                            ..Default::default()
+                        });
+                    }
+
+                    if block_range.is_some()
+                        || !diffs_by_post_diff_path.contains_key(&then_change_key.path)
+                    {
+                        let expected = Location {
+                            path: then_change_key.path.clone(),
+                            start_line: block_range.as_ref().map(|range| range.start),
+                            end_line: block_range.as_ref().map(|range| range.end),
+                        };
+                        missing.push(Diagnostic {
+                            path: then_change_key.path.clone(),
+                            start_line: block_range.as_ref().map(|range| range.start),
+                            end_line: block_range.as_ref().map(|range| range.end),
+                            message: missing_change_message(
+                                &origin,
+                                &expected.position().to_string(),
+                                if only_directives_changed {
+                                    " (with strict granularity, changes to the if-change and then-change lines alone don't count)"
+                                } else {
+                                    ""
+                                },
+                            ),
+                            related: Some(origin.clone()),
+                            ..Default::default()
+                        });
+                    }
+                    missing
+                };
+
+                if missing.is_empty() {
+                    any_target_changed = true;
+                    continue;
+                }
+
+                // Owners named on the target block take precedence over CODEOWNERS.
+                let owners =
+                    match file_nodes_by_path
+                        .get(&then_change_key.path)
+                        .and_then(|file_node| {
+                            file_node.get_corresponding_block(ictc_block, then_change_key)
+                        }) {
+                        Some(target_block) if !target_block.owners.is_empty() => {
+                            &target_block.owners
+                        }
+                        _ => codeowners
+                            .get_or_init(|| CodeOwners::load(&options.tree))
+                            .owners_of(&then_change_key.path),
+                    };
+                let owned_by = if owners.is_empty() {
+                    String::new()
+                } else {
+                    format!(" (owned by {})", owners.join(" "))
+                };
+                missing_changes.extend(missing.into_iter().map(|diagnostic| Diagnostic {
+                    message: diagnostic.message + &owned_by,
+                    severity: if then_change_key.optional {
+                        Severity::Warning
+                    } else {
+                        diagnostic.severity
+                    },
+                    ..diagnostic
+                }));
+            }
+
+            // Blocks are often annotated before their counterparts are, so a block this diff
+            // introduces can be let off, if the config says so.
+            if options.config.relax_new_blocks
+                && diffs_by_post_diff_path
+                    .get(&ictc_block.key.path)
+                    .is_some_and(|diff| adds_line(diff, ictc_block.content_range().start))
+            {
+                log::debug!(
+                    "not reporting missing changes for new block {}",
+                    origin.position()
+                );
+                continue;
+            }
+            if let Some(reason) = &ictc_block.reason {
+                for diagnostic in missing_changes.iter_mut() {
+                    diagnostic.message += &format!(" (reason: {})", reason);
+                }
+            }
+            if ictc_block.then_change_any {
+                if any_target_changed {
+                    continue;
+                }
+                for diagnostic in missing_changes.iter_mut() {
+                    diagnostic.message += " (a change to any one then-change-any target will do)";
+                }
+            }
+            diagnostics.extend(missing_changes);
+        }
+        diagnostics
+    };
+    let modified_file_nodes = modified_blocks_by_path.values().collect::<Vec<_>>();
+    diagnostics.extend(
+        map_concurrently(&modified_file_nodes, parallelism, |file_node| {
+            check_file(file_node)
+        })
+        .into_iter()
+        .flatten(),
+    );
+
+    // With transitive enforcement, a change to a block also requires changes to the blocks its
+    // then-change targets point at in turn, and so on.
+    if options.config.transitive {
+        let mut checked_names = HashSet::new();
+        for ictc_block in modified_blocks_by_path
+            .values()
+            .flat_map(|file_node| file_node.blocks.iter())
+        {
+            if let Some(name) = &ictc_block.key.name {
+                if !checked_names.insert((&ictc_block.key.path, name)) {
+                    continue;
+                }
+            }
+            let origin = ictc_block.location();
+            for chain in transitive_chains(ictc_block, &file_nodes_by_path) {
+                let target = chain[chain.len() - 1];
+                // Cycles were reported as a whole above, and chains through a changed block were
+                // checked when checking that block.
+                let in_same_cycle = cycle_indices
+                    .get(&target.location())
+                    .is_some_and(|index| cycle_indices.get(&origin) == Some(index));
+                if in_same_cycle || chain[1..].iter().any(|block| is_modified(block)) {
+                    continue;
+                }
+                let range = target.content_range();
+                let expected = Location {
+                    path: target.key.path.clone(),
+                    start_line: Some(range.start),
+                    end_line: Some(range.end),
+                };
+                diagnostics.push(Diagnostic {
+                    path: target.key.path.clone(),
+                    start_line: Some(range.start),
+                    end_line: Some(range.end),
+                    message: missing_change_message(
+                        &origin,
+                        &expected.position().to_string(),
+                        &format!(
+                            " (through {})",
+                            chain
+                                .iter()
+                                .map(|block| block.location().position().to_string())
+                                .collect::<Vec<_>>()
+                                .join(" -> ")
+                        ),
+                    ),
+                    related: Some(origin.clone()),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    // Problems with the diff itself are always reported.
+    diagnostics
+        .retain(|diagnostic| diagnostic.path == "stdin" || options.paths.matches(&diagnostic.path));
+    diagnostics.sort();
+    // Regions of a discontiguous block share then-change lines, and so problems with them.
+    diagnostics.dedup();
+
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::check::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn diffs_are_parsed_a_file_at_a_time() -> anyhow::Result<()> {
+        let input = "diff --git a/a.sh b/a.sh\r
+--- a/a.sh\r
++++ b/a.sh\r
+@@ -1 +1 @@\r
+-echo a\r
++echo b\r
+diff --git a/b.sh b/c.sh\r
+similarity index 100%\r
+rename from b.sh\r
+rename to c.sh\r
+diff --git a/d.sh b/d.sh\r
+--- a/d.sh\r
++++ b/d.sh\r
+@@ -1,2 +1 @@\r
+ echo d\r
+--- not a header\r
+";
+        let diff = parse_diff(input.as_bytes())?;
+
+        assert_that!(diff.is_git_diff).is_true();
+        assert_that!(diff
+            .patch_set
+            .files()
+            .iter()
+            .map(|file| (file.target_file.as_str(), file.removed()))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![("b/a.sh", 1), ("b/d.sh", 1)]);
+        assert_that!(diff.renames)
+            .is_equal_to(HashMap::from([("b.sh".to_string(), "c.sh".to_string())]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn diff_paths_use_forward_slashes() -> anyhow::Result<()> {
+        let input = "\
+--- a\\src\\a.sh
++++ b\\src\\a.sh
+@@ -1 +1 @@
+-echo a
++echo b
+";
+        let diff = parse_diff(input.as_bytes())?;
+        assert_that!(diff.patch_set.files()[0].path()).is_equal_to("src/a.sh".to_string());
+
+        let input = "\
+diff --git a/old.sh b/src/new.sh
+similarity index 100%
+rename from old.sh
+rename to src\\new.sh
+";
+        let diff = parse_diff(input.as_bytes())?;
+        assert_that!(diff.renames).is_equal_to(HashMap::from([(
+            "old.sh".to_string(),
+            "src/new.sh".to_string(),
+        )]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_concurrently_preserves_order() -> anyhow::Result<()> {
+        let items = (0..100).collect::<Vec<u64>>();
+        let squares = items.iter().map(|i| i * i).collect::<Vec<_>>();
+        for concurrency in [0, 1, 8, 200] {
+            let results = map_concurrently(&items, concurrency, |i| {
+                // Later items finish first, if they're run concurrently.
+                std::thread::sleep(Duration::from_micros(100 - i));
+                i * i
+            });
+            assert_that!(results).is_equal_to(&squares);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resident_cache_rereads_modified_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("ictc-resident-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("a.sh");
+        let path_str = path.to_str().unwrap();
+        let write = |contents: &str, modified: SystemTime| -> std::io::Result<()> {
+            std::fs::write(&path, contents)?;
+            std::fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)
+        };
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let cache = ParseCache::resident();
+
+        write("a\n", an_hour_ago)?;
+        assert_that!(cache.read(&Tree::WorkingTree, path_str)?.0.to_string())
+            .is_equal_to("a\n".to_string());
+        // Same mtime and size, so it's served from memory.
+        write("b\n", an_hour_ago)?;
+        assert_that!(cache.read(&Tree::WorkingTree, path_str)?.0.to_string())
+            .is_equal_to("a\n".to_string());
+        write("b\n", an_hour_ago + Duration::from_secs(1))?;
+        assert_that!(cache.read(&Tree::WorkingTree, path_str)?.0.to_string())
+            .is_equal_to("b\n".to_string());
+        // Files modified just now are always re-read.
+        write("c\n", SystemTime::now())?;
+        assert_that!(cache.read(&Tree::WorkingTree, path_str)?.0.to_string())
+            .is_equal_to("c\n".to_string());
+        write("d\n", SystemTime::now())?;
+        assert_that!(cache.read(&Tree::WorkingTree, path_str)?.0.to_string())
+            .is_equal_to("d\n".to_string());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
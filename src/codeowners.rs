@@ -0,0 +1,96 @@
+// Lookup of file owners in CODEOWNERS, so that diagnostics can say who should review the change
+// they're asking for.
+
+use crate::tree::Tree;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// Where CODEOWNERS is looked for, in the order that GitHub looks for it.
+const LOCATIONS: [&str; 3] = [".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+#[derive(Default)]
+pub struct CodeOwners {
+    // In file order; the last matching rule wins.
+    rules: Vec<(Gitignore, Vec<String>)>,
+}
+
+impl CodeOwners {
+    /// Loads the first CODEOWNERS found in `tree`, or no rules at all if there is none.
+    pub fn load(tree: &Tree) -> CodeOwners {
+        LOCATIONS
+            .iter()
+            .find_map(|location| tree.read_to_string(location).ok())
+            .map(|contents| CodeOwners::from_str(&contents))
+            .unwrap_or_default()
+    }
+
+    /// Parses CODEOWNERS rules, i.e. lines of the form "pattern @owner1 @owner2", where patterns
+    /// follow gitignore syntax. Rules with invalid patterns are skipped.
+    pub fn from_str(contents: &str) -> CodeOwners {
+        let mut rules = Vec::new();
+
+        for line in contents.lines() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line);
+            let mut words = line.split_whitespace();
+            let Some(pattern) = words.next() else {
+                continue;
+            };
+            // GitLab-style section headers, e.g. "[Docs] @docs-team".
+            if pattern.starts_with('[') || pattern.starts_with("^[") {
+                continue;
+            }
+
+            let mut builder = GitignoreBuilder::new("");
+            let matcher = builder
+                .add_line(None, pattern)
+                .and_then(|builder| builder.build());
+            match matcher {
+                Ok(matcher) => rules.push((matcher, words.map(str::to_string).collect())),
+                Err(err) => {
+                    log::warn!("skipping invalid CODEOWNERS pattern {:?}: {}", pattern, err)
+                }
+            }
+        }
+
+        CodeOwners { rules }
+    }
+
+    /// Returns the owners of `path`, which is relative to the root of the tree.
+    pub fn owners_of(&self, path: &str) -> &[String] {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(matcher, _)| matcher.matched_path_or_any_parents(path, false).is_ignore())
+            .map_or(&[], |(_, owners)| owners.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::codeowners::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn last_matching_rule_wins() -> anyhow::Result<()> {
+        let codeowners = CodeOwners::from_str(
+            "\
+# comment
+*           @everyone
+*.rs        @rustaceans
+/docs/      @docs-team @writers
+docs/internal.md
+[Section] @ignored
+",
+        );
+
+        assert_that!(codeowners.owners_of("README.md")).is_equal_to(&["@everyone".to_string()][..]);
+        assert_that!(codeowners.owners_of("src/main.rs"))
+            .is_equal_to(&["@rustaceans".to_string()][..]);
+        assert_that!(codeowners.owners_of("docs/guide/intro.md"))
+            .is_equal_to(&["@docs-team".to_string(), "@writers".to_string()][..]);
+        assert_that!(codeowners.owners_of("docs/internal.md").is_empty()).is_true();
+        assert_that!(CodeOwners::default().owners_of("src/main.rs").is_empty()).is_true();
+
+        Ok(())
+    }
+}
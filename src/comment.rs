@@ -0,0 +1,247 @@
+// Finer-grained knowledge of which lines can hold directives than the parser's guesswork (see
+// if_change_then_change2::is_comment_prefix): with tree-sitter, we can confirm that directives
+// actually sit inside comments, so that e.g. "if-change" in a string literal isn't taken to be a
+// directive; and in Markdown, we can tell which lines are examples in fenced code blocks.
+
+use std::ops::Range;
+
+/// Returns, for each line of `contents` (the contents of `path`), the byte ranges within the
+/// line which are covered by comments. Returns None if the language of `path` isn't recognized,
+/// in which case the parser falls back to guessing.
+#[cfg(feature = "comments")]
+pub fn comment_spans(path: &str, contents: &str) -> Option<Vec<Vec<Range<usize>>>> {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+    let (language, comment_kinds): (tree_sitter::Language, &[&str]) = match extension {
+        "rs" => (
+            tree_sitter_rust::LANGUAGE.into(),
+            &["line_comment", "block_comment"],
+        ),
+        "py" => (tree_sitter_python::LANGUAGE.into(), &["comment"]),
+        "js" | "jsx" | "mjs" | "cjs" => (tree_sitter_javascript::LANGUAGE.into(), &["comment"]),
+        _ => return None,
+    };
+
+    let mut parser = tree_sitter::Parser::new();
+    parser.set_language(&language).ok()?;
+    let tree = parser.parse(contents, None)?;
+
+    let line_starts = std::iter::once(0)
+        .chain(contents.match_indices('\n').map(|(i, _)| i + 1))
+        .collect::<Vec<_>>();
+    let mut spans = vec![Vec::new(); contents.lines().count()];
+
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        if comment_kinds.contains(&node.kind()) {
+            let bytes = node.byte_range();
+            for lineno in node.start_position().row..=node.end_position().row {
+                let (Some(spans), Some(&line_start)) =
+                    (spans.get_mut(lineno), line_starts.get(lineno))
+                else {
+                    continue;
+                };
+                let line_end = line_starts
+                    .get(lineno + 1)
+                    .map_or(contents.len(), |next| next - 1);
+                spans.push(
+                    bytes.start.max(line_start) - line_start..bytes.end.min(line_end) - line_start,
+                );
+            }
+            continue;
+        }
+        let mut cursor = node.walk();
+        stack.extend(node.children(&mut cursor));
+    }
+
+    Some(spans)
+}
+
+#[cfg(not(feature = "comments"))]
+pub fn comment_spans(_path: &str, _contents: &str) -> Option<Vec<Vec<Range<usize>>>> {
+    None
+}
+
+/// Whether `line`, a line of `path`, consists only of a comment, going by the comment syntax of
+/// its language (as guessed from its extension). Lines of files in unrecognized languages are
+/// never comments.
+pub fn is_comment_line(path: &str, line: &str) -> bool {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+    let leaders: &[&str] = match extension {
+        "c" | "cc" | "cpp" | "h" | "hpp" | "cs" | "go" | "java" | "js" | "jsx" | "kt" | "mjs"
+        | "cjs" | "proto" | "rs" | "scala" | "swift" | "ts" | "tsx" => {
+            // "* " and "*/" for the continuation lines of block comments.
+            &["//", "/*", "* ", "*/"]
+        }
+        "css" | "scss" => &["/*", "* ", "*/"],
+        "bash" | "bzl" | "cfg" | "pl" | "py" | "r" | "rb" | "sh" | "toml" | "yaml" | "yml"
+        | "zsh" => &["#"],
+        "hs" | "lua" | "sql" => &["--"],
+        "html" | "md" | "xml" => &["<!--"],
+        "clj" | "el" | "ini" | "lisp" | "s" => &[";"],
+        _ => &[],
+    };
+
+    let line = line.trim();
+    line == "*" || leaders.iter().any(|leader| line.starts_with(leader))
+}
+
+pub fn is_markdown(path: &str) -> bool {
+    path.ends_with(".md") || path.ends_with(".mdx")
+}
+
+/// Returns, for each line of `contents` (the contents of a Markdown file), whether it's part of
+/// a fenced code block, including the fences themselves.
+pub fn markdown_fenced_lines(contents: &str) -> Vec<bool> {
+    // The fence character and length of the code block we're in, if any.
+    let mut open_fence: Option<(char, usize)> = None;
+
+    contents
+        .lines()
+        .map(|line| {
+            let trimmed = line.trim_start();
+            // Fences may be indented by up to three spaces; any more makes it an indented code
+            // block, whose contents can't be confused with a fence.
+            let fence = (line.len() - trimmed.len() <= 3)
+                .then(|| {
+                    ['`', '~'].into_iter().find_map(|ch| {
+                        let len = trimmed.len() - trimmed.trim_start_matches(ch).len();
+                        (len >= 3).then_some((ch, len))
+                    })
+                })
+                .flatten();
+
+            match (open_fence, fence) {
+                (None, Some(fence)) => open_fence = Some(fence),
+                (Some((open_ch, open_len)), Some((ch, len)))
+                    if ch == open_ch
+                        && len >= open_len
+                        && trimmed.trim_start_matches(ch).trim().is_empty() =>
+                {
+                    open_fence = None;
+                    return true;
+                }
+                _ => {}
+            }
+            open_fence.is_some()
+        })
+        .collect()
+}
+
+/// Returns, for each line of `contents`, whether it starts inside a double-quoted string, going
+/// by whether the unescaped '"'s before it are unbalanced. This is only a guess (it knows nothing
+/// about comments or character literals), but it covers the common ways directives end up in
+/// string literals: multi-line Rust strings and Python triple-quoted strings in test fixtures.
+pub fn string_lines(contents: &str) -> Vec<bool> {
+    let mut in_string = false;
+    contents
+        .lines()
+        .map(|line| {
+            let starts_in_string = in_string;
+            in_string ^= !count_unescaped_quotes(line, '"').is_multiple_of(2);
+            starts_in_string
+        })
+        .collect()
+}
+
+/// Counts the occurrences of `quote` in `s` which aren't escaped with a backslash.
+pub fn count_unescaped_quotes(s: &str, quote: char) -> usize {
+    let mut count = 0;
+    let mut escaped = false;
+    for ch in s.chars() {
+        if escaped {
+            escaped = false;
+        } else if ch == '\\' {
+            escaped = true;
+        } else if ch == quote {
+            count += 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use crate::comment::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn finds_markdown_fences() -> anyhow::Result<()> {
+        let contents = "\
+# Usage
+```sh
+# if-change
+```
+~~~~
+```
+~~~
+~~~~
+  ```
+";
+        assert_that!(markdown_fenced_lines(contents))
+            .is_equal_to(vec![false, true, true, true, true, true, true, true, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_string_lines() -> anyhow::Result<()> {
+        let contents = r#"let fixture = "\
+# if-change
+a \" b
+";
+# "quoted" text
+""" docstring
+"""
+"#;
+        assert_that!(string_lines(contents))
+            .is_equal_to(vec![false, true, true, true, false, false, true]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn finds_comment_lines() -> anyhow::Result<()> {
+        assert_that!(is_comment_line("a.rs", "    // timeout, in seconds")).is_true();
+        assert_that!(is_comment_line("a.rs", "     * in seconds")).is_true();
+        assert_that!(is_comment_line("a.rs", "    *timeout = 30;")).is_false();
+        assert_that!(is_comment_line("a.rs", "    let timeout = 30; // seconds")).is_false();
+        assert_that!(is_comment_line("a.py", "# timeout, in seconds")).is_true();
+        assert_that!(is_comment_line("a.py", "// not a comment")).is_false();
+        assert_that!(is_comment_line("a.unknown", "# who knows")).is_false();
+
+        Ok(())
+    }
+
+    #[cfg(feature = "comments")]
+    #[test]
+    fn finds_rust_comments() -> anyhow::Result<()> {
+        let contents = "\
+// line
+let s = \"// not a comment\";
+/* block
+   comment */ let t = 1;
+";
+        let spans = comment_spans("a.rs", contents).map(|spans| {
+            spans
+                .iter()
+                .map(|spans| spans.iter().map(|span| (span.start, span.end)).collect())
+                .collect::<Vec<Vec<_>>>()
+        });
+        assert_that!(spans).is_equal_to(Some(vec![
+            vec![(0, 7)],
+            vec![],
+            vec![(0, 8)],
+            vec![(0, 13)],
+        ]));
+        assert_that!(comment_spans("a.unknown", contents)).is_none();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,515 @@
+use crate::if_change_then_change2::{
+    self, BlockKey, BlockNode, Granularity, Keywords, ParseOptions,
+};
+use anyhow::{anyhow, Context, Result};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::marker::PhantomData;
+use std::path::Path;
+
+/// Name of the config file, which lives at the root of the repository.
+pub const CONFIG_FILE: &str = ".ictc.toml";
+
+/// The default for Config::max_file_size.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Repository-wide settings, read from CONFIG_FILE. Every setting has a default, so a repository
+/// without a config file behaves exactly as one with an empty config file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Couplings between files which can't (or shouldn't) carry if-change-then-change comments,
+    /// declared as `[[rule]]` tables.
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+    /// Blocks in files whose format has no comments (e.g. JSON, lockfiles), declared as
+    /// `[[block]]` tables.
+    #[serde(default, rename = "block")]
+    pub blocks: Vec<VirtualBlock>,
+    /// Aliases for the directive keywords, declared as a `[keywords]` table.
+    #[serde(default)]
+    pub keywords: Keywords,
+    /// Ignore directives inside fenced code blocks in Markdown files, which are usually examples
+    /// rather than real blocks.
+    #[serde(default)]
+    pub skip_markdown_fences: bool,
+    /// Files larger than this many bytes (DEFAULT_MAX_FILE_SIZE if unset) are skipped rather than
+    /// read into memory, e.g. data dumps which happen to be in a diff.
+    pub max_file_size: Option<u64>,
+    /// Overrides of `max_file_size` for files matching a glob, declared as `[[size_limit]]`
+    /// tables; the first match wins.
+    #[serde(default, rename = "size_limit")]
+    pub size_limits: Vec<SizeLimit>,
+    /// Markers which, anywhere in the first few lines of a file, say that it's generated, in place
+    /// of the usual conventions (Go's "// Code generated ... DO NOT EDIT." and "@generated" in a
+    /// comment). Generated files aren't parsed for blocks, but can still be then-change targets.
+    #[serde(default, deserialize_with = "generated_markers")]
+    pub generated_markers: Option<Vec<String>>,
+    /// Don't count changes which only add, remove or move whitespace (e.g. from a formatter) as
+    /// changes to a block or its then-change targets.
+    #[serde(default)]
+    pub ignore_whitespace: bool,
+    /// Don't count changes to lines which are only comments (as best we can tell from the file's
+    /// language) as changes to a block or its then-change targets, e.g. editing a block's prose.
+    #[serde(default)]
+    pub ignore_comments: bool,
+    /// Warn about then-change targets which point at the file they're in, which are otherwise
+    /// ignored when checking a diff (`lint` always reports them).
+    #[serde(default)]
+    pub warn_self_references: bool,
+    /// Don't report missing changes to the then-change targets of blocks which the diff itself
+    /// adds (i.e. whose if-change line is new), since counterparts often follow separately.
+    #[serde(default)]
+    pub relax_new_blocks: bool,
+    /// Require changes to every block reachable from a changed block through then-changes, not
+    /// just its direct targets, e.g. A -> B -> C means a change to A requires a change to C.
+    #[serde(default)]
+    pub transitive: bool,
+    /// How much of a then-change target has to change, for blocks without an
+    /// "if-change(granularity=...)" of their own (Granularity::Block if unset).
+    pub granularity: Option<Granularity>,
+    /// Globs for then-change targets (e.g. changelogs) which are satisfied by a change anywhere
+    /// in them, as with "if-change(granularity=file)", rather than needing a corresponding block.
+    #[serde(default, deserialize_with = "checked_list::<IsGlob, _>")]
+    pub file_granularity: Vec<String>,
+}
+
+/// A change to any file matching `when` must be accompanied by a change to some file matching
+/// `then`, e.g. `when = "proto/**.proto", then = "gen/**"` for generated code.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    #[serde(deserialize_with = "checked::<IsGlob, _>")]
+    pub when: String,
+    #[serde(deserialize_with = "checked::<IsGlob, _>")]
+    pub then: String,
+}
+
+impl Rule {
+    /// Compiles the `when` and `then` globs.
+    pub fn matchers(&self) -> Result<(GlobMatcher, GlobMatcher)> {
+        let compile = |glob: &str| {
+            Glob::new(glob)
+                .map(|glob| glob.compile_matcher())
+                .with_context(|| format!("invalid glob in rule: '{}'", glob))
+        };
+        Ok((compile(&self.when)?, compile(&self.then)?))
+    }
+}
+
+/// Files matching `path` may be up to `max_bytes` long, e.g.
+/// `path = "data/**.csv", max_bytes = 1000000`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SizeLimit {
+    #[serde(deserialize_with = "checked::<IsGlob, _>")]
+    pub path: String,
+    pub max_bytes: u64,
+}
+
+impl SizeLimit {
+    pub fn matcher(&self) -> Result<GlobMatcher> {
+        Glob::new(&self.path)
+            .map(|glob| glob.compile_matcher())
+            .with_context(|| format!("invalid glob in size_limit: '{}'", self.path))
+    }
+}
+
+/// A block made up of the lines of `path` matching `regex`, which behaves as if those lines were
+/// wrapped in "if-change(name)" and "then-change" comments listing `then_change`, e.g.
+/// `path = "package.json", name = "version", regex = '"version":', then_change = ["src/version.rs"]`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VirtualBlock {
+    pub path: String,
+    #[serde(deserialize_with = "checked::<IsBlockName, _>")]
+    pub name: String,
+    #[serde(deserialize_with = "checked::<IsRegex, _>")]
+    pub regex: String,
+    #[serde(deserialize_with = "checked_list::<IsTarget, _>")]
+    pub then_change: Vec<String>,
+}
+
+impl VirtualBlock {
+    /// Returns a block for every line of `contents` (the contents of `path`) matching `regex`.
+    pub fn blocks(&self, contents: &str) -> Result<Vec<BlockNode>> {
+        if !if_change_then_change2::is_valid_block_name(&self.name) {
+            return Err(anyhow!("invalid name in block: '{}'", self.name));
+        }
+        let regex = Regex::new(&self.regex)
+            .with_context(|| format!("invalid regex in block: '{}'", self.regex))?;
+        let then_change = self
+            .then_change
+            .iter()
+            .map(|target| BlockKey::from_target(target).map_err(|err| anyhow!(err)))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| regex.is_match(line))
+            .map(|(lineno, _)| {
+                BlockNode::virtual_block(&self.path, &self.name, lineno, then_change.clone())
+            })
+            .collect())
+    }
+}
+
+// Settings are checked as they're deserialized, rather than once the whole config has been, so
+// that toml can tell us where the offending value is. For lists, that's where the list starts,
+// so the message names the offending element.
+
+/// What a string setting must be, e.g. a glob which compiles.
+trait Check {
+    fn check(value: &str) -> Result<(), String>;
+}
+
+/// A string setting which has passed `C`'s check.
+struct Checked<C>(String, PhantomData<C>);
+
+impl<'de, C: Check> Deserialize<'de> for Checked<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        C::check(&value).map_err(D::Error::custom)?;
+        Ok(Checked(value, PhantomData))
+    }
+}
+
+fn checked<'de, C: Check, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Checked::<C>::deserialize(deserializer).map(|Checked(value, _)| value)
+}
+
+fn checked_list<'de, C: Check, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    Ok(Vec::<Checked<C>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|Checked(value, _)| value)
+        .collect())
+}
+
+enum IsGlob {}
+
+impl Check for IsGlob {
+    fn check(glob: &str) -> Result<(), String> {
+        Glob::new(glob)
+            .map(|_| ())
+            .map_err(|err| format!("invalid glob '{}': {}", glob, err.kind()))
+    }
+}
+
+enum IsBlockName {}
+
+impl Check for IsBlockName {
+    fn check(name: &str) -> Result<(), String> {
+        if if_change_then_change2::is_valid_block_name(name) {
+            return Ok(());
+        }
+        Err(format!(
+            "invalid block name '{}': expected letters, digits, '-', '_' and '.'",
+            name
+        ))
+    }
+}
+
+enum IsRegex {}
+
+impl Check for IsRegex {
+    fn check(regex: &str) -> Result<(), String> {
+        Regex::new(regex)
+            .map(|_| ())
+            .map_err(|err| format!("invalid regex '{}': {}", regex, err))
+    }
+}
+
+enum IsTarget {}
+
+impl Check for IsTarget {
+    fn check(target: &str) -> Result<(), String> {
+        BlockKey::from_target(target).map(|_| ())
+    }
+}
+
+enum IsMarker {}
+
+impl Check for IsMarker {
+    fn check(marker: &str) -> Result<(), String> {
+        match marker.is_empty() {
+            true => Err("generated markers may not be empty".to_string()),
+            false => Ok(()),
+        }
+    }
+}
+
+fn generated_markers<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    checked_list::<IsMarker, D>(deserializer).map(Some)
+}
+
+enum IsKeyword {}
+
+impl Check for IsKeyword {
+    fn check(keyword: &str) -> Result<(), String> {
+        if keyword.is_empty() || keyword.contains(char::is_whitespace) {
+            return Err(format!(
+                "keyword '{}' must be non-empty and may not contain whitespace",
+                keyword
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes a list of aliases for a keyword; see Keywords.
+pub fn keyword_aliases<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    checked_list::<IsKeyword, D>(deserializer)
+}
+
+/// Describes a deserialization error at the line and column (both 1-indexed) it points at in
+/// `contents`, e.g. "3:8: invalid glob 'gen/[': unclosed character class; missing ']'".
+fn located_error(contents: &str, err: toml::de::Error) -> anyhow::Error {
+    let Some(span) = err.span() else {
+        return anyhow!("{}", err.message());
+    };
+    let before = &contents[..span.start];
+    let line = before.matches('\n').count() + 1;
+    let column = before[before.rfind('\n').map_or(0, |i| i + 1)..]
+        .chars()
+        .count()
+        + 1;
+    anyhow!("{}:{}: {}", line, column, err.message())
+}
+
+impl Config {
+    /// Parses and checks a config, with errors located in `contents` as "line:column: message".
+    pub fn from_str(contents: &str) -> Result<Config> {
+        toml::from_str(contents).map_err(|err| located_error(contents, err))
+    }
+
+    pub fn parse_options(&self) -> ParseOptions {
+        ParseOptions {
+            keywords: self.keywords.clone(),
+            skip_markdown_fences: self.skip_markdown_fences,
+            strict: false,
+            generated_markers: self.generated_markers.clone(),
+        }
+    }
+
+    /// Returns the blocks that `blocks` define in `path`, given its `contents`.
+    pub fn virtual_blocks(&self, path: &str, contents: &str) -> Result<Vec<BlockNode>> {
+        let mut ret = Vec::new();
+        for block in self.blocks.iter().filter(|block| block.path == path) {
+            ret.extend(block.blocks(contents)?);
+        }
+        Ok(ret)
+    }
+
+    /// The size in bytes above which `path` is too large to analyze.
+    pub fn max_file_size(&self, path: &str) -> u64 {
+        self.size_limits
+            .iter()
+            .find(|limit| limit.matcher().is_ok_and(|matcher| matcher.is_match(path)))
+            .map(|limit| limit.max_bytes)
+            .or(self.max_file_size)
+            .unwrap_or(DEFAULT_MAX_FILE_SIZE)
+    }
+
+    /// Whether any change to `path` satisfies a then-change targeting it; see file_granularity.
+    pub fn has_file_granularity(&self, path: &str) -> bool {
+        self.file_granularity
+            .iter()
+            .any(|glob| Glob::new(glob).is_ok_and(|glob| glob.compile_matcher().is_match(path)))
+    }
+
+    /// How much of `target` has to change to satisfy a change to `block`: the block's own
+    /// granularity wins, then file_granularity, then the default granularity.
+    pub fn granularity(&self, block: &BlockNode, target: &str) -> Granularity {
+        block
+            .granularity
+            .or_else(|| {
+                self.has_file_granularity(target)
+                    .then_some(Granularity::File)
+            })
+            .or(self.granularity)
+            .unwrap_or_default()
+    }
+
+    /// Loads the config from `repo_root`, returning None if there is no config file.
+    pub fn load(repo_root: &Path) -> Result<Option<Config>> {
+        let path = repo_root.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        // Errors are located in the file like a compiler's, e.g. "./.ictc.toml:3:8: ...".
+        Config::from_str(&contents)
+            .map_err(|err| anyhow!("{}:{}", path.display(), err))
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn empty_config_is_valid() -> anyhow::Result<()> {
+        assert_that!(Config::from_str("")).is_ok();
+
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() -> anyhow::Result<()> {
+        assert_that!(Config::from_str("no_such_setting = true")).is_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn errors_are_located() -> anyhow::Result<()> {
+        let error = |contents| Config::from_str(contents).unwrap_err().to_string();
+
+        assert_that!(error("ignore_comments = true\nno_such_setting = true\n")).starts_with(
+            "2:1: unknown field `no_such_setting`, expected one of `rule`, `block`, `keywords`",
+        );
+        assert_that!(error("granularity = \"line\"\n")).is_equal_to(
+            "1:15: unknown variant `line`, expected one of `file`, `block`, `strict`".to_string(),
+        );
+        assert_that!(error("[[rule]]\nwhen = \"proto/**\"\nthen = \"gen/[\"\n")).is_equal_to(
+            "3:8: invalid glob 'gen/[': unclosed character class; missing ']'".to_string(),
+        );
+        assert_that!(error(
+            "[keywords]\nif_change = [\"IfChange\", \"If Change\"]\n"
+        ))
+        .is_equal_to(
+            "2:13: keyword 'If Change' must be non-empty and may not contain whitespace"
+                .to_string(),
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rules() -> anyhow::Result<()> {
+        let config = Config::from_str(
+            "\
+[[rule]]
+when = \"proto/**.proto\"
+then = \"gen/**\"
+",
+        )?;
+        assert_that!(config.rules.len()).is_equal_to(1);
+        let (when, then) = config.rules[0].matchers()?;
+        assert_that!(when.is_match("proto/v1/api.proto")).is_true();
+        assert_that!(when.is_match("proto/README.md")).is_false();
+        assert_that!(then.is_match("gen/v1/api.pb.go")).is_true();
+
+        assert_that!(Config::from_str("[[rule]]\nwhen = \"proto/**\"\n")).is_err();
+        assert_that!(Config::from_str(
+            "[[rule]]\nwhen = \"proto/[\"\nthen = \"gen/**\"\n"
+        ))
+        .is_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn virtual_blocks() -> anyhow::Result<()> {
+        let config = Config::from_str(
+            "\
+[[block]]
+path = \"package.json\"
+name = \"version\"
+regex = '\"version\":'
+then_change = [\"src/version.rs\"]
+",
+        )?;
+        let blocks = config.virtual_blocks(
+            "package.json",
+            "{\n  \"name\": \"ictc\",\n  \"version\": \"1.2.3\"\n}\n",
+        )?;
+        assert_that!(blocks
+            .iter()
+            .map(|block| block.location().start_line)
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![Some(2)]);
+        assert_that!(config
+            .virtual_blocks("other.json", "\"version\": 1\n")?
+            .is_empty())
+        .is_true();
+
+        assert_that!(Config::from_str(
+            "[[block]]\npath = \"a.json\"\nname = \"v\"\nregex = \"(\"\nthen_change = []\n"
+        ))
+        .is_err();
+        assert_that!(Config::from_str(
+            "[[block]]\npath = \"a.json\"\nname = \"v w\"\nregex = \"v\"\nthen_change = []\n"
+        ))
+        .is_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn size_limits() -> anyhow::Result<()> {
+        assert_that!(Config::default().max_file_size("a.sh")).is_equal_to(DEFAULT_MAX_FILE_SIZE);
+
+        let config = Config::from_str(
+            "\
+max_file_size = 1000
+
+[[size_limit]]
+path = \"data/**.csv\"
+max_bytes = 5000000
+
+[[size_limit]]
+path = \"data/**\"
+max_bytes = 10
+",
+        )?;
+        assert_that!(config.max_file_size("data/big.csv")).is_equal_to(5000000);
+        assert_that!(config.max_file_size("data/small.json")).is_equal_to(10);
+        assert_that!(config.max_file_size("src/main.rs")).is_equal_to(1000);
+
+        assert_that!(Config::from_str(
+            "[[size_limit]]\npath = \"data/[\"\nmax_bytes = 10\n"
+        ))
+        .is_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn granularity() -> anyhow::Result<()> {
+        assert_that!(Config::default().has_file_granularity("CHANGELOG.md")).is_false();
+
+        let config = Config::from_str("file_granularity = [\"CHANGELOG.md\", \"**/*.toml\"]\n")?;
+        assert_that!(config.has_file_granularity("CHANGELOG.md")).is_true();
+        assert_that!(config.has_file_granularity("crates/core/Cargo.toml")).is_true();
+        assert_that!(config.has_file_granularity("src/main.rs")).is_false();
+
+        assert_that!(Config::from_str("file_granularity = [\"docs/[\"]\n")).is_err();
+
+        let config =
+            Config::from_str("granularity = \"strict\"\nfile_granularity = [\"CHANGELOG.md\"]\n")?;
+        let mut block = BlockNode::virtual_block("a.rs", "a", 0, Vec::new());
+        assert_that!(config.granularity(&block, "b.rs")).is_equal_to(Granularity::Strict);
+        assert_that!(config.granularity(&block, "CHANGELOG.md")).is_equal_to(Granularity::File);
+        block.granularity = Some(Granularity::Block);
+        assert_that!(config.granularity(&block, "CHANGELOG.md")).is_equal_to(Granularity::Block);
+        assert_that!(Config::default().granularity(&block, "b.rs")).is_equal_to(Granularity::Block);
+
+        assert_that!(Config::from_str("granularity = \"line\"\n")).is_err();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,117 @@
+#[cfg(unix)]
+use crate::check::{self, Options, ParseCache};
+use crate::diagnostic::Diagnostic;
+#[cfg(unix)]
+use crate::UsageError;
+#[cfg(unix)]
+use anyhow::{anyhow, Context};
+use anyhow::{bail, Result};
+#[cfg(unix)]
+use std::io::{Read, Write};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub const DEFAULT_SOCKET: &str = ".ictc.sock";
+
+// The protocol is deliberately minimal: the client writes a diff and shuts down its write half,
+// and the daemon responds with a status line ("ok", "usage-error <message>" if the diff couldn't
+// be parsed, or "error <message>"), followed (if ok) by the diagnostics as a JSON array, for the
+// client to count and print (or exit with) as a one-off invocation would.
+
+/// Listens on `socket_path` until killed, checking every diff sent to it. Parsed files are kept
+/// in a resident ParseCache across requests, so only files which were modified get re-read, and
+/// only those whose contents changed get re-parsed.
+///
+/// Paths in diffs are resolved relative to the daemon's working directory, not the client's.
+#[cfg(unix)]
+pub fn serve(socket_path: &Path) -> Result<()> {
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            bail!("a daemon is already listening on {}", socket_path.display());
+        }
+        // Left behind by a daemon that did not shut down cleanly.
+        std::fs::remove_file(socket_path)
+            .with_context(|| format!("failed to remove stale socket {}", socket_path.display()))?;
+    }
+
+    let listener = UnixListener::bind(socket_path)
+        .with_context(|| format!("failed to listen on {}", socket_path.display()))?;
+    log::info!("daemon listening on {}", socket_path.display());
+
+    let options = Options::load()?;
+    let mut cache = ParseCache::resident();
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|mut stream| handle(&mut stream, &options, &mut cache));
+        if let Err(err) = result {
+            log::warn!("failed to handle request: {:#}", err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn handle(stream: &mut UnixStream, options: &Options, cache: &mut ParseCache) -> Result<()> {
+    let mut input = Vec::new();
+    stream.read_to_end(&mut input)?;
+    let input = String::from_utf8_lossy(&input).into_owned();
+
+    let diff = match check::parse_diff(input.as_bytes()) {
+        Ok(diff) => diff,
+        Err(err) => {
+            writeln!(stream, "usage-error {:#}", err)?;
+            return Ok(());
+        }
+    };
+    match check::check_diff(&diff, options, cache) {
+        Ok(diagnostics) => {
+            writeln!(stream, "ok")?;
+            serde_json::to_writer(&mut *stream, &diagnostics)?;
+        }
+        Err(err) => {
+            writeln!(stream, "error {:#}", err)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `input` to the daemon listening on `socket_path` and returns its diagnostics.
+#[cfg(unix)]
+pub fn request(socket_path: &Path, input: &str) -> Result<Vec<Diagnostic>> {
+    let mut stream = UnixStream::connect(socket_path)
+        .with_context(|| format!("failed to connect to daemon at {}", socket_path.display()))?;
+    stream.write_all(input.as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let (status, output) = response.split_once('\n').unwrap_or((&response, ""));
+    if let Some(message) = status.strip_prefix("usage-error ") {
+        return Err(UsageError(anyhow!("{}", message)).into());
+    }
+    if let Some(message) = status.strip_prefix("error ") {
+        return Err(anyhow!("{}", message));
+    }
+    if status != "ok" {
+        bail!("unexpected response from daemon: {:?}", status);
+    }
+
+    serde_json::from_str(output).context("failed to parse diagnostics from daemon")
+}
+
+// Windows has no Unix domain sockets to listen on; `serve` is the long-lived alternative there.
+
+#[cfg(not(unix))]
+pub fn serve(_socket_path: &Path) -> Result<()> {
+    bail!("the daemon requires Unix domain sockets; use `serve` instead")
+}
+
+#[cfg(not(unix))]
+pub fn request(_socket_path: &Path, _input: &str) -> Result<Vec<Diagnostic>> {
+    bail!("--connect requires Unix domain sockets, which this platform does not have")
+}
@@ -0,0 +1,83 @@
+// Calendar dates, as used by "if-change(expires=YYYY-MM-DD)". Dates are kept as YYYY-MM-DD
+// strings, which order the same way as the dates they represent.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Whether `date` is a real date of the form YYYY-MM-DD.
+pub fn is_valid_date(date: &str) -> bool {
+    let parts = date.split('-').collect::<Vec<_>>();
+    let [year, month, day] = parts[..] else {
+        return false;
+    };
+    if [(year, 4), (month, 2), (day, 2)]
+        .iter()
+        .any(|(part, len)| part.len() != *len || !part.chars().all(|ch| ch.is_ascii_digit()))
+    {
+        return false;
+    }
+
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse(), month.parse(), day.parse()) else {
+        return false;
+    };
+    (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Today's date (in UTC) as YYYY-MM-DD.
+pub fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() / 86400);
+    from_days_since_epoch(days as i64)
+}
+
+// Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar; see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn from_days_since_epoch(days: i64) -> String {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::date::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn validates_and_computes_dates() -> anyhow::Result<()> {
+        assert_that!(is_valid_date("2025-12-31")).is_true();
+        assert_that!(is_valid_date("2024-02-29")).is_true();
+        assert_that!(is_valid_date("2025-02-29")).is_false();
+        assert_that!(is_valid_date("2025-13-01")).is_false();
+        assert_that!(is_valid_date("2025-1-01")).is_false();
+        assert_that!(is_valid_date("next week")).is_false();
+
+        assert_that!(from_days_since_epoch(0).as_str()).is_equal_to("1970-01-01");
+        assert_that!(from_days_since_epoch(19_722).as_str()).is_equal_to("2023-12-31");
+        assert_that!(from_days_since_epoch(19_782).as_str()).is_equal_to("2024-02-29");
+        assert_that!(is_valid_date(&today())).is_true();
+
+        Ok(())
+    }
+}
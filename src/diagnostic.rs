@@ -1,5 +1,6 @@
+use crate::fix::Fix;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
-use std::ops::Range;
 
 pub struct DiagnosticPosition<'a> {
     pub path: &'a String,
@@ -13,10 +14,11 @@ impl<'a> fmt::Display for DiagnosticPosition<'a> {
         if let Some(start_line) = self.start_line {
             // We _could_ just always show "a.sh:4-4" when the line range only consists of one line, but
             // "a.sh:4" is much more obvious at first glance; c.f. the GH permalink format.
-            if let Some(end_line) = self.end_line {
-                write!(f, "{}:{}-{}", self.path, start_line + 1, end_line)
-            } else {
-                write!(f, "{}:{}", self.path, start_line + 1)
+            match self.end_line {
+                Some(end_line) if end_line > start_line + 1 => {
+                    write!(f, "{}:{}-{}", self.path, start_line + 1, end_line)
+                }
+                _ => write!(f, "{}:{}", self.path, start_line + 1),
             }
         } else {
             write!(f, "{}", self.path)
@@ -24,29 +26,125 @@ impl<'a> fmt::Display for DiagnosticPosition<'a> {
     }
 }
 
+// Serialized line numbers match the ones we print, i.e. "a.sh:4-6" serializes as a start_line of
+// 4 and an end_line of 6. Since end_line is exclusive, it needs no adjustment.
+fn serialize_start_line<S: Serializer>(
+    start_line: &Option<usize>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    start_line.map(|lineno| lineno + 1).serialize(serializer)
+}
+
+fn deserialize_start_line<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<usize>, D::Error> {
+    Ok(Option::<usize>::deserialize(deserializer)?.map(|lineno| lineno.saturating_sub(1)))
+}
+
+// Owned counterpart to DiagnosticPosition, for when a diagnostic needs to point at a second
+// location (e.g. the block whose change caused the diagnostic).
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Location {
+    pub path: String,
+    // 0-indexed, inclusive-exclusive
+    #[serde(
+        default,
+        serialize_with = "serialize_start_line",
+        deserialize_with = "deserialize_start_line"
+    )]
+    pub start_line: Option<usize>,
+    pub end_line: Option<usize>,
+}
+
+impl Location {
+    pub fn position(&self) -> DiagnosticPosition<'_> {
+        DiagnosticPosition {
+            path: &self.path,
+            start_line: self.start_line,
+            end_line: self.end_line,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Error,
+    // For soft expectations, e.g. optional then-change targets.
+    Warning,
+    // For reminders which can never be satisfied, e.g. then-change URLs.
+    Info,
+}
+
 // Diagnostics should always be tied to the location where we want the user to
 // make a change, i.e. if a.sh contains a "if change ... then change b.sh", a.sh
 // has been changed but b.sh has not, then the diagnostic should be tied to b.sh.
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Diagnostic {
     pub path: String,
     // 0-indexed, inclusive-exclusive
     // NB: I don't love this representation, but it doesn't make a big difference to me
+    #[serde(
+        default,
+        serialize_with = "serialize_start_line",
+        deserialize_with = "deserialize_start_line"
+    )]
     pub start_line: Option<usize>,
     pub end_line: Option<usize>,
     pub message: String,
+    // The if-change-then-change block responsible for this diagnostic, if any; i.e. for the
+    // a.sh/b.sh example above, this would point at the block in a.sh.
+    pub related: Option<Location>,
+    pub severity: Severity,
+    // How `--fix` can resolve this diagnostic, if it can.
+    #[serde(skip)]
+    pub fix: Option<Fix>,
+}
+
+impl Diagnostic {
+    /// Whether this is something that has to be dealt with, which fails the check, rather than
+    /// advice or information which is just worth knowing. Warnings only count if
+    /// `deny_warnings` is set.
+    pub fn is_violation(&self, deny_warnings: bool) -> bool {
+        match self.severity {
+            Severity::Error => true,
+            Severity::Warning => deny_warnings,
+            Severity::Info => false,
+        }
+    }
+
+    pub fn location(&self) -> Location {
+        Location {
+            path: self.path.clone(),
+            start_line: self.start_line,
+            end_line: self.end_line,
+        }
+    }
+}
+
+impl Severity {
+    /// How messages of this severity are prefixed, e.g. "warning: ".
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Severity::Error => "",
+            Severity::Warning => "warning: ",
+            Severity::Info => "info: ",
+        }
+    }
 }
 
 impl fmt::Display for Diagnostic {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} - {}",
+            "{} - {}{}",
             DiagnosticPosition {
                 path: &self.path,
                 start_line: self.start_line,
                 end_line: self.end_line,
             },
+            self.severity.prefix(),
             self.message
         )
     }
@@ -0,0 +1,64 @@
+// Content digests, as used by "if-change(hash=sha256:...)" to record what a block looked like
+// when its then-change targets were last known to be in sync.
+
+use sha2::{Digest, Sha256};
+
+const PREFIX: &str = "sha256:";
+// Abbreviated digests are fine, but not so short that a stale block could plausibly match.
+const MIN_HEX_DIGITS: usize = 8;
+
+/// Whether `hash` is of the form "sha256:<hex digest>", possibly abbreviated.
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.strip_prefix(PREFIX).is_some_and(|hex| {
+        (MIN_HEX_DIGITS..=64).contains(&hex.len())
+            && hex
+                .chars()
+                .all(|ch| ch.is_ascii_digit() || ('a'..='f').contains(&ch))
+    })
+}
+
+/// The digest of `lines` (each taken to end with a newline), as "sha256:<hex digest>".
+pub fn digest<'a>(lines: impl Iterator<Item = &'a str>) -> String {
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+
+    let hex = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    format!("{}{}", PREFIX, hex)
+}
+
+/// `digest`, abbreviated to the same length as `hash` if that is abbreviated.
+pub fn abbreviate_like<'a>(digest: &'a str, hash: &str) -> &'a str {
+    &digest[..hash
+        .len()
+        .clamp(PREFIX.len() + MIN_HEX_DIGITS, digest.len())]
+}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn digests_lines() -> anyhow::Result<()> {
+        let digest = digest(["lorem", "ipsum"].into_iter());
+
+        assert_that!(digest.as_str())
+            .is_equal_to("sha256:df6e317732d215afdadd3fa5dfd7c725dd10dfd2a7e019adefb88695105273c7");
+        assert_that!(is_valid_hash(&digest)).is_true();
+        assert_that!(is_valid_hash("sha256:df6e3177")).is_true();
+        assert_that!(is_valid_hash("sha256:df6e")).is_false();
+        assert_that!(is_valid_hash("sha256:DF6E3177")).is_false();
+        assert_that!(is_valid_hash("md5:df6e3177")).is_false();
+        assert_that!(abbreviate_like(&digest, "sha256:00000000")).is_equal_to("sha256:df6e3177");
+
+        Ok(())
+    }
+}
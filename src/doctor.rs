@@ -0,0 +1,164 @@
+use crate::config::{Config, CONFIG_FILE};
+use crate::if_change_then_change2::FileNode;
+use crate::{git, hook};
+use std::fmt;
+
+#[derive(PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    // What the user should do about a warning or failure.
+    remediation: Option<String>,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        writeln!(f, "{:<5} {}: {}", status, self.name, self.detail)?;
+        if let Some(remediation) = &self.remediation {
+            writeln!(f, "      -> {}", remediation)?;
+        }
+        Ok(())
+    }
+}
+
+const SAMPLE_PATH: &str = "sample.sh";
+const SAMPLE: &str = "\
+echo before
+# if-change
+echo inside
+# then-change other.sh
+echo after
+";
+
+/// Checks that everything the tool relies on is in working order, printing what was checked and
+/// how to fix anything that isn't. Returns whether every check passed; warnings are not failures.
+pub fn doctor() -> bool {
+    let mut checks = Vec::new();
+
+    checks.push(match git::version() {
+        Ok(version) => Check {
+            name: "git",
+            status: Status::Ok,
+            detail: version,
+            remediation: None,
+        },
+        Err(err) => Check {
+            name: "git",
+            status: Status::Fail,
+            detail: format!("{:#}", err),
+            remediation: Some("install git and make sure it is on your PATH".to_string()),
+        },
+    });
+
+    match git::repo_root() {
+        Ok(repo_root) => {
+            checks.push(Check {
+                name: "repo root",
+                status: Status::Ok,
+                detail: repo_root.display().to_string(),
+                remediation: None,
+            });
+            checks.push(match Config::load(&repo_root) {
+                Ok(Some(_)) => Check {
+                    name: "config",
+                    status: Status::Ok,
+                    detail: format!("{} is valid", CONFIG_FILE),
+                    remediation: None,
+                },
+                Ok(None) => Check {
+                    name: "config",
+                    status: Status::Ok,
+                    detail: format!("no {}, using defaults", CONFIG_FILE),
+                    remediation: None,
+                },
+                Err(err) => Check {
+                    name: "config",
+                    status: Status::Fail,
+                    detail: format!("{:#}", err),
+                    remediation: Some(format!(
+                        "fix or remove {}",
+                        repo_root.join(CONFIG_FILE).display()
+                    )),
+                },
+            });
+        }
+        Err(err) => checks.push(Check {
+            name: "repo root",
+            status: Status::Fail,
+            detail: format!("{:#}", err),
+            remediation: Some("run this from inside a git repository".to_string()),
+        }),
+    }
+
+    checks.push(match git::hooks_dir() {
+        Ok(hooks_dir) => match hook::installed(&hooks_dir).as_slice() {
+            [] => Check {
+                name: "hooks",
+                status: Status::Warn,
+                detail: format!(
+                    "no hook in {} runs {}",
+                    hooks_dir.display(),
+                    env!("CARGO_PKG_NAME")
+                ),
+                remediation: Some(format!(
+                    "run `{} install-hook --pre-commit` (or --pre-push)",
+                    env!("CARGO_PKG_NAME"),
+                )),
+            },
+            installed => Check {
+                name: "hooks",
+                status: Status::Ok,
+                detail: format!("installed as {}", installed.join(", ")),
+                remediation: None,
+            },
+        },
+        Err(err) => Check {
+            name: "hooks",
+            status: Status::Fail,
+            detail: format!("{:#}", err),
+            remediation: Some("run this from inside a git repository".to_string()),
+        },
+    });
+
+    let parsed = FileNode::from_str(SAMPLE_PATH, SAMPLE);
+    checks.push(
+        match parsed.as_ref().map(|file_node| file_node.blocks.as_slice()) {
+            Ok([block])
+                if block.then_change.len() == 1
+                    && block.then_change[0].1.path == "other.sh"
+                    && block.content_range() == (1..4) =>
+            {
+                Check {
+                    name: "parser",
+                    status: Status::Ok,
+                    detail: "parsed sample block".to_string(),
+                    remediation: None,
+                }
+            }
+            _ => Check {
+                name: "parser",
+                status: Status::Fail,
+                detail: format!("unexpected result parsing sample: {:?}", parsed),
+                remediation: Some("this is a bug; please report it".to_string()),
+            },
+        },
+    );
+
+    for check in checks.iter() {
+        print!("{}", check);
+    }
+
+    checks.iter().all(|check| check.status != Status::Fail)
+}
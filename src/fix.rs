@@ -0,0 +1,1059 @@
+use crate::diagnostic::Diagnostic;
+use crate::digest;
+use crate::if_change_then_change2::{self, BlockNode, FileNode, Keywords, ParseOptions};
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Returns the (start, end) comment delimiters we use when writing directives into `path`.
+///
+/// This is only a best-effort guess based on the file extension; the parser itself does not
+/// care which comment format is used, so all this affects is how natural the result looks.
+fn comment_delimiters(path: &str) -> (&'static str, &'static str) {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    match extension {
+        "c" | "cc" | "cpp" | "h" | "hpp" | "cs" | "go" | "java" | "js" | "jsx" | "kt" | "proto"
+        | "rs" | "scala" | "swift" | "ts" | "tsx" => ("//", ""),
+        "css" => ("/*", " */"),
+        "html" | "md" | "xml" => ("<!--", " -->"),
+        "hs" | "lua" | "sql" => ("--", ""),
+        _ => ("#", ""),
+    }
+}
+
+/// Renders an empty if-change-then-change block for `path` which points at `then_change_path`.
+pub fn scaffold_block(path: &str, then_change_path: &str) -> String {
+    let (start, end) = comment_delimiters(path);
+
+    format!("{start} if-change{end}\n{start} then-change {then_change_path}{end}\n")
+}
+
+/// An edit which resolves a diagnostic, applied by `--fix` to the diagnostic's file.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fix {
+    /// Add a block, named `name` if the then-change pointing at it names one, which points back
+    /// at `then_change`. `origin_content` is the content of the block pointing at it, to guess
+    /// which lines it should go around.
+    ScaffoldBlock {
+        name: Option<String>,
+        then_change: String,
+        origin_content: String,
+    },
+    /// Remove the then-change targets on lines `linenos`, which do not exist, along with the
+    /// if-change-then-change itself if those are all of its targets.
+    RemoveThenChange { linenos: Vec<usize> },
+    /// Point the then-change target on line `lineno` at `new_path`, which `old_path` was renamed
+    /// to.
+    RenameThenChange {
+        lineno: usize,
+        old_path: String,
+        new_path: String,
+    },
+}
+
+/// A line which says where to scaffold blocks in a file, e.g. "# ictc-scaffold", so that they
+/// don't need moving into place afterwards.
+const SCAFFOLD_MARKER: &str = "ictc-scaffold";
+
+/// The words in `s` long enough to say something about what a line is for, e.g. "VERSION".
+fn words(s: &str) -> HashSet<&str> {
+    s.split(|ch: char| !ch.is_alphanumeric() && ch != '_')
+        .filter(|word| word.len() >= 3)
+        .collect()
+}
+
+/// Returns the edits which scaffold a block in `contents`, the contents of `path`, pointing back
+/// at `then_change`: in place of a SCAFFOLD_MARKER line if there is one, otherwise around the
+/// line outside of any block sharing the most words with `origin_content`, and failing that, at
+/// the end of the file.
+fn scaffold_edits(
+    path: &str,
+    contents: &str,
+    name: Option<&str>,
+    then_change: &str,
+    origin_content: &str,
+) -> Vec<LineEdit> {
+    let (start, end) = comment_delimiters(path);
+    let name = name.map(|name| format!("({})", name)).unwrap_or_default();
+    let lines = contents.lines().collect::<Vec<_>>();
+    let directives = |indent: &str| {
+        (
+            format!("{indent}{start} if-change{name}{end}"),
+            format!("{indent}{start} then-change {then_change}{end}"),
+        )
+    };
+
+    let marker = lines.iter().position(|line| {
+        line.split_once(SCAFFOLD_MARKER)
+            .is_some_and(|(prefix, suffix)| {
+                if_change_then_change2::is_comment_prefix(prefix)
+                    && if_change_then_change2::is_comment_prefix(suffix)
+            })
+    });
+    if let Some(lineno) = marker {
+        let indent = &lines[lineno][..lines[lineno].len() - lines[lineno].trim_start().len()];
+        let (if_change, then_change) = directives(indent);
+        return vec![LineEdit {
+            lineno,
+            lines: vec![if_change, then_change],
+        }];
+    }
+
+    let block_ranges = FileNode::from_str(path, contents)
+        .map(|file_node| {
+            file_node
+                .blocks
+                .iter()
+                .map(BlockNode::content_range)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let origin_words = words(origin_content);
+    let best_guess = lines
+        .iter()
+        .enumerate()
+        .filter(|(lineno, _)| !block_ranges.iter().any(|range| range.contains(lineno)))
+        .map(|(lineno, line)| (words(line).intersection(&origin_words).count(), lineno))
+        .filter(|(shared, _)| *shared > 0)
+        // The first of the lines sharing the most words.
+        .min_by_key(|(shared, lineno)| (std::cmp::Reverse(*shared), *lineno));
+    if let Some((_, lineno)) = best_guess {
+        let line = lines[lineno];
+        let (if_change, then_change) = directives(&line[..line.len() - line.trim_start().len()]);
+        return vec![LineEdit {
+            lineno,
+            lines: vec![if_change, line.to_string(), then_change],
+        }];
+    }
+
+    let (if_change, then_change) = directives("");
+    match lines.last() {
+        Some(last) => vec![LineEdit {
+            lineno: lines.len() - 1,
+            lines: vec![last.to_string(), if_change, then_change],
+        }],
+        // There's no line to replace in an empty file, so there's nothing to do but start it.
+        None => Vec::new(),
+    }
+}
+
+/// Returns the edits which remove the then-change targets on `linenos` from `contents`, the
+/// contents of `path`, and whether they remove the whole if-change-then-change. Targets which
+/// share a line with a directive are only removed along with the whole block, as are the
+/// targets of named blocks split into several regions.
+fn remove_then_change_edits(
+    path: &str,
+    contents: &str,
+    linenos: &[usize],
+) -> (Vec<LineEdit>, bool) {
+    let delete = |lineno| LineEdit {
+        lineno,
+        lines: Vec::new(),
+    };
+    let Ok(file_node) = FileNode::from_str(path, contents) else {
+        return (Vec::new(), false);
+    };
+    let Some(block) = file_node.blocks.iter().find(|block| {
+        linenos
+            .first()
+            .is_some_and(|lineno| block.then_change_range().contains(lineno))
+    }) else {
+        return (Vec::new(), false);
+    };
+
+    let then_change_range = block.then_change_range();
+    if block
+        .then_change
+        .iter()
+        .all(|(lineno, _)| linenos.contains(lineno))
+    {
+        let is_split = block.key.name.as_ref().is_some_and(|name| {
+            file_node
+                .blocks
+                .iter()
+                .filter(|other| other.key.name.as_ref() == Some(name))
+                .count()
+                > 1
+        });
+        if is_split {
+            return (Vec::new(), false);
+        }
+        let edits = std::iter::once(block.content_range().start)
+            .chain(then_change_range)
+            .map(delete)
+            .collect();
+        return (edits, true);
+    }
+
+    let edits = linenos
+        .iter()
+        .copied()
+        .filter(|lineno| then_change_range.start < *lineno && lineno + 1 < then_change_range.end)
+        .map(delete)
+        .collect();
+    (edits, false)
+}
+
+impl Fix {
+    /// The edits which apply this fix to `contents`, the contents of `path`.
+    pub fn edits(&self, path: &str, contents: &str) -> Vec<LineEdit> {
+        match self {
+            Fix::ScaffoldBlock {
+                name,
+                then_change,
+                origin_content,
+            } => scaffold_edits(path, contents, name.as_deref(), then_change, origin_content),
+            Fix::RemoveThenChange { linenos } => {
+                remove_then_change_edits(path, contents, linenos).0
+            }
+            // There's only one target per line, so the first occurrence of the old path is it.
+            // A target written relative to this file is rewritten relative to it too.
+            Fix::RenameThenChange {
+                lineno,
+                old_path,
+                new_path,
+            } => {
+                let relative =
+                    |target: &str| if_change_then_change2::relative_target_path(path, target);
+                contents
+                    .lines()
+                    .nth(*lineno)
+                    .and_then(|line| {
+                        let old_relative = relative(old_path);
+                        if line.contains(old_relative.as_str()) {
+                            Some(line.replacen(old_relative.as_str(), &relative(new_path), 1))
+                        } else if line.contains(old_path.as_str()) {
+                            Some(line.replacen(old_path.as_str(), new_path, 1))
+                        } else {
+                            None
+                        }
+                    })
+                    .map(|line| LineEdit {
+                        lineno: *lineno,
+                        lines: vec![line],
+                    })
+                    .into_iter()
+                    .collect()
+            }
+        }
+    }
+
+    /// What applying this fix to `contents`, the contents of `path`, does, e.g. for
+    /// "b.sh - fixed: ...".
+    pub fn description(&self, path: &str, contents: &str) -> String {
+        match self {
+            Fix::ScaffoldBlock { then_change, .. } => format!(
+                "added an if-change-then-change pointing at {}; check that it's around the right lines",
+                then_change
+            ),
+            Fix::RemoveThenChange { linenos } => {
+                if remove_then_change_edits(path, contents, linenos).1 {
+                    "removed the if-change-then-change, none of whose then-change targets exist"
+                        .to_string()
+                } else {
+                    "removed the then-change target, which does not exist".to_string()
+                }
+            }
+            Fix::RenameThenChange { new_path, .. } => {
+                format!("updated the then-change to '{}'", new_path)
+            }
+        }
+    }
+}
+
+/// The edits to each file which fix a set of diagnostics (see plan_fixes).
+#[derive(Default)]
+pub struct FixPlan {
+    /// What fixing each diagnostic which can be fixed does, by index.
+    pub fixed: BTreeMap<usize, String>,
+    /// The contents of each file to edit, and the edits (sorted by line) to make to it.
+    pub edits_by_path: BTreeMap<String, (String, Vec<LineEdit>)>,
+}
+
+impl FixPlan {
+    /// The edits as a unified diff.
+    pub fn diff(&self) -> String {
+        self.edits_by_path
+            .iter()
+            .map(|(path, (contents, edits))| unified_diff(path, contents, edits))
+            .collect()
+    }
+}
+
+/// Works out how to apply the fixes attached to `diagnostics` to the files they're in.
+/// Diagnostics sharing a fix (e.g. the several diagnostics about one missing block) are fixed
+/// together; fixes which would edit the same line as another fix to the same file are left for the
+/// next run.
+pub fn plan_fixes(diagnostics: &[Diagnostic]) -> Result<FixPlan> {
+    let mut fixes_by_path: BTreeMap<&str, Vec<(&Fix, Vec<usize>)>> = BTreeMap::new();
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        let Some(fix) = &diagnostic.fix else {
+            continue;
+        };
+        let fixes = fixes_by_path.entry(&diagnostic.path).or_default();
+        match fixes.iter_mut().find(|(other, _)| *other == fix) {
+            Some((_, indices)) => indices.push(i),
+            None => fixes.push((fix, vec![i])),
+        }
+    }
+
+    let mut fixed = BTreeMap::new();
+    let mut edits_by_path = BTreeMap::new();
+    for (path, fixes) in fixes_by_path {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {} to fix it", path))?;
+        let mut edits: Vec<LineEdit> = Vec::new();
+        for (fix, indices) in fixes {
+            let fix_edits = fix.edits(path, &contents);
+            if fix_edits.is_empty()
+                || fix_edits
+                    .iter()
+                    .any(|edit| edits.iter().any(|other| other.lineno == edit.lineno))
+            {
+                continue;
+            }
+            edits.extend(fix_edits);
+            let description = fix.description(path, &contents);
+            fixed.extend(indices.into_iter().map(|i| (i, description.clone())));
+        }
+        if edits.is_empty() {
+            continue;
+        }
+        edits.sort_by_key(|edit| edit.lineno);
+        edits_by_path.insert(path.to_string(), (contents, edits));
+    }
+
+    Ok(FixPlan {
+        fixed,
+        edits_by_path,
+    })
+}
+
+/// Applies the fixes attached to `diagnostics` to the files they're in (see plan_fixes), unless
+/// `dry_run` is set, and returns what was (or would be) done.
+pub fn apply_fixes(diagnostics: &[Diagnostic], dry_run: bool) -> Result<FixPlan> {
+    let plan = plan_fixes(diagnostics)?;
+    if !dry_run {
+        for (path, (contents, edits)) in plan.edits_by_path.iter() {
+            std::fs::write(path, apply_edits(contents, edits))
+                .with_context(|| format!("failed to write {}", path))?;
+        }
+    }
+    Ok(plan)
+}
+
+/// The edits which turn `contents` into `new_contents`, which has the same number of lines.
+pub fn line_edits(contents: &str, new_contents: &str) -> Vec<LineEdit> {
+    contents
+        .lines()
+        .zip(new_contents.lines())
+        .enumerate()
+        .filter(|(_, (line, new_line))| line != new_line)
+        .map(|(lineno, (_, new_line))| LineEdit {
+            lineno,
+            lines: vec![new_line.to_string()],
+        })
+        .collect()
+}
+
+/// Returns `contents` (the contents of the file parsed as `file_node`) with every stale hash
+/// replaced by the digest of its block's current content, along with the blocks that were
+/// updated. Abbreviated hashes stay abbreviated.
+pub fn update_digests<'a>(contents: &str, file_node: &'a FileNode) -> (String, Vec<&'a BlockNode>) {
+    let mut lines = contents
+        .split_inclusive('\n')
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    let mut updated = Vec::new();
+
+    for block in file_node.blocks.iter() {
+        let Some(hash) = &block.hash else {
+            continue;
+        };
+        if !block.has_stale_hash(contents) {
+            continue;
+        }
+        let content_digest = block.content_digest(contents);
+        let line = &mut lines[block.content_range().start];
+        *line = line.replacen(
+            &format!("hash={}", hash),
+            &format!("hash={}", digest::abbreviate_like(&content_digest, hash)),
+            1,
+        );
+        updated.push(block);
+    }
+
+    (lines.concat(), updated)
+}
+
+/// A replacement of line `lineno` (0-indexed) of a file by `lines`, of which there may be more
+/// than one, or none to delete the line.
+#[derive(Debug, PartialEq)]
+pub struct LineEdit {
+    pub lineno: usize,
+    pub lines: Vec<String>,
+}
+
+/// Splits `line` around `keyword`, if it's preceded only by a comment prefix.
+fn split_comment_keyword<'a>(line: &'a str, keyword: &str) -> Option<(&'a str, &'a str)> {
+    line.split_once(keyword)
+        .filter(|(prefix, _)| if_change_then_change2::is_comment_prefix(prefix))
+}
+
+/// Converts a Google-style target, e.g. "//a/b.cc:label", to our syntax, e.g. "a/b.cc#label".
+fn native_target(target: &str) -> String {
+    match if_change_then_change2::parse_target(target, true) {
+        Ok(key) => key.to_string(),
+        Err(_) => target.to_string(),
+    }
+}
+
+/// Migrates a line inside a multi-line "LINT.ThenChange(", returning the lines to replace it
+/// with (if any) and whether it closes the then-change.
+fn migrate_google_target_line(line: &str) -> (Option<Vec<String>>, bool) {
+    let closes = line.contains(')');
+    let Some(word) = line
+        .split_whitespace()
+        .find(|word| word.chars().any(char::is_alphanumeric))
+    else {
+        // e.g. the "// )" closing the then-change
+        if closes {
+            return (Some(vec![line.replacen(')', "end-change", 1)]), true);
+        }
+        return (None, false);
+    };
+
+    let start = line.find(word).unwrap_or_default();
+    let (lead, after) = (&line[..start], &line[start + word.len()..]);
+    let target = native_target(word.trim_end_matches([',', ')']));
+    if closes {
+        let after = after.replacen(')', "", 1);
+        return (
+            Some(vec![
+                format!("{lead}{target}"),
+                format!("{lead}end-change{after}"),
+            ]),
+            true,
+        );
+    }
+    (Some(vec![format!("{lead}{target}{after}")]), false)
+}
+
+/// Returns the edits which rewrite the legacy markers in `contents` to our syntax: Google-style
+/// "LINT.IfChange" and "LINT.ThenChange(...)", and the keyword aliases in `keywords`. Comment
+/// delimiters are left as they are.
+pub fn migrate(contents: &str, keywords: &Keywords) -> Vec<LineEdit> {
+    // Edits replace whole lines, so a byte-order mark has to be put back by hand.
+    let (bom, contents) = match contents.strip_prefix('\u{feff}') {
+        Some(contents) => ("\u{feff}", contents),
+        None => ("", contents),
+    };
+    let renames = std::iter::once(("LINT.IfChange", "if-change"))
+        .chain(
+            keywords
+                .if_change
+                .iter()
+                .map(|alias| (alias.as_str(), "if-change")),
+        )
+        .chain(
+            keywords
+                .then_change
+                .iter()
+                .map(|alias| (alias.as_str(), "then-change")),
+        )
+        .chain(
+            keywords
+                .end_change
+                .iter()
+                .map(|alias| (alias.as_str(), "end-change")),
+        )
+        .collect::<Vec<_>>();
+
+    let mut edits = Vec::new();
+    // Whether we're inside a multi-line "LINT.ThenChange(".
+    let mut in_google_then_change = false;
+
+    for (lineno, line) in contents.lines().enumerate() {
+        let lines = if in_google_then_change {
+            let (lines, closes) = migrate_google_target_line(line);
+            in_google_then_change = !closes;
+            lines
+        } else if let Some((prefix, rest)) = split_comment_keyword(line, "LINT.ThenChange") {
+            match rest.strip_prefix('(').map(|rest| rest.split_once(')')) {
+                Some(Some((targets, suffix))) => {
+                    let targets = targets
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|target| !target.is_empty())
+                        .map(native_target)
+                        .collect::<Vec<_>>();
+                    match targets.as_slice() {
+                        [] => None,
+                        [target] => Some(vec![format!("{prefix}then-change {target}{suffix}")]),
+                        targets => {
+                            let mut lines = vec![format!("{prefix}then-change{suffix}")];
+                            lines.extend(
+                                targets
+                                    .iter()
+                                    .map(|target| format!("{prefix}  {target}{suffix}")),
+                            );
+                            lines.push(format!("{prefix}end-change{suffix}"));
+                            Some(lines)
+                        }
+                    }
+                }
+                Some(None) => {
+                    in_google_then_change = true;
+                    Some(vec![format!("{prefix}then-change{}", &rest[1..])])
+                }
+                None => None,
+            }
+        } else {
+            renames.iter().find_map(|(alias, keyword)| {
+                split_comment_keyword(line, alias)
+                    .map(|(prefix, rest)| vec![format!("{prefix}{keyword}{rest}")])
+            })
+        };
+
+        if let Some(lines) = lines {
+            if lines != [line] {
+                edits.push(LineEdit { lineno, lines });
+            }
+        }
+    }
+
+    if let Some(edit) = edits.first_mut().filter(|edit| edit.lineno == 0) {
+        edit.lines[0].insert_str(0, bom);
+    }
+
+    edits
+}
+
+/// The delimiters which start line comments, or block comments on the lines directives are on.
+const COMMENT_STARTS: &[&str] = &["<!--", "/*", "//", "--", "#", ";", "%", "*"];
+
+/// A comment line split into its indentation, the delimiter starting the comment (e.g. "//", or
+/// "///" which repeats its last character), the text of the comment, and the delimiter closing it
+/// if it's a block comment ending on this line (e.g. "-->").
+struct CommentLine<'a> {
+    indent: &'a str,
+    start: &'a str,
+    text: &'a str,
+    end: &'a str,
+}
+
+impl<'a> CommentLine<'a> {
+    fn split(line: &'a str) -> Option<CommentLine<'a>> {
+        let rest = line.trim_start();
+        let indent = &line[..line.len() - rest.len()];
+        let token = COMMENT_STARTS
+            .iter()
+            .find(|token| rest.starts_with(**token))?;
+        let last = token.chars().last()?;
+        let start_len = token.len() + rest[token.len()..].len()
+            - rest[token.len()..].trim_start_matches(last).len();
+        let (start, text) = rest.split_at(start_len);
+        let (text, end) = ["*/", "-->"]
+            .iter()
+            .find_map(|end| text.trim_end().strip_suffix(end).map(|text| (text, *end)))
+            .unwrap_or((text, ""));
+        Some(CommentLine {
+            indent,
+            start,
+            text: text.trim(),
+            end,
+        })
+    }
+
+    /// Renders the comment with `text`, spaced the way we write directives.
+    fn with_text(&self, text: &str) -> String {
+        let end = if self.end.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", self.end)
+        };
+        format!("{}{} {}{}", self.indent, self.start, text, end)
+    }
+}
+
+/// How `format` lays out directives.
+pub struct FormatOptions<'a> {
+    pub parse_options: &'a ParseOptions,
+    /// Inline then-changes longer than this many characters are split into a then-change,
+    /// end-change and the target between them.
+    pub max_width: usize,
+}
+
+/// Rewrites the directive `line` (whose keyword is `keyword` or one of `aliases`) with one space
+/// after the comment delimiter and no more than one between its parts, and with its keyword
+/// spelled the usual way.
+fn format_directive(line: &str, keyword: &str, aliases: &[String]) -> Option<String> {
+    let comment = CommentLine::split(line)?;
+    let rest = std::iter::once(keyword)
+        .chain(aliases.iter().map(String::as_str))
+        .find_map(|keyword| comment.text.strip_prefix(keyword))?;
+    // Keyword modifiers and if-change attributes stay attached to the keyword.
+    let (attached, rest) = match rest.find(char::is_whitespace) {
+        Some(i) if !rest.starts_with(char::is_whitespace) => rest.split_at(i),
+        Some(_) => ("", rest),
+        None => (rest, ""),
+    };
+    let rest = rest.split_whitespace().collect::<Vec<_>>().join(" ");
+    let text = if rest.is_empty() {
+        format!("{keyword}{attached}")
+    } else {
+        format!("{keyword}{attached} {rest}")
+    };
+    Some(comment.with_text(&text))
+}
+
+/// Returns the edits which format the directives in `contents`, the contents of `path`:
+///
+///   - keyword aliases are spelled the usual way, with one space after the comment delimiter,
+///   - the targets of a then-change ... end-change are sorted and indented alike, and
+///   - inline then-changes longer than `options.max_width` are split over several lines.
+///
+/// Google-style directives (see `migrate`), and the targets of then-changes in block comments
+/// spanning several lines, are left as they are.
+pub fn format(path: &str, contents: &str, options: &FormatOptions) -> Vec<LineEdit> {
+    let Ok(file_node) = FileNode::from_str_with_options(path, contents, options.parse_options)
+    else {
+        return Vec::new();
+    };
+    let lines = contents.lines().collect::<Vec<_>>();
+    let keywords = &options.parse_options.keywords;
+
+    let mut edits = Vec::new();
+    let mut edit = |lineno: usize, new_lines: Vec<String>| {
+        if new_lines != [lines[lineno]] {
+            edits.push(LineEdit {
+                lineno,
+                lines: new_lines,
+            });
+        }
+    };
+    for block in file_node.blocks.iter() {
+        let if_change_lineno = block.content_range().start;
+        let then_change_range = block.then_change_range();
+        if then_change_range
+            .clone()
+            .any(|lineno| lines[lineno].contains("LINT.ThenChange"))
+        {
+            continue;
+        }
+        if let Some(line) =
+            format_directive(lines[if_change_lineno], "if-change", &keywords.if_change)
+        {
+            edit(if_change_lineno, vec![line]);
+        }
+
+        let then_change_lineno = then_change_range.start;
+        let end_change_lineno = then_change_range.end - 1;
+        let (Some(then_change), Some(comment)) = (
+            format_directive(
+                lines[then_change_lineno],
+                "then-change",
+                &keywords.then_change,
+            ),
+            CommentLine::split(lines[then_change_lineno]),
+        ) else {
+            continue;
+        };
+
+        if then_change_lineno == end_change_lineno {
+            // Inline then-changes with a reason stay inline, since the reason goes with it.
+            let split = block.then_change.len() == 1
+                && then_change.chars().count() > options.max_width
+                && comment.end.is_empty()
+                && !then_change.contains(" -- ");
+            if !split {
+                edit(then_change_lineno, vec![then_change]);
+                continue;
+            }
+            let text = then_change.trim_start()[comment.start.len()..].trim_start();
+            let Some((directive, target)) = text.split_once(' ') else {
+                continue;
+            };
+            let prefix = format!("{}{}", comment.indent, comment.start);
+            edit(
+                then_change_lineno,
+                vec![
+                    format!("{prefix} {directive}"),
+                    format!("{prefix}   {target}"),
+                    format!("{prefix} end-change"),
+                ],
+            );
+            continue;
+        }
+
+        edit(then_change_lineno, vec![then_change]);
+        if let Some(line) =
+            format_directive(lines[end_change_lineno], "end-change", &keywords.end_change)
+        {
+            edit(end_change_lineno, vec![line]);
+        }
+
+        // Targets are only rewritten if each is on a line comment of its own, like the
+        // then-change, rather than e.g. in the middle of a block comment.
+        let targets = (then_change_lineno + 1..end_change_lineno)
+            .map(|lineno| {
+                CommentLine::split(lines[lineno])
+                    .filter(|target| target.start == comment.start && target.end.is_empty())
+                    .map(|target| target.text)
+            })
+            .collect::<Option<Vec<_>>>();
+        let Some(mut targets) = targets else {
+            continue;
+        };
+        targets.sort_by_key(|target| target.trim_start_matches('?'));
+        for (lineno, target) in (then_change_lineno + 1..end_change_lineno).zip(targets) {
+            edit(
+                lineno,
+                vec![format!("{}{}   {}", comment.indent, comment.start, target)],
+            );
+        }
+    }
+
+    edits.sort_by_key(|edit| edit.lineno);
+    edits
+}
+
+/// Returns `contents` with `edits` (sorted by line) applied.
+pub fn apply_edits(contents: &str, edits: &[LineEdit]) -> String {
+    let mut edits = edits.iter().peekable();
+    let mut ret = String::new();
+    for (lineno, line) in contents.split_inclusive('\n').enumerate() {
+        match edits.next_if(|edit| edit.lineno == lineno) {
+            Some(edit) => {
+                let ending = &line[line.trim_end_matches(['\r', '\n']).len()..];
+                for new_line in edit.lines.iter() {
+                    ret.push_str(new_line);
+                    ret.push_str(if ending.is_empty() { "\n" } else { ending });
+                }
+                if ending.is_empty() && !edit.lines.is_empty() {
+                    ret.pop();
+                }
+            }
+            None => ret.push_str(line),
+        }
+    }
+    ret
+}
+
+/// Renders `edits` (sorted by line) to `contents`, the contents of `path`, as a git-style
+/// unified diff with three lines of context.
+pub fn unified_diff(path: &str, contents: &str, edits: &[LineEdit]) -> String {
+    rename_diff(path, path, contents, edits)
+}
+
+/// Like unified_diff, but for a file which is also moved from `old_path` to `new_path`.
+pub fn rename_diff(old_path: &str, new_path: &str, contents: &str, edits: &[LineEdit]) -> String {
+    const CONTEXT: usize = 3;
+
+    let old_lines = contents.lines().collect::<Vec<_>>();
+    let mut ret = format!("diff --git a/{old_path} b/{new_path}\n");
+    if old_path != new_path {
+        ret += &format!("rename from {old_path}\nrename to {new_path}\n");
+    }
+    if edits.is_empty() {
+        return ret;
+    }
+    ret += &format!("--- a/{old_path}\n+++ b/{new_path}\n");
+    // How many more lines the new file has than the old one, as of the current hunk.
+    let mut offset = 0isize;
+
+    let mut i = 0;
+    while i < edits.len() {
+        // Edits whose context would overlap go in the same hunk.
+        let mut j = i + 1;
+        while j < edits.len() && edits[j].lineno <= edits[j - 1].lineno + 2 * CONTEXT {
+            j += 1;
+        }
+        let hunk_edits = &edits[i..j];
+
+        let start = hunk_edits[0].lineno.saturating_sub(CONTEXT);
+        let end = (hunk_edits[j - i - 1].lineno + 1 + CONTEXT).min(old_lines.len());
+        let mut body = String::new();
+        let mut new_len = 0;
+        let mut hunk_edits_iter = hunk_edits.iter().peekable();
+        for (lineno, line) in old_lines.iter().enumerate().take(end).skip(start) {
+            match hunk_edits_iter.next_if(|edit| edit.lineno == lineno) {
+                Some(edit) => {
+                    body += &format!("-{}\n", line);
+                    for new_line in edit.lines.iter() {
+                        body += &format!("+{}\n", new_line);
+                    }
+                    new_len += edit.lines.len();
+                }
+                None => {
+                    body += &format!(" {}\n", line);
+                    new_len += 1;
+                }
+            }
+        }
+        let old_len = end - start;
+        ret += &format!(
+            "@@ -{},{} +{},{} @@\n",
+            start + 1,
+            old_len,
+            (start as isize + offset + 1),
+            new_len
+        );
+        ret += &body;
+        offset += new_len as isize - old_len as isize;
+
+        i = j;
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fix::*;
+    use crate::if_change_then_change2::FileNode;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn scaffold_block_is_well_formed() -> anyhow::Result<()> {
+        for path in ["a.sh", "a.rs", "a.md", "a.css", "a.sql", "Makefile"] {
+            let parsed = FileNode::from_str(path, &scaffold_block(path, "b.sh"))?;
+
+            assert_that!(parsed.blocks)
+                .named(format!("blocks scaffolded for {:?}", path).as_str())
+                .has_length(1);
+            assert_that!(parsed.blocks[0].then_change[0].1.path.as_str()).is_equal_to("b.sh");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn scaffold_edits_placement() -> anyhow::Result<()> {
+        let fix = Fix::ScaffoldBlock {
+            name: None,
+            then_change: "a.sh".to_string(),
+            origin_content: "TIMEOUT_SECS=30".to_string(),
+        };
+
+        // Around the line sharing the most words with the origin, outside of existing blocks...
+        let contents = "\
+# if-change
+TIMEOUT_SECS = 30
+# then-change c.sh
+RETRIES = 3
+TIMEOUT_SECS = 30
+";
+        assert_that!(apply_edits(contents, &fix.edits("b.py", contents)).as_str()).is_equal_to(
+            "\
+# if-change
+TIMEOUT_SECS = 30
+# then-change c.sh
+RETRIES = 3
+# if-change
+TIMEOUT_SECS = 30
+# then-change a.sh
+",
+        );
+
+        // ...or at the end, if there's no such line.
+        let contents = "RETRIES = 3\n";
+        assert_that!(apply_edits(contents, &fix.edits("b.py", contents)).as_str()).is_equal_to(
+            "\
+RETRIES = 3
+# if-change
+# then-change a.sh
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn remove_then_change_edits_split_blocks() -> anyhow::Result<()> {
+        let contents = "\
+# if-change(api)
+PORT=80
+# end-change
+HOST=example.com
+# if-change(api)
+SCHEME=https
+# then-change gone.sh
+";
+        // Removing the only target of a named block split into regions would leave the other
+        // region without one, so that's left to the user.
+        let fix = Fix::RemoveThenChange { linenos: vec![6] };
+        assert_that!(fix.edits("a.sh", contents)).is_empty();
+
+        Ok(())
+    }
+
+    #[test]
+    fn rename_then_change_keeps_relative_targets_relative() -> anyhow::Result<()> {
+        let fix = Fix::RenameThenChange {
+            lineno: 0,
+            old_path: "src/old.sh".to_string(),
+            new_path: "bin/new.sh".to_string(),
+        };
+        assert_that!(fix.edits("src/a.sh", "# then-change ./old.sh\n")).is_equal_to(vec![
+            LineEdit {
+                lineno: 0,
+                lines: vec!["# then-change ../bin/new.sh".to_string()],
+            },
+        ]);
+        assert_that!(fix.edits("docs/a.md", "<!-- then-change src/old.sh -->\n")).is_equal_to(
+            vec![LineEdit {
+                lineno: 0,
+                lines: vec!["<!-- then-change bin/new.sh -->".to_string()],
+            }],
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn format_is_idempotent() -> anyhow::Result<()> {
+        let parse_options = ParseOptions::default();
+        let options = FormatOptions {
+            parse_options: &parse_options,
+            max_width: 30,
+        };
+        let contents = "\
+<!--if-change   -->
+| flag | default |
+<!--then-change   docs/flags/defaults.md-->
+//if-change(api)
+//then-change-any?   b.rs  -- only one client needs it
+";
+        let formatted = apply_edits(contents, &format("a.md", contents, &options));
+        assert_that!(formatted.as_str()).is_equal_to(
+            "\
+<!-- if-change -->
+| flag | default |
+<!-- then-change docs/flags/defaults.md -->
+// if-change(api)
+// then-change-any? b.rs -- only one client needs it
+",
+        );
+        assert_that!(format("a.md", &formatted, &options)).is_empty();
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_digests_rewrites_stale_hashes() -> anyhow::Result<()> {
+        let contents = "\
+# if-change(hash=sha256:00000000)
+lorem ipsum dolor
+sit amet
+# then-change b.sh
+# if-change(api, hash=sha256:e1a80849)
+lorem ipsum dolor
+sit amet
+# then-change b.sh
+";
+        let file_node = FileNode::from_str("a.sh", contents)?;
+        let (updated_contents, updated) = update_digests(contents, &file_node);
+
+        assert_that!(updated
+            .iter()
+            .map(|block| block.location().start_line)
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![Some(0)]);
+        assert_that!(updated_contents.as_str()).is_equal_to(
+            "\
+# if-change(hash=sha256:e1a80849)
+lorem ipsum dolor
+sit amet
+# then-change b.sh
+# if-change(api, hash=sha256:e1a80849)
+lorem ipsum dolor
+sit amet
+# then-change b.sh
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrate_rewrites_legacy_markers() -> anyhow::Result<()> {
+        let contents = "\
+// LINT.IfChange(flags)
+const FLAGS = [\"verbose\"];
+// LINT.ThenChange(//docs/flags.md, //src/flags.h:flags)
+/* LINT.IfChange */
+const LIMIT = 10;
+/* LINT.ThenChange(
+       //src/limit.h) */
+# IfChange
+# ThenChange(
+#   //a.sh,
+# )
+";
+        let keywords = Keywords {
+            if_change: vec!["IfChange".to_string()],
+            ..Default::default()
+        };
+        let edits = migrate(contents, &keywords);
+        let migrated = apply_edits(contents, &edits);
+        assert_that!(migrated.as_str()).is_equal_to(
+            "\
+// if-change(flags)
+const FLAGS = [\"verbose\"];
+// then-change
+//   docs/flags.md
+//   src/flags.h#flags
+// end-change
+/* if-change */
+const LIMIT = 10;
+/* then-change
+       src/limit.h
+       end-change */
+# if-change
+# ThenChange(
+#   //a.sh,
+# )
+",
+        );
+        assert_that!(FileNode::from_str(
+            "a.js",
+            &migrated[..migrated.find("# if").unwrap()]
+        ))
+        .is_ok();
+
+        let contents = "\u{feff}# LINT.IfChange\nlorem\n# LINT.ThenChange(//b.sh)\n";
+        assert_that!(apply_edits(contents, &migrate(contents, &Keywords::default())).as_str())
+            .is_equal_to("\u{feff}# if-change\nlorem\n# then-change b.sh\n");
+
+        assert_that!(unified_diff(
+            "a.js",
+            "one\ntwo\nthree\n",
+            &[LineEdit {
+                lineno: 1,
+                lines: vec!["2".to_string(), "2.5".to_string()],
+            }]
+        )
+        .as_str())
+        .is_equal_to(
+            "\
+diff --git a/a.js b/a.js
+--- a/a.js
++++ b/a.js
+@@ -1,3 +1,4 @@
+ one
+-two
++2
++2.5
+ three
+",
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::process::Command;
+
+fn git(args: &[&str]) -> Result<String> {
+    git_untrimmed(args).map(|output| output.trim().to_string())
+}
+
+// For output whose whitespace matters, e.g. diffs.
+fn git_untrimmed(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the output of `git --version`, e.g. "git version 2.43.0".
+pub fn version() -> Result<String> {
+    git(&["--version"])
+}
+
+/// Returns the root of the working tree containing the current directory.
+pub fn repo_root() -> Result<PathBuf> {
+    git(&["rev-parse", "--show-toplevel"]).map(PathBuf::from)
+}
+
+/// Returns the directory git runs hooks from, respecting core.hooksPath.
+pub fn hooks_dir() -> Result<PathBuf> {
+    git(&["rev-parse", "--path-format=absolute", "--git-path", "hooks"]).map(PathBuf::from)
+}
+
+/// Whether `path` is tracked but left out of the working tree, e.g. by a sparse checkout.
+pub fn is_skip_worktree(path: &str) -> Result<bool> {
+    // Entries marked skip-worktree are tagged "S", rather than "H" for cached.
+    Ok(git(&["ls-files", "-t", "--", path])?.starts_with("S "))
+}
+
+/// Returns the diff of the changes to `paths` which are staged for commit.
+pub fn staged_diff(paths: &[String]) -> Result<String> {
+    let mut args = vec!["diff", "--cached", "--no-ext-diff", "--no-color", "--"];
+    args.extend(paths.iter().map(String::as_str));
+    git_untrimmed(&args)
+}
+
+/// Returns a diff adding each file under `paths` which git neither tracks nor ignores, as if
+/// it had been `git add`ed.
+pub fn untracked_diff(paths: &[String]) -> Result<String> {
+    let mut args = vec!["ls-files", "--others", "--exclude-standard", "--"];
+    args.extend(paths.iter().map(String::as_str));
+    let mut diff = String::new();
+    for path in git(&args)?.lines() {
+        let output = Command::new("git")
+            .args(["diff", "--no-index", "--no-ext-diff", "--no-color", "--"])
+            .args(["/dev/null", path])
+            .output()
+            .context("failed to run git")?;
+        // With --no-index, git exits with 1 when the files differ, as they always do here.
+        if output.status.code() != Some(1) {
+            bail!(
+                "`git diff --no-index /dev/null {}` failed: {}",
+                path,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        diff += &String::from_utf8_lossy(&output.stdout);
+    }
+    Ok(diff)
+}
+
+/// Returns the commits reachable from HEAD but not from `since`, oldest first, skipping merges.
+pub fn commits_since(since: &str) -> Result<Vec<String>> {
+    let range = format!("{}..HEAD", since);
+    Ok(git(&["rev-list", "--reverse", "--no-merges", &range])?
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns "<abbreviated hash> <subject>" for `commit`.
+pub fn describe_commit(commit: &str) -> Result<String> {
+    git(&["show", "--no-patch", "--format=%h %s", commit])
+}
+
+/// Returns the diff `commit` made to its first parent.
+pub fn commit_diff(commit: &str) -> Result<String> {
+    let parent = format!("{}^", commit);
+    git_untrimmed(&["diff", "--no-ext-diff", "--no-color", &parent, commit])
+}
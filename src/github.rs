@@ -0,0 +1,557 @@
+// Posting diagnostics to GitHub, either to a pull request as inline review comments, or to a
+// commit as a check run with annotations, so that results show up where code is reviewed instead
+// of (or as well as) in CI logs.
+
+use crate::diagnostic::{Diagnostic, Location, Severity};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+
+// Appended to everything we post, so that later runs can tell our comments apart from humans'.
+const MARKER: &str = "<!-- if-change-then-change -->";
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReviewComment {
+    pub path: String,
+    // 1-indexed, on the post-diff side of the diff.
+    pub line: usize,
+    pub body: String,
+}
+
+/// The comments to leave for a set of diagnostics: inline where the diff lets us, and in the
+/// review's body otherwise (e.g. for files the pull request does not touch).
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Review {
+    pub comments: Vec<ReviewComment>,
+    pub body: Option<String>,
+}
+
+/// The lines (1-indexed, post-diff) of each file which GitHub allows review comments on, i.e.
+/// the added and context lines of the diff `input`.
+fn commentable_lines(input: &str) -> Result<HashMap<String, BTreeSet<usize>>> {
+    let mut patch_set = unidiff::PatchSet::new();
+    patch_set
+        .parse(input.replace("\r\n", "\n"))
+        .context("Error parsing diff")?;
+
+    Ok(patch_set
+        .files()
+        .iter()
+        .filter(|patched_file| patched_file.target_file != "/dev/null")
+        .map(|patched_file| {
+            let path = patched_file
+                .target_file
+                .strip_prefix("b/")
+                .unwrap_or(&patched_file.target_file)
+                .to_string();
+            let lines = patched_file
+                .hunks()
+                .iter()
+                .flat_map(|hunk| hunk.lines())
+                .filter_map(|line| line.target_line_no)
+                .collect();
+            (path, lines)
+        })
+        .collect())
+}
+
+/// Maps `diagnostics` to review comments on the diff `input`. A diagnostic goes on the first line
+/// of its range that's in the diff, failing that on the block responsible for it (which, for the
+/// usual "change in ... requires a change in ...", is where the diff changed something), and
+/// failing that in the review's body.
+pub fn review(input: &str, diagnostics: &[Diagnostic]) -> Result<Review> {
+    let commentable_lines = commentable_lines(input)?;
+    let place = |location: &Location| -> Option<usize> {
+        let lines = commentable_lines.get(&location.path)?;
+        let start = location.start_line? + 1;
+        let end = location.end_line.unwrap_or(start);
+        lines.range(start..=end.max(start)).next().copied()
+    };
+
+    let mut review = Review::default();
+    let mut unplaced = Vec::new();
+    for diagnostic in diagnostics {
+        let location = diagnostic.location();
+        if let Some(line) = place(&location) {
+            review.comments.push(ReviewComment {
+                path: location.path,
+                line,
+                body: format!(
+                    "{}{}\n\n{}",
+                    diagnostic.severity.prefix(),
+                    diagnostic.message,
+                    MARKER
+                ),
+            });
+        } else if let Some((related, line)) = diagnostic
+            .related
+            .as_ref()
+            .and_then(|related| Some((related, place(related)?)))
+        {
+            review.comments.push(ReviewComment {
+                path: related.path.clone(),
+                line,
+                body: format!("`{}`\n\n{}", diagnostic, MARKER),
+            });
+        } else {
+            unplaced.push(format!("- `{}`", diagnostic));
+        }
+    }
+    review.comments.sort();
+    review.comments.dedup();
+    if !unplaced.is_empty() {
+        review.body = Some(format!(
+            "if-change-then-change found problems outside this diff:\n\n{}\n\n{}",
+            unplaced.join("\n"),
+            MARKER
+        ));
+    }
+
+    Ok(review)
+}
+
+struct Client {
+    api_url: String,
+    graphql_url: String,
+    token: String,
+}
+
+impl Client {
+    // GitHub Actions sets all of these, including the URLs for GitHub Enterprise Server.
+    fn from_env() -> Result<Client> {
+        let api_url = std::env::var("GITHUB_API_URL")
+            .unwrap_or_else(|_| "https://api.github.com".to_string());
+        Ok(Client {
+            graphql_url: std::env::var("GITHUB_GRAPHQL_URL")
+                .unwrap_or_else(|_| format!("{}/graphql", api_url)),
+            api_url,
+            token: std::env::var("GITHUB_TOKEN")
+                .map_err(|_| anyhow!("GITHUB_TOKEN must be set to post review comments"))?,
+        })
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        ureq::request(method, url)
+            .set("Authorization", &format!("Bearer {}", self.token))
+            .set("Accept", "application/vnd.github+json")
+            .set("X-GitHub-Api-Version", "2022-11-28")
+            .set("User-Agent", "if-change-then-change")
+    }
+
+    fn call(&self, method: &str, path: &str, body: Option<Value>) -> Result<Value> {
+        let url = format!("{}{}", self.api_url, path);
+        let request = self.request(method, &url);
+        let response = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        }
+        .map_err(|err| anyhow!("{} {} failed: {}", method, url, err))?;
+        response
+            .into_json()
+            .with_context(|| format!("{} {} returned invalid JSON", method, url))
+    }
+
+    /// GETs every page of the list at `path`.
+    fn list(&self, path: &str) -> Result<Vec<Value>> {
+        let mut items = Vec::new();
+        for page in 1.. {
+            let batch =
+                match self.call("GET", &format!("{}?per_page=100&page={}", path, page), None)? {
+                    Value::Array(batch) => batch,
+                    other => bail!("expected a list from GET {}, but got {}", path, other),
+                };
+            let done = batch.len() < 100;
+            items.extend(batch);
+            if done {
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    fn graphql(&self, query: &str, variables: Value) -> Result<Value> {
+        let response = self
+            .request("POST", &self.graphql_url)
+            .send_json(json!({ "query": query, "variables": variables }))
+            .map_err(|err| anyhow!("POST {} failed: {}", self.graphql_url, err))?
+            .into_json::<Value>()?;
+        if let Some(errors) = response.get("errors") {
+            bail!("GraphQL request failed: {}", errors);
+        }
+        Ok(response["data"].clone())
+    }
+}
+
+const REVIEW_THREADS_QUERY: &str = "
+query($owner: String!, $name: String!, $pr: Int!) {
+  repository(owner: $owner, name: $name) {
+    pullRequest(number: $pr) {
+      reviewThreads(first: 100) {
+        nodes { id isResolved comments(first: 1) { nodes { databaseId } } }
+      }
+    }
+  }
+}";
+
+const RESOLVE_THREAD_MUTATION: &str = "
+mutation($thread: ID!) {
+  resolveReviewThread(input: { threadId: $thread }) { thread { id } }
+}";
+
+/// What posting a review did.
+#[derive(Debug, Default)]
+pub struct Posted {
+    pub comments: usize,
+    pub resolved: usize,
+}
+
+/// Posts `review` to pull request `pr` of `repo` ("owner/name"), skipping comments which were
+/// already posted by an earlier run and are still unresolved, and resolving the threads of our
+/// earlier comments which no longer apply.
+pub fn post(repo: &str, pr: u64, review: &Review) -> Result<Posted> {
+    let (owner, name) = repo.split_once('/').ok_or_else(|| {
+        anyhow!(
+            "expected a repository of the form owner/name, got '{}'",
+            repo
+        )
+    })?;
+    let client = Client::from_env()?;
+    let pull = format!("/repos/{}/pulls/{}", repo, pr);
+
+    // Thread IDs (and whether they're resolved) are only exposed over GraphQL.
+    let threads = client.graphql(
+        REVIEW_THREADS_QUERY,
+        json!({ "owner": owner, "name": name, "pr": pr }),
+    )?;
+    let threads_by_comment = threads["repository"]["pullRequest"]["reviewThreads"]["nodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|thread| {
+            let comment_id = thread["comments"]["nodes"][0]["databaseId"].as_u64()?;
+            let thread_id = thread["id"].as_str()?.to_string();
+            Some((comment_id, (thread_id, thread["isResolved"] == true)))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut posted = Posted::default();
+    let mut existing = BTreeSet::new();
+    for comment in client.list(&format!("{}/comments", pull))? {
+        let (Some(id), Some(path), Some(body)) = (
+            comment["id"].as_u64(),
+            comment["path"].as_str(),
+            comment["body"].as_str(),
+        ) else {
+            continue;
+        };
+        if !body.contains(MARKER) {
+            continue;
+        }
+        // Outdated comments have no line; they can only be stale.
+        let current = comment["line"].as_u64().map(|line| ReviewComment {
+            path: path.to_string(),
+            line: line as usize,
+            body: body.to_string(),
+        });
+        let Some((thread_id, is_resolved)) = threads_by_comment.get(&id) else {
+            // A reply, rather than the start of a thread.
+            continue;
+        };
+        // Resolved threads are left alone, and their comments posted again if they still apply.
+        if *is_resolved {
+            continue;
+        }
+        match current {
+            Some(current) if review.comments.contains(&current) => {
+                existing.insert(current);
+            }
+            _ => {
+                client.graphql(RESOLVE_THREAD_MUTATION, json!({ "thread": thread_id }))?;
+                posted.resolved += 1;
+            }
+        }
+    }
+
+    let comments = review
+        .comments
+        .iter()
+        .filter(|comment| !existing.contains(*comment))
+        .collect::<Vec<_>>();
+    let body = match &review.body {
+        Some(body) => {
+            let already_posted = client
+                .list(&format!("{}/reviews", pull))?
+                .iter()
+                .any(|posted| posted["body"].as_str() == Some(body.as_str()));
+            (!already_posted).then_some(body.as_str())
+        }
+        None => None,
+    };
+    if comments.is_empty() && body.is_none() {
+        return Ok(posted);
+    }
+
+    let head = client.call("GET", &pull, None)?;
+    let commit_id = head["head"]["sha"]
+        .as_str()
+        .ok_or_else(|| anyhow!("GET {} did not return the head commit", pull))?;
+    client.call(
+        "POST",
+        &format!("{}/reviews", pull),
+        Some(json!({
+            "commit_id": commit_id,
+            "event": "COMMENT",
+            "body": body.unwrap_or_default(),
+            "comments": comments
+                .iter()
+                .map(|comment| json!({
+                    "path": comment.path,
+                    "line": comment.line,
+                    "side": "RIGHT",
+                    "body": comment.body,
+                }))
+                .collect::<Vec<_>>(),
+        })),
+    )?;
+    posted.comments = comments.len();
+
+    Ok(posted)
+}
+
+// GitHub rejects check run updates with more annotations than this, so they're sent in batches.
+const ANNOTATIONS_PER_REQUEST: usize = 50;
+
+/// The annotation for `diagnostic` in a check run.
+fn annotation(diagnostic: &Diagnostic) -> Value {
+    // Annotations need a line; diagnostics about whole files go on the first.
+    let start_line = diagnostic.start_line.map_or(1, |lineno| lineno + 1);
+    let end_line = diagnostic.end_line.unwrap_or(start_line).max(start_line);
+    json!({
+        "path": diagnostic.path,
+        "start_line": start_line,
+        "end_line": end_line,
+        "annotation_level": match diagnostic.severity {
+            Severity::Error => "failure",
+            Severity::Warning => "warning",
+            Severity::Info => "notice",
+        },
+        "message": diagnostic.message,
+    })
+}
+
+/// The outputs to update a check run with, in order, so that it ends up with an annotation for
+/// every diagnostic.
+fn check_run_outputs(diagnostics: &[Diagnostic]) -> Vec<Value> {
+    let count = |severity| {
+        diagnostics
+            .iter()
+            .filter(|diagnostic| diagnostic.severity == severity)
+            .count()
+    };
+    let summary = format!(
+        "{} errors, {} warnings, {} notices",
+        count(Severity::Error),
+        count(Severity::Warning),
+        count(Severity::Info)
+    );
+    let title = match diagnostics.len() {
+        0 => "No problems found".to_string(),
+        1 => "1 problem found".to_string(),
+        n => format!("{} problems found", n),
+    };
+
+    let batches = diagnostics
+        .chunks(ANNOTATIONS_PER_REQUEST)
+        .map(|batch| batch.iter().map(annotation).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    // Even without diagnostics, the check run needs an output.
+    let batches = if batches.is_empty() {
+        vec![Vec::new()]
+    } else {
+        batches
+    };
+    batches
+        .into_iter()
+        .map(|annotations| {
+            json!({
+                "title": title,
+                "summary": summary,
+                "annotations": annotations,
+            })
+        })
+        .collect()
+}
+
+/// The commit to attach a check run to. For pull requests, $GITHUB_SHA is the merge commit
+/// GitHub creates for testing, so we use the head of the pull request from the event payload.
+fn head_sha() -> Result<String> {
+    if let Ok(event_path) = std::env::var("GITHUB_EVENT_PATH") {
+        let event = std::fs::read_to_string(&event_path)
+            .with_context(|| format!("failed to read event payload from {}", event_path))?;
+        let event = serde_json::from_str::<Value>(&event)
+            .with_context(|| format!("failed to parse event payload from {}", event_path))?;
+        if let Some(sha) = event["pull_request"]["head"]["sha"].as_str() {
+            return Ok(sha.to_string());
+        }
+    }
+    std::env::var("GITHUB_SHA").map_err(|_| {
+        anyhow!("GITHUB_SHA must be set to know which commit to create a check run for")
+    })
+}
+
+/// Creates a check run called `name` on the commit being checked (see head_sha) in `repo`
+/// ("owner/name"), annotated with `diagnostics`, and returns its URL (or its id, if GitHub does
+/// not say). The check run fails if there are any errors.
+pub fn check_run(repo: &str, name: &str, diagnostics: &[Diagnostic]) -> Result<String> {
+    let client = Client::from_env()?;
+    let check_run = client.call(
+        "POST",
+        &format!("/repos/{}/check-runs", repo),
+        Some(json!({
+            "name": name,
+            "head_sha": head_sha()?,
+            "status": "in_progress",
+        })),
+    )?;
+    let id = check_run["id"]
+        .as_u64()
+        .ok_or_else(|| anyhow!("creating a check run did not return its id"))?;
+
+    let conclusion = if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        "failure"
+    } else {
+        "success"
+    };
+    let outputs = check_run_outputs(diagnostics);
+    let last = outputs.len() - 1;
+    for (i, output) in outputs.into_iter().enumerate() {
+        let mut update = json!({ "output": output });
+        if i == last {
+            update["status"] = json!("completed");
+            update["conclusion"] = json!(conclusion);
+        }
+        client.call(
+            "PATCH",
+            &format!("/repos/{}/check-runs/{}", repo, id),
+            Some(update),
+        )?;
+    }
+
+    Ok(check_run["html_url"]
+        .as_str()
+        .map_or_else(|| id.to_string(), str::to_string))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::diagnostic::{Diagnostic, Location, Severity};
+    use crate::github::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn review_places_comments_in_diff() -> anyhow::Result<()> {
+        let input = "\
+diff --git a/a.sh b/a.sh
+--- a/a.sh
++++ b/a.sh
+@@ -3,3 +3,3 @@
+ # if-change
+-echo 1
++echo 2
+ # then-change b.sh
+";
+        let diagnostics = [
+            Diagnostic {
+                path: "a.sh".to_string(),
+                start_line: Some(0),
+                end_line: Some(4),
+                message: "if-change has no then-change".to_string(),
+                severity: Severity::Warning,
+                ..Default::default()
+            },
+            Diagnostic {
+                path: "b.sh".to_string(),
+                start_line: Some(0),
+                end_line: Some(3),
+                message: "expected change here due to change in a.sh:3-5".to_string(),
+                related: Some(Location {
+                    path: "a.sh".to_string(),
+                    start_line: Some(2),
+                    end_line: Some(5),
+                }),
+                ..Default::default()
+            },
+            Diagnostic {
+                path: "c.sh".to_string(),
+                message: "then-change references file that does not exist".to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let review = review(input, &diagnostics)?;
+        assert_that!(review.comments).is_equal_to(vec![
+            ReviewComment {
+                path: "a.sh".to_string(),
+                line: 3,
+                body: format!(
+                    "`b.sh:1-3 - expected change here due to change in a.sh:3-5`\n\n{}",
+                    MARKER
+                ),
+            },
+            ReviewComment {
+                path: "a.sh".to_string(),
+                line: 3,
+                body: format!("warning: if-change has no then-change\n\n{}", MARKER),
+            },
+        ]);
+        assert_that!(review.body).is_equal_to(Some(format!(
+            "if-change-then-change found problems outside this diff:\n\n\
+             - `c.sh - then-change references file that does not exist`\n\n{}",
+            MARKER
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn check_run_outputs_batch_annotations() -> anyhow::Result<()> {
+        let diagnostics = (0..120)
+            .map(|i| Diagnostic {
+                path: "a.sh".to_string(),
+                start_line: (i % 2 == 0).then_some(i),
+                end_line: (i % 2 == 0).then_some(i + 2),
+                message: "expected change here".to_string(),
+                severity: if i < 100 {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        let outputs = check_run_outputs(&diagnostics);
+        assert_that!(outputs
+            .iter()
+            .map(|output| output["annotations"].as_array().unwrap().len())
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![50, 50, 20]);
+        assert_that!(outputs[2]["summary"])
+            .is_equal_to(json!("100 errors, 20 warnings, 0 notices"));
+        assert_that!(outputs[0]["annotations"][0]).is_equal_to(json!({
+            "path": "a.sh",
+            "start_line": 1,
+            "end_line": 2,
+            "annotation_level": "failure",
+            "message": "expected change here",
+        }));
+        assert_that!(outputs[0]["annotations"][1]["start_line"]).is_equal_to(json!(1));
+        assert_that!(check_run_outputs(&[])).has_length(1);
+
+        Ok(())
+    }
+}
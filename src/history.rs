@@ -0,0 +1,36 @@
+// Replaying the diffs of past commits through the check, to find when a coupling broke.
+
+use crate::check::{self, Options, ParseCache};
+use crate::diagnostic::Diagnostic;
+use crate::git;
+use crate::tree::Tree;
+use anyhow::{Context, Result};
+
+/// A commit whose diff has diagnostics.
+pub struct Violation {
+    /// The commit's abbreviated hash and subject.
+    pub commit: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Checks the diff of every commit since `since` (see git::commits_since) against the tree at its
+/// parent, and returns the commits which introduced diagnostics, oldest first.
+pub fn history(since: &str, options: &Options) -> Result<Vec<Violation>> {
+    let mut cache = ParseCache::default();
+    let mut violations = Vec::new();
+    for commit in git::commits_since(since)? {
+        let options = Options {
+            tree: Tree::GitRevision(format!("{}^", commit)),
+            ..options.clone()
+        };
+        let diagnostics = check::check(git::commit_diff(&commit)?, &options, &mut cache)
+            .with_context(|| format!("failed to check {}", commit))?;
+        if !diagnostics.is_empty() {
+            violations.push(Violation {
+                commit: git::describe_commit(&commit)?,
+                diagnostics,
+            });
+        }
+    }
+    Ok(violations)
+}
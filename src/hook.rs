@@ -0,0 +1,142 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+
+/// The git hooks we know how to run from.
+pub const HOOKS: [&str; 2] = ["pre-commit", "pre-push"];
+
+/// Marks a hook script as one we wrote, so that it can safely be overwritten later.
+const MARKER: &str = concat!("# installed by ", env!("CARGO_PKG_NAME"));
+
+/// Returns the hooks in `hooks_dir` which run this tool.
+pub fn installed(hooks_dir: &Path) -> Vec<&'static str> {
+    HOOKS
+        .into_iter()
+        .filter(|hook| {
+            std::fs::read_to_string(hooks_dir.join(hook))
+                .is_ok_and(|contents| contents.contains(env!("CARGO_PKG_NAME")))
+        })
+        .collect()
+}
+
+/// Renders the script for `hook`, which fails if the check fails for the changes being committed
+/// (pre-commit) or pushed (pre-push), printing its diagnostics as it goes. If the hook being
+/// replaced was renamed to `<hook>.chained` (see `install`), it is run first and must pass as well.
+fn script(hook: &str) -> String {
+    let bin = env!("CARGO_PKG_NAME");
+    let check = format!("  {bin} || exit $?");
+
+    match hook {
+        "pre-commit" => format!(
+            r#"#!/bin/sh
+{MARKER}
+if [ -x "$0.chained" ]; then
+  "$0.chained" "$@" || exit 1
+fi
+git diff --cached | {{
+{check}
+}}
+"#
+        ),
+        // pre-push is given one "<local ref> <local sha> <remote ref> <remote sha>" line per ref
+        // being pushed on stdin; check everything that the remote does not already have.
+        _ => format!(
+            r#"#!/bin/sh
+{MARKER}
+input=$(cat)
+if [ -x "$0.chained" ]; then
+  printf '%s\n' "$input" | "$0.chained" "$@" || exit 1
+fi
+zero=$(git hash-object --stdin </dev/null | tr '[0-9a-f]' '0')
+printf '%s\n' "$input" | while read local_ref local_sha remote_ref remote_sha; do
+  # Deleting a ref never needs checking.
+  [ -z "$local_sha" ] || [ "$local_sha" = "$zero" ] && continue
+  if [ "$remote_sha" = "$zero" ]; then
+    # New branch: compare against wherever it forked from the remote's default branch.
+    remote_sha=$(git merge-base "$local_sha" "$1/HEAD" 2>/dev/null) || continue
+  fi
+  git diff "$remote_sha" "$local_sha" | {{
+{check}
+  }} || exit $?
+done
+"#
+        ),
+    }
+}
+
+/// Installs `hook` into `hooks_dir`, returning the path of the hook script.
+///
+/// Hooks we installed ourselves are overwritten. Any other existing hook is renamed to
+/// `<hook>.chained` and run by ours before the check, so that installing never changes what
+/// the existing hook does.
+pub fn install(hooks_dir: &Path, hook: &str) -> Result<PathBuf> {
+    if !HOOKS.contains(&hook) {
+        bail!(
+            "unsupported hook: {} (expected one of {})",
+            hook,
+            HOOKS.join(", ")
+        );
+    }
+
+    let path = hooks_dir.join(hook);
+    if let Ok(contents) = std::fs::read_to_string(&path) {
+        if !contents.contains(MARKER) {
+            let chained_path = hooks_dir.join(format!("{}.chained", hook));
+            if chained_path.exists() {
+                bail!(
+                    "cannot chain {} into our hook: {} already exists",
+                    path.display(),
+                    chained_path.display()
+                );
+            }
+            std::fs::rename(&path, &chained_path).with_context(|| {
+                format!(
+                    "failed to move {} to {}",
+                    path.display(),
+                    chained_path.display()
+                )
+            })?;
+            log::info!("chaining existing hook {}", chained_path.display());
+        }
+    }
+
+    std::fs::create_dir_all(hooks_dir)
+        .with_context(|| format!("failed to create {}", hooks_dir.display()))?;
+    std::fs::write(&path, script(hook))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {} executable", path.display()))?;
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hook::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn install_chains_existing_hooks() -> anyhow::Result<()> {
+        let hooks_dir = std::env::temp_dir().join(format!("ictc-hook-test-{}", std::process::id()));
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("pre-push"), "#!/bin/sh\necho hello\n")?;
+
+        assert_that!(install(&hooks_dir, "pre-commit")).is_ok();
+        assert_that!(install(&hooks_dir, "pre-commit")).is_ok();
+        assert_that!(hooks_dir.join("pre-commit.chained").exists()).is_false();
+        assert_that!(install(&hooks_dir, "pre-push")).is_ok();
+        assert_that!(std::fs::read_to_string(hooks_dir.join("pre-push.chained"))?.as_str())
+            .is_equal_to("#!/bin/sh\necho hello\n");
+        assert_that!(install(&hooks_dir, "pre-push")).is_ok();
+        assert_that!(installed(&hooks_dir)).is_equal_to(vec!["pre-commit", "pre-push"]);
+
+        std::fs::remove_dir_all(&hooks_dir)?;
+
+        Ok(())
+    }
+}
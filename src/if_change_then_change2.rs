@@ -1,4 +1,10 @@
-use crate::diagnostic::Diagnostic;
+use crate::comment;
+use crate::date;
+use crate::diagnostic::{Diagnostic, Location};
+use crate::digest;
+use crate::symbol;
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
 use std::ops::Range;
 
@@ -8,8 +14,10 @@ enum ParseState {
     NoOp,
     // if-change records the line number where we switched to if-change parsing
     IfChange(usize, BlockNodeBuilder),
-    // then-change records the line number where we switched to then-change parsing
-    ThenChange(usize, BlockNodeBuilder),
+    // then-change records the line number where we switched to then-change parsing, whether
+    // it was a "then-change?" (i.e. whether its targets are optional), and whether it was a
+    // "LINT.ThenChange(" (i.e. whether its targets use Google-style syntax and it ends at ")")
+    ThenChange(usize, BlockNodeBuilder, bool, bool),
     // then-change records the line number where we switched to then-change parsing
     ThenChangeInvalid(usize),
 }
@@ -18,48 +26,327 @@ enum LineType<'a> {
     // We can't distinguish between "Comment" and "NotComment" source code lines because we support
     // using block comments for if-change-then-change directives; see Parser::from_str
     SourceCode,
-    IfChange,
-    ThenChangeInline(&'a str),
-    ThenChangeBlockStart,
+    IfChange(IfChangeAttrs<'a>),
+    // Usually a single target, but "LINT.ThenChange(a, b)" can list several.
+    ThenChangeInline(Vec<&'a str>, ThenChangeFlags<'a>),
+    ThenChangeBlockStart(ThenChangeFlags<'a>),
     EndChangeAkaThenChangeBlockEnd,
 }
 
+// Attributes of an if-change, e.g. "if-change(name, expires=2025-12-31, hash=sha256:...) owner=@team".
+#[derive(Default)]
+struct IfChangeAttrs<'a> {
+    name: Option<&'a str>,
+    owners: Vec<&'a str>,
+    expires: Option<&'a str>,
+    hash: Option<&'a str>,
+    ignore: Option<String>,
+    granularity: Option<Granularity>,
+    // Every attribute as written, including the ones above; see BlockNode::attributes.
+    attributes: Vec<(String, String)>,
+}
+
+// Modifiers on a then-change: "then-change-any", "then-change?" and a trailing "-- reason".
+// Google-style "LINT.ThenChange(...)" directives are also flagged, since their targets are
+// written differently; see google_target.
+#[derive(Clone, Copy, Default)]
+struct ThenChangeFlags<'a> {
+    any: bool,
+    optional: bool,
+    reason: Option<&'a str>,
+    google: bool,
+}
+
+/// Aliases for the directive keywords, for repositories with existing conventions, e.g.
+/// "IfChange" and "ThenChange". These are recognized in addition to the default spellings.
+/// Every alias must be a single word, so that it can be found in a line.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keywords {
+    #[serde(deserialize_with = "crate::config::keyword_aliases")]
+    pub if_change: Vec<String>,
+    #[serde(deserialize_with = "crate::config::keyword_aliases")]
+    pub then_change: Vec<String>,
+    #[serde(deserialize_with = "crate::config::keyword_aliases")]
+    pub end_change: Vec<String>,
+}
+
+// How many lines at the top of a file are searched for generated markers.
+const GENERATED_MARKER_LINES: usize = 10;
+// What comment lines start with, for finding "@generated" markers.
+const GENERATED_MARKER_COMMENT_PREFIXES: &[&str] = &["//", "#", "/*", "*", "--", "<!--", ";", "%"];
+
+/// Whether `line` says that its file is generated by one of the usual conventions: Go's
+/// "// Code generated <by whatever> DO NOT EDIT.", or an "@generated" token in a comment, e.g.
+/// "// @generated by protoc". Anything else which happens to say "DO NOT EDIT" is left alone.
+fn is_default_generated_marker(line: &str) -> bool {
+    let line = line.trim_end();
+    if line.starts_with("// Code generated ") && line.ends_with(" DO NOT EDIT.") {
+        return true;
+    }
+    let trimmed = line.trim_start();
+    GENERATED_MARKER_COMMENT_PREFIXES
+        .iter()
+        .any(|prefix| trimmed.starts_with(prefix))
+        && line.match_indices("@generated").any(|(i, marker)| {
+            !line[i + marker.len()..]
+                .starts_with(|ch: char| ch.is_alphanumeric() || ch == '_' || ch == '-')
+        })
+}
+
+// Every directive contains one of these (or an alias from Keywords).
+const DIRECTIVE_KEYWORDS: &[&str] = &[
+    "if-change",
+    "LINT.IfChange",
+    "then-change",
+    "LINT.ThenChange",
+    "end-change",
+];
+
+/// Settings which affect how files are parsed, from the config; see config::Config.
+#[derive(Clone, Debug, Default)]
+pub struct ParseOptions {
+    pub keywords: Keywords,
+    // Whether to ignore directives inside fenced code blocks in Markdown files, e.g. examples in
+    // documentation.
+    pub skip_markdown_fences: bool,
+    // Whether directives which look like they're in string literals, rather than comments, should
+    // be reported as errors instead of being silently ignored (or, if we can't tell, obeyed).
+    pub strict: bool,
+    // Markers which, in the first few lines of a file, say that it's generated, and so should not
+    // be parsed for blocks; None means the usual conventions (see is_default_generated_marker).
+    pub generated_markers: Option<Vec<String>>,
+}
+
+impl ParseOptions {
+    /// Whether `contents` has a generated marker in its first few lines.
+    pub fn is_generated(&self, contents: &str) -> bool {
+        let mut lines = contents.lines().take(GENERATED_MARKER_LINES);
+        match &self.generated_markers {
+            Some(markers) => {
+                lines.any(|line| markers.iter().any(|marker| line.contains(marker.as_str())))
+            }
+            None => lines.any(is_default_generated_marker),
+        }
+    }
+
+    /// Whether `contents` could contain a directive, i.e. has a keyword anywhere in it. Most files
+    /// don't, and searching for keywords is much cheaper than parsing line by line.
+    pub fn may_contain_directives(&self, contents: &str) -> bool {
+        self.directive_keywords()
+            .any(|keyword| memchr::memmem::find(contents.as_bytes(), keyword.as_bytes()).is_some())
+    }
+
+    /// The (0-indexed, inclusive) first and last lines of `contents` which every block must be
+    /// between, i.e. the first and last with a keyword on them (or, if the last is a multi-line
+    /// "LINT.ThenChange(", its closing ")"); None if there are no keywords.
+    pub fn directive_lines(&self, contents: &str) -> Option<(usize, usize)> {
+        let bytes = contents.as_bytes();
+        let (first, (mut last, last_keyword)) = self
+            .directive_keywords()
+            .flat_map(|keyword| {
+                let first = memchr::memmem::find(bytes, keyword.as_bytes())?;
+                let last = memchr::memmem::rfind(bytes, keyword.as_bytes())?;
+                Some((first, (last, keyword)))
+            })
+            .reduce(|(first, last), (other_first, other_last)| {
+                (first.min(other_first), last.max(other_last))
+            })?;
+        if last_keyword == "LINT.ThenChange" {
+            last += memchr::memchr(b')', &bytes[last..]).unwrap_or(bytes.len() - last);
+        }
+        let line_of = |offset: usize| memchr::memchr_iter(b'\n', &bytes[..offset]).count();
+        Some((line_of(first), line_of(last)))
+    }
+
+    fn directive_keywords(&self) -> impl Iterator<Item = &str> {
+        DIRECTIVE_KEYWORDS
+            .iter()
+            .copied()
+            .chain(self.keywords.if_change.iter().map(String::as_str))
+            .chain(self.keywords.then_change.iter().map(String::as_str))
+            .chain(self.keywords.end_change.iter().map(String::as_str))
+    }
+}
+
 struct Parser<'a> {
     input_path: &'a str,
     input_content: &'a str,
+    options: &'a ParseOptions,
+    // For each line, the byte ranges within it which are comments, if we know; see
+    // comment::comment_spans.
+    comment_spans: Option<Vec<Vec<Range<usize>>>>,
+    // For each line, whether it is part of a fenced code block that we've been told to skip.
+    fenced_lines: Vec<bool>,
+    // For each line, whether it starts inside a multi-line string; only computed in strict mode
+    // when comment_spans is unknown. See comment::string_lines.
+    string_lines: Vec<bool>,
 
     block_nodes: Vec<BlockNode>,
     errors: Vec<Diagnostic>,
     parse_state: ParseState,
+    // Blocks enclosing the one being parsed, innermost last, each with the line number of its
+    // if-change. Parsing of each resumes (in the IfChange state) once its inner block closes.
+    enclosing_blocks: Vec<(usize, BlockNodeBuilder)>,
+}
+
+/// Whether `ch` is punctuation: ASCII punctuation, or one of the Unicode punctuation blocks that
+/// comment leaders are drawn from in practice, e.g. "＃" and "／／" in CJK-commented files.
+fn is_punctuation(ch: char) -> bool {
+    ch.is_ascii_punctuation()
+        || matches!(ch,
+            // Latin-1 punctuation, e.g. "§", "«", "¶", "·"
+            '\u{a1}' | '\u{a7}' | '\u{ab}' | '\u{b6}' | '\u{b7}' | '\u{bb}' | '\u{bf}'
+            // General Punctuation, e.g. "‐", "—", "‘", "•", "…"
+            | '\u{2010}'..='\u{2027}' | '\u{2030}'..='\u{205e}'
+            // CJK Symbols and Punctuation, e.g. "、", "。", "「", "【", and the katakana "・"
+            | '\u{3001}'..='\u{3003}' | '\u{3008}'..='\u{3011}' | '\u{3014}'..='\u{301f}'
+            | '\u{30fb}'
+            // Fullwidth and halfwidth forms of ASCII punctuation, e.g. "＃", "／", "－", "｡"
+            | '\u{ff01}'..='\u{ff0f}' | '\u{ff1a}'..='\u{ff20}' | '\u{ff3b}'..='\u{ff40}'
+            | '\u{ff5b}'..='\u{ff65}')
+}
+
+/// Whether `ch` may appear in the comment delimiters around a directive: punctuation (see
+/// is_punctuation) or whitespace, including Unicode whitespace such as the ideographic space.
+fn is_comment_char(ch: char) -> bool {
+    is_punctuation(ch) || ch.is_whitespace()
+}
+
+/// Comment prefixes may contain only punctuation or whitespace; they may not have ascii
+/// alphanumeric, UTF-8 alphanumeric e.g. umlauts/accents, emojis, etc. This allows
+/// "<!--if-change-->" and "# if-change" and "#if-change" and "＃ if-change" while disallowing all
+/// else.
+pub fn is_comment_prefix(prefix: &str) -> bool {
+    prefix.chars().all(is_comment_char)
+}
+
+fn error_at<S: Into<String>>(path: &str, lineno: usize, message: S) -> Diagnostic {
+    Diagnostic {
+        path: path.to_string(),
+        start_line: Some(lineno),
+        end_line: None,
+        message: message.into(),
+        ..Default::default()
+    }
+}
+
+/// Errors for blocks which partially overlap, i.e. which share lines without one being nested
+/// inside the other, since a change to those lines would be attributed to both of them. `blocks`
+/// must be sorted by their if-change line.
+fn overlap_errors(path: &str, blocks: &[BlockNode]) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+    for (j, block) in blocks.iter().enumerate() {
+        let range = block.content_range();
+        let Some(other) = blocks[..j].iter().find(|other| {
+            let other_range = other.content_range();
+            range.start < other_range.end && other_range.end < range.end
+        }) else {
+            continue;
+        };
+        errors.push(error_at(
+            path,
+            block.if_change_lineno,
+            format!(
+                "if-change block overlaps the block on lines {}-{} without being nested inside it, so changes to the lines they share would count towards both",
+                other.if_change_lineno + 1,
+                other.end_change_lineno + 1
+            ),
+        ));
+    }
+    errors
 }
 
 impl<'a> Parser<'a> {
-    fn new(path: &'a str, s: &'a str) -> Parser<'a> {
+    fn new(path: &'a str, s: &'a str, options: &'a ParseOptions) -> Parser<'a> {
+        let fenced_lines = if options.skip_markdown_fences && comment::is_markdown(path) {
+            comment::markdown_fenced_lines(s)
+        } else {
+            Vec::new()
+        };
+        let comment_spans = comment::comment_spans(path, s);
+        let string_lines = if options.strict && comment_spans.is_none() {
+            comment::string_lines(s)
+        } else {
+            Vec::new()
+        };
         Parser {
             input_path: path,
             input_content: s,
+            options,
+            comment_spans,
+            fenced_lines,
+            string_lines,
             block_nodes: Vec::new(),
             errors: Vec::new(),
             parse_state: ParseState::NoOp,
+            enclosing_blocks: Vec::new(),
         }
     }
 
     fn record_error<S: Into<String>>(&mut self, lineno: usize, message: S) {
-        self.errors.push(Diagnostic {
-            path: self.input_path.to_string(),
-            start_line: Some(lineno),
-            end_line: None,
-            message: message.into(),
-        })
+        self.errors.push(error_at(self.input_path, lineno, message))
     }
 
-    /// Comment prefixes may contain only punctuation or whitespace; they may not have ascii
-    /// alphanumeric, UTF-8 alphanumeric e.g. umlauts/accents, emojis, etc. This allows
-    /// "<!--if-change-->" and "# if-change" and "#if-change" while disallowing all else.
-    fn is_comment_prefix(prefix: &str) -> bool {
-        prefix
-            .chars()
-            .all(|ch| ch.is_ascii_punctuation() || ch.is_ascii_whitespace())
+    /// Whether byte `offset` of line `i` is inside a comment, or might be, if we don't know where
+    /// the comments are.
+    fn is_in_comment(&self, i: usize, offset: usize) -> bool {
+        match &self.comment_spans {
+            Some(spans) => spans
+                .get(i)
+                .is_some_and(|spans| spans.iter().any(|span| span.contains(&offset))),
+            None => true,
+        }
+    }
+
+    /// Whether a directive on line `i`, after `prefix`, looks like it's inside a string literal
+    /// rather than a comment: per tree-sitter if we know where the comments are, otherwise if the
+    /// quotes before it are unbalanced.
+    fn is_in_string(&self, i: usize, prefix: &str) -> bool {
+        if self.comment_spans.is_some() {
+            return !self.is_in_comment(i, prefix.len());
+        }
+        let starts_in_string = self.string_lines.get(i).copied().unwrap_or(false);
+        (starts_in_string ^ !comment::count_unescaped_quotes(prefix, '"').is_multiple_of(2))
+            || ['\'', '`']
+                .into_iter()
+                .any(|quote| !comment::count_unescaped_quotes(prefix, quote).is_multiple_of(2))
+    }
+
+    /// Whether `keyword`, found on line `i` after `prefix`, should be treated as a directive.
+    /// Directives outside comments are ignored, but in strict mode, we report them too: a
+    /// "# if-change" in a test fixture is much more likely to be a mistake than intentional.
+    fn accept_directive(&mut self, i: usize, prefix: &str, keyword: &str) -> bool {
+        let in_string = if self.options.strict {
+            self.is_in_string(i, prefix)
+        } else {
+            !self.is_in_comment(i, prefix.len())
+        };
+        if in_string && self.options.strict {
+            self.record_error(
+                i,
+                format!(
+                    "{} appears to be inside a string literal rather than a comment, so it is not being enforced",
+                    keyword
+                ),
+            );
+        }
+        !in_string
+    }
+
+    /// Splits `line` around the first of `keyword` and `aliases` which is preceded only by a
+    /// comment prefix, e.g. "# IfChange(api)" splits into "# " and "(api)".
+    fn split_keyword(
+        line: &'a str,
+        keywords: &[&str],
+        aliases: &[String],
+    ) -> Option<(&'a str, &'a str)> {
+        keywords
+            .iter()
+            .copied()
+            .chain(aliases.iter().map(String::as_str))
+            .filter_map(|keyword| line.split_once(keyword))
+            .find(|(prefix, _)| is_comment_prefix(prefix))
     }
 
     /// Comment suffixes must start with a word boundary and end with only punctuation or
@@ -69,61 +356,322 @@ impl<'a> Parser<'a> {
     ///
     /// See `test::comment_suffix_label` for a specification of this method's behavior.
     fn comment_suffix_label(suffix: &'a str) -> Option<&'a str> {
-        let trimmed = suffix
-            .trim_end_matches(|ch: char| ch.is_ascii_punctuation() || ch.is_ascii_whitespace());
+        let trimmed = suffix.trim_end_matches(is_comment_char);
 
         if trimmed.is_empty() {
             return Some("");
         }
-        if trimmed
-            .chars()
-            .nth(0)
-            .map_or(false, |ch| ch.is_ascii_whitespace())
-        {
+        if trimmed.chars().nth(0).is_some_and(char::is_whitespace) {
             return Some(trimmed.trim_start());
         }
         None
     }
 
-    fn line_type(&mut self, i: usize, line: &'a str) -> LineType<'a> {
-        if let Some((prefix, suffix)) = line.split_once("if-change") {
-            if Parser::is_comment_prefix(prefix) {
-                if let Some(label) = Parser::comment_suffix_label(suffix) {
-                    if !label.is_empty() {
+    /// The then-change target on a line of its own, e.g. "#   ../docs/api.md": the line without
+    /// the comment around it, except for the punctuation of a leading "./", "../" or "//".
+    fn target_on_line(line: &'a str) -> &'a str {
+        let target = line.trim_matches(is_comment_char);
+        if target.is_empty() {
+            return target;
+        }
+        let start = line.len() - line.trim_start_matches(is_comment_char).len();
+        let prefix = &line[..start];
+        let path_prefix_len = prefix.len() - prefix.trim_end_matches(['.', '/']).len();
+        &line[start - path_prefix_len..start + target.len()]
+    }
+
+    /// Splits the optional "(...)" off the front of an if-change suffix, e.g. the suffix of
+    /// "# if-change(api-schema, expires=2025-12-31)" is "(api-schema, expires=2025-12-31)".
+    /// Parentheses inside quoted attribute values don't count.
+    fn split_parenthesized(suffix: &'a str) -> (Option<&'a str>, &'a str) {
+        if let Some(rest) = suffix.strip_prefix('(') {
+            if let Some(end) = Parser::find_unquoted(rest, ')').next() {
+                return (Some(&rest[..end]), &rest[end + 1..]);
+            }
+        }
+        (None, suffix)
+    }
+
+    /// The byte offsets of `ch` in `s` outside of double-quoted strings, in which a backslash
+    /// escapes the next character.
+    fn find_unquoted(s: &str, ch: char) -> impl Iterator<Item = usize> + '_ {
+        let mut in_quotes = false;
+        let mut escaped = false;
+        s.char_indices().filter_map(move |(i, c)| {
+            if escaped {
+                escaped = false;
+            } else if in_quotes && c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_quotes = !in_quotes;
+            } else if !in_quotes && c == ch {
+                return Some(i);
+            }
+            None
+        })
+    }
+
+    /// Splits the attributes of an if-change on commas outside of quoted values.
+    fn split_attrs(parenthesized: &'a str) -> Vec<&'a str> {
+        let mut attrs = Vec::new();
+        let mut start = 0;
+        for end in Parser::find_unquoted(parenthesized, ',') {
+            attrs.push(&parenthesized[start..end]);
+            start = end + 1;
+        }
+        attrs.push(&parenthesized[start..]);
+        attrs
+    }
+
+    /// Unquotes an attribute value written as a string, e.g. `"^\\s*version = "`, in which `\"` and
+    /// `\\` are escapes; any other value is returned as it is.
+    fn unquote(value: &str) -> String {
+        let Some(quoted) = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+        else {
+            return value.to_string();
+        };
+        let mut ret = String::new();
+        let mut chars = quoted.chars();
+        while let Some(ch) = chars.next() {
+            match (ch, chars.clone().next()) {
+                ('\\', Some(next @ ('"' | '\\'))) => {
+                    ret.push(next);
+                    chars.next();
+                }
+                _ => ret.push(ch),
+            }
+        }
+        ret
+    }
+
+    /// Parses the attributes of an if-change: a name and key=value pairs in parentheses, and
+    /// owner=@team words in its label.
+    fn if_change_attrs(
+        &mut self,
+        i: usize,
+        parenthesized: Option<&'a str>,
+        label: &'a str,
+    ) -> IfChangeAttrs<'a> {
+        let mut attrs = IfChangeAttrs::default();
+
+        for item in parenthesized.into_iter().flat_map(Parser::split_attrs) {
+            let item = item.trim();
+            // "name=x" is the explicit spelling of a bare "x".
+            let item = item.strip_prefix("name=").unwrap_or(item);
+            let errors = self.errors.len();
+            match item.split_once('=') {
+                Some(("owner", owner)) => attrs.owners.push(owner),
+                Some(("expires", expires)) => {
+                    if date::is_valid_date(expires) {
+                        attrs.expires = Some(expires);
+                    } else {
+                        self.record_error(
+                            i,
+                            format!("if-change has expiry date '{}', but expiry dates must be of the form YYYY-MM-DD", expires));
+                    }
+                }
+                Some(("hash", hash)) => {
+                    if digest::is_valid_hash(hash) {
+                        attrs.hash = Some(hash);
+                    } else {
                         self.record_error(
                             i,
-                            format!("if-change has label '{}', but if-change statements may not be labelled", label));
+                            format!("if-change has hash '{}', but hashes must be of the form sha256:<hex digest>", hash));
+                    }
+                }
+                Some(("ignore", pattern)) => {
+                    let pattern = Parser::unquote(pattern);
+                    match regex::Regex::new(&pattern) {
+                        Ok(_) => attrs.ignore = Some(pattern),
+                        Err(err) => self.record_error(
+                            i,
+                            format!("if-change has ignore pattern '{}', but it is not a valid regex: {}", pattern, err)),
                     }
-                    return LineType::IfChange;
                 }
+                Some(("granularity", granularity)) => match granularity {
+                    "file" => attrs.granularity = Some(Granularity::File),
+                    "block" => attrs.granularity = Some(Granularity::Block),
+                    "strict" => attrs.granularity = Some(Granularity::Strict),
+                    _ => self.record_error(
+                        i,
+                        format!("if-change has granularity '{}', but granularity must be 'file', 'block' or 'strict'", granularity)),
+                },
+                // Anything else is kept for whatever wants it, as long as it's spelled like a key.
+                Some((key, _)) if !is_valid_attribute_key(key) => self.record_error(
+                    i,
+                    format!("if-change has attribute '{}', but attribute names must be non-empty and may only contain letters, digits, '-', '_' and '.'", key)),
+                Some(_) => {}
+                None if attrs.name.is_some() => self.record_error(
+                    i,
+                    format!("if-change has a second name '{}', but blocks may only have one name", item)),
+                None if !is_valid_block_name(item) => self.record_error(
+                    i,
+                    format!("if-change has name '{}', but names must be non-empty and may only contain letters, digits, '-', '_' and '.'", item)),
+                None => attrs.name = Some(item),
+            }
+            if self.errors.len() == errors {
+                let (key, value) = item.split_once('=').unwrap_or(("name", item));
+                attrs
+                    .attributes
+                    .push((key.to_string(), Parser::unquote(value)));
             }
         }
 
-        if let Some((prefix, suffix)) = line.split_once("then-change") {
-            if Parser::is_comment_prefix(prefix) {
-                if let Some(label) = Parser::comment_suffix_label(suffix) {
-                    if label.is_empty() {
-                        return LineType::ThenChangeBlockStart;
-                    }
-                    return LineType::ThenChangeInline(label);
+        let (owners, rest): (Vec<&str>, Vec<&str>) = label
+            .split_whitespace()
+            .partition(|word| word.starts_with("owner="));
+        for owner in owners.iter().filter_map(|word| word.strip_prefix("owner=")) {
+            attrs.owners.push(owner);
+            attrs
+                .attributes
+                .push(("owner".to_string(), owner.to_string()));
+        }
+        attrs.owners.retain(|owner| !owner.is_empty());
+        attrs
+            .attributes
+            .retain(|(key, value)| key != "owner" || !value.is_empty());
+        if !rest.is_empty() {
+            self.record_error(
+                i,
+                format!(
+                    "if-change has label '{}', but if-change statements may not be labelled",
+                    rest.join(" ")
+                ),
+            );
+        }
+
+        attrs
+    }
+
+    /// The state to resume once a block has been closed: the block enclosing it, if any.
+    fn resume_enclosing_block(&mut self) -> ParseState {
+        match self.enclosing_blocks.pop() {
+            Some((i_if, builder)) => ParseState::IfChange(i_if, builder),
+            None => ParseState::NoOp,
+        }
+    }
+
+    fn start_block(&self, i: usize, attrs: IfChangeAttrs) -> BlockNodeBuilder {
+        let mut builder = BlockNodeBuilder::default();
+        builder.key(BlockKey::named(self.input_path, attrs.name));
+        builder.owners(attrs.owners.iter().map(|owner| owner.to_string()).collect());
+        builder.expires(attrs.expires.map(str::to_string));
+        builder.hash(attrs.hash.map(str::to_string));
+        builder.ignore(attrs.ignore);
+        builder.granularity(attrs.granularity);
+        builder.attributes(attrs.attributes);
+        builder.if_change_lineno(i);
+        builder
+    }
+
+    /// Splits the optional "-- reason" off the end of a then-change label, e.g. the label of
+    /// "# then-change foo.rs -- keep wire format in sync" is "foo.rs -- keep wire format in sync".
+    fn split_reason(label: &'a str) -> (&'a str, Option<&'a str>) {
+        let split = match label.strip_prefix("--") {
+            Some(reason) => Some(("", reason)),
+            None => label.split_once(" -- "),
+        };
+        match split {
+            Some((label, reason)) if !reason.trim().is_empty() => {
+                (label.trim_end(), Some(reason.trim()))
+            }
+            _ => (label, None),
+        }
+    }
+
+    fn line_type(&mut self, i: usize, line: &'a str) -> LineType<'a> {
+        if self.fenced_lines.get(i).copied().unwrap_or(false) {
+            return LineType::SourceCode;
+        }
+
+        let options: &'a ParseOptions = self.options;
+        let keywords = &options.keywords;
+
+        if let Some((_, suffix)) =
+            Parser::split_keyword(line, &["if-change", "LINT.IfChange"], &keywords.if_change)
+                .filter(|(prefix, _)| self.accept_directive(i, prefix, "if-change"))
+        {
+            let (parenthesized, suffix) = Parser::split_parenthesized(suffix);
+            if let Some(label) = Parser::comment_suffix_label(suffix) {
+                return LineType::IfChange(self.if_change_attrs(i, parenthesized, label));
+            }
+        }
+
+        if let Some((_, suffix)) =
+            Parser::split_keyword(line, &["then-change"], &keywords.then_change)
+                .filter(|(prefix, _)| self.accept_directive(i, prefix, "then-change"))
+        {
+            let (any, suffix) = match suffix.strip_prefix("-any") {
+                Some(suffix) => (true, suffix),
+                None => (false, suffix),
+            };
+            let (optional, suffix) = match suffix.strip_prefix('?') {
+                Some(suffix) => (true, suffix),
+                None => (false, suffix),
+            };
+            if let Some(label) = Parser::comment_suffix_label(suffix) {
+                let (label, reason) = Parser::split_reason(label);
+                let flags = ThenChangeFlags {
+                    any,
+                    optional,
+                    reason,
+                    google: false,
+                };
+                if label.is_empty() {
+                    return LineType::ThenChangeBlockStart(flags);
                 }
+                return LineType::ThenChangeInline(vec![label], flags);
             }
         }
 
-        if let Some((prefix, suffix)) = line.split_once("end-change") {
-            if Parser::is_comment_prefix(prefix) {
-                if let Some(label) = Parser::comment_suffix_label(suffix) {
-                    if !label.is_empty() {
-                        self.record_error(
-                            i,
-                            format!("end-change has label '{}', but end-change statements may not be labelled", label));
+        // Google-style "LINT.ThenChange(//a.cc, //b.cc:label)", which may also be split over
+        // several lines, with the closing ")" on its own line.
+        if let Some((_, suffix)) = Parser::split_keyword(line, &["LINT.ThenChange"], &[])
+            .filter(|(prefix, _)| self.accept_directive(i, prefix, "LINT.ThenChange"))
+        {
+            let flags = ThenChangeFlags {
+                google: true,
+                ..Default::default()
+            };
+            match Parser::split_parenthesized(suffix) {
+                (Some(targets), rest) if Parser::comment_suffix_label(rest) == Some("") => {
+                    return LineType::ThenChangeInline(
+                        targets
+                            .split(',')
+                            .map(str::trim)
+                            .filter(|target| !target.is_empty())
+                            .collect(),
+                        flags,
+                    );
+                }
+                (None, rest) => {
+                    if let Some(rest) = rest.strip_prefix('(') {
+                        if Parser::comment_suffix_label(rest) == Some("") {
+                            return LineType::ThenChangeBlockStart(flags);
+                        }
                     }
-                    return LineType::EndChangeAkaThenChangeBlockEnd;
                 }
+                _ => {}
+            }
+        }
+
+        if let Some((_, suffix)) =
+            Parser::split_keyword(line, &["end-change"], &keywords.end_change)
+                .filter(|(prefix, _)| self.accept_directive(i, prefix, "end-change"))
+        {
+            if let Some(label) = Parser::comment_suffix_label(suffix) {
+                if !label.is_empty() {
+                    self.record_error(
+                        i,
+                        format!("end-change has label '{}', but end-change statements may not be labelled", label));
+                }
+                return LineType::EndChangeAkaThenChangeBlockEnd;
             }
         }
 
-        return LineType::SourceCode;
+        LineType::SourceCode
     }
 
     /// Parsing follows these principles:
@@ -219,8 +767,8 @@ impl<'a> Parser<'a> {
     ///     ```
     ///
     ///     and because we use somewhat crude logic for identifying comments (1- we do our parsing
-    ///     line-by-line, not token-by-token, and 2- we use is_ascii_punctuation and
-    ///     is_ascii_whitespace to do a best-effort guess as to whether or not a token is a comment)
+    ///     line-by-line, not token-by-token, and 2- we use is_comment_char to do a best-effort
+    ///     guess as to whether or not a token is a comment)
     ///     we can't actually recognize when the next entry in a then-change block is actually
     ///     another then-change path or just a line of code.
     ///
@@ -244,25 +792,25 @@ impl<'a> Parser<'a> {
     ///
     ///     We do this to support maximally permissive block comment formats without having to
     ///     hardcode support for individual comment formats.
-    ///     
+    ///
+    #[allow(clippy::doc_overindented_list_items)]
     fn parse(mut self) -> Result<Vec<BlockNode>, Vec<Diagnostic>> {
         for (i, line) in self.input_content.lines().enumerate() {
             let line_type = self.line_type(i, line);
+            // Whether this line is definitely code, i.e. not blank and not in a comment.
+            let is_code = !line.trim().is_empty()
+                && !self.is_in_comment(i, line.len() - line.trim_start().len());
             match self.parse_state {
                 ParseState::NoOp => {
                     match line_type {
                         LineType::SourceCode => {}
-                        LineType::IfChange => {
-                            let mut builder = BlockNodeBuilder::default();
-                            builder.key(BlockKey::new(self.input_path));
-                            builder.if_change_lineno(i);
-
-                            self.parse_state = ParseState::IfChange(i, builder);
+                        LineType::IfChange(attrs) => {
+                            self.parse_state = ParseState::IfChange(i, self.start_block(i, attrs));
                         }
-                        LineType::ThenChangeInline(_) => {
+                        LineType::ThenChangeInline(..) => {
                             self.record_error(i, "then-change must close an if-change, but found no if-change to close");
                         }
-                        LineType::ThenChangeBlockStart => {
+                        LineType::ThenChangeBlockStart(_) => {
                             self.record_error(i, "then-change must close an if-change, but found no if-change to close");
                             self.parse_state = ParseState::ThenChangeInvalid(i);
                         }
@@ -276,21 +824,37 @@ impl<'a> Parser<'a> {
                 }
                 ParseState::IfChange(i_if, ref mut builder) => match line_type {
                     LineType::SourceCode => {}
-                    LineType::IfChange => {
-                        self.record_error(
-                            i_if,
-                            "if-change must be closed by a then-change, but found no such then-change",
-                        );
-                        self.record_error(i, "if-change may not be nested in another if-change");
-
-                        let mut builder = BlockNodeBuilder::default();
-                        builder.key(BlockKey::new(self.input_path));
-                        builder.if_change_lineno(i);
-
-                        self.parse_state = ParseState::IfChange(i, builder);
+                    LineType::IfChange(attrs) => {
+                        // Nested blocks are tracked independently of the blocks enclosing them.
+                        self.enclosing_blocks.push((i_if, std::mem::take(builder)));
+                        self.parse_state = ParseState::IfChange(i, self.start_block(i, attrs));
                     }
-                    LineType::ThenChangeInline(then_change_path) => {
-                        builder.then_change_push((i, BlockKey::new(then_change_path)));
+                    LineType::ThenChangeInline(then_change_paths, flags) => {
+                        builder.then_change_any(flags.any);
+                        builder.reason(flags.reason.map(str::to_string));
+                        if then_change_paths.is_empty() {
+                            self.errors.push(error_at(
+                                self.input_path,
+                                i,
+                                "LINT.ThenChange() must list at least one target",
+                            ));
+                            builder.then_change(Vec::new());
+                        }
+                        for then_change_path in then_change_paths {
+                            match parse_target(then_change_path, flags.google) {
+                                Ok(mut key) => {
+                                    key.path = resolve_target_path(self.input_path, &key.path);
+                                    key.optional = flags.optional;
+                                    builder.then_change_push((i, key));
+                                }
+                                Err(message) => {
+                                    self.errors.push(error_at(self.input_path, i, message));
+                                    // Parsing fails regardless; this just keeps the builder
+                                    // from also reporting the block as incomplete.
+                                    builder.then_change_push((i, BlockKey::new(then_change_path)));
+                                }
+                            }
+                        }
                         builder.then_change_lineno(i);
                         builder.end_change_lineno(i);
 
@@ -302,53 +866,126 @@ impl<'a> Parser<'a> {
                             ),
                         }
 
-                        self.parse_state = ParseState::NoOp;
+                        self.parse_state = self.resume_enclosing_block();
                     }
-                    LineType::ThenChangeBlockStart => {
-                        self.parse_state =
-                            ParseState::ThenChange(i, builder.then_change_lineno(i).clone());
-                    }
-                    LineType::EndChangeAkaThenChangeBlockEnd => {
-                        self.record_error(
+                    LineType::ThenChangeBlockStart(flags) => {
+                        builder.then_change_any(flags.any);
+                        builder.reason(flags.reason.map(str::to_string));
+                        self.parse_state = ParseState::ThenChange(
                             i,
-                            format!(
-                                "end-change must close an if-change and then-change, but found no then-change to close (found if-change on line {})",
-                                i_if + 1
-                            )
+                            builder.then_change_lineno(i).clone(),
+                            flags.optional,
+                            flags.google,
                         );
                     }
+                    LineType::EndChangeAkaThenChangeBlockEnd => {
+                        // A named region closed by a bare end-change shares the then-change list
+                        // of the other region(s) with the same name; see share_then_change.
+                        if builder.key.as_ref().is_some_and(|key| key.name.is_some()) {
+                            builder.then_change(Vec::new());
+                            builder.then_change_lineno(i);
+                            builder.end_change_lineno(i);
+
+                            match builder.build() {
+                                Ok(block_node) => self.block_nodes.push(block_node),
+                                Err(_) => self.record_error(
+                                    i,
+                                    "internal error: failed to parse if-change-then-change",
+                                ),
+                            }
+
+                            self.parse_state = self.resume_enclosing_block();
+                        } else {
+                            self.record_error(
+                                i,
+                                format!(
+                                    "end-change must close an if-change and then-change, but found no then-change to close (found if-change on line {})",
+                                    i_if + 1
+                                )
+                            );
+                        }
+                    }
                 },
-                ParseState::ThenChange(i_then, ref mut builder) => {
+                ParseState::ThenChange(i_then, ref mut builder, optional, google) => {
                     match line_type {
+                        // If we know where the comments are, the then-change must have ended
+                        // without an end-change once we find code.
+                        LineType::SourceCode if is_code => {
+                            self.record_error(
+                                i_then,
+                                "then-change must be closed by an end-change, but found no such end-change",
+                            );
+                            self.parse_state = self.resume_enclosing_block();
+                        }
                         LineType::SourceCode => {
-                            let path = line.trim_matches(|ch: char| {
-                                ch.is_ascii_punctuation() || ch.is_ascii_whitespace()
-                            });
+                            let path = Parser::target_on_line(line);
+                            // A multi-line "LINT.ThenChange(" ends at its closing ")", which may
+                            // follow the last target or be on a line of its own.
+                            let closes_google_block = google
+                                && line
+                                    .trim_end_matches(|ch: char| is_comment_char(ch) && ch != ')')
+                                    .ends_with(')');
+                            if closes_google_block && path.is_empty() {
+                                builder.end_change_lineno(i);
+                                match builder.build() {
+                                    Ok(block_node) => self.block_nodes.push(block_node),
+                                    Err(_) => self.record_error(
+                                        i,
+                                        "internal error: failed to parse if-change-then-change",
+                                    ),
+                                }
+                                self.parse_state = self.resume_enclosing_block();
+                                continue;
+                            }
+                            // Individual targets in a then-change block can be marked optional
+                            // with a leading "?", e.g. "#   ?docs/changelog.md".
+                            let marked_optional = line
+                                .trim_start_matches(|ch: char| is_comment_char(ch) && ch != '?')
+                                .starts_with('?');
 
                             // NB: if $path is empty, we do produce a diagnostic about that;
                             // we just don't do it here.
-                            builder.then_change_push((i, BlockKey::new(path)));
+                            match parse_target(path, google) {
+                                Ok(mut key) => {
+                                    key.path = resolve_target_path(self.input_path, &key.path);
+                                    key.optional = optional || marked_optional;
+                                    builder.then_change_push((i, key));
+                                }
+                                Err(message) => {
+                                    self.errors.push(error_at(self.input_path, i, message));
+                                    // Parsing fails regardless; this just keeps the builder
+                                    // from also reporting the block as incomplete.
+                                    builder.then_change_push((i, BlockKey::new(path)));
+                                }
+                            }
+                            if closes_google_block {
+                                builder.end_change_lineno(i);
+                                match builder.build() {
+                                    Ok(block_node) => self.block_nodes.push(block_node),
+                                    Err(_) => self.record_error(
+                                        i,
+                                        "internal error: failed to parse if-change-then-change",
+                                    ),
+                                }
+                                self.parse_state = self.resume_enclosing_block();
+                            }
                         }
-                        LineType::IfChange => {
+                        LineType::IfChange(attrs) => {
                             self.record_error(
                             i_then,
                             "then-change must be closed by an end-change, but found no such end-change",
                         );
 
-                            let mut builder = BlockNodeBuilder::default();
-                            builder.key(BlockKey::new(self.input_path));
-                            builder.if_change_lineno(i);
-
-                            self.parse_state = ParseState::IfChange(i, builder);
+                            self.parse_state = ParseState::IfChange(i, self.start_block(i, attrs));
                         }
-                        LineType::ThenChangeInline(_) => {
+                        LineType::ThenChangeInline(..) => {
                             self.record_error(
                             i_then,
                             "then-change must be closed by an end-change, but found no such end-change",
                         );
                             self.record_error(i, "then-change must close an if-change, but found no if-change to close");
                         }
-                        LineType::ThenChangeBlockStart => {
+                        LineType::ThenChangeBlockStart(_) => {
                             self.record_error(
                             i_then,
                             "then-change must be closed by an end-change, but found no such end-change",
@@ -366,7 +1003,7 @@ impl<'a> Parser<'a> {
                                 ),
                             }
 
-                            self.parse_state = ParseState::NoOp;
+                            self.parse_state = self.resume_enclosing_block();
                         }
                     }
                 }
@@ -375,17 +1012,13 @@ impl<'a> Parser<'a> {
                 ParseState::ThenChangeInvalid(_) => {
                     match line_type {
                         LineType::SourceCode => {}
-                        LineType::IfChange => {
-                            let mut builder = BlockNodeBuilder::default();
-                            builder.key(BlockKey::new(self.input_path));
-                            builder.if_change_lineno(i);
-
-                            self.parse_state = ParseState::IfChange(i, builder);
+                        LineType::IfChange(attrs) => {
+                            self.parse_state = ParseState::IfChange(i, self.start_block(i, attrs));
                         }
-                        LineType::ThenChangeInline(_) => {
+                        LineType::ThenChangeInline(..) => {
                             self.record_error(i, "then-change must close an if-change, but found no if-change to close");
                         }
-                        LineType::ThenChangeBlockStart => {
+                        LineType::ThenChangeBlockStart(_) => {
                             self.record_error(i, "then-change must close an if-change, but found no if-change to close");
                         }
                         LineType::EndChangeAkaThenChangeBlockEnd => {
@@ -406,7 +1039,7 @@ impl<'a> Parser<'a> {
                     "if-change must be closed by a then-change, but found no such then-change",
                 );
             }
-            ParseState::ThenChange(i, _) => {
+            ParseState::ThenChange(i, ..) => {
                 // Although we could try to guess where this then-change should be terminate, that
                 // feels likely to be very error-prone: (1) we'd have to add some kind of comment
                 // vs non-comment heuristic and (2) we do not want to use EOF as an implied
@@ -426,6 +1059,19 @@ impl<'a> Parser<'a> {
             }
         }
 
+        for (i, _) in std::mem::take(&mut self.enclosing_blocks) {
+            self.record_error(
+                i,
+                "if-change must be closed by a then-change, but found no such then-change",
+            );
+        }
+        // Inner blocks are closed (and so pushed) before the blocks enclosing them.
+        self.block_nodes.sort_by_key(|block| block.if_change_lineno);
+
+        self.share_then_change();
+        self.errors
+            .extend(overlap_errors(self.input_path, &self.block_nodes));
+
         if !self.errors.is_empty() {
             return Err(self.errors);
         }
@@ -433,6 +1079,70 @@ impl<'a> Parser<'a> {
         Ok(self.block_nodes)
     }
 
+    /// Gives every named region without a then-change (i.e. one closed by a bare end-change) the
+    /// then-change list of the region with the same name that has one, so that the regions act
+    /// as one discontiguous block.
+    ///
+    /// Names are also how then-changes in other files refer to a specific block, so only one
+    /// region per name may have a then-change list.
+    fn share_then_change(&mut self) {
+        let declared = self
+            .block_nodes
+            .iter()
+            .filter(|block| !block.then_change.is_empty())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for block in self.block_nodes.iter_mut() {
+            let Some(name) = &block.key.name else {
+                continue;
+            };
+            if !block.then_change.is_empty() {
+                continue;
+            }
+            match declared
+                .iter()
+                .find(|other| other.key.name.as_ref() == Some(name))
+            {
+                Some(other) => {
+                    block.then_change = other.then_change.clone();
+                    block.then_change_any = other.then_change_any;
+                    block.reason = other.reason.clone();
+                }
+                None => self.errors.push(error_at(
+                    self.input_path,
+                    block.if_change_lineno,
+                    format!(
+                        "if-change({}) is closed by an end-change, so another if-change({}) in this file must have a then-change, but found none",
+                        name, name
+                    ),
+                )),
+            }
+        }
+
+        for (j, block) in declared.iter().enumerate() {
+            let Some(name) = &block.key.name else {
+                continue;
+            };
+            if let Some(first) = declared[..j]
+                .iter()
+                .find(|other| other.key.name.as_ref() == Some(name))
+            {
+                self.errors.push(Diagnostic {
+                    path: self.input_path.to_string(),
+                    start_line: Some(block.if_change_lineno),
+                    end_line: None,
+                    message: format!(
+                        "if-change name '{}' is already used by the if-change on line {}",
+                        name,
+                        first.if_change_lineno + 1
+                    ),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
     /*
     enum ParseState {
         NOOP
@@ -526,7 +1236,7 @@ impl<'a> Parser<'a> {
      */
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct FileNodeParseError {
     pub diagnostics: Vec<Diagnostic>,
 }
@@ -534,7 +1244,7 @@ pub struct FileNodeParseError {
 impl fmt::Display for FileNodeParseError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for diagnostic in self.diagnostics.iter() {
-            write!(f, "{}\n", diagnostic)?;
+            writeln!(f, "{}", diagnostic)?;
         }
 
         fmt::Result::Ok(())
@@ -543,34 +1253,160 @@ impl fmt::Display for FileNodeParseError {
 
 impl std::error::Error for FileNodeParseError {}
 
-// Represents all if-change-then-change nodes found within a single file.
-#[derive(Debug)]
+// Represents all if-change-then-change nodes found within a single file. Serialized FileNodes
+// deserialize back to the same FileNode, e.g. for the on-disk parse cache.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct FileNode {
     pub blocks: Vec<BlockNode>,
+    // Generated files (see ParseOptions::is_generated) are not parsed for blocks, but can still be
+    // then-change targets, which any change to the file satisfies.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub generated: bool,
 }
 
 impl FileNode {
     pub fn new(blocks: Vec<BlockNode>) -> FileNode {
-        FileNode { blocks: blocks }
+        FileNode {
+            blocks,
+            generated: false,
+        }
     }
 
-    pub fn get_corresponding_block(&self, src_block: &BlockNode) -> Option<&BlockNode> {
+    /// Returns the block in this file that `src_block`'s then-change `target` refers to, or the
+    /// first region of it if it's discontiguous; see `correspondence`.
+    pub fn get_corresponding_block(
+        &self,
+        src_block: &BlockNode,
+        target: &BlockKey,
+    ) -> Option<&BlockNode> {
+        match self.correspondence(src_block, target) {
+            Correspondence::Block(regions) => regions.first().copied(),
+            Correspondence::None | Correspondence::Ambiguous(_) => None,
+        }
+    }
+
+    /// Resolves `src_block`'s then-change `target` to the block in this file it refers to.
+    ///
+    /// If `target` names a block ("then-change b.rs#api-schema"), that block is the only match.
+    /// Otherwise, we fall back to matching by path: the blocks with a then-change pointing back
+    /// at `src_block`'s file (and, if it names a block, at `src_block` itself). When there are
+    /// several, we prefer, in order:
+    ///
+    ///   - blocks whose then-change names `src_block`,
+    ///   - blocks with the same name as `src_block`, and
+    ///   - the block nearest to `src_block`, by line number,
+    ///
+    /// and only give up (with Correspondence::Ambiguous) if that leaves a tie.
+    ///
+    /// Line range, symbol and URL targets never have a corresponding block.
+    pub fn correspondence(&self, src_block: &BlockNode, target: &BlockKey) -> Correspondence<'_> {
+        if !target.expects_block() {
+            return Correspondence::None;
+        }
+        if let Some(name) = &target.name {
+            let regions = self.get_blocks_by_name(name);
+            if regions.is_empty() {
+                return Correspondence::None;
+            }
+            return Correspondence::Block(regions);
+        }
+
         // Linear search is fast enough for our purposes. It's very unlikely that a file will
         // have enough ICTC blocks for linear search to be slow (working around this would
         // require indexing the ICTC blocks, which is hard in Rust because that means
         // self-referential structs).
-        for dst_block in self.blocks.iter() {
-            for (_, then_change_key) in dst_block.then_change.iter() {
-                if then_change_key == &src_block.key {
-                    return Some(&dst_block);
-                }
+        let ranked = self
+            .blocks
+            .iter()
+            .filter_map(|dst_block| {
+                dst_block
+                    .then_change
+                    .iter()
+                    .filter(|(_, then_change_key)| then_change_key.path == src_block.key.path)
+                    .filter_map(|(_, then_change_key)| {
+                        match (&then_change_key.name, &src_block.key.name) {
+                            (Some(name), Some(src_name)) if name == src_name => Some(0),
+                            (Some(_), _) => None,
+                            (None, Some(src_name))
+                                if dst_block.key.name.as_ref() == Some(src_name) =>
+                            {
+                                Some(1)
+                            }
+                            (None, _) => Some(2),
+                        }
+                    })
+                    .min()
+                    .map(|rank| (rank, dst_block))
+            })
+            .collect::<Vec<_>>();
+        let Some(best_rank) = ranked.iter().map(|(rank, _)| *rank).min() else {
+            return Correspondence::None;
+        };
+
+        // Regions of a discontiguous block are one candidate, not several.
+        let mut candidates: Vec<Vec<&BlockNode>> = Vec::new();
+        for (_, dst_block) in ranked.into_iter().filter(|(rank, _)| *rank == best_rank) {
+            match candidates.iter_mut().find(|regions| {
+                dst_block.key.name.is_some() && regions[0].key.name == dst_block.key.name
+            }) {
+                Some(regions) => regions.push(dst_block),
+                None => candidates.push(vec![dst_block]),
             }
         }
-        None
+
+        let distance = |regions: &Vec<&BlockNode>| {
+            regions
+                .iter()
+                .map(|block| block.if_change_lineno.abs_diff(src_block.if_change_lineno))
+                .min()
+        };
+        let nearest = candidates.iter().map(distance).min().flatten();
+        let mut candidates = candidates
+            .into_iter()
+            .filter(|regions| distance(regions) == nearest)
+            .collect::<Vec<_>>();
+        if candidates.len() == 1 {
+            return Correspondence::Block(candidates.remove(0));
+        }
+        Correspondence::Ambiguous(candidates.into_iter().flatten().collect())
+    }
+
+    fn get_blocks_by_name(&self, name: &str) -> Vec<&BlockNode> {
+        self.blocks
+            .iter()
+            .filter(|block| block.key.name.as_deref() == Some(name))
+            .collect()
+    }
+
+    pub fn get_block_by_name(&self, name: &str) -> Option<&BlockNode> {
+        self.blocks
+            .iter()
+            .find(|block| block.key.name.as_deref() == Some(name))
     }
 
     pub fn from_str(path: &str, s: &str) -> Result<FileNode, FileNodeParseError> {
-        match Parser::new(path, s).parse() {
+        FileNode::from_str_with_options(path, s, &ParseOptions::default())
+    }
+
+    /// Like `from_str`, but with the settings from the config, e.g. keyword aliases.
+    pub fn from_str_with_options(
+        path: &str,
+        s: &str,
+        options: &ParseOptions,
+    ) -> Result<FileNode, FileNodeParseError> {
+        // A byte-order mark would otherwise make a directive on the first line look like it is
+        // preceded by something other than a comment prefix.
+        let s = s.strip_prefix('\u{feff}').unwrap_or(s);
+        if options.is_generated(s) {
+            return Ok(FileNode {
+                blocks: Vec::new(),
+                generated: true,
+            });
+        }
+        if !options.may_contain_directives(s) {
+            return Ok(FileNode::new(Vec::new()));
+        }
+        match Parser::new(path, s, options).parse() {
             Ok(block_nodes) => Ok(FileNode::new(block_nodes)),
             Err(errors) => Err(FileNodeParseError {
                 diagnostics: errors,
@@ -579,74 +1415,714 @@ impl FileNode {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
-pub struct BlockKey {
-    pub path: String,
+/// Parses a then-change target written in the usual syntax (see BlockKey::from_target) or, for
+/// "LINT.ThenChange(...)", in Google's: "//path/to/file" (relative to the repository root, like
+/// every other target) and "//path/to/file:label" for a named block.
+pub fn parse_target(target: &str, google: bool) -> Result<BlockKey, String> {
+    if !google || is_url(target) {
+        return BlockKey::from_target(target);
+    }
+    let target = forward_slashes(target);
+    let target = target.strip_prefix("//").unwrap_or(&target);
+    match target.rsplit_once(':') {
+        Some((path, label))
+            if !path.is_empty()
+                && is_valid_block_name(label)
+                && !label.chars().all(|ch| ch.is_ascii_digit()) =>
+        {
+            Ok(BlockKey::named(path, Some(label)))
+        }
+        _ => BlockKey::from_target(target),
+    }
 }
 
-impl BlockKey {
-    fn new<S: Into<String>>(path: S) -> BlockKey {
-        BlockKey { path: path.into() }
+/// Normalizes `path` lexically, e.g. "./a/../b//c.sh" to "b/c.sh", so that different spellings of
+/// the same path compare equal.
+pub fn normalize_path(path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." if components.last().is_some_and(|last| *last != "..") => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
     }
+    components.join("/")
 }
 
-#[derive(Builder, Clone, Debug, PartialEq, Eq)]
-pub struct BlockNode {
-    // BlockNode keys are NOT required to be unique per BlockNode.
-    // We allow using the then-change paths to resolve a BlockNode; that is,
-    // if foo.sh contains an "if-change ... then-change bar1.sh" on L4-6 and
-    // "if-change ... then-change bar2.sh" on L8-10, when searching for the
-    // BlockNode corresponding to bar2.sh, we can use "then-change bar2.sh"
-    // to resolve to the second BlockNode.
-    pub key: BlockKey,
-
-    // pairs of (lineno, then_change_block)
-    #[builder(setter(each(name = "then_change_push")))]
-    pub then_change: Vec<(usize, BlockKey)>,
-
-    // content_range is if_change_lineno to end_change_lineno + 1
-    if_change_lineno: usize,
-    then_change_lineno: usize,
-    end_change_lineno: usize,
+/// Resolves a then-change path written relative to `file_path`, the file containing it (i.e.
+/// starting with "./" or "../"), to one relative to the root like any other, e.g. "../b.sh" in
+/// "src/a.sh" to "b.sh".
+pub fn resolve_target_path(file_path: &str, target_path: &str) -> String {
+    if !target_path.starts_with("./") && !target_path.starts_with("../") {
+        return target_path.to_string();
+    }
+    let dir = file_path.rsplit_once('/').map_or("", |(dir, _)| dir);
+    normalize_path(&format!("{}/{}", dir, target_path))
 }
 
-impl BlockNode {
-    // The line range which we expect to see a modification in.
-    //
-    // It's important that this encompasses the delimiting if-change and then-change
-    // directives, because that allows us to handle changes to those clauses correctly,
-    // e.g. when we're wrapping existing code in if-change-then-change clauses.
-    pub fn content_range(&self) -> Range<usize> {
-        self.if_change_lineno..self.end_change_lineno + 1
+/// The inverse of resolve_target_path: `path` as it would be written relative to `file_path`,
+/// e.g. "b.sh" from "src/a.sh" as "../b.sh".
+pub fn relative_target_path(file_path: &str, path: &str) -> String {
+    let dir = file_path
+        .rsplit_once('/')
+        .map_or(Vec::new(), |(dir, _)| dir.split('/').collect());
+    let components = path.split('/').collect::<Vec<_>>();
+    let common = dir
+        .iter()
+        .zip(&components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let up = "../".repeat(dir.len() - common);
+    let down = components[common..].join("/");
+    if up.is_empty() {
+        format!("./{}", down)
+    } else {
+        format!("{}{}", up, down)
     }
 }
 
-// single-file format
-// ---
-// if-change
-// lorem ipsum dolor
-// sit amet
-// then-change a/b/c.rs
+/// Replaces Windows path separators with the '/' used everywhere else (in diffs, directives and
+/// diagnostics), so that a target written as "src\b.sh" means the same file as "src/b.sh".
+pub fn forward_slashes(path: &str) -> String {
+    path.replace('\\', "/")
+}
 
-// multi-file format
-// ---
-// if-change
-// lorem ipsum dolor
-// sit amet
-// then-change
-//   a/b/c.rs
-//   a/b/c2.rs
-//   a/b/c3.rs
-// end-change
+fn is_url(target: &str) -> bool {
+    target.starts_with("https://") || target.starts_with("http://")
+}
 
-#[cfg(test)]
-mod test {
-    use crate::if_change_then_change2::*;
-    use anyhow::anyhow;
-    use spectral::prelude::*;
-    use test_log::test;
+pub fn is_valid_block_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' || ch == '.')
+}
 
-    #[test]
+/// Whether `key` can be the key of an attribute, e.g. "expires" in "if-change(expires=...)".
+fn is_valid_attribute_key(key: &str) -> bool {
+    is_valid_block_name(key)
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockKey {
+    pub path: String,
+    // Set by "if-change(name)" on a block, or by "then-change path#name" on a target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // Set by "then-change path:10-40" on a target, for files that have no block of their own:
+    // a change anywhere in these lines satisfies the then-change. 0-indexed, inclusive-exclusive.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_lines",
+        deserialize_with = "deserialize_lines"
+    )]
+    pub lines: Option<Range<usize>>,
+    // Set by "then-change path@symbol" on a target, e.g. "src/api.rs@fn handle_request"; like a
+    // line range, except that the lines are found by parsing the file (see symbol::resolve).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    // Set by "then-change? path" on a target: missing changes to it are only warnings.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub optional: bool,
+    // Set by "then-change path?exists=optional" on a target: the file need not exist, e.g.
+    // because it's only in some forks, and it's only reported as info if it doesn't.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub may_not_exist: bool,
+    // Every "key=value" in "then-change path?key=value&...", including exists=optional, in the
+    // order they were written.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributes: Vec<(String, String)>,
+}
+
+// Line ranges serialize the way they're written, e.g. "10-40".
+fn serialize_lines<S: Serializer>(
+    lines: &Option<Range<usize>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match lines {
+        Some(lines) => serializer.serialize_str(&format!("{}-{}", lines.start + 1, lines.end)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_lines<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Range<usize>>, D::Error> {
+    let lines = String::deserialize(deserializer)?;
+    lines
+        .split_once('-')
+        .and_then(|(start, end)| {
+            Some(start.parse::<usize>().ok()?.checked_sub(1)?..end.parse().ok()?)
+        })
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("invalid line range '{}'", lines)))
+}
+
+impl BlockKey {
+    fn new<S: Into<String>>(path: S) -> BlockKey {
+        BlockKey {
+            path: path.into(),
+            name: None,
+            lines: None,
+            symbol: None,
+            optional: false,
+            may_not_exist: false,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Whether this then-change target is satisfied by a corresponding block in the target file,
+    /// as opposed to by any change to a span of lines in it.
+    pub fn expects_block(&self) -> bool {
+        self.lines.is_none() && self.symbol.is_none() && !self.is_url()
+    }
+
+    /// Whether this then-change target is a URL (e.g. a wiki page), which can't be checked and
+    /// is only a reminder to update whatever it points at.
+    pub fn is_url(&self) -> bool {
+        is_url(&self.path)
+    }
+
+    fn named<S: Into<String>>(path: S, name: Option<&str>) -> BlockKey {
+        BlockKey {
+            name: name.map(str::to_string),
+            ..BlockKey::new(path)
+        }
+    }
+
+    /// For targets which are satisfied by a change to a span of lines (see `expects_block`),
+    /// returns that span given the target file's `contents`. Returns None for block targets.
+    pub fn resolve_lines(&self, contents: &str) -> Option<Result<Range<usize>, String>> {
+        if let Some(lines) = &self.lines {
+            if contents.lines().count() < lines.end {
+                return Some(Err(format!(
+                    "then-change references lines {}-{}, which are past the end of '{}'",
+                    lines.start + 1,
+                    lines.end,
+                    self.path
+                )));
+            }
+            return Some(Ok(lines.clone()));
+        }
+        self.symbol
+            .as_ref()
+            .map(|symbol| symbol::resolve(&self.path, contents, symbol))
+    }
+
+    /// Parses a then-change target, which is one of "path", "path#name", "path:N", "path:N-M"
+    /// (1-indexed and inclusive, like editors and diagnostics), "path@symbol", or a URL. Paths
+    /// are relative to the root (see --root), and may say so explicitly with "//path"; those
+    /// starting with "./" or "../" are left for the parser to resolve (see resolve_target_path).
+    /// Any but URLs may be followed by attributes, e.g. "?exists=optional&owner=@team", of which
+    /// exists=optional is for files which need not exist; the rest are kept as they are.
+    pub fn from_target(target: &str) -> Result<BlockKey, String> {
+        // URLs may contain any of the characters below, and are never anything but a URL.
+        if is_url(target) {
+            return Ok(BlockKey::new(target));
+        }
+        let target = forward_slashes(target);
+        let target = target.strip_prefix("//").unwrap_or(&target);
+        // Only "key=value" pairs make a query; otherwise the "?" is just part of the path.
+        if let Some((path, query)) = target.rsplit_once('?') {
+            if !path.is_empty() && !query.is_empty() && query.split('&').all(|a| a.contains('=')) {
+                let mut key = BlockKey::from_target(path)?;
+                for attribute in query.split('&') {
+                    let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+                    match (name, value) {
+                        ("exists", "optional") => key.may_not_exist = true,
+                        ("exists", _) => {
+                            return Err(format!(
+                                "then-change has exists '{}', but the only value for exists is 'optional'",
+                                value
+                            ))
+                        }
+                        _ if !is_valid_attribute_key(name) => {
+                            return Err(format!(
+                                "then-change has attribute '{}', but attribute names must be non-empty and may only contain letters, digits, '-', '_' and '.'",
+                                name
+                            ))
+                        }
+                        _ => {}
+                    }
+                    key.attributes.push((name.to_string(), value.to_string()));
+                }
+                return Ok(key);
+            }
+        }
+        if let Some((path, symbol)) = target.rsplit_once('@') {
+            if !path.is_empty() && symbol::is_valid_symbol(symbol) {
+                return Ok(BlockKey {
+                    symbol: Some(symbol.to_string()),
+                    ..BlockKey::new(path)
+                });
+            }
+        }
+        if let Some((path, lines)) = target.rsplit_once(':') {
+            if !path.is_empty()
+                && !lines.is_empty()
+                && lines.chars().all(|ch| ch.is_ascii_digit() || ch == '-')
+            {
+                let (start, end) = lines.split_once('-').unwrap_or((lines, lines));
+                return match (start.parse::<usize>(), end.parse::<usize>()) {
+                    (Ok(start), Ok(end)) if 1 <= start && start <= end => Ok(BlockKey {
+                        lines: Some(start - 1..end),
+                        ..BlockKey::new(path)
+                    }),
+                    _ => Err(format!(
+                        "then-change has invalid line range '{}': expected N or N-M, where 1 <= N <= M",
+                        lines
+                    )),
+                };
+            }
+        }
+
+        Ok(match target.rsplit_once('#') {
+            Some((path, name)) if !path.is_empty() && is_valid_block_name(name) => {
+                BlockKey::named(path, Some(name))
+            }
+            _ => BlockKey::new(target),
+        })
+    }
+}
+
+impl fmt::Display for BlockKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path)?;
+        if let Some(name) = &self.name {
+            write!(f, "#{}", name)?;
+        }
+        if let Some(lines) = &self.lines {
+            write!(f, ":{}-{}", lines.start + 1, lines.end)?;
+        }
+        if let Some(symbol) = &self.symbol {
+            write!(f, "@{}", symbol)?;
+        }
+        for (i, (key, value)) in self.attributes.iter().enumerate() {
+            write!(f, "{}{}={}", if i == 0 { '?' } else { '&' }, key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a then-change target resolves to blocks in the target file; see
+/// `FileNode::correspondence`.
+#[derive(Debug, PartialEq)]
+pub enum Correspondence<'a> {
+    None,
+    /// The regions of the corresponding block: just the one, unless it's discontiguous.
+    Block(Vec<&'a BlockNode>),
+    /// Every block that could be the corresponding block, with nothing to choose between them.
+    Ambiguous(Vec<&'a BlockNode>),
+}
+
+/// How much of a then-change target has to change to satisfy a change to a block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    /// Any change to the target file will do, e.g. for changelogs.
+    File,
+    /// The target's corresponding block must change, including its if-change and then-change
+    /// lines.
+    #[default]
+    Block,
+    /// The body of the target's corresponding block must change, i.e. the lines between its
+    /// if-change and then-change; changes to those lines alone don't count.
+    Strict,
+}
+
+#[derive(Builder, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockNode {
+    // BlockNode keys are NOT required to be unique per BlockNode.
+    // We allow using the then-change paths to resolve a BlockNode; that is,
+    // if foo.sh contains an "if-change ... then-change bar1.sh" on L4-6 and
+    // "if-change ... then-change bar2.sh" on L8-10, when searching for the
+    // BlockNode corresponding to bar2.sh, we can use "then-change bar2.sh"
+    // to resolve to the second BlockNode.
+    pub key: BlockKey,
+
+    // pairs of (lineno, then_change_block)
+    #[builder(setter(each(name = "then_change_push")))]
+    #[serde(
+        serialize_with = "serialize_then_change",
+        deserialize_with = "deserialize_then_change"
+    )]
+    pub then_change: Vec<(usize, BlockKey)>,
+
+    // Set by "then-change-any", meaning that a change to any one of the then-change targets
+    // suffices, instead of requiring a change to every one of them.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub then_change_any: bool,
+
+    // Set by a trailing "-- reason" on the then-change, explaining why the coupling exists.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    // Set by "owner=@team" on the if-change; these take precedence over CODEOWNERS when telling
+    // people who to talk to about a change to this block.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub owners: Vec<String>,
+
+    // Set by "if-change(expires=YYYY-MM-DD)"; after this date, diagnostics ask for the coupling
+    // to be re-examined.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+
+    // Set by "if-change(hash=sha256:...)", a digest of the block's content (see content_digest)
+    // as of when its then-change targets were last known to be in sync.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+
+    // Set by "if-change(ignore=\"regex\")": added or removed lines matching it don't count as
+    // changes to this block, e.g. version bumps or timestamps.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore: Option<String>,
+
+    // Set by "if-change(granularity=...)": how much of each then-change target has to change for
+    // a change to this block to be satisfied. If unset, this comes from the config.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub granularity: Option<Granularity>,
+
+    // Every attribute of the if-change, as "key=value" pairs in the order they were written: the
+    // name, the ones with fields of their own above, and any others, which are kept for whatever
+    // wants them (e.g. tools built on `parse` output) rather than being reported as errors.
+    #[builder(default)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributes: Vec<(String, String)>,
+
+    // content_range is if_change_lineno to end_change_lineno + 1
+    #[serde(
+        rename = "if_change_line",
+        serialize_with = "serialize_lineno",
+        deserialize_with = "deserialize_lineno"
+    )]
+    if_change_lineno: usize,
+    #[serde(
+        rename = "then_change_line",
+        serialize_with = "serialize_lineno",
+        deserialize_with = "deserialize_lineno"
+    )]
+    then_change_lineno: usize,
+    #[serde(
+        rename = "end_change_line",
+        serialize_with = "serialize_lineno",
+        deserialize_with = "deserialize_lineno"
+    )]
+    end_change_lineno: usize,
+}
+
+// Line numbers are 0-indexed internally, but serialized 1-indexed so that they match what users
+// see in their editors and in diagnostics.
+fn serialize_lineno<S: Serializer>(lineno: &usize, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(*lineno as u64 + 1)
+}
+
+fn deserialize_lineno<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    usize::deserialize(deserializer)?
+        .checked_sub(1)
+        .ok_or_else(|| serde::de::Error::custom("line numbers start at 1"))
+}
+
+fn serialize_then_change<S: Serializer>(
+    then_change: &[(usize, BlockKey)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct ThenChange<'a> {
+        line: usize,
+        #[serde(flatten)]
+        key: &'a BlockKey,
+    }
+
+    let mut seq = serializer.serialize_seq(Some(then_change.len()))?;
+    for (lineno, key) in then_change {
+        seq.serialize_element(&ThenChange {
+            line: lineno + 1,
+            key,
+        })?;
+    }
+    seq.end()
+}
+
+fn deserialize_then_change<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(usize, BlockKey)>, D::Error> {
+    #[derive(Deserialize)]
+    struct ThenChange {
+        #[serde(deserialize_with = "deserialize_lineno")]
+        line: usize,
+        #[serde(flatten)]
+        key: BlockKey,
+    }
+
+    Ok(Vec::<ThenChange>::deserialize(deserializer)?
+        .into_iter()
+        .map(|then_change| (then_change.line, then_change.key))
+        .collect())
+}
+
+impl BlockNode {
+    /// A block with no if-change-then-change comments, spanning only line `lineno` of `path`,
+    /// e.g. a line matched by a regex in the config (see config::VirtualBlock).
+    pub fn virtual_block(
+        path: &str,
+        name: &str,
+        lineno: usize,
+        then_change: Vec<BlockKey>,
+    ) -> BlockNode {
+        BlockNode {
+            key: BlockKey::named(path, Some(name)),
+            then_change: then_change
+                .into_iter()
+                .map(|target| (lineno, target))
+                .collect(),
+            then_change_any: false,
+            reason: None,
+            owners: Vec::new(),
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: Vec::new(),
+            if_change_lineno: lineno,
+            then_change_lineno: lineno,
+            end_change_lineno: lineno,
+        }
+    }
+
+    // The line range which we expect to see a modification in.
+    //
+    // It's important that this encompasses the delimiting if-change and then-change
+    // directives, because that allows us to handle changes to those clauses correctly,
+    // e.g. when we're wrapping existing code in if-change-then-change clauses.
+    pub fn content_range(&self) -> Range<usize> {
+        self.if_change_lineno..self.end_change_lineno + 1
+    }
+
+    /// Whether this block has a then-change targeting `src_block`, either by name or by its file.
+    pub fn points_back_at(&self, src_block: &BlockNode) -> bool {
+        self.then_change.iter().any(|(_, then_change_key)| {
+            then_change_key.path == src_block.key.path
+                && then_change_key
+                    .name
+                    .as_ref()
+                    .is_none_or(|name| src_block.key.name.as_ref() == Some(name))
+        })
+    }
+
+    /// Removes then-change targets which repeat an earlier one, including ones spelled
+    /// differently (e.g. "./a.sh" and "a.sh"), and returns each removed target along with the
+    /// line of the target it repeats. A repeat which is required makes the target required.
+    pub fn dedup_then_change(&mut self) -> Vec<((usize, BlockKey), usize)> {
+        // Empty paths aren't targets at all, and are each reported as such.
+        let same_target = |a: &BlockKey, b: &BlockKey| {
+            !a.path.is_empty()
+                && normalize_path(&a.path) == normalize_path(&b.path)
+                && a.name == b.name
+                && a.lines == b.lines
+                && a.symbol == b.symbol
+        };
+
+        let mut kept: Vec<(usize, BlockKey)> = Vec::new();
+        let mut removed = Vec::new();
+        for (lineno, key) in self.then_change.drain(..) {
+            match kept
+                .iter_mut()
+                .find(|(_, kept_key)| same_target(kept_key, &key))
+            {
+                Some((kept_lineno, kept_key)) => {
+                    kept_key.optional &= key.optional;
+                    kept_key.may_not_exist &= key.may_not_exist;
+                    removed.push(((lineno, key), *kept_lineno));
+                }
+                None => kept.push((lineno, key)),
+            }
+        }
+        self.then_change = kept;
+        removed
+    }
+
+    /// The lines between the if-change and the then-change, i.e. content_range without the
+    /// directives; blocks without directives (see virtual_block) are all body.
+    pub fn body_range(&self) -> Range<usize> {
+        if self.if_change_lineno == self.then_change_lineno {
+            return self.content_range();
+        }
+        self.if_change_lineno + 1..self.then_change_lineno
+    }
+
+    /// The lines from the then-change to the end of the block, i.e. the then-change directive and
+    /// its targets.
+    pub fn then_change_range(&self) -> Range<usize> {
+        self.then_change_lineno..self.end_change_lineno + 1
+    }
+
+    /// The digest of the lines between the if-change and the then-change in `contents` (the
+    /// contents of the file this block is in), for comparing against `hash`.
+    pub fn content_digest(&self, contents: &str) -> String {
+        digest::digest(
+            contents
+                .lines()
+                .skip(self.if_change_lineno + 1)
+                .take(self.then_change_lineno - self.if_change_lineno - 1),
+        )
+    }
+
+    /// Whether this block has a hash, and the hash does not match its content.
+    pub fn has_stale_hash(&self, contents: &str) -> bool {
+        self.hash
+            .as_ref()
+            .is_some_and(|hash| !self.content_digest(contents).starts_with(hash.as_str()))
+    }
+
+    pub fn location(&self) -> Location {
+        Location {
+            path: self.key.path.clone(),
+            start_line: Some(self.content_range().start),
+            end_line: Some(self.content_range().end),
+        }
+    }
+}
+
+// single-file format
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change a/b/c.rs
+
+// named format, where then-change targets a specific block in the other file
+// ---
+// if-change(api-schema)
+// lorem ipsum dolor
+// sit amet
+// then-change a/b/c.rs#api-schema
+
+// line range format, where the other file has no block of its own
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change config.yaml:10-40
+
+// symbol format, where the other file has no block of its own
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change src/api.rs@fn handle_request
+
+// multi-file format
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change
+//   a/b/c.rs
+//   a/b/c2.rs
+//   a/b/c3.rs
+// end-change
+
+// optional format, where missing changes to a target are only warnings; in a multi-file
+// then-change, individual targets can be marked optional with a leading "?"
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change? docs/changelog.md
+
+// owner format, where the owners are named in diagnostics asking for a change to the block
+// ---
+// if-change owner=@team-x
+// lorem ipsum dolor
+// sit amet
+// then-change other.file
+
+// expiry format, where the block is reported for re-examination after the given date
+// ---
+// if-change(expires=2025-12-31)
+// lorem ipsum dolor
+// sit amet
+// then-change other.file
+
+// URL format, where changes to the block produce a reminder to update the linked page
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change https://wiki.example.com/WireFormat
+
+// digest format, where the hash of "lorem ipsum dolor\nsit amet\n" must be kept up to date (e.g.
+// with "lint --update-digests") for the block to pass checks
+// ---
+// if-change(hash=sha256:e1a80849)
+// lorem ipsum dolor
+// sit amet
+// then-change other.file
+
+// reason format, where the text after "--" is included in diagnostics about the block
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change wire.proto -- keep wire format in sync
+
+// any-of format, where a change to either file suffices
+// ---
+// if-change
+// lorem ipsum dolor
+// sit amet
+// then-change-any
+//   a/b/c.rs
+//   a/b/c2.rs
+// end-change
+
+// nested format, where a change to "sit amet" only requires a change to c.rs, but a change to
+// "dolor" requires a change to both c.rs and c2.rs
+// ---
+// if-change
+// lorem ipsum
+// if-change
+// dolor
+// then-change a/b/c2.rs
+// sit amet
+// then-change a/b/c.rs
+
+// discontiguous format, where regions with the same name are one block, and all but one of the
+// regions are closed by a bare end-change
+// ---
+// if-change(name=flags)
+// lorem ipsum
+// then-change a/b/c.rs
+// dolor
+// if-change(name=flags)
+// sit amet
+// end-change
+
+// Google format, where "//" paths are relative to the repository root and ":label" names a block
+// ---
+// LINT.IfChange(flags)
+// lorem ipsum
+// LINT.ThenChange(//a/b/c.rs:flags, //a/b/c2.rs)
+
+#[cfg(test)]
+mod test {
+    use crate::if_change_then_change2::*;
+    use anyhow::anyhow;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
     fn then_change_well_formed() -> anyhow::Result<()> {
         let parsed = FileNode::from_str(
             "if-change.foo",
@@ -685,6 +2161,14 @@ mod test {
         assert_that!(parsed.blocks[0]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(5, BlockKey::new("then-change.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 1,
             then_change_lineno: 5,
             end_change_lineno: 5,
@@ -695,6 +2179,14 @@ mod test {
                 (13, BlockKey::new("then-change1.foo")),
                 (14, BlockKey::new("then-change2.foo")),
             ],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 9,
             then_change_lineno: 12,
             end_change_lineno: 15,
@@ -708,6 +2200,14 @@ mod test {
                 (23, BlockKey::new("then-change3.foo")),
                 (24, BlockKey::new("then-change4.foo")),
             ],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 17,
             then_change_lineno: 21,
             end_change_lineno: 25,
@@ -765,6 +2265,14 @@ mod test {
         assert_that!(parsed.blocks[0]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(5, BlockKey::new("then-change1.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 1,
             then_change_lineno: 5,
             end_change_lineno: 5,
@@ -772,6 +2280,14 @@ mod test {
         assert_that!(parsed.blocks[1]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(10, BlockKey::new("then-change2.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 8,
             then_change_lineno: 10,
             end_change_lineno: 10,
@@ -779,6 +2295,14 @@ mod test {
         assert_that!(parsed.blocks[2]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(16, BlockKey::new("then-change3.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 13,
             then_change_lineno: 15,
             end_change_lineno: 17,
@@ -789,6 +2313,14 @@ mod test {
                 (23, BlockKey::new("then-change4a.foo")),
                 (24, BlockKey::new("then-change4b.foo")),
             ],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 19,
             then_change_lineno: 22,
             end_change_lineno: 25,
@@ -796,6 +2328,14 @@ mod test {
         assert_that!(parsed.blocks[4]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(35, BlockKey::new("then-change5.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 31,
             then_change_lineno: 35,
             end_change_lineno: 35,
@@ -816,6 +2356,13 @@ mod test {
             ("<!-- then-change other.file -->", Some("other.file")),
             ("<!-- then-change other.file-->", Some("other.file")),
             ("<!-- then-change other.file----->", Some("other.file")),
+            ("＃ then-change other.file。", Some("other.file")),
+            (
+                "／／\u{3000}then-change\u{3000}other.file",
+                Some("other.file"),
+            ),
+            ("【then-change】", Some("")),
+            ("# then-change设置", None),
         ] {
             let (_, suffix) = line
                 .split_once("then-change")
@@ -881,6 +2428,14 @@ mod test {
         assert_that!(parsed.blocks[0]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(5, BlockKey::new("then-change1.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 1,
             then_change_lineno: 5,
             end_change_lineno: 5,
@@ -888,6 +2443,14 @@ mod test {
         assert_that!(parsed.blocks[1]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(12, BlockKey::new("then-change2.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 8,
             then_change_lineno: 12,
             end_change_lineno: 12,
@@ -895,6 +2458,14 @@ mod test {
         assert_that!(parsed.blocks[2]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(17, BlockKey::new("then-change3.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 15,
             then_change_lineno: 17,
             end_change_lineno: 17,
@@ -902,6 +2473,14 @@ mod test {
         assert_that!(parsed.blocks[3]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(23, BlockKey::new("then-change4.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 20,
             then_change_lineno: 23,
             end_change_lineno: 23,
@@ -909,6 +2488,14 @@ mod test {
         assert_that!(parsed.blocks[4]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(27, BlockKey::new("then-change5.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 25,
             then_change_lineno: 27,
             end_change_lineno: 27,
@@ -916,6 +2503,14 @@ mod test {
         assert_that!(parsed.blocks[5]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(31, BlockKey::new("then-change6.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 28,
             then_change_lineno: 31,
             end_change_lineno: 31,
@@ -923,6 +2518,14 @@ mod test {
         assert_that!(parsed.blocks[6]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(41, BlockKey::new("then-change7.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 37,
             then_change_lineno: 41,
             end_change_lineno: 41,
@@ -997,6 +2600,14 @@ mod test {
         assert_that!(parsed.blocks[0]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(4, BlockKey::new("then-change1.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 0,
             then_change_lineno: 3,
             end_change_lineno: 5,
@@ -1008,6 +2619,14 @@ mod test {
                 (12, BlockKey::new("then-change2b.foo")),
                 (13, BlockKey::new("then-change2c.foo")),
             ],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 8,
             then_change_lineno: 10,
             end_change_lineno: 14,
@@ -1015,6 +2634,14 @@ mod test {
         assert_that!(parsed.blocks[2]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(22, BlockKey::new("then-change3.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 17,
             then_change_lineno: 21,
             end_change_lineno: 23,
@@ -1022,6 +2649,14 @@ mod test {
         assert_that!(parsed.blocks[3]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(29, BlockKey::new("then-change4.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 26,
             then_change_lineno: 28,
             end_change_lineno: 30,
@@ -1029,6 +2664,14 @@ mod test {
         assert_that!(parsed.blocks[4]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(35, BlockKey::new("then-change5.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 32,
             then_change_lineno: 34,
             end_change_lineno: 36,
@@ -1039,6 +2682,14 @@ mod test {
                 (43, BlockKey::new("then-change6a.foo")),
                 (44, BlockKey::new("then-change6b.foo")),
             ],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 38,
             then_change_lineno: 42,
             end_change_lineno: 45,
@@ -1046,6 +2697,14 @@ mod test {
         assert_that!(parsed.blocks[6]).is_equal_to(BlockNode {
             key: BlockKey::new("if-change.foo"),
             then_change: vec![(53, BlockKey::new("then-change7.foo"))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![],
             if_change_lineno: 48,
             then_change_lineno: 52,
             end_change_lineno: 54,
@@ -1055,26 +2714,108 @@ mod test {
     }
 
     #[test]
-    fn error_when_if_change_end_change_have_labels() -> anyhow::Result<()> {
+    fn named_blocks_well_formed() -> anyhow::Result<()> {
         let parsed = FileNode::from_str(
             "if-change.foo",
             "\
-lorem
-ipsum
-# if-change fizzbuzz
-dolor
+// if-change(api-schema)
+1 lorem
+// then-change then-change.foo#api-schema
+<!-- if-change(v2.config_file) -->
+4 ipsum
+<!-- then-change
+       then-change.foo#v2.config_file
+       then-change.foo
+     end-change -->
+",
+        )?;
+        assert_that!(parsed.blocks).has_length(2);
+        assert_that!(parsed.blocks[0]).is_equal_to(BlockNode {
+            key: BlockKey::named("if-change.foo", Some("api-schema")),
+            then_change: vec![(2, BlockKey::named("then-change.foo", Some("api-schema")))],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![("name".to_string(), "api-schema".to_string())],
+            if_change_lineno: 0,
+            then_change_lineno: 2,
+            end_change_lineno: 2,
+        });
+        assert_that!(parsed.blocks[1]).is_equal_to(BlockNode {
+            key: BlockKey::named("if-change.foo", Some("v2.config_file")),
+            then_change: vec![
+                (
+                    6,
+                    BlockKey::named("then-change.foo", Some("v2.config_file")),
+                ),
+                (7, BlockKey::new("then-change.foo")),
+            ],
+            then_change_any: false,
+            reason: None,
+            owners: vec![],
+            expires: None,
+            hash: None,
+            ignore: None,
+            granularity: None,
+            attributes: vec![("name".to_string(), "v2.config_file".to_string())],
+            if_change_lineno: 3,
+            then_change_lineno: 5,
+            end_change_lineno: 8,
+        });
+        assert_that!(parsed.get_block_by_name("v2.config_file"))
+            .is_equal_to(Some(&parsed.blocks[1]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn line_range_targets() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change
 # then-change
-# then-change.foo
-# end-change foobar
-sit
-amet
+#   config.yaml:10-40
+#   config.yaml:7
+# end-change
+",
+        )?;
+        assert_that!(parsed.blocks[0].then_change).is_equal_to(vec![
+            (
+                2,
+                BlockKey {
+                    lines: Some(9..40),
+                    ..BlockKey::new("config.yaml")
+                },
+            ),
+            (
+                3,
+                BlockKey {
+                    lines: Some(6..7),
+                    ..BlockKey::new("config.yaml")
+                },
+            ),
+        ]);
+
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change
+# then-change
+#   config.yaml:40-10
+#   config.yaml:0
+# end-change
 ",
         );
         assert_that!(parsed).is_err();
         assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
             "\
-if-change.foo:3 - if-change has label 'fizzbuzz', but if-change statements may not be labelled
-if-change.foo:7 - end-change has label 'foobar', but end-change statements may not be labelled
+if-change.foo:3 - then-change has invalid line range '40-10': expected N or N-M, where 1 <= N <= M
+if-change.foo:4 - then-change has invalid line range '0': expected N or N-M, where 1 <= N <= M
 ",
         );
 
@@ -1082,7 +2823,643 @@ if-change.foo:7 - end-change has label 'foobar', but end-change statements may n
     }
 
     #[test]
-    #[ignore]
+    fn normalizes_paths() -> anyhow::Result<()> {
+        assert_that!(normalize_path("a.sh").as_str()).is_equal_to("a.sh");
+        assert_that!(normalize_path("./a/../b//c.sh").as_str()).is_equal_to("b/c.sh");
+        assert_that!(normalize_path("../a.sh").as_str()).is_equal_to("../a.sh");
+
+        // Targets written with Windows separators name the same files.
+        assert_that!(BlockKey::from_target("src\\b.sh#name"))
+            .is_equal_to(Ok(BlockKey::named("src/b.sh", Some("name"))));
+        assert_that!(parse_target("//src\\config:label", true))
+            .is_equal_to(Ok(BlockKey::named("src/config", Some("label"))));
+        assert_that!(BlockKey::from_target("//src/b.sh"))
+            .is_equal_to(Ok(BlockKey::new("src/b.sh")));
+        assert_that!(BlockKey::from_target("https://example.com/a\\b"))
+            .is_equal_to(Ok(BlockKey::new("https://example.com/a\\b")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn targets_which_may_not_exist() -> anyhow::Result<()> {
+        assert_that!(BlockKey::from_target("fork/b.rs#name?exists=optional")).is_equal_to(Ok(
+            BlockKey {
+                may_not_exist: true,
+                attributes: vec![("exists".to_string(), "optional".to_string())],
+                ..BlockKey::named("fork/b.rs", Some("name"))
+            },
+        ));
+        assert_that!(BlockKey::from_target("fork/b.rs?exists=optional").map(|key| key.to_string()))
+            .is_equal_to(Ok("fork/b.rs?exists=optional".to_string()));
+        // Without "key=value" pairs, the "?" is part of the path.
+        assert_that!(BlockKey::from_target("why?.md")).is_equal_to(Ok(BlockKey::new("why?.md")));
+        assert_that!(BlockKey::from_target("b.rs?exists=maybe")).is_equal_to(Err(
+            "then-change has exists 'maybe', but the only value for exists is 'optional'"
+                .to_string(),
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn relative_targets() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "src/api/handler.rs",
+            "\
+// if-change
+// then-change ./schema.json
+// if-change
+// then-change
+//   ../../docs/api.md
+//   ?./README.md
+//   //src/main.rs
+// end-change
+",
+        )?;
+        assert_that!(parsed.blocks[0].then_change)
+            .is_equal_to(vec![(1, BlockKey::new("src/api/schema.json"))]);
+        assert_that!(parsed.blocks[1]
+            .then_change
+            .iter()
+            .map(|(_, key)| key.path.as_str())
+            .collect::<Vec<_>>())
+        .is_equal_to(vec!["docs/api.md", "src/api/README.md", "src/main.rs"]);
+
+        assert_that!(relative_target_path("src/api/handler.rs", "docs/api.md"))
+            .is_equal_to("../../docs/api.md".to_string());
+        assert_that!(relative_target_path(
+            "src/api/handler.rs",
+            "src/api/schema.json"
+        ))
+        .is_equal_to("./schema.json".to_string());
+        assert_that!(relative_target_path("a.sh", "b/c.sh")).is_equal_to("./b/c.sh".to_string());
+
+        Ok(())
+    }
+
+    #[test]
+    fn dedups_then_change() -> anyhow::Result<()> {
+        let mut parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change
+# then-change
+#   then-change.foo
+#   then-change.foo#name
+#   dir/../then-change.foo
+#   then-change.foo:1-2
+#   then-change.foo#name
+# end-change
+",
+        )?;
+        let block = &mut parsed.blocks[0];
+        assert_that!(block
+            .dedup_then_change()
+            .into_iter()
+            .map(|((lineno, key), first_lineno)| (lineno, key.to_string(), first_lineno))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (4, "dir/../then-change.foo".to_string(), 2),
+            (6, "then-change.foo#name".to_string(), 3),
+        ]);
+        assert_that!(block
+            .then_change
+            .iter()
+            .map(|(lineno, _)| *lineno)
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![2, 3, 5]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reports_overlapping_blocks() -> anyhow::Result<()> {
+        let block = |if_change_lineno, end_change_lineno| BlockNode {
+            if_change_lineno,
+            then_change_lineno: end_change_lineno,
+            end_change_lineno,
+            ..BlockNode::virtual_block("if-change.foo", "block", 0, Vec::new())
+        };
+
+        // Disjoint and nested blocks are fine...
+        assert_that!(overlap_errors("if-change.foo", &[block(0, 2), block(3, 5)])).is_empty();
+        assert_that!(overlap_errors("if-change.foo", &[block(0, 9), block(3, 5)])).is_empty();
+        // ...but partially overlapping ones are not.
+        assert_that!(overlap_errors("if-change.foo", &[block(0, 4), block(3, 6)])
+            .iter()
+            .map(|error| error.to_string())
+            .collect::<Vec<_>>())
+        .is_equal_to(vec!["if-change.foo:4 - if-change block overlaps the block on lines 1-5 without being nested inside it, so changes to the lines they share would count towards both".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn then_change_any_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change
+# then-change-any then-change.foo
+# if-change
+# then-change-any
+#   then-change1.foo
+#   then-change2.foo
+# end-change
+# if-change
+# then-change then-change.foo
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (block.then_change.len(), block.then_change_any))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![(1, true), (2, true), (1, false)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn optional_targets_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change
+# then-change? then-change.foo
+# if-change
+# then-change
+#   then-change1.foo
+#   ?then-change2.foo
+# end-change
+# if-change
+# then-change-any?
+#   then-change1.foo
+#   then-change2.foo
+# end-change
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| {
+                block
+                    .then_change
+                    .iter()
+                    .map(|(_, key)| (key.path.as_str(), key.optional))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            vec![("then-change.foo", true)],
+            vec![("then-change1.foo", false), ("then-change2.foo", true)],
+            vec![("then-change1.foo", true), ("then-change2.foo", true)],
+        ]);
+        assert_that!(parsed.blocks[2].then_change_any).is_true();
+
+        Ok(())
+    }
+
+    #[test]
+    fn reasons_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change
+# then-change then-change.foo -- keep wire format in sync
+# if-change
+# then-change -- keep wire format in sync
+#   then-change1.foo
+#   then-change2.foo
+# end-change
+<!-- if-change -->
+<!-- then-change then-change.foo -- keep docs in sync -->
+# if-change
+# then-change then-change.foo --
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| {
+                (
+                    block.then_change.len(),
+                    block.then_change[0].1.path.as_str(),
+                    block.reason.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (1, "then-change.foo", Some("keep wire format in sync")),
+            (2, "then-change1.foo", Some("keep wire format in sync")),
+            (1, "then-change.foo", Some("keep docs in sync")),
+            (1, "then-change.foo", None),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn owners_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change owner=@team-x
+# then-change then-change.foo
+# if-change(api) owner=@team-x owner=@alice
+# then-change then-change.foo
+# if-change
+# then-change then-change.foo
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| block.owners.clone())
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            vec!["@team-x".to_string()],
+            vec!["@team-x".to_string(), "@alice".to_string()],
+            vec![],
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn expiry_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(expires=2025-12-31)
+# then-change then-change.foo
+# if-change(api, expires=2025-12-31, owner=@team-x)
+# then-change then-change.foo
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (
+                block.key.name.as_deref(),
+                block.expires.as_deref(),
+                block.owners.clone()
+            ))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (None, Some("2025-12-31"), vec![]),
+            (Some("api"), Some("2025-12-31"), vec!["@team-x".to_string()]),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_attribute() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            r#"# if-change(version, ignore="^\\s*version = \"[0-9.]+\"", owner=@release)
+version = "1.2.3"
+# then-change then-change.foo
+# if-change(ignore="(built|updated), at")
+# then-change then-change.foo
+"#,
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (block.key.name.as_deref(), block.ignore.as_deref()))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (Some("version"), Some(r#"^\s*version = "[0-9.]+""#)),
+            (None, Some("(built|updated), at")),
+        ]);
+
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "# if-change(ignore=\"(\")\n# then-change then-change.foo\n",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).starts_with(
+            "if-change.foo:1 - if-change has ignore pattern '(', but it is not a valid regex",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn granularity_attribute() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(granularity=file)
+# then-change CHANGELOG.md
+# if-change(api, granularity=block)
+# then-change then-change.foo
+# if-change(granularity=strict)
+# then-change then-change.foo
+# if-change
+# then-change then-change.foo
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| block.granularity)
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            Some(Granularity::File),
+            Some(Granularity::Block),
+            Some(Granularity::Strict),
+            None,
+        ]);
+
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "# if-change(granularity=line)\n# then-change then-change.foo\n",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "\
+if-change.foo:1 - if-change has granularity 'line', but granularity must be 'file', 'block' or 'strict'
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn arbitrary_attributes() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(api, severity=warning, team=\"payments, core\") owner=@payments
+# then-change then-change.foo?review=required&exists=optional
+",
+        )?;
+        let attributes = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_that!(parsed.blocks[0].attributes).is_equal_to(attributes(&[
+            ("name", "api"),
+            ("severity", "warning"),
+            ("team", "payments, core"),
+            ("owner", "@payments"),
+        ]));
+        assert_that!(parsed.blocks[0].owners).is_equal_to(vec!["@payments".to_string()]);
+        let target = &parsed.blocks[0].then_change[0].1;
+        assert_that!(target.attributes).is_equal_to(attributes(&[
+            ("review", "required"),
+            ("exists", "optional"),
+        ]));
+        assert_that!(target.may_not_exist).is_true();
+        assert_that!(target.to_string().as_str())
+            .is_equal_to("then-change.foo?review=required&exists=optional");
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_when_attributes_invalid() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(expires=someday)
+# then-change then-change.foo
+# if-change(col or=blue)
+# then-change then-change.foo
+# if-change(api, schema)
+# then-change then-change.foo
+# if-change(hash=md5:abc)
+# then-change then-change.foo
+",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "\
+if-change.foo:1 - if-change has expiry date 'someday', but expiry dates must be of the form YYYY-MM-DD
+if-change.foo:3 - if-change has attribute 'col or', but attribute names must be non-empty and may only contain letters, digits, '-', '_' and '.'
+if-change.foo:5 - if-change has a second name 'schema', but blocks may only have one name
+if-change.foo:7 - if-change has hash 'md5:abc', but hashes must be of the form sha256:<hex digest>
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn url_targets() -> anyhow::Result<()> {
+        for url in [
+            "https://wiki.example.com/WireFormat",
+            "https://wiki.example.com/WireFormat#header",
+            "http://localhost:8080/docs/@latest",
+        ] {
+            let key = BlockKey::from_target(url).map_err(|err| anyhow!(err))?;
+            assert_that!(key.path.as_str()).is_equal_to(url);
+            assert_that!(key.is_url()).is_true();
+            assert_that!(key.expects_block()).is_false();
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn nested_blocks_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(outer)
+lorem
+# if-change(inner)
+ipsum
+# if-change(innermost)
+dolor
+# then-change then-change1.foo
+# then-change
+#   then-change2.foo
+# end-change
+sit
+# then-change then-change3.foo
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (block.key.name.as_deref(), block.content_range()))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (Some("outer"), 0..12),
+            (Some("inner"), 2..10),
+            (Some("innermost"), 4..7),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn discontiguous_blocks_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(name=flags)
+lorem
+# end-change
+# if-change(flags)
+ipsum
+# then-change-any
+#   then-change1.foo
+#   then-change2.foo
+# end-change
+# if-change(flags)
+dolor
+# end-change
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (
+                block.content_range(),
+                block.then_change.len(),
+                block.then_change_any
+            ))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![(0..3, 2, true), (3..9, 2, true), (9..12, 2, true)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_when_discontiguous_block_has_no_then_change() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(flags)
+lorem
+# end-change
+# if-change(other)
+ipsum
+# then-change then-change.foo
+",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "\
+if-change.foo:1 - if-change(flags) is closed by an end-change, so another if-change(flags) in this file must have a then-change, but found none
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn corresponding_block_disambiguation() -> anyhow::Result<()> {
+        let src = FileNode::from_str(
+            "a.foo",
+            "\
+# if-change(api)
+# then-change b.foo
+# if-change
+# then-change b.foo
+# if-change
+# then-change b.foo
+",
+        )?;
+        let dst = FileNode::from_str(
+            "b.foo",
+            "\
+# if-change
+# then-change a.foo
+lorem
+ipsum
+# if-change
+# then-change a.foo
+dolor
+sit
+# if-change(api)
+# then-change a.foo
+",
+        )?;
+        let content_range = |correspondence: Correspondence| match correspondence {
+            Correspondence::Block(regions) => Some(regions[0].content_range()),
+            _ => None,
+        };
+        let target = BlockKey::new("b.foo");
+
+        // By name, even though another block is nearer.
+        assert_that!(content_range(dst.correspondence(&src.blocks[0], &target)))
+            .is_equal_to(Some(8..10));
+        // By line, since neither block is named.
+        assert_that!(content_range(dst.correspondence(&src.blocks[2], &target)))
+            .is_equal_to(Some(4..6));
+        // A tie: the blocks on lines 1 and 5 are both 2 lines from line 3.
+        assert_that!(dst.correspondence(&src.blocks[1], &target)).matches(|correspondence| {
+            matches!(correspondence, Correspondence::Ambiguous(candidates) if candidates.len() == 2)
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_when_block_names_invalid_or_duplicated() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+# if-change(api schema)
+# then-change then-change.foo
+# if-change()
+# then-change then-change.foo
+# if-change(api)
+# then-change then-change.foo
+# if-change(api)
+# then-change then-change.foo
+",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "\
+if-change.foo:1 - if-change has name 'api schema', but names must be non-empty and may only contain letters, digits, '-', '_' and '.'
+if-change.foo:3 - if-change has name '', but names must be non-empty and may only contain letters, digits, '-', '_' and '.'
+if-change.foo:7 - if-change name 'api' is already used by the if-change on line 5
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_when_if_change_end_change_have_labels() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+lorem
+ipsum
+# if-change fizzbuzz
+dolor
+# then-change
+# then-change.foo
+# end-change foobar
+sit
+amet
+",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "\
+if-change.foo:3 - if-change has label 'fizzbuzz', but if-change statements may not be labelled
+if-change.foo:7 - end-change has label 'foobar', but end-change statements may not be labelled
+",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore]
     fn error_when_then_change_not_closed() -> anyhow::Result<()> {
         let parsed = FileNode::from_str(
             "if-change.foo",
@@ -1106,7 +3483,6 @@ then-change-above is not closed
     }
 
     #[test]
-    #[ignore]
     fn error_when_if_change_not_closed() -> anyhow::Result<()> {
         let parsed = FileNode::from_str(
             "if-change.foo",
@@ -1124,7 +3500,407 @@ then-change-above is not closed
         );
         assert_that!(parsed).is_err();
         assert_that!(parsed.unwrap_err().to_string().as_str())
-            .is_equal_to("if-change.foo:4 - if-change nesting is not allowed\n");
+            .is_equal_to("if-change.foo:2 - if-change must be closed by a then-change, but found no such then-change\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn files_without_keywords_are_not_parsed() -> anyhow::Result<()> {
+        let options = ParseOptions::default();
+        assert_that!(options.may_contain_directives("fn main() {}\n")).is_false();
+        assert_that!(options.may_contain_directives("fn main() {} // then-change a.rs\n"))
+            .is_true();
+        assert_that!(options.directive_lines("fn main() {}\n")).is_none();
+        assert_that!(options
+            .directive_lines("a\n// if-change\nb\n// then-change c.rs\nd\n// LINT.IfChange\ne\n"))
+        .is_equal_to(Some((1, 5)));
+        assert_that!(options
+            .directive_lines("// LINT.IfChange\na\n// LINT.ThenChange(\n//   b.rs,\n// )\nc\n"))
+        .is_equal_to(Some((0, 4)));
+
+        // Anything with a keyword is still parsed, and so still checked.
+        assert_that!(FileNode::from_str(
+            "a.rs",
+            "fn main() {}\n// then-change b.rs\n"
+        ))
+        .is_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn serialized_file_nodes_deserialize_to_the_same_blocks() -> anyhow::Result<()> {
+        let contents = "\
+# if-change(api, expires=2025-12-31, granularity=file) owner=@team
+lorem
+# then-change-any
+#   a.sh#api
+#   b.sh:10-40
+#   c.rs@fn handle
+# end-change
+# if-change
+ipsum
+# then-change? d.sh -- generated from this
+";
+        let parsed = FileNode::from_str("if-change.foo", contents)?;
+        let round_tripped = serde_json::from_str::<FileNode>(&serde_json::to_string(&parsed)?)?;
+        assert_that!(round_tripped.blocks).is_equal_to(parsed.blocks);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keyword_aliases_well_formed() -> anyhow::Result<()> {
+        let contents = "\
+// IfChange(api)
+lorem
+// ThenChange then-change1.foo
+// SYNC-START
+ipsum
+// ThenChange
+//   then-change2.foo
+// SYNC-END
+";
+        let keywords = Keywords {
+            if_change: vec!["IfChange".to_string(), "SYNC-START".to_string()],
+            then_change: vec!["ThenChange".to_string()],
+            end_change: vec!["SYNC-END".to_string()],
+        };
+        let options = ParseOptions {
+            keywords,
+            ..Default::default()
+        };
+        let parsed = FileNode::from_str_with_options("if-change.foo", contents, &options)?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (block.key.name.as_deref(), block.content_range()))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![(Some("api"), 0..3), (None, 3..8)]);
+
+        // Without the aliases, these are just comments.
+        assert_that!(FileNode::from_str("if-change.foo", contents)?
+            .blocks
+            .is_empty())
+        .is_true();
+
+        assert_that!(crate::config::Config::from_str(
+            "[keywords]\nif_change = [\"If Change\"]\n"
+        ))
+        .is_err();
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_generated_files() -> anyhow::Result<()> {
+        let contents = "\
+// Code generated by stringer; DO NOT EDIT.
+// if-change
+lorem
+// then-change
+";
+        let parsed = FileNode::from_str("generated.go", contents)?;
+        assert_that!(parsed.generated).is_true();
+        assert_that!(parsed.blocks.is_empty()).is_true();
+        assert_that!(
+            FileNode::from_str("generated.py", "# @generated by codegen.py\n# if-change\n")?
+                .generated
+        )
+        .is_true();
+
+        // Only the usual conventions count, not anything which happens to mention them.
+        let parsed = FileNode::from_str(
+            "ports.sh",
+            "\
+export WEB_PORT=8080
+# DO NOT EDIT the ports below without updating firewall.tf
+# if-change
+export API_PORT=8081
+# then-change firewall.tf
+# see @generated-docs for the rest
+",
+        )?;
+        assert_that!(parsed.generated).is_false();
+        assert_that!(parsed.blocks).has_length(1);
+        assert_that!(
+            FileNode::from_str(
+                "ports.sh",
+                "echo '# @generated'\n# if-change\n# then-change a.sh\n"
+            )?
+            .generated
+        )
+        .is_false();
+
+        let options = ParseOptions {
+            generated_markers: Some(vec!["AUTOGENERATED".to_string()]),
+            ..Default::default()
+        };
+        assert_that!(FileNode::from_str_with_options(
+            "generated.go",
+            contents,
+            &options
+        ))
+        .is_err();
+        assert_that!(
+            FileNode::from_str_with_options(
+                "generated.py",
+                "# AUTOGENERATED\n# if-change\n",
+                &options
+            )?
+            .generated
+        )
+        .is_true();
+
+        Ok(())
+    }
+
+    #[test]
+    fn handles_byte_order_mark() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\u{feff}# if-change\nlorem\n# then-change then-change1.foo\n",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| block.location().position().to_string())
+            .collect::<Vec<_>>())
+        .is_equal_to(vec!["if-change.foo:1-3".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn handles_crlf_line_endings() -> anyhow::Result<()> {
+        for contents in [
+            "# if-change(api)\r\nlorem\r\n# then-change\r\n#   a.foo\r\n#   b.foo#api\r\n# end-change\r\n",
+            // Mixed line endings, as left behind by editors which preserve each line's ending.
+            "# if-change(api)\nlorem\r\n# then-change\n#   a.foo\r\n#   b.foo#api\n# end-change\r\n",
+        ] {
+            let parsed = FileNode::from_str("if-change.foo", contents)?;
+            assert_that!(parsed
+                .blocks
+                .iter()
+                .map(|block| (
+                    block.key.name.as_deref(),
+                    block.content_range(),
+                    block
+                        .then_change
+                        .iter()
+                        .map(|(_, key)| key.to_string())
+                        .collect::<Vec<_>>()
+                ))
+                .collect::<Vec<_>>())
+            .is_equal_to(vec![(
+                Some("api"),
+                0..6,
+                vec!["a.foo".to_string(), "b.foo#api".to_string()],
+            )]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn google_style_well_formed() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+// LINT.IfChange
+lorem
+// LINT.ThenChange(//then-change1.foo)
+// LINT.IfChange(api)
+ipsum
+// LINT.ThenChange(//then-change2.foo:api, then-change3.foo)
+// LINT.IfChange
+dolor
+// LINT.ThenChange(
+//     //then-change4.foo,
+//     //then-change5.foo:schema,
+// )
+// LINT.IfChange
+sit
+/* LINT.ThenChange(
+       //then-change6.foo) */
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (
+                block.key.name.as_deref(),
+                block.content_range(),
+                block
+                    .then_change
+                    .iter()
+                    .map(|(_, key)| key.to_string())
+                    .collect::<Vec<_>>()
+            ))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (None, 0..3, vec!["then-change1.foo".to_string()]),
+            (
+                Some("api"),
+                3..6,
+                vec![
+                    "then-change2.foo#api".to_string(),
+                    "then-change3.foo".to_string(),
+                ],
+            ),
+            (
+                None,
+                6..12,
+                vec![
+                    "then-change4.foo".to_string(),
+                    "then-change5.foo#schema".to_string(),
+                ],
+            ),
+            (None, 12..16, vec!["then-change6.foo".to_string()]),
+        ]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn handles_unicode_comment_formats() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.foo",
+            "\
+＃ if-change
+设置 = 1
+＃ then-change then-change1.foo
+／／\u{3000}if-change(api)
+接口 = 2
+／／\u{3000}then-change\u{3000}then-change2.foo。
+【if-change】
+端口 = 3
+【then-change】
+【　then-change3.foo　】
+【end-change】
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| (
+                block.content_range(),
+                block
+                    .then_change
+                    .iter()
+                    .map(|(_, key)| key.to_string())
+                    .collect::<Vec<_>>()
+            ))
+            .collect::<Vec<_>>())
+        .is_equal_to(vec![
+            (0..3, vec!["then-change1.foo".to_string()]),
+            (3..6, vec!["then-change2.foo".to_string()]),
+            (6..11, vec!["then-change3.foo".to_string()]),
+        ]);
+
+        // CJK text is not a comment prefix, any more than ASCII text is.
+        assert_that!(FileNode::from_str("if-change.foo", "设置 if-change\n")?
+            .blocks
+            .is_empty())
+        .is_true();
+
+        Ok(())
+    }
+
+    #[cfg(feature = "comments")]
+    #[test]
+    fn directives_must_be_in_comments() -> anyhow::Result<()> {
+        let parsed = FileNode::from_str(
+            "if-change.rs",
+            "\
+let usage = \"# if-change\";
+// if-change
+let timeout = 30;
+/* then-change
+     then-change1.foo
+   end-change */
+let help = \"then-change\";
+",
+        )?;
+        assert_that!(parsed
+            .blocks
+            .iter()
+            .map(|block| block.location().position().to_string())
+            .collect::<Vec<_>>())
+        .is_equal_to(vec!["if-change.rs:2-6".to_string()]);
+
+        let parsed = FileNode::from_str(
+            "if-change.rs",
+            "\
+// if-change
+let timeout = 30;
+// then-change
+//   then-change1.foo
+let retries = 3;
+",
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "if-change.rs:3 - then-change must be closed by an end-change, but found no such end-change\n",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn strict_mode_rejects_directives_in_strings() -> anyhow::Result<()> {
+        let options = ParseOptions {
+            strict: true,
+            ..Default::default()
+        };
+
+        let parsed = FileNode::from_str_with_options(
+            "fixtures.go",
+            "\
+var lines = []string{
+\t\"// if-change\",
+\t\"// then-change other.go\",
+}
+// if-change
+var timeout = \"30\"
+// then-change other.go
+",
+            &options,
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "\
+fixtures.go:2 - if-change appears to be inside a string literal rather than a comment, so it is not being enforced
+fixtures.go:3 - then-change appears to be inside a string literal rather than a comment, so it is not being enforced
+",
+        );
+
+        let parsed = FileNode::from_str_with_options(
+            "fixtures.py",
+            "\
+FIXTURE = \"\"\"
+# if-change
+\"\"\"
+",
+            &options,
+        );
+        assert_that!(parsed).is_err();
+        assert_that!(parsed.unwrap_err().to_string().as_str()).is_equal_to(
+            "fixtures.py:2 - if-change appears to be inside a string literal rather than a comment, so it is not being enforced\n",
+        );
+
+        let parsed = FileNode::from_str_with_options(
+            "fixtures.go",
+            "\
+var name = \"it's\"
+// if-change
+var timeout = 30
+// then-change other.go
+",
+            &options,
+        )?;
+        assert_that!(parsed.blocks).has_length(1);
 
         Ok(())
     }
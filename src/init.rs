@@ -0,0 +1,150 @@
+use crate::config::CONFIG_FILE;
+use crate::{fix, git, hook};
+use anyhow::{Context, Result};
+use std::path::Path;
+
+const STARTER_CONFIG: &str = "\
+# Configuration for if-change-then-change checks. Every setting is optional.
+
+# Ignore directives inside fenced code blocks in Markdown files, e.g. examples in documentation.
+#
+# skip_markdown_fences = true
+
+# Don't count changes which only touch whitespace, e.g. from running a formatter, as changes to a
+# block or its then-change targets.
+#
+# ignore_whitespace = true
+
+# Likewise for changes to lines which are only comments, e.g. editing the prose inside a block.
+#
+# ignore_comments = true
+
+# Warn about then-change targets which point at the file they're in, which are almost always
+# mistakes; otherwise they're ignored.
+#
+# warn_self_references = true
+
+# Don't report missing changes for blocks which the diff being checked adds, so that a block can be
+# annotated before its counterparts are.
+#
+# relax_new_blocks = true
+
+# Require changes to every block reachable through then-changes, not just a block's direct targets:
+# if A points at B and B points at C, a change to A also requires a change to C.
+#
+# transitive = true
+
+# How much of a then-change target has to change, for blocks without an if-change(granularity=...):
+# \"file\" (anywhere in the file), \"block\" (anywhere in its corresponding block, the default) or
+# \"strict\" (between its corresponding block's if-change and then-change, not just those lines).
+#
+# granularity = \"strict\"
+
+# Then-change targets matching these globs have \"file\" granularity, e.g. changelogs.
+#
+# file_granularity = [\"CHANGELOG.md\"]
+
+# Files which say they're generated in their first 10 lines (by default, with Go's \"// Code
+# generated ... DO NOT EDIT.\" or \"@generated\" in a comment) aren't parsed for blocks, but any
+# change to them satisfies a then-change pointing at them. To look for other markers instead:
+#
+# generated_markers = [\"AUTOGENERATED\"]
+
+# Skip files larger than this many bytes (32 MiB by default) rather than reading them into memory.
+# Files matching a `[[size_limit]]` glob get that limit instead.
+#
+# max_file_size = 33554432
+#
+# [[size_limit]]
+# path = \"data/**.csv\"
+# max_bytes = 1000000000
+
+# Couple files which can't (or shouldn't) carry if-change-then-change comments, e.g. generated code:
+# any change to a file matching `when` must be accompanied by a change to a file matching `then`.
+#
+# [[rule]]
+# when = \"proto/**.proto\"
+# then = \"gen/**\"
+
+# Treat the lines of a file matching `regex` as an if-change(name) block, for files whose format
+# has no comments (e.g. JSON, lockfiles).
+#
+# [[block]]
+# path = \"package.json\"
+# name = \"version\"
+# regex = '\"version\":'
+# then_change = [\"src/version.rs\"]
+
+# Recognize other spellings of the directives, in addition to if-change, then-change and end-change.
+#
+# [keywords]
+# if_change = [\"IfChange\"]
+# then_change = [\"ThenChange\"]
+# end_change = [\"EndChange\"]
+";
+
+const EXAMPLE_DIR: &str = "ictc-example";
+
+/// Sets up a repository to use the tool: writes a starter config at the repository root,
+/// optionally installs `hook`, and (with `example`) writes an example pair of files which point
+/// at each other. Existing files are never overwritten.
+pub fn init(hook: Option<&str>, example: bool) -> Result<()> {
+    let repo_root = git::repo_root().context("init must be run inside a git repository")?;
+
+    let config_path = repo_root.join(CONFIG_FILE);
+    if write_new(&config_path, STARTER_CONFIG)? {
+        println!("created {}", config_path.display());
+    } else {
+        println!("{} already exists, leaving it alone", config_path.display());
+    }
+
+    if example {
+        // then-change paths are relative to the repository root.
+        let first = format!("{}/first.sh", EXAMPLE_DIR);
+        let second = format!("{}/second.sh", EXAMPLE_DIR);
+        for (path, then_change_path) in [(&first, &second), (&second, &first)] {
+            let contents = example_file(path, then_change_path);
+            if write_new(&repo_root.join(path), &contents)? {
+                println!("created {}", path);
+            } else {
+                println!("{} already exists, leaving it alone", path);
+            }
+        }
+    }
+
+    if let Some(hook) = hook {
+        let path = hook::install(&git::hooks_dir()?, hook)?;
+        println!("installed {}", path.display());
+    }
+
+    Ok(())
+}
+
+fn example_file(path: &str, then_change_path: &str) -> String {
+    let scaffold = fix::scaffold_block(path, then_change_path);
+    let (if_change, then_change) = scaffold.split_once('\n').unwrap_or((&scaffold, ""));
+
+    format!(
+        "\
+# Changes to the lines between the if-change and the then-change below must be accompanied by a
+# change to the matching block in {then_change_path}, and vice versa.
+{if_change}
+GREETING=\"hello\"
+{then_change}"
+    )
+}
+
+/// Writes `contents` to `path` unless it already exists, returning whether it was written.
+fn write_new(path: &Path, contents: &str) -> Result<bool> {
+    if path.exists() {
+        return Ok(false);
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    std::fs::write(path, contents)
+        .with_context(|| format!("failed to write {}", path.display()))?;
+
+    Ok(true)
+}
@@ -1,325 +1,832 @@
+mod baseline;
+mod bitbucket;
+mod buck;
+mod check;
+mod codeowners;
+mod comment;
+mod config;
+mod daemon;
+mod date;
 mod diagnostic;
+mod digest;
+mod doctor;
+mod fix;
+mod git;
+mod github;
+mod history;
+mod hook;
 mod if_change_then_change2;
+mod init;
+mod metrics;
+mod output;
+mod repo;
+mod server;
+mod suggest;
+mod symbol;
+mod tree;
+mod tui;
 
-use crate::diagnostic::{Diagnostic, DiagnosticPosition};
-use anyhow::Result;
-use if_change_then_change2::FileNodeParseError;
-use std::collections::VecDeque;
-use std::collections::{HashMap, HashSet};
-use std::fmt;
-use std::io::Read;
-use std::ops::Range;
+use anyhow::{anyhow, Context, Result};
+use check::{Options, ParseCache};
+use clap::{Parser, Subcommand};
+use diagnostic::Diagnostic;
+use if_change_then_change2::FileNode;
+use serde_json::json;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+use tree::Tree;
 
-fn run() -> Result<()> {
-    let mut diagnostics = Vec::new();
+/// Enforces if-change-then-change blocks for the diff on stdin.
+///
+/// Exits with 0 if there are no violations (errors, or with `--deny-warnings` warnings too), 1 if
+/// there are (or, for `fmt --check` and `doctor`, if they find problems), 2 if the arguments,
+/// config, diff or files can't be used, and 3 if anything else went wrong, e.g. git or the
+/// network failing, or a bug.
+#[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
+struct Cli {
+    /// Send the diff to a daemon listening on this socket (see `daemon`) instead of checking it
+    /// in-process.
+    #[arg(long, value_name = "SOCKET")]
+    connect: Option<PathBuf>,
 
-    let (patch_set, is_git_diff) = {
-        let mut input = String::new();
+    /// Report directives which look like they're inside string literals (e.g. test fixtures)
+    /// as errors, instead of silently ignoring or enforcing them.
+    #[arg(long, conflicts_with = "connect")]
+    strict: bool,
 
-        std::io::stdin()
-            .read_to_string(&mut input)
-            .expect("Failed to read stdin");
+    /// Report then-change targets whose corresponding block doesn't point back with a
+    /// then-change of its own, i.e. couplings which are only enforced in one direction.
+    #[arg(long, conflicts_with = "connect")]
+    require_reciprocal: bool,
 
-        let is_git_diff = input.starts_with("diff --git");
+    /// Report then-change targets which do not exist as info, rather than failing the check,
+    /// e.g. for trees which only have some of the files the blocks reference.
+    #[arg(long, conflicts_with = "connect")]
+    allow_missing_targets: bool,
 
-        let mut patch_set = unidiff::PatchSet::new();
-        // TODO- we should error more usefully if this happens
-        patch_set.parse(input).ok().expect("Error parsing diff");
+    /// Fix what can be fixed automatically, and report the rest as usual: scaffold missing
+    /// counterpart blocks (in place of an "ictc-scaffold" comment, if the file has one), remove
+    /// then-change targets which do not exist, and update then-changes anywhere in the tree
+    /// which reference files the diff renames.
+    #[arg(long, conflicts_with = "connect")]
+    fix: bool,
 
-        (patch_set, is_git_diff)
-    };
+    /// With --fix, change nothing, and instead print the edits that would have been made as a
+    /// unified diff, e.g. to review them or pipe them into `git apply`.
+    #[arg(long, requires = "fix")]
+    dry_run: bool,
 
-    // We want to key this map by the path at HEAD corresponding to a given diff
-    let diffs_by_post_diff_path = patch_set
-        .files()
-        .iter()
-        .inspect(|patched_file| {
-            log::info!("patched file in diff: {}", patched_file.target_file);
-        })
-        .filter_map(|patched_file| {
-            if is_git_diff {
-                let source_path_valid = patched_file.source_file.starts_with("a/") || patched_file.source_file == "/dev/null";
-                let target_path_valid = patched_file.target_file.starts_with("b/") || patched_file.target_file == "/dev/null";
-
-                // Do some light git diff validation. There are only two cases where the source file and target file are not
-                // prefixed with "a/" and "b/" respectively: when a file has been added (source file is /dev/null) and when
-                // a file has been deleted (target file is /dev/null).
-                if !source_path_valid || !target_path_valid {
-                    diagnostics.push(Diagnostic {
-                        path: "stdin".to_string(),
-                        // TODO- $lines should reference the lines of the diff
-                        start_line: None,
-                        end_line: None,
-                        message: format!(
-                            "invalid git diff: expected a/before.path -> b/after.path, but got '{}' -> '{}'",
-                            patched_file.source_file,
-                            patched_file.target_file,
-                        ),
-                    });
-                    return None;
-                }
+    /// Write metrics about the check (files scanned, blocks parsed, diagnostics by severity,
+    /// and how long it took) to this file, in the Prometheus text format.
+    #[arg(long, value_name = "PATH", conflicts_with = "connect")]
+    metrics_file: Option<PathBuf>,
 
-                if patched_file.target_file.starts_with("b/") {
-                    // In a "diff --git", the pre-diff and post-diff paths are prefixed with "a/" and "b/". We have
-                    // to strip these prefixes ourselves, because unidiff::PatchedFile does not expose metadata about
-                    // whether or not it represents a "diff --git" or normal diff. (PatchedFile.path() does do some
-                    // stripping here, but it uses the source file and is poorly implemented.)
-                    Some((patched_file.target_file[2..].to_string(), patched_file))
-                } else {
-                    // We don't index deleted files in diffs_by_post_diff_path, because we can't read a deleted file
-                    // (after we build this hashmap, the next thing we do is parse if-change-then-change blocks out
-                    // of all files changed in the diff).
-                    None
-                }
-            } else {
-                if patched_file.target_file == "/dev/null" {
-                    return None;
-                }
+    /// Save parsed files in this directory, keyed by their contents, so that later runs only
+    /// re-parse files which have changed since.
+    #[arg(long, value_name = "DIR", conflicts_with = "connect")]
+    cache_dir: Option<PathBuf>,
 
-                Some((patched_file.target_file.clone(), patched_file))
-            }
+    /// Only check changes to, and report problems in, files matching this pattern: a glob, or
+    /// a directory followed by "/..." for everything under it, e.g. "services/payments/...".
+    /// May be repeated.
+    #[arg(long, value_name = "PATTERN", conflicts_with = "connect")]
+    only: Vec<String>,
+
+    /// Like --only, but for files not to check. May be repeated.
+    #[arg(long, value_name = "PATTERN", conflicts_with = "connect")]
+    exclude: Vec<String>,
+
+    /// How many files to read at once, e.g. more on network filesystems, where reads are slow
+    /// but plentiful. The results don't depend on it.
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = check::DEFAULT_IO_CONCURRENCY,
+        conflicts_with = "connect"
+    )]
+    io_concurrency: usize,
+
+    /// How to print diagnostics.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: output::Format,
+
+    /// Print only the summary of the check (how many blocks and files it checked, and how many
+    /// violations it found), as text or (with `--format json`) JSON, instead of the diagnostics.
+    #[arg(long, conflicts_with_all = ["connect", "fix"])]
+    summary_only: bool,
+
+    /// How to print where each diagnostic is in text output, e.g. for an editor or CI system to
+    /// jump to it.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    position_format: output::PositionFormat,
+
+    /// Show this many lines of code around each diagnostic, and around the block which caused
+    /// it, below its message. Only for text output.
+    #[arg(long, global = true, value_name = "N")]
+    context: Option<usize>,
+
+    /// Log messages at this level and above to stderr: off, error, warn, info, debug or trace.
+    /// Defaults to errors only, unless $RUST_LOG says otherwise.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<log::LevelFilter>,
+
+    /// Log each message to stderr as a line of JSON, with its timestamp, level, target (the
+    /// module it came from) and message.
+    #[arg(long, global = true)]
+    log_json: bool,
+
+    /// Log nothing at all, not even why we failed (the exit status says whether we did), and
+    /// leave out the summary of the check.
+    #[arg(short, long, global = true, conflicts_with = "log_level")]
+    quiet: bool,
+
+    /// Fail on warnings (e.g. about optional then-change targets, or then-changes listing the
+    /// same target twice) as well as errors.
+    #[arg(long, global = true)]
+    deny_warnings: bool,
+
+    /// The directory that paths in diffs and then-change targets (including "//path" ones) are
+    /// relative to, and that the config is loaded from. We run from there, so paths given as
+    /// arguments are relative to it too, except for the files we write. Defaults to the current
+    /// directory, or to the root of its git repository if that's what the diff's paths are
+    /// relative to, e.g. for `git diff` run from a subdirectory.
+    #[arg(long, global = true, value_name = "DIR")]
+    root: Option<PathBuf>,
+
+    /// Check the staged changes to these files instead of the diff on stdin, and fail if there
+    /// are any problems, as the pre-commit framework expects of hooks. Files can also be listed
+    /// in an argfile passed as "@path", and outputs under buck-out are mapped back to the
+    /// sources they were built from, for Buck2 rules.
+    files: Vec<String>,
+
+    /// With files to check, also check the files under them which git doesn't track yet (and
+    /// doesn't ignore), as if they had been added in full, e.g. `--include-untracked .` before
+    /// `git add`ing new files.
+    #[arg(long, requires = "files")]
+    include_untracked: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Interactively review the diagnostics for the diff on stdin: view both sides of each
+    /// if-change-then-change pair, acknowledge diagnostics, open files in $EDITOR, and
+    /// scaffold missing blocks.
+    Tui,
+    /// Keep parsed files warm in memory and check diffs sent over a unix socket, so that
+    /// repeated checks (e.g. from hooks and editors) skip re-parsing unchanged files.
+    Daemon {
+        #[arg(long, default_value = daemon::DEFAULT_SOCKET)]
+        socket: PathBuf,
+    },
+    /// Serve checks over HTTP: POST a diff to /check (optionally with ?base=<git revision>,
+    /// if the diff has not been applied to this checkout) and get back JSON diagnostics.
+    Serve {
+        #[arg(long, default_value = ":8080")]
+        listen: String,
+    },
+    /// Check the diff on stdin, and post the diagnostics to a GitHub pull request as review
+    /// comments (resolving the ones from earlier runs which no longer apply). Reads the token
+    /// from $GITHUB_TOKEN, and the API URLs from $GITHUB_API_URL and $GITHUB_GRAPHQL_URL.
+    GithubReview {
+        /// The number of the pull request to review.
+        #[arg(long)]
+        pr: u64,
+        /// The repository, as owner/name; defaults to $GITHUB_REPOSITORY.
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// Check the diff on stdin, and report the diagnostics as a GitHub check run with an
+    /// annotation for each, failing if there are any errors. Reads the token from
+    /// $GITHUB_TOKEN, and the commit from $GITHUB_EVENT_PATH or $GITHUB_SHA.
+    GithubCheck {
+        /// The name of the check run.
+        #[arg(long, default_value = "if-change-then-change")]
+        name: String,
+        /// The repository, as owner/name; defaults to $GITHUB_REPOSITORY.
+        #[arg(long)]
+        repo: Option<String>,
+    },
+    /// Check the diff on stdin, and publish the diagnostics to Bitbucket as a Code Insights
+    /// report on the commit, with an annotation for each. Reads the commit and repository from
+    /// the Bitbucket Pipelines environment.
+    BitbucketReport,
+    /// Check the diff of every commit since a revision against its parent, and report the
+    /// commits which introduced diagnostics, e.g. to find when a coupling first broke.
+    History {
+        /// Check the commits reachable from HEAD but not from this revision.
+        #[arg(long, value_name = "REF")]
+        since: String,
+    },
+    /// Compare two baselines (saved `--format json` outputs), and report which diagnostics were
+    /// added, fixed, or left unchanged between them.
+    Compare { old: PathBuf, new: PathBuf },
+    /// Print the if-change-then-change blocks parsed from each file as JSON, to debug why a
+    /// directive is (or isn't) being recognized.
+    Parse {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// Report every syntax error, nonexistent then-change target, and self-referential
+    /// then-change in the tree, independent of any diff.
+    Lint {
+        /// Before linting, rewrite every if-change(hash=...) which no longer matches its
+        /// block's content, i.e. record that its then-change targets have been brought in sync.
+        #[arg(long)]
+        update_digests: bool,
+        /// Fix what can be fixed automatically, e.g. remove then-change targets which do not
+        /// exist, and report the rest as usual.
+        #[arg(long)]
+        fix: bool,
+        /// Change nothing, and instead print the edits that --update-digests and --fix would
+        /// have made as a unified diff.
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// Convert legacy markers (Google-style LINT.IfChange/LINT.ThenChange, and the keyword
+    /// aliases in the config) to if-change-then-change syntax, printing the changes as a diff.
+    Migrate {
+        /// Rewrite the files, rather than only printing the diff.
+        #[arg(long)]
+        write: bool,
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// Format directives: spell keywords the usual way, space them consistently, sort and align
+    /// the targets of each then-change, and split long inline then-changes over several lines.
+    Fmt {
+        /// Only print the changes, rather than also rewriting the files, and fail if there are
+        /// any, e.g. for CI.
+        #[arg(long)]
+        check: bool,
+        /// Split inline then-changes longer than this many characters.
+        #[arg(long, default_value_t = 100)]
+        max_width: usize,
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// Move a file or directory, rewriting every then-change in the tree which references it
+    /// (or anything under it) to match.
+    Mv {
+        /// Change nothing, and instead print the move and the rewritten then-changes as a
+        /// unified diff.
+        #[arg(long)]
+        dry_run: bool,
+        from: String,
+        to: String,
+    },
+    /// Report every if-change-then-change whose then-change targets do not have a matching
+    /// if-change-then-change pointing back at it.
+    Audit {
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// Print the graph of if-change-then-change blocks, with an edge for every then-change.
+    Graph {
+        /// Render the graph in Graphviz DOT format.
+        #[arg(long)]
+        dot: bool,
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// List every if-change-then-change block and its then-change targets, one per line.
+    List {
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// Report how many blocks there are and how much of the tree they cover.
+    Stats {
+        /// Print the stats as JSON.
+        #[arg(long)]
+        json: bool,
+        #[arg(default_value = ".")]
+        paths: Vec<String>,
+    },
+    /// Check that git, the repository, its config and hooks, and the parser are all in working
+    /// order, and explain how to fix anything that isn't.
+    Doctor,
+    /// Set up this repository: write a starter config, and optionally install a git hook and
+    /// an example pair of files.
+    Init {
+        /// Install this git hook (pre-commit or pre-push) to run the check.
+        #[arg(long, value_name = "HOOK", value_parser = hook::HOOKS)]
+        hook: Option<String>,
+        /// Write an example pair of files with if-change-then-change blocks pointing at each
+        /// other.
+        #[arg(long)]
+        example: bool,
+    },
+    /// Install git hooks which run the check. Existing hooks are kept, and run before the check.
+    #[command(group(clap::ArgGroup::new("hooks").required(true).multiple(true)))]
+    InstallHook {
+        /// Check the changes being committed.
+        #[arg(long, group = "hooks")]
+        pre_commit: bool,
+        /// Check the commits being pushed.
+        #[arg(long, group = "hooks")]
+        pre_push: bool,
+    },
+}
+
+fn read_stdin() -> String {
+    let mut input = Vec::new();
+
+    std::io::stdin()
+        .read_to_end(&mut input)
+        .expect("Failed to read stdin");
+
+    // Diffs of files in legacy encodings aren't valid UTF-8 either; see tree::decode.
+    String::from_utf8_lossy(&input).into_owned()
+}
+
+fn dump_parsed(paths: &[String]) -> Result<()> {
+    let parsed = paths
+        .iter()
+        .map(|path| match Tree::WorkingTree.read_lossy(path) {
+            Err(err) => json!({ "path": path, "error": err.to_string() }),
+            Ok((contents, _)) => match FileNode::from_str(path, &contents) {
+                Ok(file_node) => json!({ "path": path, "blocks": file_node.blocks }),
+                Err(err) => json!({ "path": path, "errors": err.diagnostics }),
+            },
         })
-        .collect::<HashMap<String, &unidiff::PatchedFile>>();
-
-    // To discover and parse all the if-change-then-change blocks relevant to this change, we do a
-    // BFS starting from every path present in the diff, and then move on to every then-change
-    // referenced in each file we read.
-    let file_nodes_by_path = {
-        let mut ret = HashMap::new();
-        let mut search = diffs_by_post_diff_path
-            .keys()
-            .map(|path| {
-                (
-                    Diagnostic {
-                        path: "stdin".to_string(),
-                        // TODO- for files we're reading because they were in the diff,
-                        //       start_line should be the line in the diff
-                        start_line: None,
-                        end_line: None,
-                        // TODO- read_to_string can fail for other reasons (e.g.
-                        // $path is a dir, $path does not allow reads)
-                        message: format!("diff references file that does not exist: '{}'", path),
-                    },
-                    path.clone(),
-                )
-            })
-            .collect::<VecDeque<(Diagnostic, String)>>();
+        .collect::<Vec<_>>();
 
-        loop {
-            let Some((diagnostic_if_read_fails, path)) = search.pop_front() else {
-                break;
-            };
+    println!("{}", serde_json::to_string_pretty(&parsed)?);
 
-            // $path entries come from one of two sources: either it is a path present in the input
-            // diffs, or it is a then-change path in one of the former paths. In the first case,
-            // this is where we do the file-exists validation; in the second case, we check
-            // `Path::exists` before attempting to read the file here.
-            let Ok(file_contents) = std::fs::read_to_string(&path) else {
-                // TODO- in what cases does the post-diff path not exist?
-                // TODO- if a file is deleted, the post-diff path is... /dev/null?
-                diagnostics.push(diagnostic_if_read_fails);
-                continue;
-            };
-            match if_change_then_change2::FileNode::from_str(&path, &file_contents) {
-                Err(error) => {
-                    diagnostics.extend(error.diagnostics);
-                }
-                Ok(mut file_node) => {
-                    for block in file_node.blocks.iter_mut() {
-                        block.then_change = block
-                            .then_change
-                            .drain(..)
-                            .filter(|(then_change_lineno, then_change_key)| {
-                                if diffs_by_post_diff_path.contains_key(&then_change_key.path) {
-                                    return true;
-                                }
-                                if block.key.path == then_change_key.path {
-                                    // We silently ignore self-referential then-change entries.
-                                    return false;
-                                }
-                                if then_change_key.path.is_empty() {
-                                    diagnostics.push(Diagnostic {
-                                        path: block.key.path.clone(),
-                                        start_line: Some(*then_change_lineno),
-                                        end_line: None,
-                                        message: "then-change does not reference a valid path".to_string(),
-                                    });
-                                    return false;
-                                }
-                                if !std::path::Path::new(&then_change_key.path).exists() {
-                                    diagnostics.push(Diagnostic {
-                                        path: block.key.path.clone(),
-                                        start_line: Some(*then_change_lineno),
-                                        end_line: None,
-                                        message: format!(
-                                            "then-change references file that does not exist: '{}'",
-                                            then_change_key.path
-                                        ),
-                                    });
-                                    return false;
-                                }
-                                if !ret.contains_key(&then_change_key.path) {
-                                    search.push_back((
-                                        Diagnostic {
-                                            path: block.key.path.clone(),
-                                            start_line: Some(*then_change_lineno),
-                                            end_line: None,
-                                            message: format!(
-                                                "then-change references file that could not be read: '{}'",
-                                                then_change_key.path
-                                            ),
-                                        },
-                                        then_change_key.path.clone(),
-                                    ));
-                                }
-                                true
-                            })
-                            .collect();
+    Ok(())
+}
+
+/// Prints `diagnostics`, after fixing what can be fixed if `fix` is set; fixed diagnostics are
+/// printed as what was done to fix them instead, or (for formats other than text) left out. If
+/// `dry_run` is set, only the diff of what would have been fixed is printed. Unfixed diagnostics
+/// in text are printed with their position in `position_format`, and followed by `context` lines
+/// of context, if set. Returns how many violations are left unfixed.
+fn print_fixed(
+    diagnostics: &[Diagnostic],
+    fix: bool,
+    dry_run: bool,
+    format: output::Format,
+    position_format: output::PositionFormat,
+    context: Option<usize>,
+    deny_warnings: bool,
+) -> Result<usize> {
+    let plan = if fix {
+        fix::apply_fixes(diagnostics, dry_run)?
+    } else {
+        Default::default()
+    };
+    let unfixed = diagnostics
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !plan.fixed.contains_key(i))
+        .map(|(_, diagnostic)| diagnostic.clone())
+        .collect::<Vec<_>>();
+    if dry_run {
+        print!("{}", plan.diff());
+    } else if format != output::Format::Text {
+        print!("{}", output::render(format, &unfixed));
+    } else {
+        for (i, diagnostic) in diagnostics.iter().enumerate() {
+            match plan.fixed.get(&i) {
+                Some(description) => println!(
+                    "{} - fixed: {}",
+                    diagnostic.location().position(),
+                    description
+                ),
+                None => {
+                    println!("{}", output::text_line(position_format, diagnostic));
+                    if let Some(lines) = context {
+                        let locations = [Some(diagnostic.location()), diagnostic.related.clone()];
+                        for location in locations.iter().flatten() {
+                            print!("{}", output::context(location, lines).unwrap_or_default());
+                        }
                     }
-                    ret.insert(path.clone(), file_node);
                 }
-            };
+            }
         }
+    }
+    Ok(unfixed
+        .iter()
+        .filter(|diagnostic| diagnostic.is_violation(deny_warnings))
+        .count())
+}
+
+/// The GitHub repository to post to: `repo` if given, otherwise the one GitHub Actions is
+/// running for.
+fn github_repo(repo: Option<String>) -> Result<String> {
+    match repo {
+        Some(repo) => Ok(repo),
+        None => std::env::var("GITHUB_REPOSITORY").map_err(|_| {
+            UsageError(anyhow!("--repo is required outside of GitHub Actions")).into()
+        }),
+    }
+}
+
+// Exit codes, so that scripts can tell a check which found violations from one which couldn't
+// run at all; see Cli.
+const EXIT_VIOLATIONS: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_INTERNAL: i32 = 3;
 
-        ret
+/// A problem with the arguments or input we were given, rather than with acting on them; only
+/// these exit with EXIT_USAGE.
+#[derive(Debug)]
+struct UsageError(anyhow::Error);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+/// Marks any error from `result` as a usage error.
+fn usage<T>(result: Result<T>) -> Result<T> {
+    result.map_err(|err| UsageError(err).into())
+}
+
+/// Like `bail!`, for a usage error.
+macro_rules! bail_usage {
+    ($($arg:tt)*) => {
+        return Err(UsageError(anyhow!($($arg)*)).into())
     };
+}
 
-    // Before we can generate diagnostics, we also need to know, for each
-    // if-change-then-change block, whether or not its contents were modified.
-    //
-    // for every ictc-block
-    //   find all intersecting patch hunks
-    //   for each intersecting patch hunk
-    //     check if the intersection in the ictc-block contains added/removed lines in the hunk
-    //     (hunks have both added/removed lines and also context lines)
-    //     if so, mark the block as "modified"
-    let modified_blocks_by_path = {
-        let mut modified_blocks_by_path = HashMap::new();
-
-        for (path, file_node) in file_nodes_by_path.iter() {
-            let Some(&diff) = diffs_by_post_diff_path.get(path) else {
-                continue;
-            };
+/// Parses the diff in `input`, which is a usage error if it can't be.
+fn parse_diff(input: impl std::io::BufRead) -> Result<check::Diff> {
+    usage(check::parse_diff(input))
+}
 
-            let mut modified_blocks = Vec::new();
-
-            for ictc_block in file_node.blocks.iter() {
-                let mut intersects_any_hunk = false;
-                for hunk in diff.hunks() {
-                    // TODO- we can skip hunks with no intersection
-                    let mut in_ictc_block = false;
-                    for line in hunk.lines() {
-                        // TODO- is this algo sound? are there ways that can break this approach w in_ictc_block?
-                        if let Some(lineno) = line.target_line_no {
-                            // target_line_no is 1-indexed
-                            in_ictc_block = ictc_block.content_range().contains(&(lineno - 1));
+/// Runs the command, and returns whether it found violations.
+fn run(mut cli: Cli) -> Result<bool> {
+    // The files we write stay relative to where we were run, even if we check from elsewhere.
+    for path in [&mut cli.metrics_file, &mut cli.cache_dir]
+        .into_iter()
+        .flatten()
+    {
+        *path = std::path::absolute(&*path)?;
+    }
+    if let Some(root) = &cli.root {
+        usage(
+            std::env::set_current_dir(root)
+                .with_context(|| format!("failed to change to --root {}", root.display())),
+        )?;
+    }
+    // A config or --only/--exclude pattern which can't be used is a usage error.
+    let options = || -> Result<Options> {
+        Ok(Options {
+            strict: cli.strict,
+            require_reciprocal: cli.require_reciprocal,
+            io_concurrency: cli.io_concurrency,
+            allow_missing_targets: cli.allow_missing_targets,
+            paths: usage(check::PathFilter::new(&cli.only, &cli.exclude))?,
+            ..usage(Options::load())?
+        })
+    };
+    let cache = || match &cli.cache_dir {
+        Some(dir) => ParseCache::on_disk(dir.clone()),
+        None => ParseCache::default(),
+    };
+    // Subcommands which report diagnostics elsewhere print them as a check would, and leave what
+    // they did with them for stderr, so that stdout has only the diagnostics. Returns whether any
+    // are violations.
+    let print_reported = |diagnostics: &[Diagnostic]| -> Result<bool> {
+        let violations = print_fixed(
+            diagnostics,
+            false,
+            false,
+            cli.format,
+            cli.position_format,
+            cli.context,
+            cli.deny_warnings,
+        )?;
+        Ok(violations > 0)
+    };
+    let mut violations = false;
+    match cli.command {
+        None => {
+            let started = Instant::now();
+            let staged = if cli.files.is_empty() {
+                None
+            } else {
+                let files = cli
+                    .files
+                    .iter()
+                    .map(|file| buck::source_path(file))
+                    .collect::<Vec<_>>();
+                let mut diff = git::staged_diff(&files)?;
+                if cli.include_untracked {
+                    diff += &git::untracked_diff(&files)?;
+                }
+                Some(diff)
+            };
+            let unfixed = match cli.connect {
+                Some(socket) => {
+                    let input = staged.unwrap_or_else(read_stdin);
+                    // Only plain text output is supported with a daemon.
+                    if cli.format != output::Format::Text {
+                        bail_usage!("--format is not supported with --connect");
+                    }
+                    if cli.position_format != output::PositionFormat::Range {
+                        bail_usage!("--position-format is not supported with --connect");
+                    }
+                    if cli.context.is_some() {
+                        bail_usage!("--context is not supported with --connect");
+                    }
+                    let diagnostics = daemon::request(&socket, &input)?;
+                    for diagnostic in &diagnostics {
+                        println!("{}", diagnostic);
+                    }
+                    diagnostics
+                        .iter()
+                        .filter(|diagnostic| diagnostic.is_violation(cli.deny_warnings))
+                        .count()
+                }
+                None => {
+                    // Diffs on stdin are parsed as they're read, rather than buffered first.
+                    let diff = match staged {
+                        Some(input) => parse_diff(input.as_bytes())?,
+                        None => parse_diff(std::io::stdin().lock())?,
+                    };
+                    if cli.root.is_none() {
+                        if let Some(root) = diff.root() {
+                            log::info!("checking from the repository root, {}", root.display());
+                            std::env::set_current_dir(&root)?;
+                        }
+                    }
+                    let mut cache = cache();
+                    let mut diagnostics = check::check_diff(&diff, &options()?, &mut cache)?;
+                    // Then-changes anywhere in the tree may reference a file the diff renames, not
+                    // just the ones the check reads.
+                    if cli.fix {
+                        diagnostics.extend(repo::renamed_targets(&diff.renames));
+                        diagnostics.sort();
+                        diagnostics.dedup();
+                    }
+                    let (files_scanned, blocks_parsed) = cache.counts();
+                    if let Some(path) = &cli.metrics_file {
+                        let metrics = metrics::Metrics {
+                            files_scanned,
+                            blocks_parsed,
+                            duration: started.elapsed(),
+                        };
+                        metrics.write(path, &diagnostics)?;
+                    }
+                    let mut summary = output::Summary {
+                        blocks: blocks_parsed,
+                        files: files_scanned,
+                        violations: diagnostics
+                            .iter()
+                            .filter(|diagnostic| diagnostic.is_violation(cli.deny_warnings))
+                            .count(),
+                    };
+                    if cli.summary_only {
+                        match output::render_summary(cli.format, &summary) {
+                            Some(rendered) => print!("{}", rendered),
+                            None => {
+                                bail_usage!("--summary-only is only supported with text and json")
+                            }
                         }
-                        if in_ictc_block && (line.is_added() || line.is_removed()) {
-                            intersects_any_hunk = true;
+                        summary.violations
+                    } else {
+                        summary.violations = print_fixed(
+                            &diagnostics,
+                            cli.fix,
+                            cli.dry_run,
+                            cli.format,
+                            cli.position_format,
+                            cli.context,
+                            cli.deny_warnings,
+                        )?;
+                        // The summary goes to stderr, so that stdout is left with one diagnostic
+                        // per line for anything parsing it.
+                        if cli.format == output::Format::Text && !cli.dry_run && !cli.quiet {
+                            eprintln!("{}", summary);
                         }
+                        summary.violations
                     }
                 }
-                if intersects_any_hunk {
-                    modified_blocks.push(ictc_block.clone());
+            };
+            if unfixed > 0 {
+                if !cli.files.is_empty() {
+                    log::error!("found problems in the staged changes");
                 }
-            }
-
-            if !modified_blocks.is_empty() {
-                modified_blocks_by_path.insert(
-                    path.clone(),
-                    if_change_then_change2::FileNode::new(modified_blocks),
-                );
+                violations = true;
             }
         }
-
-        modified_blocks_by_path
-    };
-
-    // Now that we know which if-change-then-change blocks have and have not been modified in the
-    // current diff, we can actually build diagnostics
-    //
-    // for every ictc-block
-    //   if the ifchange block is in the "modified block" set
-    //     for every thenchange block
-    //       if the thenchange block exists in the "modified block" set
-    //         do nothing
-    //       else
-    //         add diagnostic
-    for ictc_block in modified_blocks_by_path
-        .values()
-        .flat_map(|file_node| file_node.blocks.iter())
-    {
-        for (_, then_change_key) in ictc_block.then_change.iter() {
-            if let Some(then_change_file_node) = modified_blocks_by_path.get(&then_change_key.path)
-            {
-                if then_change_file_node
-                    .get_corresponding_block(ictc_block)
-                    .is_some()
-                {
-                    continue;
+        Some(Command::Tui) => tui::run(check::check_diff(
+            &parse_diff(read_stdin().as_bytes())?,
+            &options()?,
+            &mut cache(),
+        )?)?,
+        Some(Command::Daemon { socket }) => daemon::serve(&socket)?,
+        Some(Command::Serve { listen }) => server::serve(&listen)?,
+        Some(Command::GithubReview { pr, repo }) => {
+            let repo = github_repo(repo)?;
+            let input = read_stdin();
+            let diagnostics =
+                check::check_diff(&parse_diff(input.as_bytes())?, &options()?, &mut cache())?;
+            violations = print_reported(&diagnostics)?;
+            let posted = github::post(&repo, pr, &github::review(&input, &diagnostics)?)?;
+            eprintln!(
+                "posted {} review comments, resolved {} stale ones",
+                posted.comments, posted.resolved
+            );
+        }
+        Some(Command::GithubCheck { name, repo }) => {
+            let repo = github_repo(repo)?;
+            let diagnostics = check::check_diff(
+                &parse_diff(read_stdin().as_bytes())?,
+                &options()?,
+                &mut cache(),
+            )?;
+            violations = print_reported(&diagnostics)?;
+            eprintln!(
+                "created check run {}",
+                github::check_run(&repo, &name, &diagnostics)?
+            );
+        }
+        Some(Command::BitbucketReport) => {
+            let diagnostics = check::check_diff(
+                &parse_diff(read_stdin().as_bytes())?,
+                &options()?,
+                &mut cache(),
+            )?;
+            violations = print_reported(&diagnostics)?;
+            bitbucket::publish(&diagnostics)?;
+            eprintln!("published report");
+        }
+        Some(Command::History { since }) => {
+            for violation in history::history(&since, &options()?)? {
+                println!("{}", violation.commit);
+                violations |= violation
+                    .diagnostics
+                    .iter()
+                    .any(|diagnostic| diagnostic.is_violation(cli.deny_warnings));
+                for diagnostic in violation.diagnostics {
+                    println!("  {}", diagnostic);
                 }
             }
-
-            let mut block_range = None;
-            if let Some(ictc_blocks) = file_nodes_by_path.get(&then_change_key.path) {
-                if let Some(ictc_block) = ictc_blocks.get_corresponding_block(&ictc_block) {
-                    block_range = Some(ictc_block.content_range());
+        }
+        Some(Command::Compare { old, new }) => print!(
+            "{}",
+            baseline::compare(&usage(baseline::read(&old))?, &usage(baseline::read(&new))?)
+        ),
+        Some(Command::Parse { files }) => dump_parsed(&files)?,
+        Some(Command::Lint {
+            update_digests,
+            fix,
+            dry_run,
+            paths,
+        }) => {
+            if update_digests {
+                let (updated, diff) = repo::update_digests(&paths, dry_run)?;
+                if dry_run {
+                    print!("{}", diff);
+                } else {
+                    for location in updated {
+                        println!("updated hash for {}", location.position());
+                    }
                 }
             }
-            if block_range.is_none() {
-                diagnostics.push(Diagnostic {
-                    path: then_change_key.path.clone(),
-                    start_line: block_range.as_ref().map(|range| range.start),
-                    end_line: block_range.as_ref().map(|range| range.end),
-                    message: format!(
-                        "expected an if-change-then-change in this file that matches {}",
-                        DiagnosticPosition {
-                            path: &ictc_block.key.path,
-                            start_line: Some(ictc_block.content_range().start),
-                            end_line: Some(ictc_block.content_range().end),
-                        },
-                    ),
-                });
+            let unfixed = print_fixed(
+                &repo::lint(&paths),
+                fix,
+                dry_run,
+                cli.format,
+                cli.position_format,
+                cli.context,
+                cli.deny_warnings,
+            )?;
+            violations = unfixed > 0;
+        }
+        Some(Command::Migrate { write, paths }) => print!("{}", repo::migrate(&paths, write)?),
+        Some(Command::Fmt {
+            check,
+            max_width,
+            paths,
+        }) => {
+            let diff = repo::fmt(&paths, max_width, !check)?;
+            print!("{}", diff);
+            if check && !diff.is_empty() {
+                log::error!("directives are not formatted; run `to-be-named fmt` to fix them");
+                violations = true;
             }
-
-            if block_range.is_some() || !diffs_by_post_diff_path.contains_key(&then_change_key.path)
-            {
-                diagnostics.push(Diagnostic {
-                    path: then_change_key.path.clone(),
-                    start_line: block_range.as_ref().map(|range| range.start),
-                    end_line: block_range.as_ref().map(|range| range.end),
-                    message: format!(
-                        "expected change here due to change in {}",
-                        DiagnosticPosition {
-                            path: &ictc_block.key.path,
-                            start_line: Some(ictc_block.content_range().start),
-                            end_line: Some(ictc_block.content_range().end),
-                        },
-                    ),
-                });
+        }
+        Some(Command::Mv { dry_run, from, to }) => {
+            let (updated, diff) = repo::mv(&from, &to, dry_run)?;
+            if dry_run {
+                print!("{}", diff);
+                return Ok(false);
+            }
+            for location in updated {
+                println!("updated then-change at {}", location.position());
+            }
+            println!("moved {} to {}", from, to);
+        }
+        Some(Command::Audit { paths }) => {
+            let diagnostics = repo::audit(&paths);
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+            violations = diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.is_violation(cli.deny_warnings));
+        }
+        Some(Command::Graph { dot, paths }) => print!("{}", repo::graph(&paths, dot)),
+        Some(Command::List { paths }) => print!("{}", repo::list(&paths)),
+        Some(Command::Stats { json, paths }) => {
+            let stats = repo::stats(&paths);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                print!("{}", stats);
+            }
+        }
+        Some(Command::Doctor) => {
+            if !doctor::doctor() {
+                log::error!("doctor found problems");
+                violations = true;
+            }
+        }
+        Some(Command::Init { hook, example }) => init::init(hook.as_deref(), example)?,
+        Some(Command::InstallHook {
+            pre_commit,
+            pre_push,
+        }) => {
+            let hooks_dir = git::hooks_dir()?;
+            for (hook, enabled) in [("pre-commit", pre_commit), ("pre-push", pre_push)] {
+                if enabled {
+                    println!("installed {}", hook::install(&hooks_dir, hook)?.display());
+                }
             }
         }
     }
 
-    diagnostics.sort();
+    Ok(violations)
+}
 
-    for diagnostic in diagnostics {
-        println!("{}", diagnostic);
+/// Sends logs to stderr, so that they never end up mixed into diagnostics (e.g. `--format json`)
+/// on stdout. Without flags (or if they couldn't be parsed), $RUST_LOG is respected as usual.
+fn init_logging(cli: Option<&Cli>) {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.target(env_logger::Target::Stderr);
+    if let Some(cli) = cli {
+        if cli.quiet {
+            builder.filter_level(log::LevelFilter::Off);
+        } else if let Some(level) = cli.log_level {
+            builder.filter_level(level);
+        }
+        if cli.log_json {
+            builder.format(|buf, record| {
+                writeln!(
+                    buf,
+                    "{}",
+                    json!({
+                        "timestamp": buf.timestamp().to_string(),
+                        "level": record.level().as_str(),
+                        "target": record.target(),
+                        "message": record.args().to_string(),
+                    })
+                )
+            });
+        }
     }
-
-    Ok(())
+    builder.init();
 }
 
 fn main() {
-    env_logger::init();
+    let cli = buck::expand_argfiles(std::env::args_os()).map(Cli::parse_from);
+    init_logging(cli.as_ref().ok());
 
     log::info!("Starting to-be-named");
 
-    match run() {
-        Ok(_) => (),
+    let result = cli.map(|cli| std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run(cli))));
+    let code = match result {
+        Ok(Ok(Ok(false))) => 0,
+        Ok(Ok(Ok(true))) => EXIT_VIOLATIONS,
+        // Argfiles which can't be expanded are as much a usage error as bad arguments.
         Err(err) => {
             log::error!("{}", err);
-            std::process::exit(1);
+            EXIT_USAGE
         }
-    }
+        Ok(Ok(Err(err))) => {
+            log::error!("{}", err);
+            if err.is::<UsageError>() {
+                EXIT_USAGE
+            } else {
+                EXIT_INTERNAL
+            }
+        }
+        // The panic hook has already said what went wrong.
+        Ok(Err(_)) => EXIT_INTERNAL,
+    };
+    std::process::exit(code);
 }
@@ -0,0 +1,79 @@
+// Metrics about a run, written in the Prometheus text format so that e.g. node_exporter's
+// textfile collector can pick them up from CI machines.
+
+use crate::diagnostic::{Diagnostic, Severity};
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+pub struct Metrics {
+    pub files_scanned: usize,
+    pub blocks_parsed: usize,
+    pub duration: Duration,
+}
+
+impl Metrics {
+    /// Renders the metrics, with `diagnostics` counted by severity.
+    pub fn render(&self, diagnostics: &[Diagnostic]) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            // Writing to a String never fails.
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+
+        metric(
+            "ictc_files_scanned_total",
+            "counter",
+            "Files read and parsed for if-change-then-change blocks.",
+            &[("", self.files_scanned.to_string())],
+        );
+        metric(
+            "ictc_blocks_parsed_total",
+            "counter",
+            "If-change-then-change blocks parsed from the files scanned.",
+            &[("", self.blocks_parsed.to_string())],
+        );
+        let violations = [
+            (r#"{severity="error"}"#, Severity::Error),
+            (r#"{severity="warning"}"#, Severity::Warning),
+            (r#"{severity="info"}"#, Severity::Info),
+        ]
+        .map(|(labels, severity)| {
+            let count = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == severity)
+                .count();
+            (labels, count.to_string())
+        });
+        metric(
+            "ictc_violations_total",
+            "counter",
+            "Diagnostics reported, by severity.",
+            &violations,
+        );
+        metric(
+            "ictc_duration_seconds",
+            "gauge",
+            "How long the check took.",
+            &[("", format!("{:.3}", self.duration.as_secs_f64()))],
+        );
+
+        out
+    }
+
+    /// Writes the metrics to `path`. The file is replaced atomically, so that a collector never
+    /// reads it half-written.
+    pub fn write(&self, path: &Path, diagnostics: &[Diagnostic]) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.render(diagnostics))
+            .with_context(|| format!("failed to write metrics to {}", path.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed to write metrics to {}", path.display()))
+    }
+}
@@ -0,0 +1,218 @@
+// Machine-readable renderings of diagnostics, for tools which consume lint results in their own
+// format rather than parsing our human-readable output.
+
+use crate::diagnostic::{Diagnostic, Location, Severity};
+use crate::tree::Tree;
+use clap::ValueEnum;
+use serde_json::{json, Value};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One diagnostic per line, e.g. "b.sh:3-5 - change in a.sh:2-5 requires a change in ...".
+    #[default]
+    Text,
+    /// A JSON list of diagnostics, e.g. to save as a baseline for `compare`.
+    Json,
+    /// The native JSON format of the Jenkins warnings-ng plugin.
+    WarningsNg,
+    /// A JSON list of lint messages, as arcanist (`arc lint`) reads them from external linters.
+    Arc,
+}
+
+/// How text output spells where each diagnostic is, for whichever editor or CI system is reading
+/// it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PositionFormat {
+    /// The range of lines, e.g. "b.sh:3-5 - change in a.sh:2-5 requires ...".
+    #[default]
+    Range,
+    /// The first line, as the GNU coding standards (and e.g. Emacs' compilation-mode) expect,
+    /// e.g. "b.sh:3: change in a.sh:2-5 requires ...".
+    Gnu,
+    /// GitHub Actions workflow commands, which GitHub shows as annotations on the lines, e.g.
+    /// "::error file=b.sh,line=3,endLine=5::change in a.sh:2-5 requires ...".
+    Github,
+    /// The first line and column, as most editors' quickfix lists expect, e.g.
+    /// "b.sh:3:1: change in a.sh:2-5 requires ...".
+    LineCol,
+}
+
+/// The line of text output for `diagnostic`, with its position in `format`.
+pub fn text_line(format: PositionFormat, diagnostic: &Diagnostic) -> String {
+    let line = diagnostic.start_line.map(|start_line| start_line + 1);
+    let message = format!("{}{}", diagnostic.severity.prefix(), diagnostic.message);
+    match (format, line) {
+        (PositionFormat::Range, _) => diagnostic.to_string(),
+        (PositionFormat::Gnu, Some(line)) => format!("{}:{}: {}", diagnostic.path, line, message),
+        (PositionFormat::LineCol, Some(line)) => {
+            format!("{}:{}:1: {}", diagnostic.path, line, message)
+        }
+        (PositionFormat::Gnu | PositionFormat::LineCol, None) => {
+            format!("{}: {}", diagnostic.path, message)
+        }
+        (PositionFormat::Github, _) => {
+            let mut properties = format!("file={}", github_escape(&diagnostic.path, true));
+            if let Some(line) = line {
+                properties += &format!(
+                    ",line={},endLine={}",
+                    line,
+                    diagnostic.end_line.unwrap_or(line).max(line)
+                );
+            }
+            let command = match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+                Severity::Info => "notice",
+            };
+            format!(
+                "::{} {}::{}",
+                command,
+                properties,
+                github_escape(&diagnostic.message, false)
+            )
+        }
+    }
+}
+
+/// Escapes `value` for a workflow command, where properties also can't contain ':' or ','.
+fn github_escape(value: &str, property: bool) -> String {
+    let escaped = value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    if property {
+        escaped.replace(':', "%3A").replace(',', "%2C")
+    } else {
+        escaped
+    }
+}
+
+/// Renders `diagnostics` in `format`.
+pub fn render(format: Format, diagnostics: &[Diagnostic]) -> String {
+    match format {
+        Format::Text => diagnostics
+            .iter()
+            .map(|diagnostic| format!("{}\n", diagnostic))
+            .collect(),
+        Format::Json => pretty(json!(diagnostics)),
+        Format::WarningsNg => pretty(json!({
+            "_class": "io.jenkins.plugins.analysis.core.restapi.ReportApi",
+            "issues": diagnostics.iter().map(warnings_ng_issue).collect::<Vec<_>>(),
+        })),
+        Format::Arc => pretty(json!(diagnostics
+            .iter()
+            .map(arc_message)
+            .collect::<Vec<_>>())),
+    }
+}
+
+/// The lines at `location` in the working tree, with `lines` more on either side, numbered and
+/// with the location's own lines marked, e.g. to show alongside a diagnostic in the terminal.
+/// There's nothing to show for whole files, or files which can't be read.
+pub fn context(location: &Location, lines: usize) -> Option<String> {
+    let start_line = location.start_line?;
+    let end_line = location.end_line.unwrap_or(0).max(start_line + 1);
+    let contents = match Tree::WorkingTree.read_lossy(&location.path) {
+        Ok((contents, _)) => contents,
+        Err(err) => {
+            log::debug!("no context for {}: {}", location.path, err);
+            return None;
+        }
+    };
+    let file_lines = contents.lines().collect::<Vec<_>>();
+    let from = start_line.saturating_sub(lines).min(file_lines.len());
+    let to = (end_line + lines).min(file_lines.len());
+    let width = to.to_string().len();
+
+    let mut out = format!("  {}:\n", location.position());
+    for (i, line) in file_lines.iter().enumerate().take(to).skip(from) {
+        let marker = if (start_line..end_line).contains(&i) {
+            '>'
+        } else {
+            ' '
+        };
+        let numbered = format!("  {} {:>width$} | {}", marker, i + 1, line);
+        out += numbered.trim_end();
+        out.push('\n');
+    }
+    Some(out)
+}
+
+/// What a check covered and found, e.g. for dashboards which only need pass/fail and counts.
+pub struct Summary {
+    pub blocks: usize,
+    pub files: usize,
+    pub violations: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checked {} blocks across {} files: {} violations",
+            self.blocks, self.files, self.violations
+        )
+    }
+}
+
+/// Renders `summary` in `format`, if it has a rendering of summaries.
+pub fn render_summary(format: Format, summary: &Summary) -> Option<String> {
+    match format {
+        Format::Text => Some(format!("{}\n", summary)),
+        Format::Json => Some(pretty(json!({
+            "passed": summary.violations == 0,
+            "blocks": summary.blocks,
+            "files": summary.files,
+            "violations": summary.violations,
+        }))),
+        Format::WarningsNg | Format::Arc => None,
+    }
+}
+
+fn pretty(value: Value) -> String {
+    format!(
+        "{}\n",
+        serde_json::to_string_pretty(&value).expect("JSON values always serialize")
+    )
+}
+
+fn warnings_ng_issue(diagnostic: &Diagnostic) -> Value {
+    let mut issue = json!({
+        "fileName": diagnostic.path,
+        "severity": match diagnostic.severity {
+            Severity::Error => "ERROR",
+            Severity::Warning => "NORMAL",
+            Severity::Info => "LOW",
+        },
+        "message": diagnostic.message,
+        "category": "if-change-then-change",
+    });
+    // warnings-ng lines are 1-indexed and inclusive, which is what our exclusive end already is.
+    if let Some(start_line) = diagnostic.start_line {
+        issue["lineStart"] = json!(start_line + 1);
+        issue["lineEnd"] = json!(diagnostic.end_line.unwrap_or(start_line + 1));
+    }
+    issue
+}
+
+fn arc_message(diagnostic: &Diagnostic) -> Value {
+    let mut message = json!({
+        "path": diagnostic.path,
+        "code": "ICTC",
+        "name": "if-change-then-change",
+        "severity": match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Info => "advice",
+        },
+        "description": diagnostic.message,
+        // arc only shows messages on changed lines by default, but ours usually point at the
+        // lines that should have changed and did not.
+        "bypassChangedLineFiltering": true,
+    });
+    if let Some(start_line) = diagnostic.start_line {
+        message["line"] = json!(start_line + 1);
+    }
+    message
+}
@@ -0,0 +1,658 @@
+// Repo-wide analysis, i.e. everything that looks at all the if-change-then-change blocks in a
+// tree rather than just the ones relevant to a diff.
+
+use crate::check::{
+    attach_remove_fix, duplicate_target_warnings, expired_block_diagnostic, generated_file_info,
+    lossy_decoding_warning, merge_conflict_diagnostic, missing_block_message,
+    renamed_target_warning, stale_hash_diagnostic,
+};
+use crate::config::Config;
+use crate::date;
+use crate::diagnostic::{Diagnostic, Location};
+use crate::fix;
+use crate::if_change_then_change2::{
+    forward_slashes, normalize_path, Correspondence, FileNode, Granularity, ParseOptions,
+};
+use crate::suggest;
+use crate::tree::{self, Tree};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+/// Returns every file under `paths`, skipping anything ignored by git.
+///
+/// Paths are returned relative to the working directory (without a leading "./"), since that's
+/// what then-change directives are relative to.
+pub fn walk(paths: &[String]) -> Vec<String> {
+    let mut ret = Vec::new();
+
+    for path in paths {
+        for entry in ignore::WalkBuilder::new(path).build() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log::warn!("failed to walk {}: {}", path, err);
+                    continue;
+                }
+            };
+            if !entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_file())
+            {
+                continue;
+            }
+            // On Windows, the walk joins what's under "." with "\"; we only ever use "/".
+            let path = forward_slashes(&entry.path().to_string_lossy());
+            ret.push(path.strip_prefix("./").unwrap_or(&path).to_string());
+        }
+    }
+
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+/// The config in the working directory, if any. A config that fails to load is reported by
+/// `doctor`, so here we just fall back to the defaults.
+fn configured() -> Config {
+    match Config::load(Path::new(".")) {
+        Ok(config) => config.unwrap_or_default(),
+        Err(err) => {
+            log::warn!("ignoring config: {:#}", err);
+            Config::default()
+        }
+    }
+}
+
+fn configured_parse_options() -> ParseOptions {
+    configured().parse_options()
+}
+
+/// Parses every file under `paths`. Files that cannot be read (e.g. binaries) are skipped, and
+/// files that fail to parse have their errors returned instead, as are warnings about files which
+/// had to be decoded lossily and notes about generated files with directives.
+pub fn parse_tree(paths: &[String]) -> (BTreeMap<String, FileNode>, Vec<Diagnostic>) {
+    let parse_options = configured_parse_options();
+    let mut file_nodes = BTreeMap::new();
+    let mut errors = Vec::new();
+
+    for path in walk(paths) {
+        let Ok((contents, lossy)) = Tree::WorkingTree.read_lossy(&path) else {
+            log::debug!("skipping unreadable file: {}", path);
+            continue;
+        };
+        if lossy {
+            errors.push(lossy_decoding_warning(&path));
+        }
+        // Conflict markers only matter in files with directives; elsewhere, e.g. in docs about
+        // resolving conflicts, they may well be intended.
+        if parse_options.directive_lines(&contents).is_some() {
+            if let Some(diagnostic) = merge_conflict_diagnostic(&path, &contents) {
+                errors.push(diagnostic);
+                continue;
+            }
+        }
+        match FileNode::from_str_with_options(&path, &contents, &parse_options) {
+            Ok(file_node) => {
+                if file_node.generated && parse_options.may_contain_directives(&contents) {
+                    errors.push(generated_file_info(&path));
+                }
+                file_nodes.insert(path, file_node);
+            }
+            Err(err) => errors.extend(err.diagnostics),
+        }
+    }
+
+    (file_nodes, errors)
+}
+
+/// Reports every problem with the if-change-then-change blocks under `paths` that can be found
+/// without a diff: syntax errors, then-change paths (or named blocks, line ranges, or symbols)
+/// which do not exist, blocks whose then-change points at their own file, expired blocks, and
+/// blocks whose hash does not match their content.
+pub fn lint(paths: &[String]) -> Vec<Diagnostic> {
+    let (mut file_nodes, mut diagnostics) = parse_tree(paths);
+    let today = date::today();
+    // Every file in the tree, for suggesting what nonexistent then-change targets meant.
+    let mut all_files = None;
+
+    for block in file_nodes
+        .values_mut()
+        .flat_map(|file_node| file_node.blocks.iter_mut())
+    {
+        diagnostics.extend(duplicate_target_warnings(block));
+    }
+
+    for (path, file_node) in file_nodes.iter() {
+        if !file_node.blocks.iter().any(|block| block.hash.is_some()) {
+            continue;
+        }
+        let Ok((contents, _)) = Tree::WorkingTree.read_lossy(path) else {
+            continue;
+        };
+        diagnostics.extend(
+            file_node
+                .blocks
+                .iter()
+                .filter_map(|block| stale_hash_diagnostic(block, &contents)),
+        );
+    }
+
+    for block in file_nodes
+        .values()
+        .flat_map(|file_node| file_node.blocks.iter())
+    {
+        diagnostics.extend(expired_block_diagnostic(block, &today));
+
+        // Targets which do not exist, for --fix to remove, and where their diagnostics start.
+        let mut dangling = Vec::new();
+        let first_diagnostic = diagnostics.len();
+        for (then_change_lineno, then_change_key) in block.then_change.iter() {
+            let message = if then_change_key.is_url() {
+                continue;
+            } else if then_change_key.path.is_empty() {
+                "then-change does not reference a valid path".to_string()
+            } else if then_change_key.path == block.key.path {
+                format!(
+                    "then-change references the file it is in: '{}'",
+                    then_change_key.path
+                )
+            } else if let Some(Err(err)) = (!Path::new(&then_change_key.path).is_dir())
+                .then(|| Tree::WorkingTree.read_lossy(&then_change_key.path))
+            {
+                let mut message = tree::read_error_message(
+                    "then-change references file",
+                    &then_change_key.path,
+                    &err,
+                );
+                if err.kind() == std::io::ErrorKind::NotFound {
+                    // Targets which need not exist are fine either way.
+                    if then_change_key.may_not_exist {
+                        continue;
+                    }
+                    let files = all_files.get_or_insert_with(|| walk(&[".".to_string()]));
+                    message += &suggest::did_you_mean(&then_change_key.path, files);
+                    dangling.push(*then_change_lineno);
+                }
+                message
+            } else if then_change_key.name.as_ref().is_some_and(|name| {
+                file_nodes
+                    .get(&then_change_key.path)
+                    .is_some_and(|file_node| {
+                        !file_node.generated && file_node.get_block_by_name(name).is_none()
+                    })
+            }) {
+                format!(
+                    "then-change references block that does not exist: '{}'",
+                    then_change_key
+                )
+            } else if let Some(Err(message)) = Tree::WorkingTree
+                .read_lossy(&then_change_key.path)
+                .ok()
+                .and_then(|(contents, _)| then_change_key.resolve_lines(&contents))
+            {
+                message
+            } else {
+                continue;
+            };
+
+            diagnostics.push(Diagnostic {
+                path: block.key.path.clone(),
+                start_line: Some(*then_change_lineno),
+                end_line: None,
+                message,
+                ..Default::default()
+            });
+        }
+        attach_remove_fix(&mut diagnostics[first_diagnostic..], dangling);
+    }
+
+    diagnostics.sort();
+    // Regions of a discontiguous block share then-change lines, and so problems with them.
+    diagnostics.dedup();
+    diagnostics
+}
+
+/// Rewrites every stale hash under `paths` to match its block's current content, and returns the
+/// blocks whose hashes were updated.
+pub fn update_digests(paths: &[String], dry_run: bool) -> Result<(Vec<Location>, String)> {
+    let (file_nodes, _) = parse_tree(paths);
+    let mut updated = Vec::new();
+    let mut diff = String::new();
+
+    for (path, file_node) in file_nodes.iter() {
+        if !file_node.blocks.iter().any(|block| block.hash.is_some()) {
+            continue;
+        }
+        // Writing back lossily-decoded contents would mangle the file.
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            log::warn!(
+                "not updating hashes in {}, since it is not valid UTF-8",
+                path
+            );
+            continue;
+        };
+        let (new_contents, updated_blocks) = fix::update_digests(&contents, file_node);
+        if updated_blocks.is_empty() {
+            continue;
+        }
+        if dry_run {
+            diff += &fix::unified_diff(path, &contents, &fix::line_edits(&contents, &new_contents));
+        } else {
+            std::fs::write(path, new_contents)
+                .with_context(|| format!("failed to update hashes in {}", path))?;
+        }
+        updated.extend(updated_blocks.iter().map(|block| block.location()));
+    }
+
+    Ok((updated, diff))
+}
+
+/// Rewrites legacy markers under `paths` to our syntax (see fix::migrate), and returns the
+/// changes as a diff. Files are only rewritten if `write` is set.
+pub fn migrate(paths: &[String], write: bool) -> Result<String> {
+    let parse_options = configured_parse_options();
+    let mut diff = String::new();
+
+    for path in walk(paths) {
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let edits = fix::migrate(&contents, &parse_options.keywords);
+        if edits.is_empty() {
+            continue;
+        }
+        diff += &fix::unified_diff(&path, &contents, &edits);
+        if write {
+            std::fs::write(&path, fix::apply_edits(&contents, &edits))
+                .with_context(|| format!("failed to write {}", path))?;
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Formats the directives under `paths` (see fix::format), and returns the changes as a diff.
+/// Files are only rewritten if `write` is set.
+pub fn fmt(paths: &[String], max_width: usize, write: bool) -> Result<String> {
+    let parse_options = configured_parse_options();
+    let options = fix::FormatOptions {
+        parse_options: &parse_options,
+        max_width,
+    };
+    let mut diff = String::new();
+
+    for path in walk(paths) {
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            continue;
+        };
+        let edits = fix::format(&path, &contents, &options);
+        if edits.is_empty() {
+            continue;
+        }
+        diff += &fix::unified_diff(&path, &contents, &edits);
+        if write {
+            std::fs::write(&path, fix::apply_edits(&contents, &edits))
+                .with_context(|| format!("failed to write {}", path))?;
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Like parse_tree, but also parses the then-change targets of every block found under `paths`
+/// (which need not be under `paths` themselves). Returns the set of paths that were actually
+/// found under `paths` alongside the usual parse_tree results.
+pub fn parse_tree_and_targets(
+    paths: &[String],
+) -> (
+    BTreeMap<String, FileNode>,
+    BTreeSet<String>,
+    Vec<Diagnostic>,
+) {
+    let (mut file_nodes, mut diagnostics) = parse_tree(paths);
+    let walked_paths = file_nodes.keys().cloned().collect::<BTreeSet<_>>();
+    let parse_options = configured_parse_options();
+
+    let targets = file_nodes
+        .values()
+        .flat_map(|file_node| file_node.blocks.iter())
+        .flat_map(|block| block.then_change.iter())
+        .map(|(_, then_change_key)| then_change_key.path.clone())
+        .filter(|path| !file_nodes.contains_key(path))
+        .collect::<BTreeSet<_>>();
+    for path in targets {
+        let Ok((contents, _)) = Tree::WorkingTree.read_lossy(&path) else {
+            continue;
+        };
+        match FileNode::from_str_with_options(&path, &contents, &parse_options) {
+            Ok(file_node) => {
+                file_nodes.insert(path, file_node);
+            }
+            Err(err) => diagnostics.extend(err.diagnostics),
+        }
+    }
+
+    (file_nodes, walked_paths, diagnostics)
+}
+
+/// Reports every then-change in the tree which still references a path that `renames` (old path
+/// to new path) moves elsewhere, not just the ones in files a diff leads to, so that `--fix` can
+/// bring them all up to date.
+pub fn renamed_targets(renames: &HashMap<String, String>) -> Vec<Diagnostic> {
+    if renames.is_empty() {
+        return Vec::new();
+    }
+    let (file_nodes, _) = parse_tree(&[".".to_string()]);
+    file_nodes
+        .values()
+        .flat_map(|file_node| file_node.blocks.iter())
+        .flat_map(|block| {
+            block
+                .then_change
+                .iter()
+                .filter_map(|(then_change_lineno, then_change_key)| {
+                    let new_path = renames.get(&then_change_key.path)?;
+                    Some(renamed_target_warning(
+                        &block.key.path,
+                        *then_change_lineno,
+                        &then_change_key.path,
+                        new_path,
+                    ))
+                })
+        })
+        .collect()
+}
+
+/// Moves the file (or directory) `from` to `to`, after rewriting every then-change in the tree
+/// which references it (or, for a directory, anything under it) to match, and returns the
+/// then-changes which were rewritten. If `dry_run` is set, nothing is changed, and the move and
+/// rewrites are returned as a diff instead.
+pub fn mv(from: &str, to: &str, dry_run: bool) -> Result<(Vec<Location>, String)> {
+    let (from, to) = (normalize_path(from), normalize_path(to));
+    if !Path::new(&from).exists() {
+        bail!("cannot move '{}': no such file or directory", from);
+    }
+    if Path::new(&to).exists() {
+        bail!(
+            "cannot move '{}' to '{}': destination already exists",
+            from,
+            to
+        );
+    }
+
+    let mut renames = HashMap::from([(from.clone(), to.clone())]);
+    if Path::new(&from).is_dir() {
+        for path in walk(std::slice::from_ref(&from)) {
+            let new_path = format!("{}{}", to, &path[from.len()..]);
+            renames.insert(path, new_path);
+        }
+    }
+    let diagnostics = renamed_targets(&renames);
+    let plan = fix::apply_fixes(&diagnostics, dry_run)?;
+    let rewritten = plan
+        .fixed
+        .keys()
+        .map(|i| {
+            let location = diagnostics[*i].location();
+            // Files under a moved directory have moved along with it.
+            match renames.get(&location.path) {
+                Some(new_path) => Location {
+                    path: new_path.clone(),
+                    ..location
+                },
+                None => location,
+            }
+        })
+        .collect();
+
+    if dry_run {
+        let mut diff = String::new();
+        let moved_files = renames
+            .keys()
+            .filter(|path| !Path::new(path).is_dir())
+            .collect::<BTreeSet<_>>();
+        let paths = plan
+            .edits_by_path
+            .keys()
+            .chain(moved_files)
+            .collect::<BTreeSet<_>>();
+        for path in paths {
+            let new_path = renames.get(path).unwrap_or(path);
+            diff += &match plan.edits_by_path.get(path) {
+                Some((contents, edits)) => fix::rename_diff(path, new_path, contents, edits),
+                None => fix::rename_diff(path, new_path, "", &[]),
+            };
+        }
+        return Ok((rewritten, diff));
+    }
+
+    if let Some(parent) = Path::new(&to)
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    std::fs::rename(&from, &to)
+        .with_context(|| format!("failed to move '{}' to '{}'", from, to))?;
+
+    Ok((rewritten, String::new()))
+}
+
+/// Reports every block whose then-change target does not have a block pointing back at it.
+///
+/// One-directional links only enforce half of the coupling: a change to the source block
+/// requires a change to the target, but not the other way around.
+pub fn audit(paths: &[String]) -> Vec<Diagnostic> {
+    let (file_nodes, walked_paths, mut diagnostics) = parse_tree_and_targets(paths);
+    let config = configured();
+
+    for path in walked_paths.iter() {
+        for block in file_nodes[path].blocks.iter() {
+            for (_, then_change_key) in block.then_change.iter() {
+                // Line range and symbol targets are, by definition, in files without a block to
+                // point back.
+                if then_change_key.path == block.key.path || !then_change_key.expects_block() {
+                    continue;
+                }
+                // Targets with file granularity are satisfied by any change, so need no block either.
+                if config.granularity(block, &then_change_key.path) == Granularity::File {
+                    continue;
+                }
+                // Nonexistent and unparseable targets are reported by lint, not audit.
+                let Some(then_change_file_node) = file_nodes.get(&then_change_key.path) else {
+                    continue;
+                };
+                // Generated files can't point back, since they aren't parsed for blocks.
+                if then_change_file_node.generated {
+                    continue;
+                }
+                // Ambiguous correspondences are reported by check, which knows which blocks matter.
+                if then_change_file_node.correspondence(block, then_change_key)
+                    != Correspondence::None
+                {
+                    continue;
+                }
+
+                let origin = block.location();
+                diagnostics.push(Diagnostic {
+                    path: then_change_key.path.clone(),
+                    start_line: None,
+                    end_line: None,
+                    message: missing_block_message(then_change_key, &origin),
+                    related: Some(origin),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    diagnostics.sort();
+    diagnostics.dedup();
+    diagnostics
+}
+
+/// Renders the blocks under `paths` (and their then-change targets) as a graph, with one edge
+/// per then-change. Edges point at the target's corresponding block if it has one, and at the
+/// target itself (i.e. the file, named block, or line range) otherwise.
+///
+/// With `dot`, the graph is rendered in Graphviz format, with the blocks in each file grouped
+/// into a cluster; otherwise it's rendered as one "source -> target" line per edge.
+pub fn graph(paths: &[String], dot: bool) -> String {
+    let (file_nodes, _, errors) = parse_tree_and_targets(paths);
+    for error in errors {
+        log::warn!("{}", error);
+    }
+
+    let mut edges = Vec::new();
+    for block in file_nodes
+        .values()
+        .flat_map(|file_node| file_node.blocks.iter())
+    {
+        let source = block.location().position().to_string();
+        for (_, then_change_key) in block.then_change.iter() {
+            if then_change_key.path.is_empty() || then_change_key.path == block.key.path {
+                continue;
+            }
+            let target = match file_nodes
+                .get(&then_change_key.path)
+                .and_then(|file_node| file_node.get_corresponding_block(block, then_change_key))
+            {
+                Some(target_block) => target_block.location().position().to_string(),
+                None => then_change_key.to_string(),
+            };
+            edges.push((source.clone(), target));
+        }
+    }
+
+    if !dot {
+        return edges
+            .iter()
+            .map(|(source, target)| format!("{} -> {}\n", source, target))
+            .collect();
+    }
+
+    let quote = |id: &str| format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut ret = "digraph ictc {\n".to_string();
+    for (i, (path, file_node)) in file_nodes
+        .iter()
+        .filter(|(_, file_node)| !file_node.blocks.is_empty())
+        .enumerate()
+    {
+        ret += &format!("  subgraph cluster_{} {{\n    label={};\n", i, quote(path));
+        for block in file_node.blocks.iter() {
+            ret += &format!("    {};\n", quote(&block.location().position().to_string()));
+        }
+        ret += "  }\n";
+    }
+    for (source, target) in edges.iter() {
+        ret += &format!("  {} -> {};\n", quote(source), quote(target));
+    }
+    ret += "}\n";
+
+    ret
+}
+
+/// Lists every block under `paths` with each of its then-change targets, one per line, so that
+/// "what is coupled to this file?" can be answered with grep.
+pub fn list(paths: &[String]) -> String {
+    let (file_nodes, errors) = parse_tree(paths);
+    for error in errors {
+        log::warn!("{}", error);
+    }
+
+    let rows = file_nodes
+        .values()
+        .flat_map(|file_node| file_node.blocks.iter())
+        .flat_map(|block| {
+            let source = block.location().position().to_string();
+            block
+                .then_change
+                .iter()
+                .map(move |(_, then_change_key)| (source.clone(), then_change_key.to_string()))
+        })
+        .collect::<Vec<_>>();
+
+    let width = rows
+        .iter()
+        .map(|(source, _)| source.len())
+        .max()
+        .unwrap_or(0);
+    rows.iter()
+        .map(|(source, target)| format!("{:width$}  {}\n", source, target, width = width))
+        .collect()
+}
+
+/// Adoption metrics for the blocks in a tree; see `stats`.
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub files: usize,
+    pub files_with_blocks: usize,
+    pub blocks: usize,
+    pub then_change_targets: usize,
+    pub lines: usize,
+    pub lines_in_blocks: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Avoid NaNs when there's nothing to divide by.
+        let ratio = |n: usize, d: usize| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+
+        writeln!(f, "files scanned:        {}", self.files)?;
+        writeln!(
+            f,
+            "files with blocks:    {} ({:.1}%)",
+            self.files_with_blocks,
+            100.0 * ratio(self.files_with_blocks, self.files)
+        )?;
+        writeln!(f, "blocks:               {}", self.blocks)?;
+        writeln!(
+            f,
+            "then-change targets:  {} ({:.2} per block)",
+            self.then_change_targets,
+            ratio(self.then_change_targets, self.blocks)
+        )?;
+        writeln!(
+            f,
+            "lines in blocks:      {} of {} ({:.1}%)",
+            self.lines_in_blocks,
+            self.lines,
+            100.0 * ratio(self.lines_in_blocks, self.lines)
+        )
+    }
+}
+
+/// Computes adoption metrics for the if-change-then-change blocks under `paths`. Only readable
+/// text files count towards the totals, and files which fail to parse are treated as having no
+/// blocks.
+pub fn stats(paths: &[String]) -> Stats {
+    let parse_options = configured_parse_options();
+    let mut stats = Stats::default();
+
+    for path in walk(paths) {
+        let Ok((contents, _)) = Tree::WorkingTree.read_lossy(&path) else {
+            continue;
+        };
+        stats.files += 1;
+        stats.lines += contents.lines().count();
+
+        let Ok(file_node) = FileNode::from_str_with_options(&path, &contents, &parse_options)
+        else {
+            continue;
+        };
+        if !file_node.blocks.is_empty() {
+            stats.files_with_blocks += 1;
+        }
+        for block in file_node.blocks.iter() {
+            stats.blocks += 1;
+            stats.then_change_targets += block.then_change.len();
+            stats.lines_in_blocks += block.content_range().len();
+        }
+    }
+
+    stats
+}
@@ -0,0 +1,110 @@
+use crate::check::{self, Options, ParseCache};
+use crate::tree::Tree;
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Serves checks over HTTP until killed:
+///
+/// ```text
+/// POST /check[?base=<git revision>]
+/// ```
+///
+/// The request body is the diff to check, and the response is a JSON object with either a
+/// `diagnostics` list or an `error` string. Without `base`, files are read from the server's
+/// working tree (as with a normal invocation); with `base`, they're read from that revision of
+/// the server's repository, with the diff applied on top.
+pub fn serve(listen: &str) -> Result<()> {
+    // Allow the Go-style ":8080" shorthand for listening on all interfaces.
+    let addr = match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    };
+    let server =
+        Server::http(&addr).map_err(|err| anyhow!("failed to listen on {}: {}", addr, err))?;
+    log::info!("serving on {}", addr);
+
+    let options = Options::load()?;
+    let mut cache = ParseCache::resident();
+    for mut request in server.incoming_requests() {
+        let (status, body) = handle(&mut request, &options, &mut cache);
+        log::info!("{} {} -> {}", request.method(), request.url(), status);
+
+        let response = Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                    .expect("static header is valid"),
+            );
+        if let Err(err) = request.respond(response) {
+            log::warn!("failed to send response: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(
+    request: &mut Request,
+    options: &Options,
+    cache: &mut ParseCache,
+) -> (u16, serde_json::Value) {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    if path != "/check" {
+        return (
+            404,
+            json!({ "error": format!("no such endpoint: {}", path) }),
+        );
+    }
+    if *request.method() != Method::Post {
+        return (
+            405,
+            json!({ "error": "expected a POST with a diff as its body" }),
+        );
+    }
+
+    let mut options = options.clone();
+    for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+        if key == "base" {
+            let base = percent_decode(value);
+            // Refuse anything git might interpret as a flag.
+            if base.is_empty() || base.starts_with('-') {
+                return (400, json!({ "error": format!("invalid base: {:?}", base) }));
+            }
+            options.tree = Tree::GitRevision(base);
+        }
+    }
+
+    let mut input = Vec::new();
+    if let Err(err) = request.as_reader().read_to_end(&mut input) {
+        return (
+            400,
+            json!({ "error": format!("failed to read request body: {}", err) }),
+        );
+    }
+
+    let input = String::from_utf8_lossy(&input).into_owned();
+    match check::check(input, &options, cache) {
+        Ok(diagnostics) => (200, json!({ "diagnostics": diagnostics })),
+        Err(err) => (400, json!({ "error": format!("{:#}", err) })),
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut chars = s.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'%' => {
+                let hex = [chars.next(), chars.next()];
+                match hex.map(|ch| ch.and_then(|ch| (ch as char).to_digit(16))) {
+                    [Some(hi), Some(lo)] => bytes.push((hi * 16 + lo) as u8),
+                    _ => bytes.push(b'%'),
+                }
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,98 @@
+// "Did you mean ...?" suggestions for then-change paths which don't exist, which are usually typos
+// or stale after a file was moved.
+
+use std::path::Path;
+
+/// The Levenshtein distance between `a` and `b`, counting chars rather than bytes.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    // Distances from the prefix of `a` seen so far to every prefix of `b`.
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_ch) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b_ch) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(a_ch != *b_ch);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Suggests the path in `candidates` that `path` most likely meant: the nearest (by edit distance)
+/// of those with the same file name, which catches moved files, or of those within a few typos of
+/// `path`. Returns None if nothing is close enough to be worth suggesting.
+pub fn suggest_path<'a>(path: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let file_name = Path::new(path).file_name();
+    // Allow one typo per few characters, but always at least a couple.
+    let max_distance = (path.chars().count() / 5).max(2);
+
+    candidates
+        .iter()
+        .filter(|candidate| candidate.as_str() != path)
+        .filter_map(|candidate| {
+            let same_file_name =
+                file_name.is_some() && Path::new(candidate).file_name() == file_name;
+            // Cheap enough to rule out most of a large tree before computing any distances.
+            if !same_file_name && candidate.len().abs_diff(path.len()) > max_distance {
+                return None;
+            }
+            let distance = edit_distance(path, candidate);
+            (same_file_name || distance <= max_distance).then_some((distance, candidate.as_str()))
+        })
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+/// A suffix for a diagnostic about `path` not existing, suggesting what it meant (see
+/// suggest_path), or nothing if there's no good suggestion.
+pub fn did_you_mean(path: &str, candidates: &[String]) -> String {
+    match suggest_path(path, candidates) {
+        Some(suggestion) => format!("; did you mean '{}'?", suggestion),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::suggest::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn computes_edit_distance() -> anyhow::Result<()> {
+        assert_that!(edit_distance("", "")).is_equal_to(0);
+        assert_that!(edit_distance("a.sh", "a.sh")).is_equal_to(0);
+        assert_that!(edit_distance("a.sh", "b.sh")).is_equal_to(1);
+        assert_that!(edit_distance("a.sh", "a.bash")).is_equal_to(2);
+        assert_that!(edit_distance("kitten", "sitting")).is_equal_to(3);
+        assert_that!(edit_distance("café", "cafe")).is_equal_to(1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn suggests_paths() -> anyhow::Result<()> {
+        let candidates = [
+            "tests/data/a.sh",
+            "tests/data/b.sh",
+            "src/config/settings.rs",
+            "docs/settings.md",
+        ]
+        .map(str::to_string);
+
+        // Typos...
+        assert_that!(suggest_path("tests/data/a.shh", &candidates))
+            .is_equal_to(Some("tests/data/a.sh"));
+        assert_that!(suggest_path("test/data/b.sh", &candidates))
+            .is_equal_to(Some("tests/data/b.sh"));
+        // ...moved files...
+        assert_that!(suggest_path("src/settings.rs", &candidates))
+            .is_equal_to(Some("src/config/settings.rs"));
+        // ...and nothing close enough.
+        assert_that!(suggest_path("lib/util.py", &candidates)).is_none();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+// Resolution of "then-change path@symbol" targets, e.g. "then-change src/api.rs@fn handle_request",
+// to the lines the symbol currently spans. Resolving at check time (rather than writing down line
+// numbers) means the directive survives unrelated churn in the target file.
+
+use std::ops::Range;
+
+/// Whether `symbol` looks like a symbol target: an identifier, optionally preceded by the kind
+/// of item it names, e.g. "handle_request" or "fn handle_request".
+pub fn is_valid_symbol(symbol: &str) -> bool {
+    let is_identifier =
+        |s: &str| !s.is_empty() && s.chars().all(|ch| ch.is_alphanumeric() || ch == '_');
+
+    match symbol.split_once(' ') {
+        Some((kind, name)) => is_identifier(kind) && is_identifier(name),
+        None => is_identifier(symbol),
+    }
+}
+
+/// Returns the lines (0-indexed, inclusive-exclusive) spanned by `symbol` in `contents`, which
+/// are the contents of `path`.
+#[cfg(feature = "symbols")]
+pub fn resolve(path: &str, contents: &str, symbol: &str) -> Result<Range<usize>, String> {
+    let (kind, name) = match symbol.split_once(' ') {
+        Some((kind, name)) => (Some(kind), name),
+        None => (None, symbol),
+    };
+
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+    // Maps the kinds users write in directives to the node kinds of each grammar.
+    let (language, node_kinds): (tree_sitter::Language, &[(&str, &str)]) = match extension {
+        "rs" => (
+            tree_sitter_rust::LANGUAGE.into(),
+            &[
+                ("fn", "function_item"),
+                ("fn", "function_signature_item"),
+                ("struct", "struct_item"),
+                ("enum", "enum_item"),
+                ("union", "union_item"),
+                ("trait", "trait_item"),
+                ("impl", "impl_item"),
+                ("mod", "mod_item"),
+                ("const", "const_item"),
+                ("static", "static_item"),
+                ("type", "type_item"),
+                ("macro", "macro_definition"),
+            ],
+        ),
+        "py" => (
+            tree_sitter_python::LANGUAGE.into(),
+            &[
+                ("def", "function_definition"),
+                ("class", "class_definition"),
+            ],
+        ),
+        "js" | "jsx" | "mjs" | "cjs" => (
+            tree_sitter_javascript::LANGUAGE.into(),
+            &[
+                ("function", "function_declaration"),
+                ("function", "generator_function_declaration"),
+                ("class", "class_declaration"),
+                ("method", "method_definition"),
+            ],
+        ),
+        _ => return Err(format!("symbol targets are not supported for '{}'", path)),
+    };
+    if let Some(kind) = kind {
+        if !node_kinds.iter().any(|(k, _)| *k == kind) {
+            return Err(format!(
+                "'{}' is not a kind of symbol in '{}' (expected one of: {})",
+                kind,
+                path,
+                node_kinds
+                    .iter()
+                    .map(|(k, _)| *k)
+                    .collect::<std::collections::BTreeSet<_>>()
+                    .into_iter()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+    }
+
+    let mut parser = tree_sitter::Parser::new();
+    parser
+        .set_language(&language)
+        .map_err(|err| format!("failed to load grammar for '{}': {}", path, err))?;
+    let tree = parser
+        .parse(contents, None)
+        .ok_or_else(|| format!("failed to parse '{}'", path))?;
+
+    // Depth-first, so that the outermost match wins (e.g. a struct over a field of the same name).
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        let matches_kind = node_kinds
+            .iter()
+            .any(|(k, node_kind)| node.kind() == *node_kind && kind.is_none_or(|kind| kind == *k));
+        if matches_kind {
+            // impl blocks are named by the type they're for.
+            let name_node = node
+                .child_by_field_name("name")
+                .or_else(|| node.child_by_field_name("type"));
+            if name_node.and_then(|n| n.utf8_text(contents.as_bytes()).ok()) == Some(name) {
+                return Ok(node.start_position().row..node.end_position().row + 1);
+            }
+        }
+        let mut cursor = node.walk();
+        let children = node.children(&mut cursor).collect::<Vec<_>>();
+        stack.extend(children.into_iter().rev());
+    }
+
+    Err(format!(
+        "then-change references symbol that does not exist: '{}@{}'",
+        path, symbol
+    ))
+}
+
+#[cfg(not(feature = "symbols"))]
+pub fn resolve(_path: &str, _contents: &str, _symbol: &str) -> Result<Range<usize>, String> {
+    Err("then-change symbol targets require building with the `symbols` feature".to_string())
+}
+
+#[cfg(all(test, feature = "symbols"))]
+mod test {
+    use crate::symbol::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn resolves_rust_symbols() -> anyhow::Result<()> {
+        let contents = "\
+use std::fmt;
+
+struct Request {
+    path: String,
+}
+
+impl Request {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+fn handle_request(request: Request) {
+    println!(\"{}\", request.path());
+}
+";
+        assert_that!(resolve("api.rs", contents, "fn handle_request")).is_equal_to(Ok(12..15));
+        assert_that!(resolve("api.rs", contents, "struct Request")).is_equal_to(Ok(2..5));
+        assert_that!(resolve("api.rs", contents, "impl Request")).is_equal_to(Ok(6..11));
+        assert_that!(resolve("api.rs", contents, "path")).is_equal_to(Ok(7..10));
+        assert_that!(resolve("api.rs", contents, "fn missing")).is_err();
+        assert_that!(resolve("api.rs", contents, "def handle_request")).is_err();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+use std::io;
+use std::process::Command;
+
+/// Files at least this large are memory-mapped rather than read; see `Tree::read_contents`.
+const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// The contents of a file, which are valid UTF-8 either way.
+pub enum Contents {
+    Owned(String),
+    Mapped(memmap2::Mmap),
+}
+
+impl std::ops::Deref for Contents {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            Contents::Owned(contents) => contents,
+            // SAFETY: mapped contents are only constructed after checking they are UTF-8.
+            Contents::Mapped(map) => unsafe { std::str::from_utf8_unchecked(map) },
+        }
+    }
+}
+
+/// Where file contents are read from when checking a diff.
+#[derive(Clone, Debug, Default)]
+pub enum Tree {
+    /// The working tree, which is assumed to already contain the changes in the diff.
+    #[default]
+    WorkingTree,
+    /// A git revision which the diff has _not_ been applied to, e.g. the base of a PR. Files
+    /// touched by the diff are reconstructed by applying the diff to their contents at this
+    /// revision; see `apply`.
+    GitRevision(String),
+}
+
+impl Tree {
+    pub fn read_to_string(&self, path: &str) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Like read_to_string, but files which aren't valid UTF-8 are decoded lossily (see
+    /// `decode`) rather than failing; the second element says whether that happened.
+    pub fn read_lossy(&self, path: &str) -> io::Result<(String, bool)> {
+        decode(self.read(path)?)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "binary file"))
+    }
+
+    /// Like read_lossy, but large working tree files are memory-mapped rather than read into
+    /// memory, so that only the parts we actually look at (often just a couple of blocks, or
+    /// nothing at all) are ever paged in.
+    pub fn read_contents(&self, path: &str) -> io::Result<(Contents, bool)> {
+        if let Tree::WorkingTree = self {
+            // Opening a fifo would block, so we check what it is first; see `read`.
+            let metadata = std::fs::metadata(path)?;
+            if metadata.is_file() && metadata.len() >= MMAP_THRESHOLD {
+                let file = std::fs::File::open(path)?;
+                // SAFETY: the file could be modified while it's mapped, just as it could between
+                // being read and being parsed; either way we'd report on a stale view of it.
+                // Unlike a read, a truncation could fault, but we accept that for a linter.
+                let map = unsafe { memmap2::Mmap::map(&file)? };
+                if memchr::memchr(0, &map).is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "binary file"));
+                }
+                return match std::str::from_utf8(&map) {
+                    Ok(_) => Ok((Contents::Mapped(map), false)),
+                    // Lossy decoding needs a copy anyway.
+                    Err(_) => Ok((
+                        Contents::Owned(String::from_utf8_lossy(&map).into_owned()),
+                        true,
+                    )),
+                };
+            }
+        }
+        self.read_lossy(path)
+            .map(|(contents, lossy)| (Contents::Owned(contents), lossy))
+    }
+
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Tree::WorkingTree => {
+                // Reading a fifo would block until something writes to it, so we refuse anything
+                // that isn't a regular file. Directories are reported as such up front, because
+                // Windows fails to read them with "permission denied" instead.
+                let file_type = std::fs::metadata(path)?.file_type();
+                if file_type.is_dir() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::IsADirectory,
+                        "is a directory",
+                    ));
+                }
+                if !file_type.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "not a regular file",
+                    ));
+                }
+                std::fs::read(path)
+            }
+            Tree::GitRevision(rev) => {
+                let output = Command::new("git")
+                    .arg("show")
+                    .arg(format!("{}:{}", rev, path))
+                    .output()?;
+                if !output.status.success() {
+                    return Err(git_read_error(&output.stderr));
+                }
+                Ok(output.stdout)
+            }
+        }
+    }
+
+    /// The size of `path` in bytes, without reading it.
+    pub fn size(&self, path: &str) -> io::Result<u64> {
+        match self {
+            Tree::WorkingTree => std::fs::metadata(path).map(|metadata| metadata.len()),
+            Tree::GitRevision(rev) => {
+                let output = Command::new("git")
+                    .args(["cat-file", "-s"])
+                    .arg(format!("{}:{}", rev, path))
+                    .output()?;
+                if !output.status.success() {
+                    return Err(git_read_error(&output.stderr));
+                }
+                String::from_utf8_lossy(&output.stdout)
+                    .trim()
+                    .parse()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+            }
+        }
+    }
+
+    /// Whether `path` exists; errs if we can't tell, e.g. because a parent directory of `path`
+    /// can't be searched.
+    pub fn exists(&self, path: &str) -> io::Result<bool> {
+        match self {
+            Tree::WorkingTree => std::path::Path::new(path).try_exists(),
+            Tree::GitRevision(rev) => Ok(Command::new("git")
+                .args(["cat-file", "-e"])
+                .arg(format!("{}:{}", rev, path))
+                .output()
+                .is_ok_and(|output| output.status.success())),
+        }
+    }
+
+    /// Every file in the tree, e.g. for suggesting what a path which doesn't exist meant. Files
+    /// ignored by git are skipped.
+    pub fn files(&self) -> io::Result<Vec<String>> {
+        match self {
+            Tree::WorkingTree => Ok(crate::repo::walk(&[".".to_string()])),
+            Tree::GitRevision(rev) => {
+                let output = Command::new("git")
+                    .args(["ls-tree", "-r", "--name-only"])
+                    .arg(rev)
+                    .output()?;
+                if !output.status.success() {
+                    return Err(io::Error::other(
+                        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                    ));
+                }
+                Ok(String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(str::to_string)
+                    .collect())
+            }
+        }
+    }
+
+    pub fn is_dir(&self, path: &str) -> bool {
+        match self {
+            Tree::WorkingTree => std::path::Path::new(path).is_dir(),
+            Tree::GitRevision(rev) => Command::new("git")
+                .args(["cat-file", "-t"])
+                .arg(format!("{}:{}", rev, path))
+                .output()
+                .is_ok_and(|output| output.status.success() && output.stdout == b"tree\n"),
+        }
+    }
+}
+
+/// The error for git failing to read a file from a revision, given its stderr. Usually that's
+/// because the file doesn't exist there, but in a partial clone, the file's contents may not have
+/// been fetched, and fetching them on demand can fail too (e.g. when offline).
+fn git_read_error(stderr: &[u8]) -> io::Error {
+    let message = String::from_utf8_lossy(stderr).trim().to_string();
+    let kind = if message.contains("promisor") || message.contains("could not fetch") {
+        io::ErrorKind::Other
+    } else {
+        io::ErrorKind::NotFound
+    };
+    io::Error::new(kind, message)
+}
+
+/// Decodes the contents of a file which may not be UTF-8, e.g. Latin-1 or Shift-JIS sources,
+/// replacing invalid sequences with U+FFFD. Newlines are ASCII in every encoding this is likely
+/// to see, so line numbers are unaffected. Returns None for binary files (i.e. ones containing
+/// NUL, as git decides), and otherwise whether any bytes had to be replaced.
+pub fn decode(bytes: Vec<u8>) -> Option<(String, bool)> {
+    if bytes.contains(&0) {
+        return None;
+    }
+    match String::from_utf8(bytes) {
+        Ok(contents) => Some((contents, false)),
+        Err(err) => Some((String::from_utf8_lossy(err.as_bytes()).into_owned(), true)),
+    }
+}
+
+/// The message for a diagnostic about `path` not being readable, where `subject` describes how
+/// we came to read it, e.g. "then-change references file that is a directory: 'foo'" for a
+/// `subject` of "then-change references file".
+pub fn read_error_message(subject: &str, path: &str, err: &io::Error) -> String {
+    // Permission problems are the one case where the path is fine as written, so we say so.
+    if err.kind() == io::ErrorKind::PermissionDenied {
+        return format!("cannot read '{}': permission denied", path);
+    }
+    format!("{} that {}: '{}'", subject, describe_read_error(err), path)
+}
+
+/// Describes why a file could not be read, e.g. "is a directory".
+fn describe_read_error(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::NotFound => "does not exist".to_string(),
+        io::ErrorKind::IsADirectory => "is a directory".to_string(),
+        io::ErrorKind::Unsupported => "is not a regular file".to_string(),
+        io::ErrorKind::InvalidData => "is a binary file".to_string(),
+        io::ErrorKind::FileTooLarge | io::ErrorKind::OutOfMemory => {
+            "is too large to read".to_string()
+        }
+        _ => format!("could not be read ({})", err),
+    }
+}
+
+/// Applies the hunks in `diff` to `base`, returning the post-diff contents of the file.
+///
+/// We trust the diff: context and removed lines are not checked against `base`, since the only
+/// thing we need the result for is finding if-change-then-change blocks and their line numbers.
+pub fn apply(base: &str, diff: &unidiff::PatchedFile) -> String {
+    let base_lines = base.lines().collect::<Vec<_>>();
+    let mut ret = Vec::new();
+    // 0-indexed position in base_lines of the next line that has not been copied into ret
+    let mut next_base_line = 0;
+
+    for hunk in diff.hunks() {
+        // source_start is 1-indexed, except for pure insertions, where it is the line _after_
+        // which the new lines are inserted (and 0 when inserting at the top of the file)
+        let hunk_start = if hunk.source_length == 0 {
+            hunk.source_start
+        } else {
+            hunk.source_start - 1
+        };
+        while next_base_line < hunk_start && next_base_line < base_lines.len() {
+            ret.push(base_lines[next_base_line]);
+            next_base_line += 1;
+        }
+        for line in hunk.lines() {
+            if line.is_added() {
+                ret.push(line.value.as_str());
+            } else if line.is_removed() {
+                next_base_line += 1;
+            } else if line.is_context() {
+                ret.push(line.value.as_str());
+                next_base_line += 1;
+            }
+        }
+    }
+    ret.extend(base_lines.iter().skip(next_base_line));
+
+    let mut contents = ret.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    contents
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tree::*;
+    use spectral::prelude::*;
+    use test_log::test;
+
+    #[test]
+    fn apply_reconstructs_post_diff_contents() -> anyhow::Result<()> {
+        let mut patch_set = unidiff::PatchSet::new();
+        patch_set.parse(
+            "\
+--- a/foo.sh
++++ b/foo.sh
+@@ -2,3 +2,3 @@
+ two
+-three
++THREE
+ four
+@@ -7,2 +7,3 @@
+ seven
++seven and a half
+ eight
+@@ -9,0 +10,1 @@
++ten
+",
+        )?;
+
+        let base = "one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+        assert_that!(apply(base, &patch_set.files()[0]).as_str()).is_equal_to(
+            "one\ntwo\nTHREE\nfour\nfive\nsix\nseven\nseven and a half\neight\nnine\nten\n",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn apply_reconstructs_new_file() -> anyhow::Result<()> {
+        let mut patch_set = unidiff::PatchSet::new();
+        patch_set.parse(
+            "\
+--- /dev/null
++++ b/foo.sh
+@@ -0,0 +1,2 @@
++one
++two
+",
+        )?;
+
+        assert_that!(apply("", &patch_set.files()[0]).as_str()).is_equal_to("one\ntwo\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn read_errors_are_classified() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("ictc-tree-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        std::fs::write(path("binary"), b"\x7fELF\x02\x01\x01\x00")?;
+        std::fs::write(path("latin1"), b"# caf\xe9\n")?;
+        // Windows has no fifos.
+        #[cfg(unix)]
+        {
+            let status = std::process::Command::new("mkfifo")
+                .arg(path("fifo"))
+                .status()?;
+            assert_that!(status.success()).is_true();
+        }
+
+        let describe = |name: &str| {
+            Tree::WorkingTree
+                .read_lossy(&path(name))
+                .map_err(|err| describe_read_error(&err))
+        };
+        assert_that!(Tree::WorkingTree.exists(&path("missing"))?).is_false();
+        assert_that!(describe("missing")).is_equal_to(Err("does not exist".to_string()));
+        assert_that!(describe("")).is_equal_to(Err("is a directory".to_string()));
+        assert_that!(describe("binary")).is_equal_to(Err("is a binary file".to_string()));
+        assert_that!(describe("latin1")).is_equal_to(Ok(("# caf\u{fffd}\n".to_string(), true)));
+        #[cfg(unix)]
+        assert_that!(describe("fifo")).is_equal_to(Err("is not a regular file".to_string()));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn large_files_are_mapped() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("ictc-mmap-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let large = "x\n".repeat(MMAP_THRESHOLD as usize);
+        std::fs::write(path("small"), "x\n")?;
+        std::fs::write(path("large"), &large)?;
+        let mut latin1 = large.clone().into_bytes();
+        latin1.extend(b"# caf\xe9\n");
+        std::fs::write(path("large-latin1"), &latin1)?;
+
+        let read = |name: &str| Tree::WorkingTree.read_contents(&path(name));
+        assert_that!(matches!(read("small")?, (Contents::Owned(_), false))).is_true();
+        let (contents, lossy) = read("large")?;
+        assert_that!(matches!(contents, Contents::Mapped(_))).is_true();
+        assert_that!(&*contents == large).is_true();
+        assert_that!(lossy).is_false();
+        let (contents, lossy) = read("large-latin1")?;
+        assert_that!(contents.ends_with("# caf\u{fffd}\n")).is_true();
+        assert_that!(lossy).is_true();
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn permission_denied_is_not_reported_as_missing() -> anyhow::Result<()> {
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert_that!(
+            read_error_message("then-change references file", "secret.txt", &err).as_str()
+        )
+        .is_equal_to("cannot read 'secret.txt': permission denied");
+
+        let err = io::Error::from(io::ErrorKind::NotFound);
+        assert_that!(read_error_message("diff references file", "gone.txt", &err).as_str())
+            .is_equal_to("diff references file that does not exist: 'gone.txt'");
+
+        Ok(())
+    }
+}
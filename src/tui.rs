@@ -0,0 +1,289 @@
+use crate::diagnostic::{Diagnostic, Location};
+use crate::fix;
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashSet;
+
+// How many lines of surrounding code to show around a block in the side-by-side panes.
+const EXCERPT_CONTEXT_LINES: usize = 3;
+
+const HELP: &str = " j/k: move  a: acknowledge  e: open in $EDITOR  f: apply fix  q: quit ";
+
+struct App {
+    diagnostics: Vec<Diagnostic>,
+    acknowledged: HashSet<usize>,
+    // Files we've applied fixes to, whose other diagnostics' fixes may no longer line up.
+    fixed_paths: HashSet<String>,
+    list_state: ListState,
+    status: String,
+}
+
+impl App {
+    fn new(diagnostics: Vec<Diagnostic>) -> App {
+        let mut list_state = ListState::default();
+        if !diagnostics.is_empty() {
+            list_state.select(Some(0));
+        }
+
+        App {
+            status: format!("{} diagnostics", diagnostics.len()),
+            diagnostics,
+            acknowledged: HashSet::new(),
+            fixed_paths: HashSet::new(),
+            list_state,
+        }
+    }
+
+    fn selected(&self) -> Option<&Diagnostic> {
+        self.list_state
+            .selected()
+            .and_then(|i| self.diagnostics.get(i))
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.render(frame))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => break,
+                KeyCode::Char('j') | KeyCode::Down => self.list_state.select_next(),
+                KeyCode::Char('k') | KeyCode::Up => self.list_state.select_previous(),
+                KeyCode::Char('a') | KeyCode::Char(' ') => self.toggle_acknowledged(),
+                KeyCode::Char('e') | KeyCode::Enter => self.open_in_editor(terminal)?,
+                KeyCode::Char('f') => self.apply_fix(),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn toggle_acknowledged(&mut self) {
+        let Some(i) = self.list_state.selected() else {
+            return;
+        };
+        if !self.acknowledged.remove(&i) {
+            self.acknowledged.insert(i);
+        }
+        self.status = format!(
+            "{} of {} diagnostics acknowledged",
+            self.acknowledged.len(),
+            self.diagnostics.len()
+        );
+    }
+
+    fn open_in_editor(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        let Some(diagnostic) = self.selected().cloned() else {
+            return Ok(());
+        };
+        if diagnostic.path == "stdin" {
+            self.status = "diagnostic does not point at a file".to_string();
+            return Ok(());
+        }
+
+        let editor = std::env::var("VISUAL")
+            .or_else(|_| std::env::var("EDITOR"))
+            .unwrap_or_else(|_| "vi".to_string());
+        let mut editor_args = editor.split_whitespace();
+        let Some(editor_bin) = editor_args.next() else {
+            self.status = "$EDITOR is empty".to_string();
+            return Ok(());
+        };
+        let mut command = std::process::Command::new(editor_bin);
+        command
+            .args(editor_args)
+            .arg(format!("+{}", diagnostic.start_line.unwrap_or(0) + 1))
+            .arg(&diagnostic.path);
+
+        ratatui::restore();
+        let status = command.status();
+        *terminal = ratatui::init();
+
+        self.status = match status {
+            Ok(status) if status.success() => format!("edited {}", diagnostic_path(&diagnostic)),
+            Ok(status) => format!("{} exited with {}", editor_bin, status),
+            Err(err) => format!("failed to run {}: {}", editor_bin, err),
+        };
+        Ok(())
+    }
+
+    /// Applies the selected diagnostic's fix, as `--fix` would.
+    fn apply_fix(&mut self) {
+        let Some(diagnostic) = self.selected() else {
+            return;
+        };
+        let Some(selected_fix) = diagnostic.fix.clone() else {
+            self.status = "no fix available for this diagnostic".to_string();
+            return;
+        };
+        let path = diagnostic.path.clone();
+        if self.fixed_paths.contains(&path) {
+            self.status = format!(
+                "{} has already been fixed; re-run the check to fix more of it",
+                path
+            );
+            return;
+        }
+
+        // The same fix may be attached to more than one diagnostic, e.g. every diagnostic about
+        // the same missing block; they're resolved together.
+        let (indices, diagnostics): (Vec<usize>, Vec<Diagnostic>) = self
+            .diagnostics
+            .iter()
+            .enumerate()
+            .filter(|(_, diagnostic)| {
+                diagnostic.path == path && diagnostic.fix.as_ref() == Some(&selected_fix)
+            })
+            .map(|(i, diagnostic)| (i, diagnostic.clone()))
+            .unzip();
+        let plan = match fix::apply_fixes(&diagnostics, false) {
+            Ok(plan) => plan,
+            Err(err) => {
+                self.status = format!("failed to update {}: {:#}", path, err);
+                return;
+            }
+        };
+        let Some(description) = plan.fixed.values().next() else {
+            self.status = format!("could not fix {}", path);
+            return;
+        };
+        self.status = format!("{}: {}", path, description);
+        self.fixed_paths.insert(path);
+        for i in plan.fixed.keys() {
+            self.acknowledged.insert(indices[*i]);
+        }
+    }
+
+    fn render(&mut self, frame: &mut Frame) {
+        let [list_area, panes_area, status_area] = Layout::vertical([
+            Constraint::Percentage(40),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [expected_area, origin_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(panes_area);
+
+        let items = self
+            .diagnostics
+            .iter()
+            .enumerate()
+            .map(|(i, diagnostic)| {
+                let marker = if self.acknowledged.contains(&i) {
+                    "[x] "
+                } else {
+                    "[ ] "
+                };
+                let item = ListItem::new(format!("{}{}", marker, diagnostic));
+                if self.acknowledged.contains(&i) {
+                    item.dim()
+                } else {
+                    item
+                }
+            })
+            .collect::<Vec<_>>();
+        let list = List::new(items)
+            .block(Block::bordered().title(" violations "))
+            .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, list_area, &mut self.list_state);
+
+        let selected = self.selected().cloned();
+        render_excerpt(
+            frame,
+            expected_area,
+            " expected change ",
+            selected.as_ref().map(|d| d.location()),
+        );
+        render_excerpt(
+            frame,
+            origin_area,
+            " because of change in ",
+            selected.and_then(|d| d.related),
+        );
+
+        frame.render_widget(
+            Paragraph::new(Line::from(vec![
+                Span::raw(self.status.as_str()).bold(),
+                Span::raw(HELP).dim(),
+            ])),
+            status_area,
+        );
+    }
+}
+
+fn diagnostic_path(diagnostic: &Diagnostic) -> String {
+    diagnostic.location().position().to_string()
+}
+
+fn render_excerpt(frame: &mut Frame, area: Rect, title: &str, location: Option<Location>) {
+    let Some(location) = location else {
+        frame.render_widget(Block::bordered().title(title.to_string()), area);
+        return;
+    };
+
+    let contents = std::fs::read_to_string(&location.path);
+    let lines = match &contents {
+        Ok(contents) => excerpt(contents, &location),
+        Err(err) => vec![Line::from(format!(
+            "cannot read {}: {}",
+            location.path, err
+        ))],
+    };
+
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(format!(
+            "{}{} ",
+            title,
+            location.position()
+        ))),
+        area,
+    );
+}
+
+/// Renders the lines of `location` plus some surrounding context, highlighting the lines that
+/// are actually part of `location`. Locations without a line range show the top of the file.
+fn excerpt<'a>(contents: &'a str, location: &Location) -> Vec<Line<'a>> {
+    let range = match (location.start_line, location.end_line) {
+        (Some(start), Some(end)) => start..end,
+        (Some(start), None) => start..start + 1,
+        _ => 0..0,
+    };
+    let first = range.start.saturating_sub(EXCERPT_CONTEXT_LINES);
+
+    contents
+        .lines()
+        .enumerate()
+        .skip(first)
+        .take(range.len() + 2 * EXCERPT_CONTEXT_LINES)
+        .map(|(i, line)| {
+            let line = Line::from(vec![
+                Span::raw(format!("{:>5} ", i + 1)).dim(),
+                Span::raw(line),
+            ]);
+            if range.contains(&i) {
+                line.bold()
+            } else {
+                line
+            }
+        })
+        .collect()
+}
+
+pub fn run(diagnostics: Vec<Diagnostic>) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = App::new(diagnostics).run(&mut terminal);
+    ratatui::restore();
+    result
+}
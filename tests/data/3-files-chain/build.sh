@@ -1,8 +1,8 @@
 export BUILD_PATH="target/opt/hello-world"
-# if-change
+# if-change(version)
 export VERSION="0.3.1-alpha"
 # then-change
-#   tests/data/3-files-incomplete/push.sh
+#   tests/data/3-files-chain/release.sh#version
 # end-change
 
 echo "set up build env step 1 of 3"
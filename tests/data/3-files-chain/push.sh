@@ -1,9 +1,8 @@
 export AWS_PROFILE=production
-# if-change
+# if-change(version)
 export VERSION="0.3.1-alpha"
 # then-change
-#   tests/data/3-files-incomplete/build.sh
-#   tests/data/3-files-incomplete/release.sh
+#   tests/data/3-files-chain/build.sh#version
 # end-change
 
 echo "checking AWS credentials to ensure they're valid"
@@ -1,8 +1,8 @@
 export AWS_PROFILE=production
-# if-change
+# if-change(version)
 export VERSION="0.3.1-alpha"
 # then-change
-#   tests/data/3-files-incomplete/push.sh
+#   tests/data/3-files-chain/push.sh#version
 # end-change
 
 echo "checking AWS credentials to ensure they're valid"
@@ -0,0 +1,7 @@
+echo "a"
+echo "a"
+echo "a"
+echo "a"
+# if-change
+X=2
+# then-change tests/data/ambiguous-blocks/b.sh
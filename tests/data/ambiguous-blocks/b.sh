@@ -0,0 +1,11 @@
+# if-change
+Y=1
+# then-change tests/data/ambiguous-blocks/a.sh
+echo "b"
+echo "b"
+echo "b"
+echo "b"
+echo "b"
+# if-change
+Z=1
+# then-change tests/data/ambiguous-blocks/a.sh
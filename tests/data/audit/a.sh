@@ -0,0 +1,3 @@
+# if-change
+echo a
+# then-change tests/data/audit/b.sh
@@ -0,0 +1,3 @@
+# if-change
+echo b
+# then-change tests/data/audit/a.sh
@@ -0,0 +1,4 @@
+echo c
+# if-change
+echo c
+# then-change tests/data/audit/d.sh
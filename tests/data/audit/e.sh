@@ -0,0 +1,6 @@
+# if-change
+echo e
+# then-change
+#   tests/data/audit/a.sh
+#   tests/data/lint/a.sh
+# end-change
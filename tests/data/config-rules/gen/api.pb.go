@@ -0,0 +1,6 @@
+package api
+
+type Request struct {
+	Path   string
+	Method string
+}
@@ -0,0 +1,3 @@
+# if-change
+TIMEOUT=30
+# then-change tests/data/crlf/a.sh
@@ -0,0 +1,10 @@
+# if-change
+TIMEOUT=30
+# then-change
+#   b.sh
+#   deleted.sh
+# end-change
+
+# if-change(retries)
+RETRIES=3
+# then-change gone.sh
@@ -0,0 +1,3 @@
+# if-change(hash=sha256:0123abcd)
+FORMAT=json
+# then-change tests/data/digests/producer.sh
@@ -0,0 +1,4 @@
+echo "producing v2"
+# if-change(hash=sha256:86e69a6f9936f0b117197ff99d8268977e6ec8a5989b95620ba6fe342a74ba4a)
+FORMAT=json
+# then-change tests/data/digests/consumer.sh
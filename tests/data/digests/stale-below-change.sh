@@ -0,0 +1,4 @@
+# if-change(hash=sha256:0123abcd)
+FORMAT=json
+# then-change https://wiki.example.com/internal/Formats
+echo "consuming v2"
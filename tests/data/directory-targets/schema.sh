@@ -0,0 +1,3 @@
+# if-change
+ENDPOINTS="/health /images"
+# then-change tests/data/directory-targets/docs/
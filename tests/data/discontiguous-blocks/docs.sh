@@ -0,0 +1,3 @@
+# if-change
+echo "flags: VERBOSE, DRY_RUN"
+# then-change tests/data/discontiguous-blocks/flags.sh#flags
@@ -0,0 +1,7 @@
+# if-change(name=flags)
+VERBOSE=1
+# then-change tests/data/discontiguous-blocks/docs.sh
+echo "parsing arguments"
+# if-change(name=flags)
+DRY_RUN=1
+# end-change
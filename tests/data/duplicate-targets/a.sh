@@ -0,0 +1,7 @@
+# if-change
+PORT=8081
+# then-change
+#   tests/data/duplicate-targets/b.sh
+#   tests/data/../data/duplicate-targets/b.sh
+#   tests/data/duplicate-targets/b.sh
+# end-change
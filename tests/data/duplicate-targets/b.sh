@@ -0,0 +1,3 @@
+# if-change
+PORT=8080
+# then-change tests/data/duplicate-targets/a.sh
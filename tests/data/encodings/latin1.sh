@@ -0,0 +1,4 @@
+# if-change
+# dlai en secondes
+DELAI=30
+# then-change tests/data/encodings/config.sh
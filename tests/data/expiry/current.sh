@@ -0,0 +1,3 @@
+# if-change(expires=2999-12-31)
+TIMEOUT=60
+# then-change tests/data/expiry/legacy.sh
@@ -0,0 +1,3 @@
+# if-change(expires=2001-01-01)
+TIMEOUT=60
+# then-change tests/data/expiry/current.sh
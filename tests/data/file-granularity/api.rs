@@ -0,0 +1,7 @@
+// if-change
+pub const VERSION: u32 = 3;
+// then-change CHANGELOG.md
+
+// if-change(granularity=file)
+pub const TIMEOUT_SECS: u32 = 30;
+// then-change settings.toml
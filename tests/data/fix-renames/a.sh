@@ -0,0 +1,3 @@
+# if-change
+PORT=8081
+# then-change old.sh
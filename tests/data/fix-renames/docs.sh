@@ -0,0 +1,5 @@
+# if-change
+echo "see the port in old.sh"
+# then-change
+#   ./old.sh
+# end-change
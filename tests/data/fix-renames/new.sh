@@ -0,0 +1,4 @@
+#!/bin/sh
+# if-change
+PORT=8080
+# then-change a.sh
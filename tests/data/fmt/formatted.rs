@@ -0,0 +1,9 @@
+// if-change
+const TIMEOUT: u32 = 30;
+// then-change
+//   tests/data/fmt/alpha.sh
+//   tests/data/fmt/beta.md
+// end-change
+/* if-change */
+const RETRIES: u32 = 3;
+/* then-change tests/data/fmt/alpha.sh */
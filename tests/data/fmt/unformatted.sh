@@ -0,0 +1,11 @@
+#if-change(api)
+API_VERSION=3
+#   then-change
+#  tests/data/fmt/zeta.sh
+#      tests/data/fmt/alpha.sh
+#   ?tests/data/fmt/beta.md
+#end-change
+
+  # IfChange
+  TIMEOUT=30
+  #then-change   tests/data/fmt/this/path/is/long/enough/that/the/directive/goes/past/the/limit.sh
@@ -0,0 +1,10 @@
+// Code generated by protoc-gen-go. DO NOT EDIT.
+// source: api.proto
+
+package api
+
+type Request struct {
+	// if-change
+	Timeout int32
+	// then-change tests/data/generated/api.pb.go
+}
@@ -0,0 +1,9 @@
+// LINT.IfChange(flags)
+const char* kFlags[] = {
+    "verbose",
+    "dry_run",
+};
+// LINT.ThenChange(
+//     //tests/data/google-style/flags.h:flags,
+//     //tests/data/google-style/flags.md,
+// )
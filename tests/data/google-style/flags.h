@@ -0,0 +1,3 @@
+// LINT.IfChange(flags)
+extern const char* kFlags[2];
+// LINT.ThenChange(//tests/data/google-style/flags.cc:flags)
@@ -0,0 +1,5 @@
+// if-change
+/// How long to wait for a response, in seconds; keep in sync with the
+/// client's retry budget.
+const TIMEOUT: u32 = 30;
+// then-change b.py
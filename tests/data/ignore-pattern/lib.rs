@@ -0,0 +1,3 @@
+// if-change
+pub const NAME: &str = "ictc";
+// then-change tests/data/ignore-pattern/pkg.toml
@@ -0,0 +1,6 @@
+# if-change
+def timeout(retries):
+    return sum(
+        30 * attempt for attempt in range(retries)
+    )
+# then-change b.py
@@ -0,0 +1,5 @@
+# IfChange
+class User:
+    id: int
+    email: str
+# ThenChange schema.sql
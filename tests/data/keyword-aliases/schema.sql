@@ -0,0 +1,6 @@
+-- IfChange
+CREATE TABLE users (
+  id INTEGER PRIMARY KEY,
+  email TEXT NOT NULL
+);
+-- ThenChange models.py
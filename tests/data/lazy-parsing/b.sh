@@ -0,0 +1,3 @@
+# if-change
+export BUCKET="s3://lazy-parsing/"
+# then-change tests/data/lazy-parsing/a.sh
@@ -0,0 +1,5 @@
+echo "starting up"
+# if-change
+export BUCKET="s3://lazy-parsing/"
+# then-change nonexistent.sh
+echo "shutting down"
@@ -0,0 +1,4 @@
+echo "starting up"
+# if-change
+export BUCKET="s3://lazy-parsing/"
+echo "shutting down"
@@ -0,0 +1,5 @@
+echo "building"
+# if-change
+export PORTS="8080 8443"
+# then-change tests/data/line-range-targets/config.yaml:3-5
+echo "done"
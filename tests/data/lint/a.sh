@@ -0,0 +1,4 @@
+echo "starting"
+# if-change
+export PORT=8080
+# then-change tests/data/lint/b.sh
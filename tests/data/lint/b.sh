@@ -0,0 +1,7 @@
+# if-change
+curl localhost:8080
+# then-change
+#   tests/data/lint/a.sh
+#   tests/data/lint/b.sh
+#   tests/data/lint/nonexistent.sh
+# end-change
@@ -0,0 +1,2 @@
+# if-change
+echo "never closed"
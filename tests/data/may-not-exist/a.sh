@@ -0,0 +1,5 @@
+echo "starting watchdog thread"
+# if-change
+echo "starting handler for /image-api/v1"
+echo "starting handler for /video-api/v1"
+# then-change tests/data/may-not-exist/fork-only.sh?exists=optional
@@ -0,0 +1,10 @@
+export LOG="thumbnail=debug;video=info;"
+# if-change
+<<<<<<< HEAD
+export VIDEO_BUCKET="s3://video-service/"
+# then-change tests/data/merge-conflict/b.sh
+=======
+export VIDEO_BUCKET="s3://video-service-v2/"
+# then-change tests/data/merge-conflict/c.sh
+>>>>>>> rename-bucket
+export VIDEO_CONFIG="video-service/config.json"
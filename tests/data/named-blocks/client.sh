@@ -0,0 +1,9 @@
+# if-change(api-version)
+echo "client for api v3"
+# then-change tests/data/named-blocks/schema.sh#api-version
+# if-change(api-schema)
+echo "expects id, name, email"
+# then-change tests/data/named-blocks/schema.sh#api-schema
+# if-change(typo)
+echo "points at a block that does not exist"
+# then-change tests/data/named-blocks/schema.sh#api-shema
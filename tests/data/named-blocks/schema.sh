@@ -0,0 +1,6 @@
+# if-change(api-schema)
+FIELDS="id name email"
+# then-change tests/data/named-blocks/client.sh#api-schema
+# if-change(api-version)
+VERSION=3
+# then-change tests/data/named-blocks/client.sh#api-version
@@ -0,0 +1,7 @@
+# if-change
+TIMEOUT=30
+# if-change
+PORT=8080
+# then-change tests/data/nested-blocks/server.sh
+RETRIES=5
+# then-change tests/data/nested-blocks/docs.sh
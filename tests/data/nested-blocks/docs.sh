@@ -0,0 +1,3 @@
+# if-change
+echo "timeout 30s, port 8080, 5 retries"
+# then-change tests/data/nested-blocks/config.sh
@@ -0,0 +1,3 @@
+# if-change
+LISTEN_PORT=8080
+# then-change tests/data/nested-blocks/config.sh
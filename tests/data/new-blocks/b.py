@@ -0,0 +1 @@
+PORT = 8080
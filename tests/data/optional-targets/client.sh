@@ -0,0 +1,3 @@
+# if-change
+echo "client for id, name and email"
+# then-change tests/data/optional-targets/schema.sh
@@ -0,0 +1,4 @@
+echo "release notes"
+# if-change
+echo "fields: id, name"
+# then-change? tests/data/optional-targets/schema.sh
@@ -0,0 +1,6 @@
+# if-change
+FIELDS="id name email"
+# then-change
+#   tests/data/optional-targets/client.sh
+#   ?tests/data/optional-targets/notes.sh
+# end-change
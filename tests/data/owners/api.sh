@@ -0,0 +1,3 @@
+# if-change
+ENDPOINT="/v2/users"
+# then-change tests/data/owners/handler.sh
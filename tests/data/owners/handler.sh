@@ -0,0 +1,3 @@
+# if-change owner=@backend-team
+ROUTE="/v1/users"
+# then-change tests/data/owners/api.sh
@@ -0,0 +1,5 @@
+#!/bin/sh
+# if-change
+PORT=8080
+# then-change tests/data/pure-deletion/b.sh
+echo done
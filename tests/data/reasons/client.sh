@@ -0,0 +1,3 @@
+# if-change
+SERVER="localhost:8080"
+# then-change tests/data/reasons/server.sh
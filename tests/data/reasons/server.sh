@@ -0,0 +1,3 @@
+# if-change
+PORT=8080
+# then-change tests/data/reasons/client.sh -- keep the client pointed at the server
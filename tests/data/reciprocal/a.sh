@@ -0,0 +1,3 @@
+# if-change
+RETRIES=3
+# then-change tests/data/reciprocal/b.sh#config
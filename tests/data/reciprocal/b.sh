@@ -0,0 +1,4 @@
+# if-change(config)
+RETRIES=3
+TIMEOUT=30
+# then-change tests/data/reciprocal/c.sh
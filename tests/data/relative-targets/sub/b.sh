@@ -0,0 +1,8 @@
+echo "building video service"
+echo "indexing video assets"
+# if-change
+echo "serving from s3://video-service/ and s3://video-thumbnails/"
+# then-change
+#   ../a.sh
+# end-change
+echo "service started on port 0000"
@@ -0,0 +1,6 @@
+echo "building video service"
+echo "indexing video assets"
+# if-change
+echo "serving from s3://video-service/ and s3://video-thumbnails/"
+# then-change //tests/data/root-targets/a.sh
+echo "service started on port 0000"
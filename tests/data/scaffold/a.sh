@@ -0,0 +1,6 @@
+# if-change(version)
+VERSION="1.4.0"
+# then-change
+#   b.py
+#   c.rs#version
+# end-change
@@ -0,0 +1,6 @@
+import sys
+
+def main():
+    print("starting")
+    VERSION = "1.3.0"
+    print(VERSION)
@@ -0,0 +1,4 @@
+fn main() {
+    // ictc-scaffold
+    let version = "1.3.0";
+}
@@ -0,0 +1,6 @@
+# if-change
+PORT=8081
+# then-change
+#   a.sh
+#   b.sh
+# end-change
@@ -0,0 +1,3 @@
+# if-change owner=@net
+PORT = 8080
+# then-change a.sh
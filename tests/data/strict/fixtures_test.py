@@ -0,0 +1,9 @@
+FIXTURE = """
+# if-change
+timeout = 30
+# then-change config.py
+"""
+
+
+def test_parse():
+    assert parse(FIXTURE).blocks
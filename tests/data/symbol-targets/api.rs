@@ -0,0 +1,13 @@
+// Handlers for the image service.
+
+fn health() -> &'static str {
+    "ok"
+}
+
+fn handle_request(path: &str) -> String {
+    match path {
+        "/health" => health().to_string(),
+        "/images" => "images".to_string(),
+        _ => "not found".to_string(),
+    }
+}
@@ -0,0 +1,4 @@
+echo "registering routes"
+# if-change
+ROUTES="/health /images"
+# then-change tests/data/symbol-targets/api.rs@fn handle_request
@@ -0,0 +1,7 @@
+echo "deploying"
+# if-change
+REGIONS="us-east-1 eu-west-1"
+# then-change-any
+#   tests/data/then-change-any/release-prod.sh
+#   tests/data/then-change-any/release-staging.sh
+# end-change
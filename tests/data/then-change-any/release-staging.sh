@@ -0,0 +1,4 @@
+echo "releasing to staging"
+# if-change
+REGION="us-east-1"
+# then-change tests/data/then-change-any/deploy.sh
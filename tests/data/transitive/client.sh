@@ -0,0 +1,4 @@
+echo "starting client"
+# if-change(api)
+CLIENT_API_VERSION=3
+# then-change docs.sh#api
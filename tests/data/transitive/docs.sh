@@ -0,0 +1,3 @@
+# if-change(api)
+echo "the client speaks version 3 of the API"
+# then-change client.sh#api
@@ -0,0 +1,3 @@
+# if-change(api)
+API_VERSION=3
+# then-change client.sh#api
@@ -0,0 +1,3 @@
+# if-change
+echo hello
+# then-change tests/data/unreadable/binary.dat
@@ -0,0 +1,3 @@
+# if-change
+HEADER_SIZE=16
+# then-change https://wiki.example.com/internal/WireFormat#header
@@ -0,0 +1,3 @@
+// if-change(version)
+pub const VERSION: &str = "1.3.0";
+// then-change package.json#version
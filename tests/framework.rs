@@ -2,7 +2,7 @@ use assert_cmd::prelude::*;
 
 use anyhow::anyhow;
 use std::fs::File;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct ToolOutput {
@@ -12,11 +12,29 @@ pub struct ToolOutput {
 
 // data_path is relative to repository root
 pub fn run_tool(data_path: &str) -> anyhow::Result<ToolOutput> {
+    run_tool_with_args(&[], data_path)
+}
+
+pub fn run_tool_with_args(args: &[&str], data_path: &str) -> anyhow::Result<ToolOutput> {
+    run_tool_in_dir(".", args, data_path)
+}
+
+// Runs the tool from dir (relative to repository root), e.g. to pick up a config file there;
+// data_path is still relative to repository root.
+pub fn run_tool_in_dir(dir: &str, args: &[&str], data_path: &str) -> anyhow::Result<ToolOutput> {
     let mut cmd = Command::cargo_bin("to-be-named")?;
 
+    cmd.current_dir(dir);
+
+    cmd.args(args);
     cmd.env("RUST_BACKTRACE", "1");
     cmd.env("RUST_LOG", "debug");
-    cmd.stdin(File::open(data_path)?);
+    // /dev/null only exists on Unix.
+    if data_path == "/dev/null" {
+        cmd.stdin(Stdio::null());
+    } else {
+        cmd.stdin(File::open(data_path)?);
+    }
 
     let output = cmd.output()?;
 
@@ -25,11 +43,11 @@ pub fn run_tool(data_path: &str) -> anyhow::Result<ToolOutput> {
         String::from_utf8(output.stderr)?
     );
 
-    return Ok(ToolOutput {
+    Ok(ToolOutput {
         stdout: String::from_utf8(output.stdout)?,
         exit_code: output
             .status
             .code()
             .ok_or(anyhow!("No exit code - process was cancelled, maybe?"))?,
-    });
+    })
 }